@@ -0,0 +1,60 @@
+// Headless verification: loads a machine (config JSON or built-in template)
+// and a G-code file, verifies the program offline and prints the job report
+// JSON. Exit code 0 = clean, 1 = alarms, incomplete run or travel exceeded,
+// 2 = bad input.
+//
+//   vmill-verify <machine.json|template> <program.nc> [--channel N]
+
+use machine_core::MachineBrain;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: vmill-verify <machine.json|template> <program.nc> [--channel N]";
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1).collect()) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(msg) => {
+            eprintln!("vmill-verify: {msg}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run(args: Vec<String>) -> Result<bool, String> {
+    let mut positional = Vec::new();
+    let mut channel = 0usize;
+    let mut it = args.into_iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--channel" => {
+                let value = it.next().ok_or(USAGE)?;
+                channel = value.parse().map_err(|_| format!("bad channel index '{value}'"))?;
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => positional.push(arg),
+        }
+    }
+    let [machine, program] = positional.as_slice() else {
+        return Err(USAGE.to_string());
+    };
+
+    let mut brain = MachineBrain::new();
+    if machine.ends_with(".json") {
+        let json = std::fs::read_to_string(machine).map_err(|e| format!("{machine}: {e}"))?;
+        let report = brain.apply_config_json(&json);
+        if let Some(err) = report.error {
+            return Err(format!("{machine}: {err}"));
+        }
+    } else if !brain.load_machine_template(machine.clone()) {
+        return Err(format!("unknown machine template '{machine}'"));
+    }
+
+    let code = std::fs::read_to_string(program).map_err(|e| format!("{program}: {e}"))?;
+    let report = brain
+        .verification_report(channel, &code)
+        .ok_or_else(|| format!("machine has no channel {channel}"))?;
+    let json = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+    println!("{json}");
+    Ok(report.passed())
+}
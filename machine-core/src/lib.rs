@@ -1,6 +1,8 @@
+
 use wasm_bindgen::prelude::*;
 use serde::{Serialize, Deserialize};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::rc::Rc;
 
 // --- LOGGING ---
 #[cfg(target_arch = "wasm32")]
@@ -12,8 +14,111 @@ extern "C" {
 
 #[cfg(not(target_arch = "wasm32"))]
 fn log(_s: &str) {}
-macro_rules! console_log {
-    ($($t:tt)*) => (log(&format!($($t)*)))
+
+// `log_at!(brain, Info, "homing", "...", args)` writes through the brain's
+// `Logger`. The message is only formatted when the level passes the filter.
+macro_rules! log_at {
+    ($brain:expr, $level:ident, $subsystem:expr, $($t:tt)*) => {
+        if $brain.logger.enabled(LogLevel::$level, $subsystem) {
+            let at_s = $brain.sim_time_s;
+            $brain.logger.write(at_s, LogLevel::$level, $subsystem, format!($($t)*));
+        }
+    };
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+}
+
+impl LogLevel {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "trace" => Some(LogLevel::Trace),
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" | "warning" => Some(LogLevel::Warn),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LogLevel::Trace => "TRACE",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct LogRecord {
+    pub at_s: f64, // simulation time
+    pub level: LogLevel,
+    pub subsystem: String, // core, interpreter, homing, alarms, ...
+    pub message: String,
+}
+
+const LOG_BUFFER_DEFAULT: usize = 500;
+
+// Leveled log with a threshold per subsystem (None = off) and three sinks:
+// the browser console, a ring buffer read by `get_log_records`, and a JS
+// callback receiving each `LogRecord`.
+#[derive(Clone)]
+struct Logger {
+    level: Option<LogLevel>, // subsystems without their own threshold
+    subsystems: HashMap<String, Option<LogLevel>>,
+    console: bool,
+    buffer: VecDeque<LogRecord>,
+    buffer_max: usize, // 0 = ring buffer off
+    callback: Option<js_sys::Function>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger {
+            level: Some(LogLevel::Info),
+            subsystems: HashMap::new(),
+            console: true,
+            buffer: VecDeque::new(),
+            buffer_max: LOG_BUFFER_DEFAULT,
+            callback: None,
+        }
+    }
+}
+
+impl Logger {
+    fn enabled(&self, level: LogLevel, subsystem: &str) -> bool {
+        let threshold = self.subsystems.get(subsystem).copied().unwrap_or(self.level);
+        threshold.is_some_and(|t| level >= t) && (self.console || self.buffer_max > 0 || self.callback.is_some())
+    }
+
+    fn write(&mut self, at_s: f64, level: LogLevel, subsystem: &str, message: String) {
+        let record = LogRecord { at_s, level, subsystem: subsystem.to_string(), message };
+        if self.console {
+            log(&format!("[{}] {}: {}", record.level.name(), record.subsystem, record.message));
+        }
+        if let Some(callback) = &self.callback {
+            let arg = serde_wasm_bindgen::to_value(&record).unwrap_or(JsValue::NULL);
+            let _ = callback.call1(&JsValue::NULL, &arg);
+        }
+        if self.buffer_max > 0 {
+            self.buffer.push_back(record);
+            while self.buffer.len() > self.buffer_max {
+                self.buffer.pop_front();
+            }
+        }
+    }
+
+    // Offline copies of the machine (verification, scans) log nowhere.
+    fn silence(&mut self) {
+        self.level = None;
+        self.subsystems.clear();
+    }
 }
 
 #[cfg(test)]
@@ -32,51 +137,18 @@ mod tests {
 
     fn make_xyz_brain() -> MachineBrain {
         let mut brain = MachineBrain::new();
-        let x = brain.add_axis("X".to_string(), AxisType::Linear, -10_000.0, 10_000.0);
-        let y = brain.add_axis("Y".to_string(), AxisType::Linear, -10_000.0, 10_000.0);
-        let z = brain.add_axis("Z".to_string(), AxisType::Linear, -10_000.0, 10_000.0);
+        let x = brain.add_axis("X".to_string(), AxisType::Linear, -10_000.0, 10_000.0).unwrap();
+        let y = brain.add_axis("Y".to_string(), AxisType::Linear, -10_000.0, 10_000.0).unwrap();
+        let z = brain.add_axis("Z".to_string(), AxisType::Linear, -10_000.0, 10_000.0).unwrap();
 
-        brain.channels.push(Channel {
-            id: 0,
-            axis_map: vec![
+        brain.channels.push(Channel::new(
+            0,
+            vec![
                 ChannelAxisMap { axis_id: x, display_label: "X".to_string() },
                 ChannelAxisMap { axis_id: y, display_label: "Y".to_string() },
                 ChannelAxisMap { axis_id: z, display_label: "Z".to_string() },
             ],
-            is_running: false,
-            paused: false,
-            pc: 0,
-            active_pc: -1,
-            program: Vec::new(),
-            feed_rate: 1000.0,
-            current_motion: 0,
-            abs_mode: true,
-            units_mm: true,
-            plane: 17,
-            exact_stop: false,
-            cutter_comp: 40,
-            tool_radius: 4.0,
-            length_comp_active: false,
-            tool_length: 50.0,
-            active_tool: 0,
-            active_d: 0,
-            active_h: 0,
-            spindle_rpm: 0.0,
-            spindle_mode: 5,
-            coolant_on: false,
-            feed_override: 1.0,
-            single_block: false,
-            step_once: false,
-            pause_pending: false,
-            tool_table: HashMap::from([
-                (0, ToolTableEntry { radius: 4.0, length: 50.0 }),
-                (1, ToolTableEntry { radius: 4.0, length: 50.0 }),
-            ]),
-            comp_linear_prev: None,
-            comp_entry_pending: false,
-            pending: VecDeque::new(),
-            programmed_work: HashMap::new(),
-        });
+        ));
 
         brain
     }
@@ -122,7 +194,8 @@ mod tests {
             .pending
             .back()
             .expect("expected arc segments for G41")
-            .clone();
+            .targets()
+            .to_vec();
         let g41_y = last_g41.iter().find(|(id, _)| *id == 1).map(|(_, v)| *v).unwrap_or(0.0);
         approx_eq(g41_y, 8.0);
 
@@ -134,7 +207,8 @@ mod tests {
             .pending
             .back()
             .expect("expected arc segments for G42")
-            .clone();
+            .targets()
+            .to_vec();
         let g42_y = last_g42.iter().find(|(id, _)| *id == 1).map(|(_, v)| *v).unwrap_or(0.0);
         approx_eq(g42_y, 12.0);
     }
@@ -208,7 +282,7 @@ mod tests {
         approx_eq(brain.axes[1].target, -7.0);
 
         // Then queued final target follows the offset contour.
-        let final_seg = brain.channels[0].pending.back().expect("expected queued final segment");
+        let final_seg = brain.channels[0].pending.back().expect("expected queued final segment").targets();
         let fx = final_seg
             .iter()
             .find(|(id, _)| *id == 0)
@@ -277,7 +351,7 @@ mod tests {
         assert!(brain.channels[0].length_comp_active);
         assert_eq!(brain.channels[0].cutter_comp, 41);
 
-        brain.parse_line(0, "T0");
+        brain.parse_line(0, "T0 M6");
         assert_eq!(brain.channels[0].active_tool, 0);
         approx_eq(brain.channels[0].tool_length, 0.0);
         approx_eq(brain.channels[0].tool_radius, 0.0);
@@ -293,7 +367,7 @@ mod tests {
         // First compensated single-axis block inserts an entry point onto the offset line.
         approx_eq(brain.axes[0].target, 0.0);
         approx_eq(brain.axes[1].target, 4.0);
-        let first_final = brain.channels[0].pending.back().expect("expected final entry move");
+        let first_final = brain.channels[0].pending.back().expect("expected final entry move").targets();
         let x1 = first_final.iter().find(|(id, _)| *id == 0).map(|(_, v)| *v).unwrap_or(f64::NAN);
         let y1 = first_final.iter().find(|(id, _)| *id == 1).map(|(_, v)| *v).unwrap_or(f64::NAN);
         approx_eq(x1, 10.0);
@@ -306,7 +380,7 @@ mod tests {
         // Inside corner uses miter join at intersection (6,4), then final endpoint (6,10).
         approx_eq(brain.axes[0].target, 6.0);
         approx_eq(brain.axes[1].target, 4.0);
-        let last = brain.channels[0].pending.back().expect("expected queued final segment");
+        let last = brain.channels[0].pending.back().expect("expected queued final segment").targets();
         let x = last.iter().find(|(id, _)| *id == 0).map(|(_, v)| *v).unwrap_or(f64::NAN);
         let y = last.iter().find(|(id, _)| *id == 1).map(|(_, v)| *v).unwrap_or(f64::NAN);
         approx_eq(x, 6.0);
@@ -324,6 +398,12 @@ mod tests {
 
         brain.parse_line(0, "G21 G1 X2");
         approx_eq(brain.axes[0].target, 2.0);
+
+        // Rotary words stay in degrees under G20.
+        let a = brain.add_axis("A".to_string(), AxisType::Rotary, -360.0, 360.0).unwrap();
+        brain.channels[0].axis_map.push(ChannelAxisMap { axis_id: a, display_label: "A".to_string() });
+        brain.parse_line(0, "G20 G0 A10");
+        approx_eq(find_axis(&brain.axes, a).unwrap().target, 10.0);
     }
 
     #[test]
@@ -383,439 +463,8983 @@ mod tests {
         approx_eq(brain.axes[1].target, -1.4);
         approx_eq(brain.axes[2].target, 7.0);
     }
-}
-#[wasm_bindgen]
-#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
-pub enum AxisType { Linear, Rotary }
 
-#[derive(Serialize, Clone)]
-pub struct Axis {
-    pub id: u32,
-    pub physical_name: String,
-    pub position: f64,
-    pub target: f64,
-    pub axis_type: AxisType,
-    pub min_range: f64,
-    pub max_range: f64,
-    pub homed: bool,
-    pub velocity: f64,   // current speed mm/min
-    pub accel: f64,      // mm/min per second²
-    pub invert: bool,    // flip direction in 3D view
-    pub machine_zero: f64,
-}
+    #[test]
+    fn m30_counts_parts_and_stops_when_target_reached() {
+        let mut brain = make_xyz_brain();
+        brain.set_part_target(0, 2);
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct ChannelAxisMap {
-    pub axis_id: u32,
-    pub display_label: String,
-}
+        brain.parse_line(0, "M30");
+        assert_eq!(brain.channels[0].part_count, 1);
+        assert!(!brain.channels[0].parts_target_reached);
 
-#[derive(Serialize)]
-pub struct ChannelStatus {
-    pub id: u32,
-    pub is_running: bool,
-    pub paused: bool,
-    pub active_pc: i32,
-    pub pc: usize,
-    pub axis_map: Vec<ChannelAxisMap>,
-    pub current_motion: i32,
-    pub exact_stop: bool,
-    pub cutter_comp: i32,
-    pub tool_radius: f64,
-    pub length_comp_active: bool,
-    pub tool_length: f64,
-    pub active_tool: i32,
-    pub active_d: i32,
-    pub active_h: i32,
-    pub spindle_rpm: f64,
-    pub spindle_mode: i32,
-    pub coolant_on: bool,
-    pub feed_rate: f64,
-    pub feed_override: f64,
-    pub single_block: bool,
-    pub programmed_work: Vec<AxisOffset>,
-}
+        brain.channels[0].is_running = true;
+        brain.parse_line(0, "M30");
+        assert_eq!(brain.channels[0].part_count, 2);
+        assert_eq!(brain.channels[0].parts_total, 2);
+        assert!(brain.channels[0].parts_target_reached);
+        assert!(!brain.channels[0].is_running);
 
-pub struct Channel {
-    pub id: u32,
-    pub axis_map: Vec<ChannelAxisMap>,
-    pub is_running: bool,
-    pub paused: bool,
-    pub pc: usize,
-    pub active_pc: i32,
-    pub program: Vec<String>,
-    pub feed_rate: f64,
-    pub current_motion: i32, // last commanded motion mode (0/1/2/3)
+        // Counter reset clears the reached flag but keeps the lifetime total.
+        brain.reset_part_count(0);
+        assert_eq!(brain.channels[0].part_count, 0);
+        assert_eq!(brain.channels[0].parts_total, 2);
+        assert!(!brain.channels[0].parts_target_reached);
 
-    // --- Simple modal state (per-channel) ---
-    pub abs_mode: bool,      // G90/G91
-    pub units_mm: bool,      // G21(true)/G20(false)
-    pub plane: u8,           // 17=XY only for now
-    pub exact_stop: bool,    // G61 exact-stop / G64 continuous
-    pub cutter_comp: i32,    // 40/41/42
-    pub tool_radius: f64,    // D value (mm)
-    pub length_comp_active: bool, // G43/G49
-    pub tool_length: f64,    // H value (mm)
-    pub active_tool: i32,    // T value
-    pub active_d: i32,       // Active D slot number
-    pub active_h: i32,       // Active H slot number
-    pub spindle_rpm: f64,    // S value (RPM)
-    pub spindle_mode: i32,   // M3/M4/M5
-    pub coolant_on: bool,    // M8/M9
-    pub feed_override: f64,  // 0.0..2.0 multiplier
-    pub single_block: bool,  // stop after each completed block
-    pub step_once: bool,     // run one block then hold
-    pub pause_pending: bool, // internal: wait block completion then pause
-    // Tool compensation table, indexed by D/H number.
-    // Slot 0 is treated as the active/default tool.
-    tool_table: HashMap<i32, ToolTableEntry>,
-    // Last compensated linear segment for corner intersection smoothing.
-    comp_linear_prev: Option<CompLinearState>,
-    // True when G41/G42 was armed without an XY move and still needs first-entry transition.
-    comp_entry_pending: bool,
-    // Pending linear targets (expanded arcs). Each entry is (axis_id, machine_target)
-    pending: VecDeque<Vec<(u32, f64)>>,
-    // Programmed work-coordinate position (uncompensated geometry), per axis.
-    programmed_work: HashMap<u32, f64>,
-}
+        // A dedicated counting M code replaces M30.
+        brain.set_part_count_m_code(0, 99);
+        brain.parse_line(0, "M30");
+        assert_eq!(brain.channels[0].part_count, 0);
+        brain.parse_line(0, "M99");
+        assert_eq!(brain.channels[0].part_count, 1);
+    }
 
-#[derive(Clone, Copy)]
-struct ToolTableEntry {
-    radius: f64,
-    length: f64,
-}
+    #[test]
+    fn utilization_timeline_merges_spans_and_summarizes_states() {
+        let mut brain = make_xyz_brain();
+        brain.tick(500.0);
+        brain.tick(500.0);
+        brain.load_program(0, "G1 X100 F6000".to_string());
+        brain.tick(250.0);
+        brain.set_estop(true);
+        brain.tick(250.0);
 
-#[derive(Clone, Copy)]
-struct CompLinearState {
-    end_prog_x: f64,
-    end_prog_y: f64,
-    end_off_x: f64,
-    end_off_y: f64,
-    dir_x: f64,
-    dir_y: f64,
-    mode: i32,
-    radius: f64,
-}
+        let states: Vec<MachineActivity> = brain.utilization_spans.iter().map(|s| s.state).collect();
+        assert_eq!(states, vec![MachineActivity::Idle, MachineActivity::Running, MachineActivity::Alarm]);
+        approx_eq(brain.utilization_spans[0].end_s, 1.0);
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct AxisOffset {
-    pub axis_id: u32,
-    pub value: f64,
-}
+        let summary = brain.utilization_summary();
+        approx_eq(summary.total_s, 1.5);
+        approx_eq(summary.idle_s, 1.0);
+        approx_eq(summary.running_s, 0.25);
+        approx_eq(summary.alarm_s, 0.25);
+        approx_eq(summary.utilization, 0.25 / 1.5);
+    }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct WorkOffset {
-    pub label: String,
-    pub offsets: Vec<AxisOffset>,
-}
+    #[test]
+    fn observability_snapshot_maps_execution_and_conditions() {
+        let mut brain = make_xyz_brain();
+        let snap = brain.observability_snapshot();
+        assert_eq!(snap.emergency_stop, "ARMED");
+        assert_eq!(snap.paths[0].execution, "READY");
+        assert_eq!(snap.paths[0].controller_mode, "MANUAL");
+        assert_eq!(snap.conditions[0].level, "NORMAL");
+        assert_eq!(snap.axes.len(), 3);
 
-#[derive(Serialize)]
-pub struct MachineState {
-    pub axes: Vec<Axis>,
-    pub channels: Vec<ChannelStatus>,
-    pub estop: bool,
-    pub feed_hold: bool,
-    pub active_wcs: usize,
-    pub work_offsets: Vec<WorkOffset>,
-    pub is_homing: bool,
-}
+        brain.load_program(0, "G1 X10 F600".to_string());
+        brain.set_feed_override(0, 0.5);
+        let snap = brain.observability_snapshot();
+        assert_eq!(snap.paths[0].execution, "ACTIVE");
+        assert_eq!(snap.paths[0].controller_mode, "AUTOMATIC");
+        approx_eq(snap.paths[0].path_feedrate_override, 50.0);
 
-#[wasm_bindgen]
-pub struct MachineBrain {
-    axes: Vec<Axis>,
-    channels: Vec<Channel>,
-    estop: bool,
-    feed_hold: bool,
-    work_offsets: Vec<WorkOffset>,
-    active_wcs: usize,
-    is_homing: bool,
-    homing_sequence: Vec<u32>,
-    homing_index: usize,
-    homing_feed: f64,
-    homing_rapid: bool,
-}
+        brain.set_feed_hold(true);
+        assert_eq!(brain.observability_snapshot().paths[0].execution, "FEED_HOLD");
 
-fn default_work_offsets() -> Vec<WorkOffset> {
-    vec![
-        WorkOffset { label: "G54".to_string(), offsets: Vec::new() },
-        WorkOffset { label: "G55".to_string(), offsets: Vec::new() },
-        WorkOffset { label: "G56".to_string(), offsets: Vec::new() },
-        WorkOffset { label: "G57".to_string(), offsets: Vec::new() },
-        WorkOffset { label: "G58".to_string(), offsets: Vec::new() },
-        WorkOffset { label: "G59".to_string(), offsets: Vec::new() },
-        WorkOffset { label: "G153".to_string(), offsets: Vec::new() },
-    ]
-}
+        brain.set_estop(true);
+        let snap = brain.observability_snapshot();
+        assert_eq!(snap.emergency_stop, "TRIGGERED");
+        assert_eq!(snap.paths[0].execution, "STOPPED");
+        assert_eq!(snap.conditions[0].level, "FAULT");
+    }
 
-fn normalize_rotary_target(value: f64) -> f64 {
-    let mut wrapped = value % 360.0;
-    if wrapped > 180.0 {
-        wrapped -= 360.0;
-    } else if wrapped <= -180.0 {
-        wrapped += 360.0;
+    #[test]
+    fn alarm_lifecycle_counts_occurrences_and_moves_to_history() {
+        let mut brain = make_xyz_brain();
+        brain.tick(1000.0);
+        brain.set_estop(true);
+        brain.set_estop(false);
+        brain.set_estop(true);
+        assert_eq!(brain.alarms.len(), 1);
+        assert_eq!(brain.alarms[0].occurrences, 2);
+        assert!(brain.alarms[0].active);
+        approx_eq(brain.alarms[0].raised_at_s, 1.0);
+
+        // Acknowledged but still active: stays open until the condition clears.
+        let id = brain.alarms[0].id;
+        assert!(brain.acknowledge_alarm(id));
+        assert_eq!(brain.alarms.len(), 1);
+        brain.set_estop(false);
+        assert!(brain.alarms.is_empty());
+        assert_eq!(brain.alarm_history.len(), 1);
+
+        // Cleared but unacknowledged: stays open until acknowledged.
+        brain.raise_alarm("TEST", "test fault", AlarmSeverity::Warning, Some(0));
+        brain.clear_alarm_condition("TEST", Some(0));
+        assert_eq!(brain.alarms.len(), 1);
+        assert!(!brain.alarms[0].active);
+        brain.acknowledge_all_alarms();
+        assert_eq!(brain.alarm_history.len(), 2);
+
+        let json = brain.export_alarm_history();
+        let mut restored = make_xyz_brain();
+        assert!(restored.import_alarm_history(&json));
+        assert_eq!(restored.alarm_history.len(), 2);
+        assert_eq!(restored.alarm_history[0].code, "ESTOP");
+        assert!(restored.next_alarm_id > brain.alarm_history[1].id);
+        assert!(!restored.import_alarm_history("not json"));
+
+        // Open alarms lead the export; an id at the top of the range imports.
+        brain.raise_alarm("OPEN", "still open", AlarmSeverity::Warning, None);
+        let exported: Vec<Alarm> = serde_json::from_str(&brain.export_alarm_history()).unwrap();
+        assert_eq!(exported.len(), 3);
+        assert_eq!(exported[0].code, "OPEN");
+        let mut last = exported[0].clone();
+        last.id = u32::MAX;
+        assert!(restored.import_alarm_history(&serde_json::to_string(&[last]).unwrap()));
     }
-    wrapped
-}
 
-const RAPID_LINEAR_MIN_MM_MIN: f64 = 50_000.0; // 50 m/min
-const RAPID_LINEAR_MAX_MM_MIN: f64 = 80_000.0; // 80 m/min
-const RAPID_ROTARY_MIN_DEG_MIN: f64 = 6_000.0;
-const RAPID_ROTARY_MAX_DEG_MIN: f64 = 30_000.0;
+    #[test]
+    fn verify_program_reports_coverage_and_path_without_touching_live_state() {
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G40");
+        let program = "%\n(FACE)\nG0 X0 Y0\n\nG1 X10 F500\nG1 Y10\nM30\n";
+        let run = brain.run_verification(0, program).expect("channel exists");
+        assert!(run.completed);
+        assert_eq!(run.coverage.total_blocks, 4);
+        assert_eq!(run.coverage.executed_blocks, 4);
+        approx_eq(run.coverage.percent, 100.0);
+        assert_eq!(run.coverage.hits[2], 1);
+        assert_eq!(run.coverage.hits[1], 1); // comment lines are still visited
 
-fn axis_rapid_feed(ax: &Axis) -> f64 {
-    match ax.axis_type {
-        AxisType::Linear => (ax.accel.max(1.0) * 30.0).clamp(RAPID_LINEAR_MIN_MM_MIN, RAPID_LINEAR_MAX_MM_MIN),
-        AxisType::Rotary => (ax.accel.max(1.0) * 20.0).clamp(RAPID_ROTARY_MIN_DEG_MIN, RAPID_ROTARY_MAX_DEG_MIN),
+        let last = run.path.last().expect("expected path points");
+        assert_eq!(last.block, 5);
+        approx_eq(last.position[0].value, 10.0);
+        approx_eq(last.position[1].value, 10.0);
+
+        // Live machine is unchanged.
+        assert!(brain.channels[0].program.is_empty());
+        assert_eq!(brain.channels[0].part_count, 0);
+        approx_eq(brain.axes[0].position, 0.0);
     }
-}
 
+    #[test]
+    fn invariant_checker_flags_limit_and_pending_violations() {
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G1 X10 Y0 F500");
+        assert!(brain.invariant_violations().is_empty());
 
-fn arc_center_matches(
-    sx: f64,
-    sy: f64,
-    ex: f64,
-    ey: f64,
-    cx: f64,
-    cy: f64,
-    cw: bool,
-    want_large: bool,
-) -> bool {
-    let a0 = (sy - cy).atan2(sx - cx);
-    let a1 = (ey - cy).atan2(ex - cx);
-    let mut da = a1 - a0;
-    if cw {
-        if da >= 0.0 { da -= std::f64::consts::TAU; }
-    } else {
-        if da <= 0.0 { da += std::f64::consts::TAU; }
+        brain.axes[0].position = 20_000.0;
+        let violations = brain.invariant_violations();
+        assert!(violations.iter().any(|v| v.contains("outside soft limits")), "{:?}", violations);
+        brain.axes[0].position = 0.0;
+
+        brain.channels[0].pending.push_back(PendingEntry::Move(vec![(0, 1.0)]));
+        let violations = brain.invariant_violations();
+        assert!(violations.iter().any(|v| v.contains("pending segments grew")), "{:?}", violations);
+        brain.channels[0].pending.clear();
+
+        brain.channels[0].pc += 1;
+        brain.channels[0].programmed_work.insert(1, 5.0);
+        let violations = brain.invariant_violations();
+        assert!(violations.iter().any(|v| v.contains("differs from programmed")), "{:?}", violations);
     }
-    let sweep = da.abs();
-    if want_large {
-        sweep >= std::f64::consts::PI - 1e-9
-    } else {
-        sweep <= std::f64::consts::PI + 1e-9
+
+    #[test]
+    fn toolpath_digest_ignores_text_changes_and_tracks_geometry() {
+        let brain = make_xyz_brain();
+        let base = brain.compute_toolpath_digest(0, "G90 G21\nG1 X10 Y0 F500\nG2 X20 Y0 R5\n".to_string(), 0.001);
+        let renumbered = brain.compute_toolpath_digest(
+            0,
+            "(OP 1)\nN10 G90 G21\nN20 G1 X10 Y0 F500 ; cut\nN30 G2 X20 Y0 R5\n".to_string(),
+            0.001,
+        );
+        assert_eq!(base, renumbered);
+        assert!(base.starts_with("tp1-"));
+
+        // Linear-only: arc points near a bucket edge could flip with any shift.
+        let square = brain.compute_toolpath_digest(0, "G90 G21\nG1 X10 Y0 F500\nG1 Y10\n".to_string(), 0.001);
+        let within_tol = brain.compute_toolpath_digest(0, "G90 G21\nG1 X10.0001 Y0 F500\nG1 Y10\n".to_string(), 0.001);
+        assert_eq!(square, within_tol);
+
+        let moved = brain.compute_toolpath_digest(0, "G90 G21\nG1 X10.5 Y0 F500\nG2 X20 Y0 R5\n".to_string(), 0.001);
+        assert_ne!(base, moved);
+
+        // Jogging and a new G54 zero don't change the digest.
+        let mut brain = brain;
+        brain.axes[0].position = 37.0;
+        brain.axes[0].target = 37.0;
+        brain.axes[2].position = -12.0;
+        brain.axes[2].target = -12.0;
+        brain.set_work_zero(0, 0, 150.0);
+        brain.parse_line(0, "G91 G20");
+        let code = "G90 G21\nG1 X10 Y0 F500\nG2 X20 Y0 R5\n".to_string();
+        assert_eq!(brain.compute_toolpath_digest(0, code, 0.001), base);
     }
-}
 
-fn build_short_arc_points(
-    cx: f64,
-    cy: f64,
-    from: (f64, f64),
-    to: (f64, f64),
-    radius: f64,
-) -> Vec<(f64, f64)> {
-    if radius <= 1e-9 {
-        return vec![to];
+    #[test]
+    fn format_program_renumbers_and_drops_redundant_modals() {
+        let src = "%\n(ROUGH)\nN5 g90 g21 G1 x10 F500\nG1   X20 F500\n\nG90 ; repeat\n/G1 Y5 F400\n%\n";
+        let out = format_gcode(src, &FormatOptions::default());
+        assert_eq!(
+            out,
+            "%\n(ROUGH)\nN10 G90 G21 G1 X10 F500\nN20 X20\n\n; repeat\n/ N30 Y5 F400\n%\n"
+        );
+
+        let stripped = format_gcode(
+            src,
+            &FormatOptions { renumber: false, strip_comments: true, strip_blank_lines: true, ..FormatOptions::default() },
+        );
+        assert_eq!(stripped, "%\nN5 G90 G21 G1 X10 F500\nX20\n/ Y5 F400\n%\n");
+
+        // Macro text passes through; a block-delete line sets no modal state.
+        let src = "#1=25.4\nG0 X#1 Y[#1/2]\n/G1 X5 F300\nG0 X0\nG1 Y1 F300\n";
+        let out = format_gcode(src, &FormatOptions { renumber: false, ..FormatOptions::default() });
+        assert_eq!(out, "#1=25.4\nG0 X#1 Y[#1/2]\n/ G1 X5 F300\nX0\nG1 Y1 F300\n");
     }
-    let a0 = (from.1 - cy).atan2(from.0 - cx);
-    let a1 = (to.1 - cy).atan2(to.0 - cx);
-    let mut da = a1 - a0;
-    while da <= -std::f64::consts::PI {
-        da += std::f64::consts::TAU;
+
+    #[test]
+    #[cfg(feature = "macros")]
+    fn formatted_programs_run_the_same_toolpath() {
+        let brain = make_xyz_brain();
+        let programs = [
+            // Loop back-edges: the body's first block follows the loop's last.
+            "G90 G21 G0 X0 Y0\nO100 REPEAT [2]\nG1 X10 F100\nG0 X0\nO100 ENDREPEAT\nM30",
+            "#1=0\nG90 G0 X0\nWHILE [#1 LT 2] DO1\nG1 X10 F100\nG0 X0\n#1=[#1+1]\nEND1\nM30",
+            "G90 G0 X0\nN10 G1 X10 F100\nG0 X0\n#2=[#2+1]\nIF [#2 LT 2] GOTO 10\nM30",
+            // A sub body runs with the caller's modal state.
+            "G90 G0 X0\nO200 CALL\nG0 X5\nO200 CALL\nM30\nO200 SUB\nG1 X10 F100\nO200 ENDSUB",
+            // Threading and canned cycles are motion modes too.
+            "G90 G21 G0 X0 Z0\nS100 M3\nG1 X1 F100\nG33 Z-10 K1\nG1 X5\nM30",
+        ];
+        for src in programs {
+            let formatted = format_gcode(src, &FormatOptions::default());
+            let a = brain.run_verification(0, src).unwrap();
+            let b = brain.run_verification(0, &formatted).unwrap();
+            assert!(a.completed && b.completed, "{}", formatted);
+            let path = |run: &VerificationRun| -> Vec<(i32, Vec<f64>)> {
+                run.path.iter().map(|p| (p.motion, p.position.iter().map(|o| o.value).collect())).collect()
+            };
+            assert_eq!(path(&a), path(&b), "{}", formatted);
+        }
+        assert_eq!(g_modal_group(84.0), Some("motion"));
+        assert_eq!(g_modal_group(153.0), Some("work offset"));
     }
-    while da > std::f64::consts::PI {
-        da -= std::f64::consts::TAU;
+
+    #[test]
+    fn compare_programs_reports_geometric_regions_only() {
+        let brain = make_xyz_brain();
+        let a = "G90 G21\nG1 X10 Y0 F500\nG1 X10 Y10\nG1 X0 Y10\n";
+        // Same path split into more blocks and reformatted.
+        let same = "N1 G90 G21\nN2 G1 X5 Y0 F800\nN3 X10\nN4 Y10\nN5 X0\n";
+        let diff = brain.compare_program_paths(0, a, same, 0.001).unwrap();
+        assert!(diff.identical, "regions: {:?}", diff.regions_a.len() + diff.regions_b.len());
+
+        // Corner moved by 0.5 mm.
+        let edited = "G90 G21\nG1 X10 Y0 F500\nG1 X10.5 Y10\nG1 X0 Y10\n";
+        let diff = brain.compare_program_paths(0, a, edited, 0.01).unwrap();
+        assert!(!diff.identical);
+        assert_eq!(diff.regions_b.len(), 1);
+        assert_eq!(diff.regions_b[0].start_block, 2);
+        approx_eq(diff.max_deviation, 0.5);
     }
-    let sweep = da.abs();
-    if sweep <= 1e-6 {
-        return vec![to];
+
+    #[test]
+    fn program_outline_groups_operations_and_keeps_comment_case() {
+        let code = "%\nO1000 (Part 42)\n(Rough pocket 1)\n(stepover 40%)\nN10 T1 M6\nG1 X10 F500\nT2 M6 (finish tool)\nG1 X20\n(Chamfer)\nT3 M6\nM30\n";
+        let outline = build_program_outline(code);
+        let names: Vec<&str> = outline.operations.iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(names, vec!["Rough pocket 1", "T2", "Chamfer"]);
+        assert_eq!(outline.operations[0].notes, vec!["stepover 40%".to_string()]);
+        assert_eq!(outline.operations[0].tool, Some(1));
+        assert_eq!((outline.operations[0].start_line, outline.operations[0].end_line), (2, 5));
+        assert_eq!(outline.operations[2].tool, Some(3));
+
+        let n10 = outline.blocks.iter().find(|b| b.line == 4).expect("N10 block");
+        assert_eq!(n10.n_number, Some(10));
+        assert_eq!(n10.tool_change, Some(1));
+        assert_eq!(n10.operation, Some(0));
+        assert_eq!(outline.program_number, Some(1000));
+        assert_eq!(outline.program_name.as_deref(), Some("Part 42"));
+
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, code.to_string());
+        assert!(brain.jump_to_operation(0, 2));
+        assert_eq!(brain.channels[0].pc, 8);
+        assert!(brain.channels[0].paused);
     }
-    let n = ((radius * sweep) / 1.2).ceil().clamp(4.0, 48.0) as usize;
-    let mut out = Vec::with_capacity(n);
-    for k in 1..=n {
-        let t = k as f64 / n as f64;
-        let a = a0 + da * t;
-        out.push((cx + radius * a.cos(), cy + radius * a.sin()));
+
+    #[test]
+    #[cfg(feature = "dialects")]
+    fn absolute_ijk_mode_uses_center_as_written_and_validates_radius() {
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G1 X20 Y10");
+        brain.parse_line(0, "G90.1 G3 X10 Y20 I10 J10");
+        assert!(brain.channels[0].arc_center_absolute);
+        assert!(brain.alarms.is_empty());
+        let last = brain.channels[0].pending.back().expect("arc segments").targets().to_vec();
+        approx_eq(last[0].1, 10.0);
+        approx_eq(last[1].1, 20.0);
+        // Midpoint of the quarter arc sits on the 10 mm circle around (10, 10).
+        let mid = brain.channels[0].pending[brain.channels[0].pending.len() / 2].targets();
+        approx_eq(((mid[0].1 - 10.0).powi(2) + (mid[1].1 - 10.0).powi(2)).sqrt(), 10.0);
+
+        // Same words in incremental mode give an inconsistent radius: alarm with a hint.
+        let mut brain = make_xyz_brain();
+        brain.channels[0].is_running = true;
+        brain.parse_line(0, "G90 G21 G1 X20 Y10");
+        brain.parse_line(0, "G91.1 G3 X10 Y20 I10 J10");
+        assert_eq!(brain.alarms.len(), 1);
+        assert_eq!(brain.alarms[0].code, "ARC_RADIUS");
+        assert!(brain.alarms[0].message.contains("G90.1"), "{}", brain.alarms[0].message);
+        assert!(!brain.channels[0].is_running);
+
+        // Dialect default applies to channels.
+        assert!(brain.set_dialect("mach3".to_string()));
+        assert!(brain.channels[0].arc_center_absolute);
+        assert!(!brain.set_dialect("unknown".to_string()));
     }
-    out
-}
 
-fn line_intersection_2d(
-    p1: (f64, f64),
-    d1: (f64, f64),
-    p2: (f64, f64),
-    d2: (f64, f64),
-) -> Option<(f64, f64)> {
-    let cross = d1.0 * d2.1 - d1.1 * d2.0;
-    if cross.abs() <= 1e-9 {
-        return None;
+    #[test]
+    fn full_circle_arc_with_ijk_and_p_turns() {
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G1 X10 Y0");
+        brain.parse_line(0, "G2 X10 Y0 I-10 J0");
+        let pending = &brain.channels[0].pending;
+        assert!(pending.len() >= 24);
+        // Halfway round the circle is the far side at X-10.
+        let mid = pending[pending.len() / 2 - 1].targets();
+        approx_eq(mid[0].1, -10.0);
+        let last = pending.back().unwrap().targets();
+        approx_eq(last[0].1, 10.0);
+        approx_eq(last[1].1, 0.0);
+
+        // Helix with P2 makes two turns; the Z drop is split across both.
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G1 X10 Y0 Z0");
+        brain.parse_line(0, "G3 X10 Y0 Z-4 I-10 J0 P2");
+        let pending = &brain.channels[0].pending;
+        let mut swept = 0.0;
+        let mut prev = 0.0_f64;
+        for seg in pending.iter().map(PendingEntry::targets) {
+            let ang = seg[1].1.atan2(seg[0].1);
+            let mut d = ang - prev;
+            if d > std::f64::consts::PI { d -= std::f64::consts::TAU; }
+            if d < -std::f64::consts::PI { d += std::f64::consts::TAU; }
+            swept += d;
+            prev = ang;
+        }
+        approx_eq(swept, 2.0 * std::f64::consts::TAU);
+        approx_eq(pending.back().unwrap().targets()[2].1, -4.0);
     }
-    let qmp = (p2.0 - p1.0, p2.1 - p1.1);
-    let t = (qmp.0 * d2.1 - qmp.1 * d2.0) / cross;
-    Some((p1.0 + t * d1.0, p1.1 + t * d1.1))
-}
 
-#[wasm_bindgen]
-impl MachineBrain {
-    #[wasm_bindgen(constructor)]
-    pub fn new() -> Self {
-        console_log!("MachineBrain v6: Zero Control Ready");
-        Self {
-            axes: Vec::new(),
-            channels: Vec::new(),
-            estop: false,
-            feed_hold: false,
-            active_wcs: 0,
-            is_homing: false,
-            work_offsets: default_work_offsets(),
-            homing_sequence: Vec::new(),
-            homing_index: 0,
-            homing_feed: 300.0,
-            homing_rapid: false,
+    #[test]
+    fn small_arcs_are_fed_within_centripetal_acceleration() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 600.0; // 10 mm/s²
         }
+        brain.parse_line(0, "G90 G21 G1 X1 Y0 F1000");
+        approx_eq(brain.effective_feed(0), 1000.0);
+        brain.parse_line(0, "G2 X1 Y0 I-1 J0");
+        // v = sqrt(a * r) = sqrt(10 mm/s² * 1 mm) per second.
+        let limit = 10.0_f64.sqrt() * 60.0;
+        approx_eq(brain.effective_feed(0), limit);
+        brain.channels[0].pending.clear();
+        brain.parse_line(0, "G1 X5");
+        approx_eq(brain.effective_feed(0), 1000.0);
+
+        // The estimate uses the clamped feed for the circle.
+        let run = brain
+            .run_verification(0, "G90 G21 G1 X1 Y0 F1000\nG2 X1 Y0 I-1 J0\n")
+            .expect("run");
+        let expected = 4.0 / 1000.0 * 60.0 + std::f64::consts::TAU / limit * 60.0;
+        assert!((run.cycle_time_s - expected).abs() < 0.01, "{} vs {}", run.cycle_time_s, expected);
     }
 
-    pub fn clear_config(&mut self) {
-        self.axes.clear();
-        self.channels.clear();
-        self.feed_hold = false;
-        self.work_offsets = default_work_offsets();
-        self.active_wcs = 0;
-        self.is_homing = false;
-        self.homing_sequence.clear();
-        self.homing_index = 0;
-        self.homing_feed = 300.0;
-        self.homing_rapid = false;
+    #[test]
+    fn g34_variable_lead_thread_and_multi_start_angle() {
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G0 X0 Y0 Z0");
+        brain.parse_line(0, "G33 Z-10 K2");
+        assert_eq!(brain.alarms[0].code, "THREAD_SPINDLE");
+
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G0 X0 Y0 Z0 S600 M3 F100");
+        brain.parse_line(0, "G33 Z-10 K2 Q180");
+        assert!(brain.alarms.is_empty());
+        approx_eq(brain.effective_feed(0), 1200.0);
+        approx_eq(brain.channels[0].thread_start_angle, 180.0);
+        approx_eq(brain.channels[0].feed_rate, 100.0);
+        brain.channels[0].feed_override = 0.5;
+        approx_eq(brain.effective_feed(0), 1200.0);
+
+        // Lead grows 1 -> 2 -> 3 mm/rev over two revolutions (s = n + n²/2):
+        // 1.5 mm after the first, 4 mm after the second, in two spindle turns.
+        brain.parse_line(0, "G0 Z0");
+        brain.axes[2].position = 0.0;
+        brain.parse_line(0, "G34 Z-4 F1 K1");
+        approx_eq(brain.effective_feed(0), 600.0);
+        brain.axes[2].position = -1.5;
+        approx_eq(brain.effective_feed(0), 1200.0);
+        brain.axes[2].position = 0.0;
+        approx_eq(brain.motion_time_s(0, 4.0), 0.2);
+
+        // A lead that would shrink below zero is rejected.
+        brain.parse_line(0, "G34 Z-20 F1 K-0.1");
+        assert_eq!(brain.alarms.last().unwrap().code, "THREAD_LEAD");
     }
 
-    fn start_homing_sequence(&mut self, mut order: Vec<u32>, rapid: bool, feed: f64) {
-        if self.estop {
-            return;
-        }
-        order.retain(|id| (*id as usize) < self.axes.len());
-        order.dedup();
-        if order.is_empty() {
-            self.is_homing = false;
-            self.homing_sequence.clear();
-            self.homing_index = 0;
-            return;
+    #[test]
+    fn trochoidal_program_takes_estimation_fast_path() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 60_000.0;
         }
-        self.is_homing = true;
-        self.homing_sequence = order;
-        self.homing_index = 0;
-        self.homing_rapid = rapid;
-        self.homing_feed = feed.max(1.0);
-        // Start homing from a clean motion state so selected feed/rapid is respected.
-        for ax in self.axes.iter_mut() {
-            ax.velocity = 0.0;
+        // Full loops of radius 2 advancing 0.5 mm each, linked by short feeds.
+        let mut code = String::from("G90 G21 G1 X2 Y0 F1200\n");
+        for k in 0..600 {
+            let cx = k as f64 * 0.5;
+            code.push_str(&format!("G3 X{:.3} Y0 I-2 J0\nG1 X{:.3}\n", cx + 2.0, cx + 2.5));
         }
-    }
+        let stats = brain.path_statistics(0, &code).expect("stats");
+        assert_eq!(stats.arc_count, 600);
+        assert!(stats.adaptive);
+        approx_eq(stats.average_arc_radius, 2.0);
+        approx_eq(stats.average_stepover, 0.5);
+        // Tool radius 4, 0.5 mm radial step: acos(1 - 0.5 / 4).
+        approx_eq(stats.average_engagement_deg, (1.0_f64 - 0.125).acos().to_degrees());
+        approx_eq(brain.estimate_cycle_time(0, code.clone()), stats.estimated_time_s);
 
-    fn machine_target_with_limits(&self, axis_id: u32, machine_target: f64) -> f64 {
-        let Some(ax) = self.axes.get(axis_id as usize) else {
-            return machine_target;
-        };
-        match ax.axis_type {
-            AxisType::Rotary => normalize_rotary_target(machine_target),
-            AxisType::Linear => machine_target.clamp(ax.min_range, ax.max_range),
+        // The fast path agrees with the interpreter run (chordal arcs are a bit shorter).
+        let run = brain.run_verification(0, &code).expect("run");
+        let rel = (run.cycle_time_s - stats.estimated_time_s).abs() / stats.estimated_time_s;
+        assert!(rel < 0.005, "{} vs {}", run.cycle_time_s, stats.estimated_time_s);
+
+        // A dwell the scan cannot time sends the estimate through the executor.
+        let dwelled = format!("{code}G4 P1000\n");
+        approx_eq(brain.estimate_cycle_time(0, dwelled), run.cycle_time_s + 1.0);
+        brain.set_block_processing_time(1.0);
+        let blocks = brain.run_verification(0, &code).expect("run").cycle_time_s;
+        approx_eq(brain.estimate_cycle_time(0, code.clone()), blocks);
+        brain.set_block_processing_time(0.0);
+
+        // So does a G52 shift that moves the path under the scan.
+        let shifted = format!("{code}G52 X-500\nG0 X0\n");
+        let run = brain.run_verification(0, &shifted).expect("run");
+        approx_eq(brain.estimate_cycle_time(0, shifted), run.cycle_time_s);
+        for block in ["G19 G2 Y1 Z0 R1", "G7", "G55", "G54.1 P2", "G53 G0 Z0", "G92 X0", "G51.1 X0"] {
+            assert!(!scan_times_program(block), "{block}");
         }
+
+        let small = brain.path_statistics(0, "G1 X10 F100\nG2 X20 Y0 R5\n").unwrap();
+        assert!(!small.adaptive);
+        assert_eq!(small.arc_count, 1);
     }
 
-    fn channel_rapid_feed(&self, channel_index: usize) -> f64 {
-        let Some(chan) = self.channels.get(channel_index) else {
-            return RAPID_LINEAR_MAX_MM_MIN;
-        };
-        let mut rapid = RAPID_LINEAR_MAX_MM_MIN;
-        let mut any = false;
-        for m in &chan.axis_map {
-            if let Some(ax) = self.axes.get(m.axis_id as usize) {
-                rapid = rapid.min(axis_rapid_feed(ax));
-                any = true;
-            }
+    struct CountingSource;
+
+    impl ProgramSource for CountingSource {
+        fn fetch(&self, start: usize, count: usize) -> Vec<String> {
+            (start..start + count).map(|i| format!("G1 X{} F6000", i)).collect()
         }
-        if any { rapid } else { RAPID_LINEAR_MAX_MM_MIN }
     }
 
-    pub fn add_axis(&mut self, name: String, kind: AxisType, min: f64, max: f64) -> u32 {
-        let id = self.axes.len() as u32;
+    #[test]
+    fn streamed_program_keeps_window_within_memory_budget() {
+        let mut brain = make_xyz_brain();
+        brain.set_program_memory_budget(4096);
+        brain.load_program(0, "G1 X1\n".repeat(2000));
+        assert_eq!(brain.alarms[0].code, "PROGRAM_MEMORY");
+        assert!(brain.channels[0].program.is_empty());
 
-        for wcs in self.work_offsets.iter_mut() {
-            wcs.offsets.push(AxisOffset { axis_id: id, value: 0.0 });
+        brain.load_program_source(0, 5000, Rc::new(CountingSource));
+        let mut path = Vec::new();
+        let mut time_s = 0.0;
+        let mut max_resident = 0;
+        while brain.channels[0].is_running {
+            brain.run_channel_instant(0, 100, &mut path, &mut time_s);
+            max_resident = max_resident.max(brain.channels[0].program.resident_bytes);
         }
-        self.axes.push(Axis {
-            id, physical_name: name, position: 0.0, target: 0.0, velocity: 0.0, accel: 0.0,
-            axis_type: kind, min_range: min, max_range: max, homed: false, invert: false, machine_zero: 0.0,
-        });
-        id
-    }
+        approx_eq(brain.axes[0].position, 4999.0);
+        assert_eq!(brain.channels[0].pc, 5000);
+        // Never more than the budget plus one fetched chunk.
+        assert!(max_resident <= 4096 + PROGRAM_STREAM_CHUNK * 20, "{}", max_resident);
+        assert!(brain.channels[0].program.get(0).is_none());
 
-    pub fn add_channel(&mut self, id: u32, mappings: JsValue) {
-        let axis_map: Vec<ChannelAxisMap> = serde_wasm_bindgen::from_value(mappings).unwrap_or_default();
-        self.channels.push(Channel {
-            id, axis_map, feed_rate: 1000.0, is_running: false,
-            paused: false, program: Vec::new(), pc: 0, active_pc: -1,
-            current_motion: 0,
-            abs_mode: true,
-            units_mm: true,
-            plane: 17,
-            exact_stop: false,
-            cutter_comp: 40,
-            tool_radius: 4.0,
-            length_comp_active: false,
-            tool_length: 50.0,
-            active_tool: 0,
-            active_d: 0,
-            active_h: 0,
-            spindle_rpm: 0.0,
-            spindle_mode: 5,
-            coolant_on: false,
-            feed_override: 1.0,
-            single_block: false,
-            step_once: false,
-            pause_pending: false,
-            tool_table: HashMap::from([
-                (0, ToolTableEntry { radius: 4.0, length: 50.0 }),
-                (1, ToolTableEntry { radius: 4.0, length: 50.0 }),
-            ]),
-            comp_linear_prev: None,
-            comp_entry_pending: false,
-            pending: VecDeque::new(),
-            programmed_work: HashMap::new(),
-        });
+        // Jumping back refetches the window around the new pc.
+        brain.jump_blocks(0, -4990);
+        brain.run_channel_instant(0, 2, &mut path, &mut time_s);
+        approx_eq(brain.axes[0].position, 10.0);
+        assert!(brain.channels[0].program.get(11).is_some());
     }
 
-    // ── Program control ────────────────────────────────────────────────────
+    #[test]
+    fn live_trace_is_decimated_by_chord_tolerance() {
+        let run = |tolerance: f64| {
+            let mut brain = make_xyz_brain();
+            for ax in brain.axes.iter_mut() {
+                ax.accel = 100_000.0;
+            }
+            brain.set_trace_tolerance(tolerance);
+            brain.load_program(0, "G90 G21 G1 X20 Y0 F3000\nG3 X0 Y20 I-20 J0\n".to_string());
+            for _ in 0..20_000 {
+                brain.tick(1.0);
+            }
+            assert!(!brain.channels[0].is_running);
+            brain
+        };
+        let dense_brain = run(0.0);
+        let dense = dense_brain.get_trace_chunk(0, 0, usize::MAX);
+        let mut brain = run(0.01);
+        let len = brain.get_trace_len(0);
+        let stride = brain.get_trace_stride(0);
+        assert_eq!(stride, 5);
+        let all = brain.get_trace_chunk(0, 0, len);
+        assert_eq!(all.len(), len * stride);
+        assert!(len * 5 < dense.len() / stride, "{} vs {}", len, dense.len() / stride);
 
-    pub fn load_program(&mut self, channel_index: usize, code: String) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            self.feed_hold = false;
-            chan.program = code.lines().map(|l| l.trim().to_uppercase()).collect();
-            chan.pc = 0;
-            chan.active_pc = -1;
-            chan.is_running = true;
-            chan.paused = false;
-            chan.current_motion = 0;
-            chan.step_once = false;
-            chan.pause_pending = false;
-            chan.programmed_work.clear();
-            chan.comp_linear_prev = None;
-            chan.comp_entry_pending = false;
+        // Every executed position stays within the tolerance of the decimated polyline.
+        let pts: Vec<&[f64]> = all.chunks(stride).map(|p| &p[..3]).collect();
+        for p in dense.chunks(stride) {
+            let d = pts
+                .windows(2)
+                .map(|w| point_polyline_deviation(&p[..3], w[0], w[1]))
+                .fold(f64::INFINITY, f64::min);
+            assert!(d <= 0.01 + 1e-9, "{}", d);
         }
+        // The straight line collapses to its end point; the tail is the final position.
+        approx_eq(all[stride], 20.0);
+        approx_eq(all[stride + 1], 0.0);
+        let last = &all[(len - 1) * stride..];
+        approx_eq(last[0], 0.0);
+        approx_eq(last[1], 20.0);
+        approx_eq(last[3], 3.0);
+
+        // Chunks continue at absolute indices after clearing.
+        brain.clear_trace(0);
+        assert_eq!(brain.get_trace_len(0), len);
+        assert!(brain.get_trace_chunk(0, 0, 10).is_empty());
     }
 
-    pub fn toggle_pause(&mut self, channel_index: usize) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            chan.paused = !chan.paused;
+    #[test]
+    fn trace_exports_segments_per_tool_and_operation() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 100_000.0;
+        }
+        brain.set_trace_tool_color(2, "#000000".to_string());
+        let code = "(ROUGH)\nT1 M6\nG90 G21 G1 X10 F3000\nG0 Z5\n(FINISH)\nT2 M6\nG1 X0\n";
+        brain.load_program(0, code.to_string());
+        for _ in 0..5000 {
+            brain.tick(1.0);
         }
+        let chan = &brain.channels[0];
+        let segments = chan.trace.segments(&chan.outline, &brain.trace_tool_colors);
+        let kinds: Vec<(i32, i32, Option<&str>)> = segments
+            .iter()
+            .map(|s| (s.tool, s.motion, s.operation_name.as_deref()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![(1, 1, Some("ROUGH")), (1, 0, Some("ROUGH")), (2, 1, Some("FINISH"))]
+        );
+        assert_eq!(segments[1].color, TRACE_RAPID_COLOR);
+        assert_eq!(segments[2].color, "#000000");
+        // Segments are connected: each starts where the previous ended.
+        assert_eq!(segments[1].points[0], *segments[0].points.last().unwrap());
+        approx_eq(segments[2].points.last().unwrap()[0], 0.0);
+
+        let json = brain.export_trace(0);
+        let parsed: Vec<TraceSegment> = serde_json::from_str(&json).expect("json");
+        assert_eq!(parsed.len(), 3);
     }
 
-    pub fn reset_program(&mut self, channel_index: usize) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            chan.pc = 0;
-            chan.active_pc = -1;
-            chan.is_running = false;
-            chan.paused = false;
-            chan.step_once = false;
-            chan.pause_pending = false;
-            chan.programmed_work.clear();
-            chan.comp_linear_prev = None;
-            chan.comp_entry_pending = false;
-        }
+    #[test]
+    fn component_poses_follow_tool_and_table_chains() {
+        let mut brain = make_xyz_brain();
+        let c = brain.add_axis("C".to_string(), AxisType::Rotary, -360.0, 360.0).unwrap();
+        brain.axes[0].position = 10.0;
+        brain.axes[1].position = 20.0;
+        brain.axes[2].position = -5.0;
+        brain.axes[c as usize].position = 90.0;
+        brain.channels[0].tool_length = 50.0;
+        let poses = brain.component_poses();
+        let pose = |name: &str| poses.iter().find(|p| p.name == name).expect(name).matrix;
+        let head = pose("head");
+        approx_eq(head[12], 10.0);
+        approx_eq(head[13], 20.0);
+        approx_eq(head[14], -5.0);
+        approx_eq(pose("tool")[14], -55.0);
+        // C turns the table 90° about Z: its X axis maps onto machine Y.
+        let table = pose("table");
+        approx_eq(table[0], 0.0);
+        approx_eq(table[1], 1.0);
+
+        // X moving the table shifts it opposite; a horizontal spindle points +Y.
+        assert!(brain.set_axis_kinematics(0, "table".to_string(), "".to_string()));
+        assert!(brain.set_spindle_mount("+Y".to_string(), 0.0, 0.0, 0.0));
+        let poses = brain.component_poses();
+        let table = poses.iter().find(|p| p.name == "table").unwrap().matrix;
+        approx_eq(table[12], -10.0);
+        let tool = poses.iter().find(|p| p.name == "tool").unwrap().matrix;
+        approx_eq(tool[12], 0.0);
+        approx_eq(tool[13], 70.0);
+        assert!(!brain.set_spindle_mount("Q".to_string(), 0.0, 0.0, 0.0));
     }
 
-    pub fn set_feed_override(&mut self, channel_index: usize, ratio: f64) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            chan.feed_override = ratio.clamp(0.0, 5.0);
-        }
+    #[test]
+    fn program_bounding_box_is_checked_against_work_envelope() {
+        let mut brain = make_xyz_brain();
+        brain.axes[0].min_range = -100.0;
+        brain.axes[0].max_range = 100.0;
+        brain.axes[1].min_range = -50.0;
+        brain.axes[1].max_range = 50.0;
+        brain.work_offsets[0].offsets[0].value = 20.0;
+        let env = brain.work_envelope();
+        approx_eq(env.box_min[0], -100.0);
+        approx_eq(env.work_box_max[0], 80.0);
+        approx_eq(env.work_box_max[1], 50.0);
+
+        // A CCW half circle from (40,0) around (0,0) reaches Y40 at its top.
+        let check = brain.program_envelope_check(0, "G90 G21 G1 X40 Y0 F500\nG3 X-40 Y0 R40\n").unwrap();
+        assert!(check.within);
+        approx_eq(check.program_max.unwrap()[1], 40.0);
+        approx_eq(check.program_max.unwrap()[0], 60.0);
+
+        // Same arc with radius 60 tops out at Y60, 10 mm past the Y travel.
+        let check = brain.program_envelope_check(0, "G90 G21 G1 X60 Y0 F500\nG3 X-60 Y0 I-60 J0\n").unwrap();
+        assert!(!check.within);
+        assert_eq!(check.exceeded.len(), 1);
+        assert_eq!(check.exceeded[0].name, "Y");
+        approx_eq(check.exceeded[0].above_by, 10.0);
+        // The run itself completes with Y clamped; the job report still fails.
+        let report = brain.verification_report(0, "G90 G21 G1 X60 Y0 F500\nG3 X-60 Y0 I-60 J0\n").unwrap();
+        assert!(report.completed && !report.passed());
+        assert_eq!(report.travel_exceeded[0].name, "Y");
+
+        // Offsets the block words do not show: a G52 shift on top of G54's X20,
+        // and a switch to G55 whose X is 90.
+        let check = brain.program_envelope_check(0, "G90 G21 G52 X70\nG1 X20 F500\nG52 X0\n").unwrap();
+        assert!(!check.within);
+        approx_eq(check.exceeded[0].above_by, 10.0);
+        brain.work_offsets[1].offsets[0].value = 90.0;
+        let check = brain.program_envelope_check(0, "G90 G21 G1 X5 F500\nG55 G1 X15\n").unwrap();
+        approx_eq(check.program_max.unwrap()[0], 105.0);
+        approx_eq(check.exceeded[0].above_by, 5.0);
+    }
+
+    #[test]
+    #[cfg(feature = "collision")]
+    fn protected_zone_stops_motion_that_would_enter_it() {
+        let chuck = |tool_length_aware| ProtectedZone {
+            id: 0,
+            name: "chuck".to_string(),
+            shape: ZoneShape::Cylinder { axis: 2, center: [0.0, 0.0], radius: 10.0, min: -100.0, max: 0.0 },
+            tool_length_aware,
+        };
+        let code = "G90 G21 G0 X-50 Y0 Z5\nG1 X50 F500\n";
+
+        // Gauge point passes 5 mm above the chuck.
+        let mut brain = make_xyz_brain();
+        for (axis, v) in [(0, -50.0), (2, 5.0)] {
+            brain.axes[axis].position = v;
+            brain.axes[axis].target = v;
+        }
+        brain.insert_protected_zone(chuck(false));
+        let run = brain.run_verification(0, code).unwrap();
+        assert!(run.completed && run.alarms.is_empty());
+
+        // The 50 mm tool tip would pass through it: stop before moving.
+        brain.channels[0].tool_length = 50.0;
+        brain.protected_zones.clear();
+        let id = brain.insert_protected_zone(chuck(true));
+        brain.load_program(0, code.to_string());
+        let mut path = Vec::new();
+        let mut t = 0.0;
+        brain.run_channel_instant(0, 100, &mut path, &mut t);
+        assert!(!brain.channels[0].is_running);
+        assert_eq!(brain.alarms[0].code, "PROTECTED_ZONE");
+        assert!(brain.alarms[0].message.contains("X-10.000"), "{}", brain.alarms[0].message);
+        approx_eq(brain.axes[0].position, -50.0);
+        approx_eq(brain.axes[0].target, -50.0);
+
+        // Zones are setup: operators can't remove them.
+        assert!(brain.set_access_level("operator".to_string(), String::new()));
+        assert!(!brain.remove_protected_zone(id));
+        assert!(brain.set_access_level("setter".to_string(), String::new()));
+        assert!(brain.remove_protected_zone(id));
+    }
+
+    #[test]
+    #[cfg(feature = "collision")]
+    fn setup_sheet_lists_wcs_extents_tools_and_clamp_clearance() {
+        let mut brain = make_xyz_brain();
+        brain.set_log_console(false);
+        brain.work_offsets[1].offsets[0].value = 100.0;
+        brain.work_offsets[1].offsets[2].value = -100.0;
+        brain.insert_protected_zone(ProtectedZone {
+            id: 0,
+            name: "clamp".to_string(),
+            shape: ZoneShape::Box { min: [125.0, -10.0, -110.0], max: [135.0, 10.0, -90.0] },
+            tool_length_aware: false,
+        });
+        let program = "G55 G90 G21 G0 X0 Y0 Z5\nG1 Z-3 F100\nG1 X20\nG0 Z5\nG54 G0 X10 Y0 Z10";
+        let sheet = brain.setup_sheet(0, program, 2.0).unwrap();
+        assert!(sheet.completed);
+
+        assert_eq!(sheet.wcs.iter().map(|w| (w.index, w.label.as_str())).collect::<Vec<_>>(), [(1, "G55"), (0, "G54")]);
+        let g55 = &sheet.wcs[0];
+        assert_eq!(g55.origin.iter().map(|o| o.value).collect::<Vec<_>>(), [100.0, 0.0, -100.0]);
+        assert_eq!(g55.min.iter().map(|o| o.value).collect::<Vec<_>>(), [0.0, 0.0, -3.0]);
+        assert_eq!(g55.max.iter().map(|o| o.value).collect::<Vec<_>>(), [20.0, 0.0, 5.0]);
+
+        assert_eq!(sheet.tools.len(), 1);
+        assert_eq!(sheet.tools[0].first_line, 1);
+        assert_eq!(sheet.tools[0].max_depth, Some(3.0));
+
+        // The cut at G55 X20 passes 5 mm from the clamp with a 4 mm radius tool.
+        assert_eq!(sheet.clamp_warnings.len(), 1);
+        let warning = &sheet.clamp_warnings[0];
+        assert_eq!((warning.zone_name.as_str(), warning.line), ("clamp", 3));
+        approx_eq(warning.clearance, 1.0);
+        assert!(brain.setup_sheet(0, program, 0.5).unwrap().clamp_warnings.is_empty());
+        assert!(brain.export_setup_sheet(0, program.to_string(), 2.0).contains("\"clamp_warnings\""));
+    }
+
+    #[test]
+    fn touch_off_accounts_for_tool_length_and_contact_side() {
+        let mut brain = make_xyz_brain();
+        brain.set_log_console(false);
+        brain.jog_axis_feed(0, 10.0, 500.0);
+        brain.jog_axis_feed(1, -10.0, 500.0);
+        brain.axes[0].position = 100.0;
+        brain.axes[1].position = 30.0;
+        brain.axes[2].position = -200.0;
+
+        // Edge finder of radius 4 moved +X onto the part's left edge.
+        assert!(brain.touch_off(0, 1, 0.0, true));
+        approx_eq(brain.work_offsets[1].offsets[0].value, 104.0);
+        // Approached from +Y, the contact is on the tool's -Y side.
+        assert!(brain.touch_off(1, 1, 20.0, true));
+        approx_eq(brain.work_offsets[1].offsets[1].value, 6.0);
+        // The tip of the 50 mm tool sits 5 mm above work Z0.
+        assert!(brain.touch_off(2, 1, 5.0, true));
+        approx_eq(brain.work_offsets[1].offsets[2].value, -255.0);
+        assert!(brain.touch_off(2, 1, 5.0, false));
+        approx_eq(brain.work_offsets[1].offsets[2].value, -205.0);
+
+        brain.jog_direction.clear();
+        assert!(!brain.touch_off(0, 1, 0.0, true));
+        assert!(brain.touch_off(0, 1, 0.0, false));
+        assert!(!brain.touch_off(9, 1, 0.0, false));
+    }
+
+    #[test]
+    fn wcs_travel_limit_guards_against_offset_mistakes() {
+        let mut brain = make_xyz_brain();
+        brain.work_offsets[1].offsets[2].value = -100.0;
+        brain.set_wcs_travel_limit(1, 2, Some(-5.0), None);
+
+        // Same depth in G54 is fine; the limit only applies to G55.
+        let run = brain.run_verification(0, "G54 G90 G21 G1 Z-8 F100\n").unwrap();
+        assert!(run.alarms.is_empty());
+
+        let run = brain.run_verification(0, "G55 G90 G21 G0 Z10\nG1 Z-4 F100\nG1 Z-6\n").unwrap();
+        assert!(!run.completed || !run.alarms.is_empty());
+        assert_eq!(run.alarms[0].code, "WCS_LIMIT");
+        assert!(run.alarms[0].message.contains("Z-6.000 below G55 limit Z-5.000"), "{}", run.alarms[0].message);
+        approx_eq(run.path.last().unwrap().position[2].value, -104.0);
+
+        // Execution: a position reached outside the parser (e.g. offset edited
+        // mid-move) is caught on the next tick.
+        brain.active_wcs = 1;
+        brain.axes[2].position = -110.0;
+        brain.axes[2].target = -120.0;
+        brain.channels[0].is_running = true;
+        brain.tick(10.0);
+        assert!(!brain.channels[0].is_running);
+        approx_eq(brain.axes[2].target, brain.axes[2].position);
+
+        // Limits are setup: operators can neither loosen nor clear them.
+        assert!(brain.set_access_level("operator".to_string(), String::new()));
+        brain.set_wcs_travel_limit(1, 2, None, None);
+        brain.clear_wcs_travel_limits();
+        assert_eq!(brain.wcs_limits.len(), 1);
+    }
+
+    #[test]
+    fn retrace_runs_back_and_stops_before_threading() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 100_000.0;
+        }
+        brain.load_program(
+            0,
+            "G90 G21 S600 M3\nG1 X10 F3000\nG33 Z-5 F1.5\nG1 X20 F3000\nG1 X20 Y10\n".to_string(),
+        );
+        for _ in 0..20_000 {
+            brain.tick(1.0);
+        }
+        assert!(!brain.channels[0].is_running);
+        brain.channels[0].paused = true;
+
+        // Back along Y, X, then the thread blocks: stop at its end point.
+        let queued = brain.retrace(0, 100);
+        assert!(queued >= 2);
+        let events = brain.events.iter().map(|e| e.code.as_str()).collect::<Vec<_>>();
+        assert_eq!(events, vec!["RETRACE_BLOCKED"]);
+        assert!(brain.events[0].message.contains("G33"));
+        for _ in 0..5_000 {
+            brain.tick(1.0);
+        }
+        assert!(!brain.channels[0].retracing);
+        approx_eq(brain.axes[0].position, 10.0);
+        approx_eq(brain.axes[1].position, 0.0);
+        approx_eq(brain.axes[2].position, -5.0);
+
+        // Nothing more to retrace past the thread.
+        assert_eq!(brain.retrace(0, 100), 0);
+        assert_eq!(brain.events.len(), 2);
+    }
+
+    #[test]
+    fn why_cannot_start_lists_actionable_reasons() {
+        let mut brain = make_xyz_brain();
+        let codes = |b: &MachineBrain| b.start_inhibits(0).into_iter().map(|i| i.code).collect::<Vec<_>>();
+        assert_eq!(codes(&brain), vec!["NOT_HOMED", "NO_PROGRAM"]);
+        assert_eq!(brain.start_inhibits(0)[0].message, "Axes not homed: X, Y, Z");
+
+        for ax in brain.axes.iter_mut() {
+            ax.homed = true;
+        }
+        brain.set_estop(true);
+        brain.set_door_open(true);
+        assert_eq!(codes(&brain), vec!["ESTOP", "DOOR_OPEN", "NO_PROGRAM"]);
+
+        brain.set_estop(false);
+        brain.set_door_open(false);
+        brain.load_program(0, "G1 X1 F100\n".to_string());
+        assert_eq!(codes(&brain), vec!["RUNNING"]);
+        brain.reset_program(0);
+        assert!(codes(&brain).is_empty());
+        assert_eq!(brain.start_inhibits(9)[0].code, "NO_CHANNEL");
+    }
+
+    #[test]
+    fn disabled_overrides_lock_at_100_percent_and_report_attempts() {
+        let mut brain = make_xyz_brain();
+        brain.set_feed_override(0, 0.5);
+        brain.set_spindle_override(0, 1.2);
+        brain.channels[0].feed_rate = 1000.0;
+        brain.channels[0].current_motion = 1;
+        approx_eq(brain.effective_feed(0), 500.0);
+
+        brain.set_override_enable(0, false, false);
+        approx_eq(brain.effective_feed(0), 1000.0);
+        brain.set_feed_override(0, 0.8);
+        brain.set_spindle_override(0, 0.9);
+        approx_eq(brain.channels[0].feed_override, 0.5);
+        approx_eq(brain.channels[0].spindle_override, 1.2);
+        assert_eq!(brain.events.len(), 2);
+        assert!(brain.events.iter().all(|e| e.code == "OVERRIDE_LOCKED"));
+
+        // Program lock: M49 locks, M48 releases back to the dial value.
+        brain.set_override_enable(0, true, true);
+        brain.parse_line(0, "M49");
+        approx_eq(brain.effective_feed(0), 1000.0);
+        brain.parse_line(0, "M48");
+        approx_eq(brain.effective_feed(0), 500.0);
+    }
+
+    #[test]
+    #[cfg(feature = "dialects")]
+    fn modal_group_conflicts_follow_the_dialect() {
+        let mut brain = make_xyz_brain();
+        assert!(brain.set_dialect("linuxcnc".to_string()));
+        brain.parse_line(0, "G90 G21 G0 G1 X10 F100");
+        assert_eq!(brain.alarms[0].code, "BLOCK_FORMAT");
+        assert_eq!(brain.alarms[0].severity, AlarmSeverity::Fault);
+        assert!(brain.alarms[0].message.starts_with("G0 and G1 in one block (motion group)"));
+        approx_eq(brain.axes[0].target, 0.0);
+
+        // Repeating the same code or mixing groups is fine.
+        assert!(block_modal_conflicts("G1 G1 G90.1 G90 M3 M8 X1").is_empty());
+        assert_eq!(block_modal_conflicts("G41 G42 G20 G21 M8 M9").len(), 3);
+        assert_eq!(block_modal_conflicts("G90.1 G91.1")[0].0, "arc distance");
+
+        // Fanuc: last one wins, flagged as a warning.
+        let mut brain = make_xyz_brain();
+        assert!(brain.set_dialect("fanuc".to_string()));
+        brain.parse_line(0, "G90 G21 G1 G0 X10");
+        assert_eq!(brain.alarms[0].severity, AlarmSeverity::Warning);
+        assert_eq!(brain.channels[0].current_motion, 0);
+        approx_eq(brain.axes[0].target, 10.0);
+    }
+
+    #[test]
+    fn word_ranges_are_validated_against_machine_limits() {
+        let mut brain = make_xyz_brain();
+        brain.set_word_limits(12_000.0, 20, 50);
+
+        brain.parse_line(0, "S24000 M3");
+        approx_eq(brain.channels[0].spindle_rpm, 12_000.0);
+        assert_eq!(brain.alarms[0].severity, AlarmSeverity::Warning);
+        assert_eq!(brain.alarms[0].message, "S24000 exceeds spindle max 12000 rpm; clamped (line 0: S24000 M3)");
+
+        for (block, part) in [("T21 M6", "T21"), ("G43 H51 Z5", "H51"), ("G1 X5 F-100", "F-100"), ("G1 X5 F0", "F0")] {
+            brain.alarms.clear();
+            brain.parse_line(0, block);
+            assert_eq!(brain.alarms.len(), 1, "{}", block);
+            assert_eq!(brain.alarms[0].severity, AlarmSeverity::Fault);
+            assert!(brain.alarms[0].message.starts_with(part), "{}", brain.alarms[0].message);
+        }
+        approx_eq(brain.axes[0].target, 0.0);
+        assert_eq!(brain.channels[0].active_tool, 0);
+
+        // Literal (non-integer) D radii are not slots.
+        brain.alarms.clear();
+        brain.parse_line(0, "T20 H50 D2.5");
+        assert!(brain.alarms.is_empty());
+    }
+
+    #[test]
+    fn tolerance_profile_tunes_arc_checks() {
+        let mut brain = make_xyz_brain();
+        assert_eq!(brain.tolerances, ToleranceProfile::default());
+        // End radius off by 0.05 mm: rejected by default, accepted when loosened.
+        brain.parse_line(0, "G90 G21 G17 G1 X10 Y0 F1000");
+        brain.parse_line(0, "G2 X0 Y-10.05 I-10 J0");
+        assert_eq!(brain.alarms[0].code, "ARC_RADIUS");
+
+        let mut brain = make_xyz_brain();
+        assert!(brain.apply_tolerance_profile(ToleranceProfile { arc_radius_mm: 0.1, ..ToleranceProfile::default() }));
+        brain.parse_line(0, "G90 G21 G17 G1 X10 Y0 F1000");
+        brain.parse_line(0, "G2 X0 Y-10.05 I-10 J0");
+        assert!(brain.alarms.is_empty());
+
+        // Finer chord error means more arc segments.
+        let segments = |chord: f64| {
+            let mut brain = make_xyz_brain();
+            brain.apply_tolerance_profile(ToleranceProfile { arc_chord_mm: chord, ..ToleranceProfile::default() });
+            brain.parse_line(0, "G90 G21 G17 G1 X10 Y0 F1000");
+            brain.parse_line(0, "G2 X-10 Y0 I-10 J0");
+            brain.channels[0].pending.len()
+        };
+        assert!(segments(0.0005) > segments(0.005) * 2);
+
+        assert!(!brain.apply_tolerance_profile(ToleranceProfile { position_mm: 0.0, ..ToleranceProfile::default() }));
+        assert!(!brain.apply_tolerance_profile(ToleranceProfile { angle_rad: f64::NAN, ..ToleranceProfile::default() }));
+    }
+
+    #[test]
+    fn rotary_axes_use_degree_rapids_and_limit_modes() {
+        let mut brain = make_xyz_brain();
+        let a = brain.add_axis("A".to_string(), AxisType::Rotary, -30.0, 120.0).unwrap();
+        brain.channels[0].axis_map.push(ChannelAxisMap { axis_id: a, display_label: "A".to_string() });
+        // A rotary axis no longer drags the linear rapid down to its deg/min rate.
+        approx_eq(brain.channel_rapid_feed(0), RAPID_LINEAR_MIN_MM_MIN);
+        approx_eq(brain.channel_rapid_feed_for(0, AxisType::Rotary).unwrap(), RAPID_ROTARY_MIN_DEG_MIN);
+
+        // Continuous: wraps; limited: clamps to the range without wrapping.
+        brain.parse_line(0, "G90 G0 A270");
+        approx_eq(brain.axes[a as usize].target, -90.0);
+        assert!(brain.set_rotary_mode(a, "limited".to_string()));
+        brain.parse_line(0, "G0 A270");
+        approx_eq(brain.axes[a as usize].target, 120.0);
+        brain.jog_axis(a, -500.0);
+        approx_eq(brain.axes[a as usize].target, -30.0);
+        assert!(!brain.set_rotary_mode(0, "limited".to_string()));
+        assert!(!brain.set_rotary_mode(a, "spiral".to_string()));
+
+        let snapshot = brain.observability_snapshot();
+        let units: Vec<&str> = snapshot.axes.iter().map(|ax| ax.units.as_str()).collect();
+        assert_eq!(units, vec!["mm", "mm", "mm", "deg"]);
+    }
+
+    #[test]
+    fn work_offset_copy_shift_and_swap() {
+        let mut brain = make_xyz_brain();
+        brain.set_work_zero(0, 0, 100.0);
+        brain.set_work_zero(2, 0, -50.0);
+        assert!(brain.copy_work_offset(0, 1));
+        approx_eq(brain.work_offsets[1].offsets[0].value, 100.0);
+        approx_eq(brain.work_offsets[1].offsets[2].value, -50.0);
+        assert_eq!(brain.work_offsets[1].label, "G55");
+
+        // Shift all editable offsets in Z; G153 stays at machine zero.
+        assert_eq!(brain.shift_work_offsets(2, 0.05), 6);
+        approx_eq(brain.work_offsets[0].offsets[2].value, -49.95);
+        approx_eq(brain.work_offsets[5].offsets[2].value, 0.05);
+        approx_eq(brain.work_offsets[6].offsets[2].value, 0.0);
+
+        brain.set_work_zero(0, 2, 7.0);
+        assert!(brain.swap_work_offsets(0, 2));
+        approx_eq(brain.work_offsets[0].offsets[0].value, 7.0);
+        approx_eq(brain.work_offsets[2].offsets[0].value, 100.0);
+
+        assert!(brain.rename_work_offset(2, "Vise".to_string()));
+        assert_eq!(brain.work_offsets[2].label, "Vise");
+        assert!(!brain.copy_work_offset(0, 6));
+        assert!(!brain.swap_work_offsets(0, 99));
+        assert!(!brain.rename_work_offset(0, " ".to_string()));
+    }
+
+    #[test]
+    fn offset_and_tool_edits_can_be_undone_and_redone() {
+        let mut brain = make_xyz_brain();
+        brain.set_edit_tag("trainee".to_string());
+        brain.set_work_zero(2, 0, -120.0);
+        brain.set_tool_table_entry(0, 3, 80.0, 5.0);
+        brain.shift_work_offsets(2, 0.05);
+        assert_eq!(brain.undo_stack.len(), 3);
+
+        // A failed edit changes nothing and is not recorded.
+        assert!(!brain.copy_work_offset(0, 6));
+        assert_eq!(brain.undo_stack.len(), 3);
+
+        assert!(brain.undo());
+        approx_eq(brain.work_offsets[0].offsets[2].value, -120.0);
+        approx_eq(brain.work_offsets[1].offsets[2].value, 0.0);
+        assert!(brain.undo());
+        assert!(!brain.channels[0].tool_table.contains_key(&3));
+        assert!(brain.redo());
+        approx_eq(brain.channels[0].tool_table[&3].length, 80.0);
+
+        // A new edit drops the redo branch.
+        brain.set_work_zero(0, 0, 5.0);
+        assert!(!brain.can_redo());
+
+        let actions: Vec<&str> = brain.audit_log.iter().map(|e| e.action.as_str()).collect();
+        assert_eq!(
+            actions,
+            vec![
+                "set_work_zero",
+                "set_tool_table_entry",
+                "shift_work_offsets",
+                "undo shift_work_offsets",
+                "undo set_tool_table_entry",
+                "redo set_tool_table_entry",
+                "set_work_zero",
+            ]
+        );
+        assert!(brain.audit_log.iter().all(|e| e.tag == "trainee"));
+
+        // Undo brings back the registers with the values; program modals stay.
+        brain.parse_line(0, "G43 H3 D3");
+        brain.set_tool_length(0, 60.0);
+        brain.parse_line(0, "G90.1");
+        assert!(brain.undo());
+        approx_eq(brain.channels[0].tool_length, 80.0);
+        assert_eq!((brain.channels[0].active_h, brain.channels[0].active_d), (3, 3));
+        assert!(brain.channels[0].arc_center_absolute);
+    }
+
+    #[test]
+    fn access_levels_gate_protected_edits() {
+        let mut brain = make_xyz_brain();
+        assert!(brain.set_access_key("admin".to_string(), "teacher".to_string()));
+        assert!(brain.set_access_level("operator".to_string(), String::new()));
+
+        // Students: no offsets, geometry, settings or programs; wear is fine.
+        brain.set_work_zero(0, 0, 12.0);
+        approx_eq(brain.work_offsets[0].offsets[0].value, 0.0);
+        brain.set_tool_table_entry(0, 1, 99.0, 3.0);
+        assert!(!brain.set_dialect("linuxcnc".to_string()));
+        brain.load_program(0, "G1 X1 F100".to_string());
+        assert!(brain.channels[0].program.is_empty());
+        assert!(brain.set_tool_wear(0, 1, -0.02, -0.01));
+        assert_eq!(brain.events.iter().filter(|e| e.code == "ACCESS_DENIED").count(), 4);
+
+        brain.parse_line(0, "T1 M6");
+        approx_eq(brain.channels[0].tool_length, 49.98);
+        approx_eq(brain.channels[0].tool_radius, 3.99);
+
+        // Raising the switch needs the key; setters may be handed offsets only.
+        assert!(!brain.set_access_level("admin".to_string(), "guess".to_string()));
+        assert!(brain.set_access_level("setter".to_string(), String::new()));
+        brain.set_work_zero(0, 0, 12.0);
+        approx_eq(brain.work_offsets[0].offsets[0].value, 12.0);
+        assert!(!brain.set_permission_level("offsets".to_string(), "admin".to_string()));
+        assert!(brain.set_access_level("admin".to_string(), "teacher".to_string()));
+        assert!(brain.set_permission_level("offsets".to_string(), "admin".to_string()));
+        assert!(brain.set_access_level("setter".to_string(), String::new()));
+        assert!(!brain.copy_work_offset(0, 1));
+        assert_eq!(brain.add_work_offset("G54.1 P1".to_string()), None);
+        assert_eq!(brain.get_access_level(), "setter");
+
+        // Persistent parameters are settings; undo and redo need the edit's access.
+        assert!(brain.set_access_level("admin".to_string(), "teacher".to_string()));
+        assert!(brain.set_parameter(0, "#500".to_string(), 3.0));
+        assert!(brain.set_access_level("operator".to_string(), String::new()));
+        assert!(!brain.set_parameter(0, "#501".to_string(), 1.0));
+        assert!(!brain.undo());
+        approx_eq(brain.persistent_params[&500], 3.0);
+        assert!(brain.set_access_level("admin".to_string(), "teacher".to_string()));
+        assert!(brain.undo());
+        assert!(brain.set_access_level("operator".to_string(), String::new()));
+        assert!(!brain.redo());
+        assert!(!brain.persistent_params.contains_key(&500));
+    }
+
+    #[test]
+    #[cfg(feature = "dialects")]
+    fn batch_commands_apply_all_or_nothing() {
+        let mut brain = make_xyz_brain();
+        let ok = r#"[
+            {"op": "set_work_zero", "wcs": 0, "axis_id": 2, "value": -100},
+            {"op": "shift_work_offset", "axis_id": 2, "delta": 0.05},
+            {"op": "set_tool_entry", "channel": 0, "slot": 4, "length": 75, "radius": 3},
+            {"op": "set_dialect", "name": "linuxcnc"}
+        ]"#;
+        let outcome = brain.apply_batch_json(ok);
+        assert!(outcome.applied, "{:?}", outcome);
+        approx_eq(brain.work_offsets[0].offsets[2].value, -99.95);
+        approx_eq(brain.work_offsets[3].offsets[2].value, 0.05);
+        assert_eq!(brain.dialect, Dialect::LinuxCnc);
+        // One undo step for the whole batch.
+        assert_eq!(brain.undo_stack.len(), 1);
+
+        let bad = r#"[
+            {"op": "set_work_zero", "wcs": 1, "axis_id": 0, "value": 5},
+            {"op": "copy_work_offset", "from": 0, "to": 6},
+            {"op": "set_tool_wear", "channel": 0, "slot": 9, "length_wear": 0.1, "radius_wear": 0}
+        ]"#;
+        let outcome = brain.apply_batch_json(bad);
+        assert!(!outcome.applied);
+        let ok_flags: Vec<bool> = outcome.results.iter().map(|r| r.ok).collect();
+        assert_eq!(ok_flags, vec![true, false, false]);
+        approx_eq(brain.work_offsets[1].offsets[0].value, 0.0);
+        assert_eq!(brain.undo_stack.len(), 1);
+
+        assert!(brain.apply_batch_json("{not json").error.is_some());
+
+        // Access levels apply per command.
+        brain.set_access_level("operator".to_string(), String::new());
+        let outcome = brain.apply_batch_json(r#"[{"op": "set_tool_wear", "channel": 0, "slot": 4, "length_wear": -0.1, "radius_wear": 0},
+            {"op": "set_word_limits", "spindle_max_rpm": 1000, "magazine_pockets": 0, "offset_slots": 0}]"#);
+        assert!(!outcome.applied);
+        assert_eq!(outcome.results[1].error.as_deref(), Some("needs Admin access"));
+    }
+
+    #[test]
+    fn channels_can_be_removed_and_remapped() {
+        let mut brain = make_xyz_brain();
+        brain.channels.push(Channel::new(7, vec![ChannelAxisMap { axis_id: 2, display_label: "Z".to_string() }]));
+        brain.set_tool_table_entry(1, 5, 40.0, 2.0);
+
+        // Remapping is refused while running, and labels must be unique.
+        brain.load_program(0, "G1 X50 F1000".to_string());
+        brain.tick(50.0);
+        let remap = vec![
+            ChannelAxisMap { axis_id: 0, display_label: "X".to_string() },
+            ChannelAxisMap { axis_id: 1, display_label: "Y".to_string() },
+        ];
+        assert!(!brain.reassign_channel_axes(0, remap.clone()));
+        assert!(!brain.relabel_channel_axis(0, 0, "y".to_string()));
+
+        // Removing a running channel stops it with its axes holding.
+        brain.raise_alarm("ARC_RADIUS", "test", AlarmSeverity::Warning, Some(0));
+        assert!(brain.remove_channel(0));
+        assert_eq!(brain.channels.len(), 1);
+        approx_eq(brain.axes[0].target, brain.axes[0].position);
+        assert!(brain.axes[0].position < 50.0);
+        assert!(brain.alarms.iter().all(|a| !a.active || a.channel != Some(0)));
+
+        assert!(brain.relabel_channel_axis(0, 2, "W".to_string()));
+        assert_eq!(brain.channels[0].axis_map[0].display_label, "W");
+        assert!(!brain.reassign_channel_axes(0, vec![ChannelAxisMap { axis_id: 9, display_label: "Q".to_string() }]));
+        assert!(brain.reassign_channel_axes(0, remap));
+
+        // Undo follows channels by id, not position.
+        assert!(brain.undo());
+        assert!(!brain.channels[0].tool_table.contains_key(&5));
+        assert!(!brain.remove_channel(3));
+
+        // Channel structure is a settings edit.
+        assert!(brain.set_access_level("operator".to_string(), String::new()));
+        assert!(!brain.relabel_channel_axis(0, 0, "U".to_string()));
+        assert!(!brain.remove_channel(0));
+        assert_eq!(brain.channels.len(), 1);
+    }
+
+    #[test]
+    fn axes_keep_stable_ids_across_removal_and_reorder() {
+        let mut brain = make_xyz_brain();
+        let a = brain.add_axis("A".to_string(), AxisType::Rotary, -360.0, 360.0).unwrap();
+        brain.set_work_zero(2, 0, -80.0);
+        assert!(brain.remove_axis(1));
+        assert_eq!(brain.channels[0].axis_map.len(), 2);
+        assert!(brain.work_offsets[0].offsets.iter().all(|o| o.axis_id != 1));
+
+        // Ids are not reused and lookups go by id.
+        let b = brain.add_axis("B".to_string(), AxisType::Rotary, -360.0, 360.0).unwrap();
+        assert_eq!((a, b), (3, 4));
+        brain.parse_line(0, "G90 G21 G1 Z-5 F100");
+        approx_eq(find_axis(&brain.axes, 2).unwrap().target, -85.0);
+
+        assert!(brain.reorder_axes(vec![4, 3, 2, 0]));
+        let names: Vec<&str> = brain.axes.iter().map(|ax| ax.physical_name.as_str()).collect();
+        assert_eq!(names, vec!["B", "A", "Z", "X"]);
+        brain.parse_line(0, "X7");
+        approx_eq(find_axis(&brain.axes, 0).unwrap().target, 7.0);
+        assert!(!brain.reorder_axes(vec![4, 3, 2]));
+
+        brain.load_program(0, "G1 X50 F100".to_string());
+        assert!(!brain.remove_axis(0));
+        assert!(!brain.remove_axis(1));
+
+        // Axis structure is a settings edit.
+        assert!(brain.set_access_level("operator".to_string(), String::new()));
+        assert!(!brain.reorder_axes(vec![0, 2, 3, 4]));
+        assert!(!brain.remove_axis(4));
+        assert_eq!(brain.axes.len(), 4);
+    }
+
+    #[test]
+    fn reconfigure_keeps_unchanged_state() {
+        let mut brain = MachineBrain::new();
+        let base = r#"{"axes": [
+            {"name": "X", "kind": "Linear", "min": -500, "max": 500},
+            {"name": "Y", "kind": "Linear", "min": -400, "max": 400},
+            {"name": "Z", "kind": "Linear", "min": -300, "max": 0}
+        ], "channels": [{"id": 0, "axes": [
+            {"axis": "X", "label": "X"}, {"axis": "Y", "label": "Y"}, {"axis": "Z", "label": "Z"}
+        ]}]}"#;
+        let report = brain.reconfigure_json(base);
+        assert!(report.applied, "{:?}", report.error);
+        assert_eq!(report.added_axes, vec!["X", "Y", "Z"]);
+        assert_eq!(report.added_channels, vec![0]);
+
+        brain.axes[0].position = 120.0;
+        brain.axes[2].position = -250.0;
+        brain.set_work_zero(0, 0, 33.0);
+        brain.load_program(0, "G1 X1 F100".to_string());
+        brain.reset_program(0);
+
+        // Tighten Z, add A on the same channel: X keeps everything, Z is clamped.
+        let next = base
+            .replace(r#""max": 0}"#, r#""max": 0, "accel": 500}, {"name": "A", "kind": "Rotary", "min": -360, "max": 360}"#)
+            .replace(r#""min": -300"#, r#""min": -200"#)
+            .replace(r#"{"axis": "Z", "label": "Z"}"#, r#"{"axis": "Z", "label": "Z"}, {"axis": "A", "label": "A"}"#);
+        let report = brain.reconfigure_json(&next);
+        assert!(report.applied, "{:?}", report.error);
+        assert_eq!(report.changed_axes, vec!["Z"]);
+        assert_eq!(report.added_axes, vec!["A"]);
+        assert_eq!(report.remapped_channels, vec![0]);
+        approx_eq(brain.axes[0].position, 120.0);
+        approx_eq(brain.axes[2].position, -200.0);
+        approx_eq(brain.work_offsets[0].offsets[0].value, 33.0);
+        assert_eq!(brain.channels[0].program.len(), 1);
+
+        // Removing Y drops it everywhere; bad configs change nothing.
+        let report = brain.reconfigure_json(&next.replace(r#"{"name": "Y", "kind": "Linear", "min": -400, "max": 400},"#, "").replace(r#"{"axis": "Y", "label": "Y"}, "#, ""));
+        assert_eq!(report.removed_axes, vec!["Y"]);
+        assert_eq!(brain.axes.len(), 3);
+        assert!(brain.reconfigure_json(r#"{"axes": [{"name": "X", "kind": "Linear", "min": 1, "max": 0}]}"#).error.is_some());
+        assert_eq!(brain.axes.len(), 3);
+
+        // Rebuilding the machine is a settings edit.
+        assert!(brain.set_access_level("operator".to_string(), String::new()));
+        let report = brain.reconfigure_json(base);
+        assert_eq!(report.error.as_deref(), Some("needs Admin access"));
+        assert_eq!(brain.axes.len(), 3);
+    }
+
+    #[test]
+    fn machine_settings_need_settings_access() {
+        let mut brain = make_xyz_brain();
+        let a = brain.add_axis("A".to_string(), AxisType::Rotary, -360.0, 360.0).unwrap();
+        assert!(brain.set_access_level("operator".to_string(), String::new()));
+
+        // Axis, kinematics and machine structure edits are all refused.
+        assert_eq!(brain.add_axis("B".to_string(), AxisType::Rotary, -360.0, 360.0), None);
+        brain.set_axis_accel(0, 123.0);
+        brain.set_axis_machine_zero(0, 5.0);
+        brain.set_axis_invert(0, true);
+        assert!(!brain.set_axis_kinematics(a, "tool".to_string(), "A".to_string()));
+        assert!(!brain.set_rotary_mode(a, "limited".to_string()));
+        assert!(!brain.set_spindle_mount("+X".to_string(), 0.0, 0.0, 10.0));
+        brain.clear_config();
+        assert_eq!(brain.events.iter().filter(|e| e.code == "ACCESS_DENIED").count(), 8);
+
+        assert_eq!(brain.axes.len(), 4);
+        assert_ne!(brain.axes[0].accel, 123.0);
+        approx_eq(brain.axes[0].machine_zero, 0.0);
+        assert!(!brain.axes[0].invert);
+        assert_eq!(brain.axes[3].side, AxisSide::Table);
+        assert_eq!(brain.axes[3].rotary_mode, RotaryMode::Continuous);
+        assert_eq!(brain.spindle_direction, "-Z");
+    }
+
+    #[test]
+    fn machine_templates_build_by_name() {
+        let mut brain = MachineBrain::new();
+        for name in MACHINE_TEMPLATES {
+            assert!(brain.load_machine_template(name.to_string()), "{name}");
+            assert!(!brain.axes.is_empty() && !brain.channels.is_empty());
+            assert!(brain.channels.iter().all(|c| !c.tool_table.is_empty()));
+        }
+        assert!(brain.load_machine_template("trunnion5".to_string()));
+        assert_eq!(brain.axes.len(), 5);
+        assert_eq!(brain.axes[3].rotary_mode, RotaryMode::Limited);
+        assert_eq!(brain.axes[4].side, AxisSide::Table);
+        assert_eq!(brain.axes[0].id, 0);
+        assert!(!brain.load_machine_template("hexapod".to_string()));
+        assert_eq!(brain.axes.len(), 5);
+        assert!(brain.set_access_level("setter".to_string(), String::new()));
+        assert!(!brain.load_machine_template("vmc3".to_string()));
+        assert_eq!(brain.axes.len(), 5);
+    }
+
+    #[test]
+    fn axis_meters_follow_motion() {
+        let mut brain = make_xyz_brain();
+        brain.set_axis_accel(0, 2000.0);
+        brain.load_program(0, "G1 X200 F1200".to_string());
+        let mut peak_load: f64 = 0.0;
+        for _ in 0..50 {
+            brain.tick(20.0);
+            peak_load = peak_load.max(brain.axes[0].load);
+        }
+        // Accelerating loads the drive more than cruising.
+        assert!(peak_load > 40.0, "{peak_load}");
+        for _ in 0..200 {
+            brain.tick(20.0);
+        }
+        assert!(brain.axes[0].feed > 600.0, "{}", brain.axes[0].feed);
+        assert!(brain.axes[0].load < 20.0, "{}", brain.axes[0].load);
+        approx_eq(brain.axes[1].feed, 0.0);
+        for _ in 0..1500 {
+            brain.tick(20.0);
+        }
+        approx_eq(brain.axes[0].position, 200.0);
+        approx_eq(brain.axes[0].feed, 0.0);
+        assert!(brain.axes[0].load < 1.0);
+    }
+
+    #[test]
+    fn run_timers_split_rapid_cutting_and_idle() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 100_000.0;
+        }
+        brain.load_program(0, "G61 G0 X50\nS1000 M3 M8\nG1 X60 F600\nM5 M9\nM30".to_string());
+        for _ in 0..400 {
+            brain.tick(10.0);
+        }
+        let report = brain.job_report(0).unwrap();
+        assert!(report.completed);
+        let t = report.timers;
+        assert!(t.rapid_s > 0.0 && t.rapid_s < 0.5, "{t:?}");
+        // 10 mm at 600 mm/min plus the deceleration ramp.
+        assert!(t.cutting_s >= 1.0 && t.cutting_s < 1.5, "{t:?}");
+        assert!((t.spindle_on_s - t.cutting_s).abs() < 0.1, "{t:?}");
+        assert!((t.coolant_on_s - t.spindle_on_s).abs() < 1e-9, "{t:?}");
+        approx_eq(t.total_s, t.rapid_s + t.cutting_s + t.idle_s);
+        assert_eq!(t.dwell_s, 0.0);
+
+        brain.load_program(0, "G1 X0 F600".to_string());
+        assert_eq!(brain.channels[0].run_timers.total_s, 0.0);
+    }
+
+    #[test]
+    #[cfg(feature = "material-removal")]
+    fn job_report_breaks_down_tools_travel_and_stock() {
+        let mut brain = make_xyz_brain();
+        brain.set_tool_table_entry(0, 1, 0.0, 5.0);
+        brain.set_tool_table_entry(0, 2, 0.0, 2.0);
+        assert!(brain.set_stock_box(0.0, 0.0, -20.0, 100.0, 50.0, 0.0));
+        // T1 slots 2 mm deep across 100 mm, T2 rapids away.
+        let code = "T1 M6\nG0 X0 Y25 Z5\nG1 Z-2 F300\nG1 X100 F600\nG0 Z5\nT2 M6\nG0 X0 Y0\nM30";
+        let json = brain.verify_job_report(0, code.to_string());
+        let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(report["source"], "verification");
+        assert_eq!(report["completed"], true);
+        let tools = report["tools"].as_array().unwrap();
+        assert_eq!(tools.len(), 2);
+        assert_eq!(tools[0]["tool"], 1);
+        assert!((tools[0]["cut_length"].as_f64().unwrap() - 107.0).abs() < 1e-6);
+        assert_eq!(tools[1]["cut_length"].as_f64().unwrap(), 0.0);
+        assert!((report["max_cut_feed"].as_f64().unwrap() - 600.0).abs() < 1e-6);
+        // 10 mm wide, 2 mm deep slot plus the round ends: ~2000 + 2·(π·25/2)·2.
+        let removed = report["stock_removed_mm3"].as_f64().unwrap();
+        assert!((removed - 2000.0).abs() < 150.0, "{removed}");
+
+        brain.clear_stock_box();
+        let report = brain.verification_job_report(0, code).unwrap();
+        assert!(report.stock_removed_mm3.is_none());
+        assert!(brain.export_job_report(9).is_empty());
+    }
+
+    #[test]
+    fn input_rule_holds_on_path_and_resumes() {
+        let mut brain = make_xyz_brain();
+        brain.axes[0].accel = 6000.0;
+        brain.axes[1].accel = 3000.0;
+        assert!(brain.add_hold_rule("probe_armed".to_string(), true, None));
+        brain.load_program(0, "G1 X100 Y50 F1200".to_string());
+        for _ in 0..10 {
+            brain.tick(10.0);
+        }
+        let (x0, y0) = (brain.axes[0].position, brain.axes[1].position);
+        let (vx, vy) = (brain.axes[0].velocity, brain.axes[1].velocity);
+        brain.set_input("probe_armed".to_string(), true);
+        brain.tick(10.0);
+        assert!(brain.channels[0].auto_hold);
+        assert!(brain.axes[0].velocity > 0.0, "decelerates instead of stopping dead");
+        for _ in 0..100 {
+            brain.tick(10.0);
+        }
+        // Both axes ramp down together, keeping the direction of travel.
+        let (x, y) = (brain.axes[0].position, brain.axes[1].position);
+        assert!(brain.axes.iter().all(|ax| ax.velocity == 0.0));
+        approx_eq((y - y0) / (x - x0), vy / vx);
+        brain.tick(10.0);
+        approx_eq(brain.axes[0].position, x);
+
+        brain.set_input("probe_armed".to_string(), false);
+        for _ in 0..2000 {
+            brain.tick(10.0);
+        }
+        assert!(!brain.channels[0].auto_hold);
+        approx_eq(brain.axes[0].position, 100.0);
+        let codes: Vec<String> = brain.events.iter().map(|e| e.code.clone()).collect();
+        assert!(codes.contains(&"AUTO_HOLD".to_string()) && codes.contains(&"AUTO_RESUME".to_string()));
+    }
+
+    #[test]
+    fn g64_p_sets_block_path_tolerance_until_restored() {
+        let mut brain = make_xyz_brain();
+        let arc_points = |brain: &mut MachineBrain, line: &str| {
+            brain.parse_line(0, "G90 G17 G0 X10 Y0");
+            brain.channels[0].pending.clear();
+            brain.parse_line(0, line);
+            let n = brain.channels[0].pending.len();
+            brain.channels[0].pending.clear();
+            n
+        };
+        let coarse = arc_points(&mut brain, "G64 P0.5 G3 X-10 Y0 I-10 J0");
+        assert_eq!(brain.channels[0].path_tolerance, Some(0.5));
+        let fine = arc_points(&mut brain, "G64 P0.001 G3 X-10 Y0 I-10 J0");
+        assert!(fine > coarse * 4, "{fine} vs {coarse}");
+        brain.parse_line(0, "G64");
+        assert_eq!(brain.channels[0].path_tolerance, None);
+        assert!(arc_points(&mut brain, "G3 X-10 Y0 I-10 J0") < fine);
+
+        // The verification path records each move's tolerance for diff reports.
+        let brain = make_xyz_brain();
+        let run = brain.run_verification(0, "G1 X5 F100\nG64 P0.2\nG1 X6\nG64\nG1 X7").unwrap();
+        let tols: Vec<f64> = run.path.iter().skip(1).map(|p| p.tolerance).collect();
+        assert_eq!(tols, vec![0.005, 0.2, 0.005]);
+    }
+
+    #[test]
+    fn rotary_centerline_error_shows_in_tcp_until_calibrated() {
+        let mut brain = make_xyz_brain();
+        let c = brain.add_axis("C".to_string(), AxisType::Rotary, -360.0, 360.0).unwrap();
+        brain.channels[0].axis_map.push(ChannelAxisMap { axis_id: c, display_label: "C".to_string() });
+        brain.axes[0].position = 40.0;
+        assert!(brain.set_axis_calibration(c, 0.05, 0.0, 0.0, 0.0, 0.0));
+        assert!(!brain.set_axis_calibration(0, 0.05, 0.0, 0.0, 0.0, 0.0));
+        // At C0 an offset centerline is invisible; half a turn doubles it.
+        approx_eq(brain.tcp_error().iter().map(|v| v.abs()).sum(), 0.0);
+        brain.axes[3].position = 180.0;
+        let e = brain.tcp_error();
+        approx_eq(e[0].abs(), 0.1);
+        approx_eq(e[1].abs() + e[2].abs(), 0.0);
+
+        // A tilted C makes the tip error grow with the tool's height above the table.
+        assert!(brain.set_axis_calibration(c, 0.0, 0.0, 0.0, 0.01, 0.0));
+        brain.axes[3].position = 0.0;
+        assert!(brain.max_tcp_error(0, "G0 X40 C90\nG0 C180".to_string()) > 0.005);
+
+        assert_eq!(brain.calibrate_axes(), 1);
+        approx_eq(brain.tcp_error().iter().map(|v| v.abs()).sum(), 0.0);
+    }
+
+    #[test]
+    fn rotary_axis_lines_follow_chain_and_calibration() {
+        let mut brain = make_xyz_brain();
+        let c = brain.add_axis("C".to_string(), AxisType::Rotary, -360.0, 360.0).unwrap();
+        assert!(brain.set_axis_kinematics(c, "table".to_string(), "".to_string()));
+        brain.axes[0].position = 40.0;
+        brain.axes[3].position = 30.0;
+        let lines = brain.rotary_axis_lines();
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0];
+        assert_eq!((line.axis_id, line.name.as_str(), line.side), (c, "C", AxisSide::Table));
+        approx_eq(line.angle_deg, 30.0);
+        assert_eq!(line.direction, [0.0, 0.0, 1.0]);
+        assert_eq!(line.origin, line.nominal_origin);
+        approx_eq(line.end[2] - line.start[2], 2.0 * ROTARY_LINE_HALF_LENGTH);
+        approx_eq(line.tool_distance, 40.0);
+
+        // An offset centerline moves the real line, not the nominal one.
+        assert!(brain.set_axis_calibration(c, 0.05, 0.0, 0.0, 0.0, 0.0));
+        let line = &brain.rotary_axis_lines()[0];
+        approx_eq(line.origin[0] - line.nominal_origin[0], 0.05);
+        approx_eq(line.tool_distance, 39.95);
+    }
+    #[test]
+    fn aggregate_tool_redirects_length_and_cutter_comp() {
+        let mut brain = make_xyz_brain();
+        brain.set_tool_table_entry(0, 3, 40.0, 2.0);
+        assert!(brain.set_tool_direction(0, 3, "+X".to_string()));
+        assert!(!brain.set_tool_direction(0, 3, "sideways".to_string()));
+
+        brain.parse_line(0, "G90 G21 T3 M6 G43 H3 G1 X0 Y0 Z0 F1000");
+        approx_eq(brain.axes[0].target, -40.0);
+        approx_eq(brain.axes[2].target, 0.0);
+        approx_eq(brain.programmed_work_at(0, 0, brain.axes[0].target), 0.0);
+
+        // Comp now works in the ZY plane: looking along +X, left of a +Z move is +Y.
+        brain.parse_line(0, "G41 D3 G1 Y0 Z10");
+        approx_eq(brain.axes[2].target, 10.0);
+        approx_eq(brain.axes[1].target, 2.0);
+        approx_eq(brain.axes[0].target, -40.0);
+    }
+    #[test]
+    fn turret_indexes_shortest_way_and_applies_station_offsets() {
+        let mut brain = make_xyz_brain();
+        assert!(brain.set_turret(0, 8, 0.5, true));
+        assert!(brain.set_turret_offset(0, 2, 1.0, 1.0));
+        assert!(brain.undo());
+        assert_eq!(brain.channels[0].turret.as_ref().unwrap().offsets[1], [0.0, 0.0]);
+        assert!(brain.set_turret_offset(0, 3, 10.0, -5.0));
+        assert!(!brain.set_turret_offset(0, 9, 1.0, 1.0));
+
+        // Two stations forward: 1 s of indexing before the move starts.
+        brain.parse_line(0, "G90 G21 T0303 G0 X0 Z0");
+        assert_eq!(brain.channels[0].active_tool, 3);
+        approx_eq(brain.axes[0].target, 10.0);
+        approx_eq(brain.axes[2].target, -5.0);
+        for _ in 0..50 {
+            brain.tick(10.0);
+        }
+        approx_eq(brain.channels[0].turret.as_ref().unwrap().angle_deg, 45.0);
+        approx_eq(brain.axes[0].position, 0.0);
+        for _ in 0..50 {
+            brain.tick(10.0);
+        }
+        approx_eq(brain.channels[0].turret.as_ref().unwrap().angle_deg, 90.0);
+        brain.tick(10.0);
+        assert!(brain.axes[0].position > 0.0);
+
+        // 3 -> 8 is three stations backwards rather than five forwards.
+        brain.parse_line(0, "T8");
+        approx_eq(brain.channels[0].turret.as_ref().unwrap().index_remaining_s, 1.5);
+        for _ in 0..150 {
+            brain.tick(10.0);
+        }
+        approx_eq(brain.channels[0].turret.as_ref().unwrap().angle_deg, 315.0);
+
+        brain.parse_line(0, "T0909");
+        assert_eq!(brain.channels[0].active_tool, 8);
+    }
+    #[test]
+    fn gang_tool_plate_offsets_apply_on_selection() {
+        let mut brain = make_xyz_brain();
+        assert!(brain.set_tool_plate_offset(0, 2, 0.0, 60.0));
+        assert!(brain.set_tool_plate_offset(0, 3, 5.0, 120.0));
+
+        brain.parse_line(0, "G90 G21 T2 M6 G0 X10 Y0 Z0");
+        approx_eq(brain.axes[0].target, 10.0);
+        approx_eq(brain.axes[1].target, 60.0);
+        brain.tick(10.0);
+        assert!(brain.axes[1].position > 0.0);
+
+        brain.parse_line(0, "T3 M6 G0 X10 Y0");
+        approx_eq(brain.axes[0].target, 15.0);
+        approx_eq(brain.axes[1].target, 120.0);
+
+        brain.undo();
+        brain.parse_line(0, "T3 M6 G0 X10 Y0");
+        approx_eq(brain.axes[1].target, 0.0);
+    }
+    #[test]
+    fn wcs_change_inside_comp_contour_faults() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 100_000.0;
+        }
+        brain.load_program(0, "G90 G21 G54 G1 X0 Y0 F1000\nG41 D2 G1 X10 Y0\nG55 G1 X20 Y0\nG1 X30 Y0\n".to_string());
+        for _ in 0..300 {
+            brain.tick(10.0);
+        }
+        assert!(!brain.channels[0].is_running);
+        assert_eq!(brain.active_wcs, 0);
+        assert!(brain.alarms.iter().any(|a| a.code == "CONTOUR_WCS_CHANGE" && a.active));
+        approx_eq(brain.axes[0].position, 10.0);
+
+        // Canned cycles run their own moves, fresh or repeated by an X/Y block.
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G41 D2 G1 X10 Y0 F1000");
+        brain.parse_line(0, "S600 M3 G84 X20 Z-5 R2 F1");
+        assert!(brain.channels[0].canned_cycle.is_none());
+        assert!(brain.alarms.iter().any(|a| a.code == "CONTOUR_CANNED_CYCLE" && a.message.contains("G84")));
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G0 Z20");
+        brain.parse_line(0, "S600 M3 G84 X10 Y5 Z-12 R2 F1.5");
+        brain.channels[0].pending.clear();
+        brain.parse_line(0, "G41 D2");
+        brain.parse_line(0, "X30");
+        assert!(brain.alarms.iter().any(|a| a.code == "CONTOUR_CANNED_CYCLE"));
+
+        // A G52 local shift moves the contour just the same.
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G54 G41 D2 G1 X10 Y0");
+        brain.parse_line(0, "G52 X5");
+        assert!(brain.local_shift.is_empty());
+        assert!(brain.alarms.iter().any(|a| a.code == "CONTOUR_WCS_CHANGE" && a.message.contains("G52")));
+
+        // Re-stating the active offset or changing it after G40 is fine.
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G90 G21 G54 G41 D2 G1 X10 Y0");
+        brain.parse_line(0, "G54 G1 X20 Y0");
+        brain.parse_line(0, "G40 G1 X30 Y0");
+        brain.parse_line(0, "G55");
+        brain.parse_line(0, "G52 X5");
+        assert_eq!(brain.active_wcs, 1);
+        assert!(brain.alarms.is_empty());
+    }
+    #[test]
+    fn assert_comments_check_state_and_fault_the_run() {
+        let mut brain = make_xyz_brain();
+        let code = "G90 G21 G1 X10 Y5 F500 (ASSERT X=10 Y=5.0 F=500)\nG91 X2 (assert X=12.0004 TOL=0.001)\nS1200 M3\n(ASSERT S=1000 Q=1)\nG1 X20\n";
+        brain.load_program(0, code.to_string());
+        brain.run_channel_instant(0, 100, &mut Vec::new(), &mut 0.0);
+
+        let report = &brain.channels[0].assertions;
+        assert_eq!(report.checked, 6);
+        assert_eq!(report.failures.len(), 2);
+        assert!(report.failures.iter().all(|f| f.line == 4));
+        assert_eq!(report.failures[0].actual, Some(1200.0));
+        assert_eq!(report.failures[1].key, "Q");
+        assert!(report.failures[1].actual.is_none());
+        assert!(!brain.channels[0].is_running);
+        assert_eq!(brain.channels[0].pc, 4);
+        assert!(brain.alarms.iter().any(|a| a.code == "ASSERTION" && a.message.contains("line 4")));
+
+        // Expected values are in the program's units.
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "G90 G20 G1 X1 F10 (ASSERT X=1 F=10)\n(ASSERT X=25.4)\n".to_string());
+        brain.run_channel_instant(0, 100, &mut Vec::new(), &mut 0.0);
+        let report = &brain.channels[0].assertions;
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].line, 2);
+        assert_eq!(report.failures[0].actual, Some(1.0));
+    }
+    struct ZFloorHook(std::cell::RefCell<Vec<String>>);
+
+    impl BlockHook for ZFloorHook {
+        fn before(&self, ctx: &BlockContext) -> BlockHookAction {
+            let word = |a: &str| ctx.words.iter().find(|w| w.address == a).map(|w| w.value);
+            let rapid = word("G").map(|g| g == 0.0).unwrap_or(ctx.modal.motion == 0);
+            if rapid && word("Z").is_some_and(|z| z < 1.0) {
+                return BlockHookAction::Veto("G0 below Z1".to_string());
+            }
+            if word("M") == Some(8.0) {
+                return BlockHookAction::Replace("M9".to_string());
+            }
+            BlockHookAction::Run
+        }
+
+        fn after(&self, ctx: &BlockContext) {
+            let z = ctx.modal.position.iter().find(|p| p.address == "Z").map(|p| p.value).unwrap_or(0.0);
+            self.0.borrow_mut().push(format!("{}:{}:{}", ctx.line, ctx.block, z));
+        }
+    }
+
+    #[test]
+    fn block_hook_can_veto_replace_and_log_blocks() {
+        let mut brain = make_xyz_brain();
+        let hook = Rc::new(ZFloorHook(std::cell::RefCell::new(Vec::new())));
+        brain.block_hook = Some(hook.clone());
+        brain.load_program(0, "G0 Z5\nM8\nG1 Z0.5 F100\nG0 Z0.5\nG0 Z10\n".to_string());
+        brain.run_channel_instant(0, 100, &mut Vec::new(), &mut 0.0);
+
+        assert!(!brain.channels[0].coolant_on);
+        assert_eq!(*hook.0.borrow(), vec!["1:G0 Z5:5", "2:M9:5", "3:G1 Z0.5 F100:0.5"]);
+        assert_eq!(brain.channels[0].pc, 3);
+        approx_eq(brain.axes[2].position, 0.5);
+        assert!(brain.alarms.iter().any(|a| a.code == "BLOCK_HOOK" && a.message.contains("G0 below Z1")));
+    }
+    #[test]
+    fn dry_geometric_mode_runs_blocks_without_dynamics() {
+        let mut brain = make_xyz_brain();
+        brain.set_dry_geometric(0, true);
+        let code: String = (1..=500).map(|i| format!("G1 X{} Y{} F10\n", i, -i)).collect();
+        brain.load_program(0, code.clone());
+        brain.tick(1.0);
+        assert!(!brain.channels[0].is_running);
+        approx_eq(brain.axes[0].position, 500.0);
+        approx_eq(brain.axes[1].position, -500.0);
+        approx_eq(brain.axes[0].velocity, 0.0);
+
+        // Single block still stops after each block.
+        brain.set_single_block(0, true);
+        brain.load_program(0, "G0 X1\nG91 G1 Z-2\nG1 Z-2\n".to_string());
+        brain.tick(1.0);
+        assert!(brain.channels[0].paused);
+        approx_eq(brain.axes[0].position, 1.0);
+        brain.toggle_pause(0);
+        brain.tick(1.0);
+        approx_eq(brain.axes[2].position, -2.0);
+        assert!(!brain.channels[0].abs_mode);
+    }
+    #[test]
+    fn block_delete_skips_slash_blocks_and_m01_stops_when_enabled() {
+        let program = "G0 X1\n/G0 Y5\nM01\nG0 X2";
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, program.to_string());
+        for _ in 0..200 {
+            brain.tick(1.0);
+        }
+        approx_eq(brain.axes[1].position, 5.0);
+        approx_eq(brain.axes[0].position, 2.0);
+        assert!(!brain.channels[0].paused);
+
+        let mut brain = make_xyz_brain();
+        brain.set_block_delete(true);
+        brain.set_optional_stop(true);
+        brain.load_program(0, program.to_string());
+        for _ in 0..200 {
+            brain.tick(1.0);
+        }
+        assert!(brain.channels[0].paused);
+        approx_eq(brain.axes[0].position, 1.0);
+        approx_eq(brain.axes[1].position, 0.0);
+        brain.toggle_pause(0);
+        for _ in 0..200 {
+            brain.tick(1.0);
+        }
+        approx_eq(brain.axes[0].position, 2.0);
+    }
+
+    #[test]
+    fn m00_stops_m02_ends_and_m30_rewinds_with_modal_reset() {
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "G0 X1\nM00\nG0 X2\nM02\nG0 X9".to_string());
+        for _ in 0..200 {
+            brain.tick(1.0);
+        }
+        assert_eq!(brain.channels[0].run_state(), "PROGRAM_STOP");
+        assert_eq!(brain.observability_snapshot().paths[0].execution, "PROGRAM_STOPPED");
+        approx_eq(brain.axes[0].position, 1.0);
+        brain.toggle_pause(0);
+        for _ in 0..200 {
+            brain.tick(1.0);
+        }
+        approx_eq(brain.axes[0].position, 2.0);
+        assert_eq!(brain.channels[0].run_state(), "FINISHED");
+        assert_eq!(brain.channels[0].pc, 5);
+
+        brain.load_program(0, "G41 D1\nG91 G0 X5\nM30\nG0 X50".to_string());
+        for _ in 0..200 {
+            brain.tick(1.0);
+        }
+        approx_eq(brain.axes[0].position, 7.0);
+        let chan = &brain.channels[0];
+        assert_eq!((chan.run_state(), chan.pc, chan.cutter_comp, chan.abs_mode), ("FINISHED", 0, 40, true));
+        assert!(brain.job_report(0).unwrap().completed);
+
+        brain.reset_program(0);
+        assert_eq!(brain.channels[0].run_state(), "STOPPED");
+    }
+
+    #[test]
+    fn block_numbers_track_and_restart_at_sequence_number() {
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "N10 G90 G0 X1\nN20 G91 X2\nG0 X3\n/N30 G0 X4\nM30".to_string());
+        assert_eq!(brain.channels[0].block_numbers, [Some(10), Some(20), None, Some(30), None]);
+        for _ in 0..200 {
+            brain.tick(1.0);
+        }
+        // The unnumbered block keeps showing the last N read.
+        assert_eq!(brain.channels[0].block_number, Some(30));
+
+        assert!(brain.goto_block_number(0, 20));
+        let chan = &brain.channels[0];
+        assert_eq!((chan.pc, chan.block_number, chan.paused, chan.abs_mode), (1, Some(20), true, true));
+        assert!(!brain.goto_block_number(0, 99));
+    }
+
+    #[test]
+    #[cfg(feature = "collision")]
+    fn setup_tools_read_edges_and_runout_without_cutting() {
+        let mut brain = make_xyz_brain();
+        brain.set_log_console(false);
+        assert_eq!(brain.set_stock_box(0.0, 0.0, -10.0, 50.0, 50.0, 0.0), MATERIAL_REMOVAL);
+        brain.insert_protected_zone(ProtectedZone {
+            id: 0,
+            name: "bore".to_string(),
+            shape: ZoneShape::Cylinder { axis: 2, center: [100.0, 100.0], radius: 20.0, min: -20.0, max: 0.0 },
+            tool_length_aware: true,
+        });
+        brain.set_tool_table_entry(0, 2, 50.0, 2.0);
+        brain.set_tool_table_entry(0, 3, 50.0, 0.0);
+        assert!(brain.set_setup_tool(0, 2, "Edge Finder".to_string()));
+        assert!(brain.set_setup_tool(0, 3, "indicator".to_string()));
+        assert!(!brain.set_setup_tool(0, 3, "laser".to_string()));
+        assert!(brain.setup_tool_reading(0).unwrap().kind.is_none());
+
+        // Edge finder 1 mm off the stock's left side; it only kicks while turning.
+        brain.channels[0].active_tool = 2;
+        let place = |brain: &mut MachineBrain, xyz: [f64; 3]| {
+            for (ax, v) in brain.axes.iter_mut().zip(xyz) {
+                ax.position = v;
+                ax.target = v;
+            }
+        };
+        place(&mut brain, [-3.0, 25.0, 45.0]);
+        if MATERIAL_REMOVAL {
+            let reading = brain.setup_tool_reading(0).unwrap();
+            assert_eq!(reading.contact.as_deref(), Some("stock"));
+            approx_eq(reading.gap.unwrap(), 1.0);
+            assert!(!reading.tripped);
+            place(&mut brain, [-2.003, 25.0, 45.0]);
+            assert!(!brain.setup_tool_reading(0).unwrap().tripped);
+            brain.parse_line(0, "S600 M3");
+            assert!(brain.setup_tool_reading(0).unwrap().tripped);
+
+            // Driving the tip into the stock faults once and cuts nothing.
+            place(&mut brain, [1.0, 25.0, 45.0]);
+            brain.tick(1.0);
+            brain.tick(1.0);
+            let alarm = brain.alarms.iter().find(|a| a.code == "SETUP_TOOL").unwrap();
+            assert_eq!((alarm.message.as_str(), alarm.occurrences), ("Edge finder driven into stock", 1));
+        }
+
+        // Indicator 0.01 mm off the bore axis reads 0.02 TIR.
+        brain.channels[0].active_tool = 3;
+        place(&mut brain, [100.01, 100.0, 60.0]);
+        let reading = brain.setup_tool_reading(0).unwrap();
+        assert_eq!(reading.indicated.as_deref(), Some("bore"));
+        approx_eq(reading.tir.unwrap(), 0.02);
+    }
+
+    #[test]
+    fn telemetry_ring_buffer_keeps_decimated_samples() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 6000.0;
+        }
+        brain.set_telemetry(20, 2);
+        assert_eq!(brain.get_telemetry_axes(), vec![0, 1, 2]);
+        brain.load_program(0, "G1 X100 F600".to_string());
+        for _ in 0..100 {
+            brain.tick(10.0);
+        }
+        let time = brain.get_telemetry_time();
+        let position = brain.get_telemetry(0, "position".to_string());
+        let velocity = brain.get_telemetry(0, "velocity".to_string());
+        let acceleration = brain.get_telemetry(0, "acceleration".to_string());
+        assert_eq!(time.len(), 20);
+        assert_eq!(position.len(), 20);
+        assert!((time[1] - time[0] - 0.02).abs() < 1e-9);
+        approx_eq(position[19], brain.axes[0].position);
+        // 600 mm/min reached after 0.1 s at 6000 mm/min/s; the window is past that.
+        assert!(velocity.iter().all(|v| (v - 600.0).abs() < 1.0), "{:?}", velocity);
+        assert!(acceleration.iter().all(|a| a.abs() < 100.0));
+        assert!(brain.get_telemetry(0, "jerk".to_string()).is_empty());
+
+        brain.clear_telemetry();
+        assert!(brain.get_telemetry_time().is_empty());
+    }
+    #[test]
+    fn sim_clock_scales_ticks_and_timestamps_state() {
+        let mut brain = make_xyz_brain();
+        assert!(brain.set_sim_speed(2.0));
+        assert!(!brain.set_sim_speed(-1.0));
+        brain.set_telemetry(100, 1);
+        for _ in 0..10 {
+            brain.tick(10.0);
+        }
+        approx_eq(brain.get_sim_time(), 0.2);
+        approx_eq(*brain.get_telemetry_time().last().unwrap(), 0.2);
+        approx_eq(brain.utilization_summary().total_s, 0.2);
+
+        brain.reset_utilization();
+        assert!(brain.add_hold_rule("door_open".to_string(), true, None));
+        brain.set_door_open(true);
+        brain.load_program(0, "G1 X10 F600".to_string());
+        brain.tick(10.0);
+        approx_eq(brain.events.back().unwrap().at_s, 0.22);
+        approx_eq(brain.get_sim_time(), 0.22);
+        approx_eq(brain.utilization_spans.front().unwrap().start_s, 0.2);
+        approx_eq(brain.job_report(0).unwrap().generated_at_s, 0.22);
+
+        assert!(brain.set_sim_speed(0.0));
+        brain.tick(10.0);
+        approx_eq(brain.get_sim_time(), 0.22);
+    }
+    #[test]
+    fn run_from_line_scans_modal_state_and_runs_safe_start() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 100_000.0;
+        }
+        brain.set_tool_table_entry(0, 2, 30.0, 3.0);
+        let code = "G90 G21 G55\nT2 M6 G43 H2\nS1500 M3\nM8\nG0 X10 Y10 Z5\nG1 Z-2 F300\nG1 X50\nG1 Y40\nM30\n";
+        brain.load_program(0, code.to_string());
+        brain.reset_program(0);
+
+        let preamble = brain.get_safe_start_preamble(0, 6);
+        assert_eq!(
+            preamble,
+            vec!["G90 G21 G40 G55", "G53 G0 Z0", "T2 M6 G43 H2", "S1500 M3", "M8", "G0 X10 Y10", "G0 Z3", "G1 Z-2 F300", "G1 G90 G21"]
+        );
+        assert!(!brain.run_from_line(0, 99, true));
+
+        assert!(brain.run_from_line(0, 6, true));
+        assert_eq!(brain.active_wcs, 1);
+        assert_eq!(brain.channels[0].active_tool, 2);
+        assert!(brain.channels[0].paused);
+        brain.toggle_pause(0);
+        for _ in 0..2000 {
+            brain.tick(10.0);
+            if !brain.channels[0].is_running {
+                break;
+            }
+        }
+        assert_eq!(brain.channels[0].spindle_mode, 3);
+        assert!(brain.channels[0].coolant_on);
+        approx_eq(brain.axes[0].position, 50.0);
+        approx_eq(brain.axes[1].position, 40.0);
+        approx_eq(brain.axes[2].position, 28.0);
+
+        // Parameters and the G52 shift set before the resume line carry over.
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "G90 G21\n#1=3\nG52 X5\nG0 X0 Y0 Z5\nG1 X#1 F300\nM30\n".to_string());
+        brain.reset_program(0);
+        assert!(brain.run_from_line(0, 4, true));
+        brain.toggle_pause(0);
+        let mut path = Vec::new();
+        let mut t = 0.0;
+        brain.run_channel_instant(0, 100, &mut path, &mut t);
+        assert!(brain.alarms.is_empty(), "{:?}", brain.alarms.iter().map(|a| &a.message).collect::<Vec<_>>());
+        approx_eq(brain.axes[0].position, 8.0);
+    }
+    #[test]
+    fn cutting_policy_warns_once_per_violation() {
+        let mut brain = make_xyz_brain();
+        let policy = CuttingPolicy { spindle_before_feed: true, coolant_before_cut: true, dry_tools: vec![5] };
+        brain.cutting_policy = policy;
+        let codes = |brain: &MachineBrain| brain.events.iter().map(|e| e.code.clone()).collect::<Vec<_>>();
+
+        brain.parse_line(0, "G0 X10");
+        assert!(brain.events.is_empty());
+        brain.parse_line(0, "G1 X20 F500");
+        brain.parse_line(0, "G1 X30");
+        brain.parse_line(0, "G0 X0");
+        brain.parse_line(0, "G1 X5");
+        assert_eq!(codes(&brain), vec!["SPINDLE_BEFORE_FEED", "COOLANT_BEFORE_CUT"]);
+        assert!(brain.events[0].message.contains("G1 X20 F500"));
+
+        brain.parse_line(0, "S1000 M3 G1 X10");
+        brain.parse_line(0, "M5");
+        brain.parse_line(0, "G2 X20 R5");
+        assert_eq!(codes(&brain)[2..], ["SPINDLE_BEFORE_FEED"]);
+
+        // Coolant-exempt tool cuts dry without a warning.
+        brain.events.clear();
+        brain.parse_line(0, "T5 S1000 M3");
+        brain.parse_line(0, "G1 X0");
+        assert!(brain.events.is_empty());
+    }
+    #[test]
+    fn plunge_and_ramp_moves_are_feed_capped() {
+        let mut brain = make_xyz_brain();
+        assert!(brain.set_descent_limits(500.0, 800.0, 30.0));
+        brain.parse_line(0, "G90 G1 Z-10 F2000");
+        approx_eq(brain.effective_feed(0), 500.0);
+        assert_eq!(brain.alarms.len(), 1);
+        assert!(brain.alarms[0].message.contains("plunge limit"));
+
+        // Shallow ramp takes the ramp cap; a steep one counts as a plunge.
+        brain.parse_line(0, "X10 Z-11");
+        approx_eq(brain.effective_feed(0), 800.0);
+        brain.parse_line(0, "X11 Z-20");
+        approx_eq(brain.effective_feed(0), 500.0);
+        assert!(brain.alarms.iter().any(|a| a.code == "RAMP_ANGLE" && a.message.contains("steeper than 30.0")));
+
+        // Retracts and level moves keep the programmed feed.
+        brain.alarms.clear();
+        brain.parse_line(0, "Z0");
+        approx_eq(brain.effective_feed(0), 2000.0);
+        brain.parse_line(0, "X30 F400");
+        brain.parse_line(0, "Z-1");
+        approx_eq(brain.effective_feed(0), 400.0);
+        assert!(brain.alarms.is_empty());
+    }
+    #[test]
+    fn tapping_cycle_reverses_spindle_at_the_bottom() {
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "G90 G0 Z20\nS600 M3\nG84 X10 Y5 Z-12 R2 F1.5\nG1 X20 F300".to_string());
+        let mut seen = Vec::new();
+        for _ in 0..40 {
+            brain.run_channel_instant(0, 1, &mut Vec::new(), &mut 0.0);
+            let chan = &brain.channels[0];
+            let z = brain.axes[2].target;
+            if chan.current_motion == 33 {
+                approx_eq(brain.effective_feed(0), 900.0);
+                seen.push((z.round() as i32, chan.spindle_mode));
+            }
+        }
+        seen.dedup();
+        assert_eq!(seen, vec![(-12, 3), (-12, 4), (2, 4), (2, 3)]);
+        let chan = &brain.channels[0];
+        assert!(!chan.is_running);
+        assert_eq!(chan.current_motion, 1);
+        assert_eq!(chan.spindle_mode, 3);
+        approx_eq(chan.feed_rate, 300.0);
+        approx_eq(brain.axes[0].position, 20.0);
+        approx_eq(brain.axes[1].position, 5.0);
+        approx_eq(brain.axes[2].position, 20.0);
+
+        // G74 runs the other way round; G99 stays at R. A missing pitch faults.
+        brain.parse_line(0, "G91 G74 Z-5 R-18 F1 G99");
+        let lines: Vec<&str> = brain.channels[0].injected.iter().map(|s| s.as_str()).collect();
+        assert_eq!(lines, ["G21 G90 G0", "Z2", "M4", "G33.1 Z-3 K1", "M3", "G21 G91 G1"]);
+        brain.parse_line(0, "G84 Z-5");
+        assert!(brain.alarms.iter().any(|a| a.code == "TAP_CYCLE"));
+
+        // The cycle stays active: each X/Y block taps again until G80, and
+        // G99 set on its own block keeps returning to R.
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "G90 G0 Z20\nS600 M3\nG99\nG84 X10 Y5 Z-12 R2 F1.5\nX20\nX30\nG80\nX40".to_string());
+        let mut bottoms = Vec::new();
+        for _ in 0..200 {
+            brain.run_channel_instant(0, 1, &mut Vec::new(), &mut 0.0);
+            if brain.channels[0].current_motion == 33 && (brain.axes[2].target + 12.0).abs() < 1e-9 {
+                bottoms.push(brain.axes[0].target.round() as i32);
+            }
+        }
+        bottoms.dedup();
+        assert_eq!(bottoms, [10, 20, 30]);
+        let chan = &brain.channels[0];
+        assert!(!chan.is_running && chan.canned_cycle.is_none());
+        approx_eq(brain.axes[0].position, 40.0);
+        approx_eq(brain.axes[2].position, 2.0);
+    }
+    #[test]
+    fn rigid_tap_spindle_follows_z_through_the_reversal() {
+        let mut brain = make_xyz_brain();
+        brain.axes[2].accel = 30_000.0;
+        brain.load_program(0, "S600 M3\nG33.1 Z-6 K1.5\nG0 X5".to_string());
+        let (mut revs, mut bottom_mode, mut slowest) = (0.0, 0, f64::INFINITY);
+        for _ in 0..400 {
+            let tapping = brain.channels[0].rigid_tap.is_some();
+            brain.tick(10.0);
+            let chan = &brain.channels[0];
+            if !tapping {
+                continue;
+            }
+            // Feed is locked to the spindle: never above S, slowing into the bottom.
+            assert!(chan.spindle_actual_rpm <= 600.0 + 1e-6, "{}", chan.spindle_actual_rpm);
+            revs += chan.spindle_actual_rpm / 60.0 * 0.01;
+            if brain.axes[2].position <= -6.0 + 1e-9 {
+                bottom_mode = chan.spindle_mode;
+                approx_eq(brain.axes[2].velocity, 0.0);
+            } else if revs > 1.0 {
+                slowest = slowest.min(chan.spindle_actual_rpm);
+            }
+        }
+        assert_eq!(bottom_mode, 4);
+        assert!(slowest < 100.0, "{}", slowest);
+        approx_eq(revs, 8.0); // 2 x 6 mm at 1.5 mm/rev
+        let chan = &brain.channels[0];
+        assert!(!chan.is_running && chan.rigid_tap.is_none());
+        assert_eq!(chan.spindle_mode, 3);
+        approx_eq(brain.axes[2].position, 0.0);
+        approx_eq(brain.axes[0].position, 5.0);
+
+        brain.parse_line(0, "M5");
+        brain.parse_line(0, "G33.1 Z-6 K1.5");
+        assert!(brain.alarms.iter().any(|a| a.code == "TAP_CYCLE" && a.message.contains("spindle")));
+    }
+    #[test]
+    #[cfg(feature = "material-removal")]
+    fn touch_off_sets_wcs_z_at_the_stock_top() {
+        let mut brain = make_xyz_brain();
+        assert!(!brain.touch_off_stock_top(0, 0));
+        assert!(brain.set_stock_box(0.0, 0.0, -20.0, 100.0, 50.0, 0.0));
+        brain.set_tool_table_entry(0, 1, 50.0, 5.0);
+        brain.parse_line(0, "T1 G43 H1");
+        brain.axes[0].position = 20.0;
+        brain.axes[1].position = 10.0;
+        assert!(brain.touch_off_stock_top(0, 1));
+        approx_eq(brain.work_offsets[1].offsets[2].value, -50.0);
+        assert_eq!(brain.events.back().map(|e| e.code.as_str()), Some("TOOL_TOUCH_OFF"));
+
+        // Programming Z0 in that offset puts the tool tip on the surface.
+        brain.parse_line(0, "G55 G0 Z0");
+        approx_eq(brain.axes[2].target, 0.0);
+
+        brain.axes[0].position = 200.0;
+        assert!(!brain.touch_off_stock_top(0, 1));
+    }
+    #[test]
+    fn comp_contours_export_compensated_xy_with_source_blocks() {
+        let mut brain = make_xyz_brain();
+        brain.set_tool_table_entry(0, 1, 50.0, 5.0);
+        let code = "G90 G21 G0 X0 Y-10\n(PROFILE)\nT1 M6 G41 D1 G1 X0 Y0 F500\nX20\nY20\nG40 G1 X30 Y30\nG0 Z10";
+        let contours = brain.comp_contours(0, code);
+        assert_eq!(contours.len(), 1);
+        let c = &contours[0];
+        assert_eq!((c.side, c.tool, c.operation_name.as_deref()), (41, 1, Some("PROFILE")));
+        assert_eq!(c.points.len(), c.blocks.len());
+        assert_eq!((c.blocks[0], *c.blocks.last().unwrap()), (0, 5));
+        approx_eq(c.points[0][1], -10.0);
+        // The G40 exit block still runs on the left side of its own travel.
+        let end = c.points[c.points.len() - 1];
+        approx_eq(end[0], 30.0 - 5.0 * std::f64::consts::FRAC_1_SQRT_2);
+        approx_eq(end[1], 30.0 + 5.0 * std::f64::consts::FRAC_1_SQRT_2);
+        // Inside corner of the X20/Y20 blocks is the miter join of the offset lines.
+        let corner = c.points.iter().zip(&c.blocks).rfind(|(_, b)| **b == 3).map(|(p, _)| *p).unwrap();
+        approx_eq(corner[0], 15.0);
+        approx_eq(corner[1], 5.0);
+    }
+    #[test]
+    #[cfg(feature = "dialects")]
+    fn g4_dwell_holds_the_next_block_for_its_duration() {
+        let mut brain = make_xyz_brain();
+        brain.axes[0].accel = 100_000.0;
+        brain.load_program(0, "G4 P300\nG1 X1 F6000".to_string());
+        brain.tick(10.0);
+        assert!(matches!(brain.channels[0].pending.front(), Some(PendingEntry::Dwell(_))));
+        brain.tick(100.0);
+        brain.set_feed_hold(true);
+        brain.tick(500.0);
+        brain.set_feed_hold(false);
+        brain.tick(100.0);
+        assert_eq!(brain.channels[0].pc, 1);
+        approx_eq(brain.axes[0].target, 0.0);
+        brain.tick(100.0);
+        brain.tick(10.0);
+        assert_eq!(brain.channels[0].pc, 2);
+        approx_eq(brain.axes[0].target, 1.0);
+        // The dwell's own 300 ms count as dwell; the feed hold stays idle.
+        let t = brain.channels[0].run_timers;
+        assert!((t.dwell_s - 0.3).abs() < 0.011, "{t:?}");
+        assert!(t.idle_s >= 0.5, "{t:?}");
+
+        // P is seconds outside Fanuc; X is always seconds. Offline runs count the time.
+        let mut brain = make_xyz_brain();
+        let run = brain.run_verification(0, "G4 X1.5\nG4 P250").unwrap();
+        approx_eq(run.cycle_time_s, 1.75);
+        assert!(brain.set_dialect("linuxcnc".to_string()));
+        let run = brain.run_verification(0, "G4 P2").unwrap();
+        approx_eq(run.cycle_time_s, 2.0);
+    }
+    #[test]
+    fn m6_travels_to_the_change_position_and_swaps_after_the_changer_time() {
+        let mut brain = make_xyz_brain();
+        brain.set_log_console(false);
+        for ax in &mut brain.axes {
+            ax.accel = 100_000.0;
+        }
+        brain.set_tool_table_entry(0, 2, 80.0, 3.0);
+        assert!(!brain.set_tool_change_position(0, 2, 100.0));
+        assert!(brain.set_tool_changer(0, 2.0));
+        assert!(brain.set_tool_change_position(0, 2, 100.0));
+        assert!(brain.set_tool_change_position(0, 0, -50.0));
+        assert!(!brain.set_tool_change_position(0, 9, 0.0));
+        assert!(brain.set_tool_change_position(0, 1, 7.0));
+        assert!(brain.undo());
+        assert_eq!(brain.channels[0].tool_changer.as_ref().unwrap().position.len(), 2);
+
+        // T only preselects; M6 heads for the change position, Z first.
+        brain.load_program(0, "T2\nG1 X5 F6000\nM6\nG0 X1".to_string());
+        let tick_until = |brain: &mut MachineBrain, done: &dyn Fn(&MachineBrain) -> bool| {
+            for _ in 0..2000 {
+                if done(brain) {
+                    return;
+                }
+                brain.tick(10.0);
+            }
+            panic!("condition not reached");
+        };
+        tick_until(&mut brain, &|b| b.channels[0].tool_change.is_some());
+        assert_eq!((brain.channels[0].active_tool, brain.channels[0].pending_tool), (0, None));
+        let event = brain.channels[0].tool_change.clone().unwrap();
+        assert_eq!((event.from_tool, event.to_tool, event.phase.as_str()), (0, 2, "APPROACH"));
+        assert_eq!(brain.channels[0].current_motion, 0);
+        tick_until(&mut brain, &|b| b.axes[2].position > 50.0);
+        approx_eq(brain.axes[0].target, 5.0);
+
+        tick_until(&mut brain, &|b| b.channels[0].tool_change.as_ref().unwrap().phase == "CHANGING");
+        approx_eq(brain.axes[0].position, -50.0);
+        approx_eq(brain.axes[2].position, 100.0);
+        let started = brain.get_sim_time();
+        assert_eq!(brain.channels[0].active_tool, 0);
+        tick_until(&mut brain, &|b| b.channels[0].active_tool == 2);
+        assert!(brain.get_sim_time() - started >= 2.0 - 1e-9);
+        assert_eq!(brain.channels[0].tool_change.as_ref().unwrap().phase, "DONE");
+        approx_eq(brain.channels[0].tool_length, 80.0);
+        tick_until(&mut brain, &|b| !b.channels[0].is_running);
+        approx_eq(brain.axes[0].position, 1.0);
+        assert!(brain.channels[0].run_timers.tool_change_s >= 2.0 - 1e-9);
+
+        // Offline runs count the changer time; M6 of the loaded tool is a no-op.
+        let run = brain.run_verification(0, "T1 M6").unwrap();
+        assert!(run.cycle_time_s >= 2.0);
+        let run = brain.run_verification(0, "T2 M6").unwrap();
+        approx_eq(run.cycle_time_s, 0.0);
+
+        // Without a changer T still only preselects; M6 swaps at once.
+        assert!(brain.set_tool_changer(0, -1.0));
+        brain.parse_line(0, "T1");
+        assert_eq!(brain.channels[0].active_tool, 2);
+        brain.parse_line(0, "M6");
+        assert_eq!(brain.channels[0].active_tool, 1);
+    }
+
+    #[test]
+    fn query_path_point_interpolates_blocks_with_work_coordinates_and_modal_state() {
+        let mut brain = make_xyz_brain();
+        brain.work_offsets[1].offsets[0].value = 100.0;
+        brain.work_offsets[1].offsets[2].value = -100.0;
+        let program = "G55 G90 G21 G0 X0 Y0 Z5\nG43 H1 G1 Z-2 F300\nG1 X10 F600\n(comment)\nG2 X20 Y0 I5 J0\nM30";
+        brain.load_program(0, program.to_string());
+        brain.set_log_console(false);
+
+        let at = |block: usize, t: f64| brain.path_point(0, block, t).unwrap();
+        let xyz = |list: &[AxisOffset]| [0, 1, 2].map(|i| list[i].value);
+
+        // Halfway along the feed move, in G55 with tool length 50 applied.
+        let p = at(2, 0.5);
+        assert_eq!((p.block, p.modal.motion, p.modal.wcs.as_str(), p.modal.length_comp_active), (2, 1, "G55", true));
+        let [mx, my, mz] = xyz(&p.machine);
+        let [wx, wy, wz] = xyz(&p.work);
+        approx_eq(mx, 105.0);
+        approx_eq(my, 0.0);
+        approx_eq(mz, -52.0);
+        approx_eq(wx, 5.0);
+        approx_eq(wy, 0.0);
+        approx_eq(wz, -2.0);
+        approx_eq(p.feed, 600.0);
+        approx_eq(p.modal.feed_rate, 600.0);
+
+        // Block ends and the midpoint of a clockwise half circle.
+        approx_eq(xyz(&at(2, 0.0).work)[0], 0.0);
+        approx_eq(xyz(&at(2, 7.0).work)[0], 10.0);
+        let [ax, ay, _] = xyz(&at(4, 0.5).work);
+        assert!((ax - 15.0).abs() < 0.05 && (ay - 5.0).abs() < 0.05, "arc midpoint at ({ax}, {ay})");
+        assert_eq!(at(4, 0.5).modal.motion, 2);
+
+        // A rapid reports the rapid rate; a block without motion stays put.
+        let rapid = at(0, 0.5);
+        approx_eq(rapid.feed, brain.channel_rapid_feed(0));
+        approx_eq(xyz(&at(3, 0.3).work)[0], 10.0);
+        assert!(brain.path_point(0, 6, 0.0).is_none());
+        assert!(brain.axes.iter().all(|ax| ax.position == 0.0), "live machine untouched");
+    }
+
+    #[test]
+    #[cfg(feature = "material-removal")]
+    fn engagement_stats_measure_width_and_depth_per_operation() {
+        let mut brain = make_xyz_brain();
+        assert!(brain.engagement_stats(0, "G1 X10 F500").is_empty());
+        assert!(brain.set_stock_box(0.0, 0.0, -20.0, 100.0, 50.0, 0.0));
+        brain.set_tool_table_entry(0, 1, 50.0, 5.0);
+        // Full slot 2 mm deep, then a pass 7 mm into the wall at the same depth.
+        let code = "G90 G21 T1 M6\n(SLOT)\nG0 X-10 Y20 Z5\nG1 Z-2 F500\nG1 X110\n(WALL)\nG0 Z5\nG0 X-10 Y27\nG1 Z-2\nG1 X110";
+        let stats = brain.engagement_stats(0, code);
+        let names: Vec<Option<&str>> = stats.iter().map(|s| s.operation_name.as_deref()).collect();
+        assert_eq!(names, vec![Some("SLOT"), Some("WALL")]);
+
+        let slot = &stats[0];
+        assert!(slot.engaged_s > 0.0 && slot.engaged_s <= slot.cutting_s);
+        approx_eq(slot.radial_width.p50, 10.0);
+        approx_eq(slot.engagement_deg.p50, 180.0);
+        approx_eq(slot.axial_depth.max, 2.0);
+
+        // The slot cleared Y15..25, so the wall pass at Y27 cuts Y25..32.
+        let wall = &stats[1];
+        assert!((wall.radial_width.p50 - 7.0).abs() <= 0.3, "{:?}", wall.radial_width);
+        assert!((wall.engagement_deg.p50 - 113.6).abs() <= 3.0, "{:?}", wall.engagement_deg);
+        approx_eq(wall.axial_depth.p90, 2.0);
+    }
+    #[test]
+    #[cfg(feature = "macros")]
+    fn o_word_flow_control_runs_subs_branches_and_loops() {
+        let mut brain = make_xyz_brain();
+        let code = "O100 SUB\nG90 G1 X[#1 * 2] F1000\nO100 ENDSUB\n#<N> = 0\nO200 WHILE [#<N> LT 3]\n#<N> = [#<N> + 1]\n\
+            O200 ENDWHILE\nO300 IF [#<N> EQ 2]\nG1 Y99\nO300 ELSEIF [#<N> EQ 3]\nG1 Y#<N> F1000\nO300 ELSE\nG1 Y98\nO300 ENDIF\n\
+            O400 REPEAT [2]\nG91 G1 Z-1\nO400 ENDREPEAT\nO100 CALL [3.75]\nM30";
+        brain.load_program(0, code.to_string());
+        assert!(brain.run_channel_instant(0, 500, &mut Vec::new(), &mut 0.0));
+        assert!(brain.alarms.is_empty(), "{:?}", brain.alarms.iter().map(|a| &a.message).collect::<Vec<_>>());
+        approx_eq(brain.axes[0].position, 7.5);
+        approx_eq(brain.axes[1].position, 3.0);
+        approx_eq(brain.axes[2].position, -2.0);
+        // Call arguments are local to the subroutine.
+        assert_eq!(brain.channels[0].params.get("1"), None);
+        assert_eq!(brain.channels[0].params.get("<N>"), Some(&3.0));
+
+        assert_eq!(eval_expression("[2 ** 3 + 10 MOD 4 - ABS[-1]]", &HashMap::new(), Dialect::Fanuc), Ok(9.0));
+        assert_eq!(expand_parameters("G1 X-#<N> (#1)", &brain.channels[0].params, Dialect::Fanuc), Ok("G1 X-3 (#1)".to_string()));
+
+        brain.load_program(0, "O500 ENDWHILE".to_string());
+        brain.run_channel_instant(0, 10, &mut Vec::new(), &mut 0.0);
+        assert_eq!(brain.alarms.last().map(|a| a.code.as_str()), Some("O_WORD"));
+    }
+    #[test]
+    fn cutting_data_recommends_speeds_and_flags_outliers() {
+        let mut brain = make_xyz_brain();
+        brain.set_tool_table_entry(0, 1, 50.0, 5.0);
+        assert!(brain.set_tool_flutes(0, 1, 3));
+        assert!(brain.cutting_recommendation(0, 1, "roughing").is_none(), "no material yet");
+        assert!(!brain.set_workpiece_material("unobtainium".to_string()));
+        assert!(brain.set_workpiece_material("Aluminium".to_string()));
+
+        // 300 m/min on a 10 mm cutter, 0.08 mm/tooth on three flutes.
+        let rough = brain.cutting_recommendation(0, 1, "roughing").unwrap();
+        approx_eq(rough.rpm, 300_000.0 / (std::f64::consts::PI * 10.0));
+        approx_eq(rough.feed, rough.rpm * 3.0 * 0.08);
+        brain.set_word_limits(6000.0, 0, 0);
+        let limited = brain.cutting_recommendation(0, 1, "roughing").unwrap();
+        assert!(limited.rpm_limited);
+        approx_eq(limited.feed, 6000.0 * 3.0 * 0.08);
+        brain.set_word_limits(0.0, 0, 0);
+
+        // Programs read the data as parameters; the later hand-typed feeds are off.
+        let code = "T1 M6\nS#<_ROUGH_RPM> M3\nG1 X10 F#<_ROUGH_FEED>\nG1 X20 F200\nG1 Z-5 F100\nM30";
+        let outliers = brain.cutting_data_outliers(0, code);
+        let found: Vec<_> = outliers.iter().map(|o| (o.line, o.operation.as_str(), o.quantity.as_str())).collect();
+        assert_eq!(found, vec![(4, "roughing", "feed"), (5, "drilling", "feed")]);
+        approx_eq(outliers[0].recommended, rough.feed);
+    }
+
+    #[test]
+    fn axis_references_restore_per_encoder_type() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.homed = true;
+        }
+        brain.axes[0].position = 42.0;
+        brain.set_axis_machine_zero(2, -12.5);
+        let saved = brain.export_axis_references();
+
+        // A fresh session with the same machine: X and Z come back homed.
+        let mut restored = make_xyz_brain();
+        assert!(restored.set_axis_encoder(0, "absolute".to_string()));
+        assert!(restored.set_axis_encoder(2, "Absolute".to_string()));
+        let rehome = restored.restore_references(&serde_json::from_str::<Vec<AxisReference>>(&saved).unwrap());
+        assert_eq!(rehome, vec!["Y".to_string()]);
+        assert_eq!(restored.axes.iter().map(|ax| ax.homed).collect::<Vec<_>>(), vec![true, false, true]);
+        approx_eq(restored.axes[0].position, 42.0);
+        approx_eq(restored.axes[0].target, 42.0);
+        approx_eq(restored.axes[2].machine_zero, -12.5);
+
+        // The same references carried by a machine config.
+        let config = format!(
+            r#"{{"axes": [{{"name": "X", "kind": "Linear", "min": -500, "max": 500, "encoder": "Incremental"}}], "references": {}}}"#,
+            saved
+        );
+        let report = MachineBrain::new().reconfigure_json(&config);
+        assert!(report.applied, "{:?}", report.error);
+        assert_eq!(report.rehome_axes, vec!["X".to_string()]);
+    }
+
+    #[test]
+    fn parameters_assign_and_substitute_within_blocks() {
+        let mut brain = make_xyz_brain();
+        assert!(brain.set_parameter(0, "#<depth>".to_string(), 3.0));
+        // Assignments sharing a block with motion take effect after it.
+        let code = "#1=25.4\nG1 X#1 Y[#1/2] F1000 #1=[#1*2] (#1 is now 50.8)\nG1 Z-#<DEPTH> X[#1 - 50]\nM30";
+        brain.load_program(0, code.to_string());
+        assert!(brain.run_channel_instant(0, 100, &mut Vec::new(), &mut 0.0));
+        approx_eq(brain.axes[0].position, 0.8);
+        approx_eq(brain.axes[1].position, 12.7);
+        approx_eq(brain.axes[2].position, -3.0);
+        assert_eq!(brain.get_parameter(0, "1".to_string()), Some(50.8));
+
+        brain.clear_parameters(0);
+        brain.load_program(0, "G1 X#<DEPTH> F1000".to_string());
+        brain.run_channel_instant(0, 10, &mut Vec::new(), &mut 0.0);
+        assert_eq!(brain.alarms.last().map(|a| a.code.as_str()), Some("PARAMETER"));
+        approx_eq(brain.axes[0].position, 0.8);
+    }
+
+    #[test]
+    fn encoder_type_decides_homing_at_power_on() {
+        let mut brain = make_xyz_brain();
+        assert_eq!(brain.start_inhibits(0)[0].code, "NOT_HOMED");
+        brain.set_axis_encoder(0, "absolute".to_string());
+        brain.set_axis_encoder(1, "absolute".to_string());
+        assert!(!brain.set_axis_encoder(2, "optical".to_string()));
+        brain.axes[2].homed = true;
+        brain.axes[0].position = 17.0;
+
+        assert_eq!(brain.power_cycle_axes(), Some(vec!["Z".to_string()]));
+        assert_eq!(brain.axes.iter().map(|ax| ax.homed).collect::<Vec<_>>(), vec![true, true, false]);
+        approx_eq(brain.axes[0].target, 17.0);
+        assert_eq!(brain.start_inhibits(0)[0].message, "Axes not homed: Z");
+
+        // New absolute axes in a machine config come up homed.
+        let mut fresh = MachineBrain::new();
+        let config = r#"{"axes": [{"name": "X", "kind": "Linear", "min": 0, "max": 100, "encoder": "Absolute"},
+            {"name": "Y", "kind": "Linear", "min": 0, "max": 100}]}"#;
+        assert!(fresh.reconfigure_json(config).applied);
+        assert_eq!(fresh.axes.iter().map(|ax| ax.homed).collect::<Vec<_>>(), vec![true, false]);
+    }
+
+    #[test]
+    fn jog_selector_scales_rapid_by_table_entry() {
+        let mut brain = make_xyz_brain();
+        let rapid = axis_rapid_feed(&brain.axes[0]);
+        assert!(!brain.set_jog_feed_table(vec![10.0, 150.0]));
+        assert!(brain.set_jog_feed_table(vec![50.0, 2.0, 10.0]));
+        assert_eq!(brain.jog.feed_table, vec![2.0, 10.0, 50.0]);
+        assert!(brain.select_jog_feed(2));
+        assert!(!brain.select_jog_feed(3));
+
+        brain.jog_axis_selected(0, 5.0);
+        approx_eq(brain.axes[0].target, 5.0);
+        approx_eq(brain.jogs[0].feed, rapid * 0.5);
+        brain.set_jog_rapid(true);
+        brain.jog_axis_selected(0, 5.0);
+        approx_eq(brain.jogs[0].feed, rapid);
+
+        // Shrinking the table keeps the selection in range.
+        assert!(brain.set_jog_feed_table(vec![1.0]));
+        assert_eq!(brain.jog.selected, 0);
+    }
+
+    #[test]
+    fn jogging_leaves_channel_modals_untouched() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 100_000.0;
+        }
+        brain.channels[0].current_motion = 1;
+        brain.channels[0].feed_rate = 0.0;
+        brain.jog_axis_feed(0, 2.0, 600.0);
+        brain.jog_axis_rapid(1, -3.0);
+        assert_eq!((brain.channels[0].current_motion, brain.channels[0].feed_rate), (1, 0.0));
+        assert_eq!(brain.jogs.iter().map(|j| (j.axis_id, j.rapid)).collect::<Vec<_>>(), vec![(0, false), (1, true)]);
+
+        // The axes travel at their jog rates even though the channel sits at F0.
+        brain.tick(100.0);
+        assert!(brain.axes[0].position > 0.5 && brain.axes[0].position < 1.5, "{}", brain.axes[0].position);
+        for _ in 0..10 {
+            brain.tick(100.0);
+        }
+        approx_eq(brain.axes[0].position, 2.0);
+        approx_eq(brain.axes[1].position, -3.0);
+        assert!(brain.jogs.is_empty());
+        assert_eq!((brain.channels[0].current_motion, brain.channels[0].feed_rate), (1, 0.0));
+    }
+
+    #[test]
+    fn api_positioning_runs_at_its_own_feed() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 100_000.0;
+        }
+        let channel_feed = brain.channels[0].feed_rate;
+        assert!(!brain.move_to_at_feed(0, 10.0, 0.0));
+        assert!(brain.set_manual_feed(600.0));
+        brain.move_to(0, 10.0);
+        assert!(brain.move_to_at_feed(1, 10.0, 1e9));
+        approx_eq(brain.jogs[1].feed, axis_rapid_feed(&brain.axes[1]));
+        // 600 mm/min is 10 mm/s.
+        brain.tick(500.0);
+        approx_eq(brain.axes[0].position, 5.0);
+        approx_eq(brain.axes[0].velocity, 600.0);
+        brain.tick(600.0);
+        approx_eq(brain.axes[0].position, 10.0);
+        approx_eq(brain.axes[1].position, 10.0);
+        assert_eq!(brain.channels[0].feed_rate, channel_feed);
+    }
+
+    #[test]
+    fn g52_shifts_the_active_work_system() {
+        let mut brain = make_xyz_brain();
+        brain.set_work_zero(0, 0, 100.0);
+        let code = "G90 G0 X10\nG52 X5 Y-2\nG0 X10 Y0\nG91 G0 X1\nG90";
+        brain.load_program(0, code.to_string());
+        brain.run_channel_instant(0, 20, &mut Vec::new(), &mut 0.0);
+        approx_eq(brain.axes[0].target, 116.0);
+        approx_eq(brain.axes[1].target, -2.0);
+        assert_eq!(brain.local_shift.get(&0), Some(&5.0));
+
+        brain.load_program(0, "G52 X0 Y0 Z0\nG0 X10".to_string());
+        brain.run_channel_instant(0, 20, &mut Vec::new(), &mut 0.0);
+        assert!(brain.local_shift.is_empty());
+        approx_eq(brain.axes[0].target, 110.0);
+
+        brain.load_program(0, "G52 Z-1".to_string());
+        brain.run_channel_instant(0, 20, &mut Vec::new(), &mut 0.0);
+        assert_eq!(brain.local_shift.len(), 1);
+        brain.reset_program(0);
+        assert!(brain.local_shift.is_empty());
+    }
+
+    #[test]
+    fn estop_reports_interrupted_block_and_recovery_steps() {
+        let mut brain = make_xyz_brain();
+        for ax in brain.axes.iter_mut() {
+            ax.accel = 100_000.0;
+            ax.homed = true;
+            ax.encoder = EncoderType::Absolute;
+        }
+        brain.axes[2].encoder = EncoderType::Incremental;
+        brain.load_program(0, "S1000 M3\nG1 X30 Z-40 F600\nM30".to_string());
+        for _ in 0..4 {
+            brain.tick(100.0);
+        }
+        brain.set_work_zero(0, 0, 1.0);
+        brain.set_estop(true);
+
+        let event = brain.events.back().expect("interruption event");
+        assert_eq!(event.code, "INTERRUPTED");
+        let report = event.interruption.as_ref().expect("report");
+        assert_eq!(report.cause, "ESTOP");
+        let chan = &report.channels[0];
+        assert_eq!((chan.line, chan.block.as_deref()), (Some(2), Some("G1 X30 Z-40 F600")));
+        assert!(chan.distance_remaining > 10.0 && chan.distance_remaining < 50.0, "{}", chan.distance_remaining);
+        assert!(chan.spindle_on);
+        // Z was moving on an incremental encoder; the offset edit is flagged too.
+        assert!(report.rehome_required);
+        assert!(!report.offsets_intact);
+        assert_eq!(report.recovery[0], "Release the emergency stop");
+        assert!(report.recovery.contains(&"Home axes: Z".to_string()), "{:?}", report.recovery);
+        assert!(brain.last_interruption.is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "macros")]
+    fn library_macros_are_callable_by_o_word_and_g65() {
+        let mut brain = make_xyz_brain();
+        let library = "%\n(probing helpers)\nO9810 SUB\nG1 X#24 Y#25 F#9\nO9810 ENDSUB\nO<PARK> SUB\nG0 Z[#1 + 5]\nO<PARK> ENDSUB";
+        assert_eq!(brain.register_macro_package("probing".into(), "1.2.0".into(), library.into()), vec!["9810", "<PARK>"]);
+        let list = brain.macro_package_list();
+        assert_eq!((list[0].name.as_str(), list[0].version.as_str()), ("probing", "1.2.0"));
+        // Another package may not redefine a library label.
+        assert!(brain.register_macro_package("other".into(), "1".into(), "O9810 SUB\nO9810 ENDSUB".into()).is_empty());
+        assert!(brain.register_macro_package("bad".into(), "1".into(), "G0 X1\nO1 SUB\nO1 ENDSUB".into()).is_empty());
+
+        brain.load_program(0, "G65 P9810 X12 Y-3 F500\nO<PARK> CALL [20]\nM30".to_string());
+        brain.run_channel_instant(0, 200, &mut Vec::new(), &mut 0.0);
+        approx_eq(brain.axes[0].target, 12.0);
+        approx_eq(brain.axes[1].target, -3.0);
+        approx_eq(brain.axes[2].target, 25.0);
+        assert!(!brain.channels[0].params.contains_key("24"), "arguments restored after the call");
+        assert!(brain.events.iter().any(|e| e.code == "MACRO_LINKED" && e.message == "O9810 from probing 1.2.0"));
+
+        // The program's own subroutine wins over the library.
+        brain.load_program(0, "O<PARK> CALL [1]\nM30\nO<PARK> SUB\nG0 Z-1\nO<PARK> ENDSUB".to_string());
+        brain.run_channel_instant(0, 200, &mut Vec::new(), &mut 0.0);
+        approx_eq(brain.axes[2].target, -1.0);
+        assert!(brain.remove_macro_package("probing".into()));
+
+        // Fanuc packages: O-number programs returning with M99.
+        let fanuc = "%\nO9000 (SAFE Z)\nIF [#26 EQ #0] GOTO 10\nG0 Z#26\nM99\nN10 G0 Z50\nM99\nO9001\nG0 X#24\nM99\n%";
+        assert_eq!(brain.register_macro_package("fanuc".into(), "1".into(), fanuc.into()), vec!["9000", "9001"]);
+        assert!(brain.register_macro_package("bad".into(), "1".into(), "O9100\nG0 X1".into()).is_empty());
+        brain.set_dialect("fanuc".to_string());
+        brain.load_program(0, "G65 P9000 Z7\nG65 P9001 X4\nM30".to_string());
+        brain.run_channel_instant(0, 200, &mut Vec::new(), &mut 0.0);
+        assert!(brain.alarms.is_empty(), "{:?}", brain.alarms.iter().map(|a| &a.message).collect::<Vec<_>>());
+        approx_eq(brain.axes[2].target, 7.0);
+        approx_eq(brain.axes[0].target, 4.0);
+        brain.load_program(0, "G65 P9000\nM30".to_string());
+        brain.run_channel_instant(0, 200, &mut Vec::new(), &mut 0.0);
+        approx_eq(brain.axes[2].target, 50.0);
+        brain.load_program(0, "G65 P9500\nM30".to_string());
+        brain.run_channel_instant(0, 200, &mut Vec::new(), &mut 0.0);
+        assert!(brain.alarms.iter().any(|a| a.message.contains("unknown program O9500")));
+    }
+
+    #[test]
+    fn g53_moves_in_machine_coordinates_for_one_block() {
+        let mut brain = make_xyz_brain();
+        brain.set_tool_table_entry(0, 1, 50.0, 0.0);
+        brain.set_work_zero(2, 0, -100.0);
+        brain.load_program(0, "T1 G43 H1\nG52 X7\nG0 X0 Z10\nG91 G53 G0 Z-5 X3\nG0 Z1\nM30".to_string());
+        brain.run_channel_instant(0, 3, &mut Vec::new(), &mut 0.0);
+        approx_eq(brain.axes[2].target, -40.0);
+        brain.run_channel_instant(0, 1, &mut Vec::new(), &mut 0.0);
+        approx_eq(brain.axes[0].target, 3.0);
+        approx_eq(brain.axes[2].target, -5.0);
+        // Non-modal: the next incremental block is back in offset work coordinates.
+        brain.run_channel_instant(0, 1, &mut Vec::new(), &mut 0.0);
+        approx_eq(brain.axes[2].target, -4.0);
+
+        brain.load_program(0, "G2 G53 X1 Y1 R1\nM30".to_string());
+        brain.run_channel_instant(0, 1, &mut Vec::new(), &mut 0.0);
+        assert!(brain.alarms.iter().any(|a| a.code == "MACHINE_COORDS"));
+    }
+
+    #[test]
+    fn g18_and_g19_arcs_interpolate_in_their_planes() {
+        let mut brain = make_xyz_brain();
+        // G18: ZX half circle around Z=0 X=10 with helical Y, viewed from +Y.
+        brain.load_program(0, "G0 X0 Y0 Z0\nG18 G2 X20 Y5 I10 K0 F500\nM30".to_string());
+        brain.run_channel_instant(0, 2, &mut Vec::new(), &mut 0.0);
+        let path: Vec<(f64, f64, f64)> = brain.channels[0]
+            .pending
+            .iter()
+            .filter_map(|p| match p {
+                PendingEntry::Move(seg) => {
+                    let at = |id| seg.iter().find(|(a, _)| *a == id).map(|(_, v)| *v).unwrap();
+                    Some((at(0), at(1), at(2)))
+                }
+                _ => None,
+            })
+            .collect();
+        // On the ZX circle; clockwise seen from +Y (Z right, X up) runs through -Z.
+        for (x, y, z) in &path {
+            approx_eq(((x - 10.0).powi(2) + z.powi(2)).sqrt(), 10.0);
+            assert!(*z <= 1e-9 && (0.0..=5.0).contains(y));
+        }
+        assert!(path.iter().any(|(_, _, z)| *z < -9.9));
+        let (x, y, z) = *path.last().unwrap();
+        assert!((x - 20.0).abs() < 1e-6 && (y - 5.0).abs() < 1e-6 && z.abs() < 1e-6);
+
+        // G19: YZ quarter arc with J/K center.
+        brain.load_program(0, "G0 X0 Y0 Z0\nG19 G3 Y10 Z10 J0 K10 F500\nM30".to_string());
+        brain.run_channel_instant(0, 200, &mut Vec::new(), &mut 0.0);
+        approx_eq(brain.axes[1].target, 10.0);
+        approx_eq(brain.axes[2].target, 10.0);
+        approx_eq(brain.axes[0].target, 0.0);
+    }
+
+    #[test]
+    #[cfg(feature = "macros")]
+    fn g66_calls_its_macro_after_each_move_until_g67() {
+        let mut brain = make_xyz_brain();
+        let program = [
+            "#1=7 #100=0",
+            "G66 P9100 Z-5 R2",
+            "G0 X10 Y10",
+            "X20",
+            "G67",
+            "X30",
+            "G65 P9200 B3",
+            "O9100 SUB",
+            "#100=[#100+1]",
+            "G1 Z#26 F100",
+            "G0 Z#18",
+            "O9100 ENDSUB",
+            "O9200 SUB",
+            "#101=#2",
+            "O9200 ENDSUB",
+        ];
+        brain.load_program(0, program.join("\n"));
+        brain.run_channel_instant(0, 500, &mut Vec::new(), &mut 0.0);
+        assert!(brain.alarms.is_empty(), "{:?}", brain.alarms.iter().map(|a| &a.message).collect::<Vec<_>>());
+        let params = &brain.channels[0].params;
+        // Two moves in G66 mode; the macro's own Z moves do not re-trigger it.
+        approx_eq(params["100"], 2.0);
+        approx_eq(params["101"], 3.0);
+        // The caller's locals come back after each call.
+        approx_eq(params["1"], 7.0);
+        assert!(!params.contains_key("2") && !params.contains_key("26"));
+        approx_eq(brain.axes[0].target, 30.0);
+        approx_eq(brain.axes[2].target, 2.0);
+
+        // A macro starts with an empty local frame: #1 is vacant there.
+        brain.load_program(0, "#1=7\n#102=1\nG65 P1\nO1 SUB\n#102=#1\nO1 ENDSUB".to_string());
+        brain.run_channel_instant(0, 50, &mut Vec::new(), &mut 0.0);
+        assert!(!brain.channels[0].params.contains_key("102"));
+    }
+
+    #[test]
+    fn parameter_scopes_follow_control_memory() {
+        let mut brain = make_xyz_brain();
+        brain.channels.push(Channel::new(1, vec![ChannelAxisMap { axis_id: 2, display_label: "Z".to_string() }]));
+        brain.load_program(0, "#1=4 #100=5\n#500=[#1 * 2]".to_string());
+        brain.run_channel_instant(0, 10, &mut Vec::new(), &mut 0.0);
+        // Persistent values are machine-wide.
+        assert_eq!(brain.get_parameter(1, "#500".into()), Some(8.0));
+        assert_eq!(brain.export_persistent_parameters(), r#"{"500":8.0}"#);
+
+        // A new program starts with empty locals; commons carry over.
+        brain.load_program(0, "#101=[#100 + #1]".to_string());
+        brain.run_channel_instant(0, 10, &mut Vec::new(), &mut 0.0);
+        assert_eq!(brain.get_parameter(0, "101".into()), Some(5.0));
+
+        assert!(brain.power_cycle_axes().is_some());
+        assert_eq!(brain.get_parameter(0, "100".into()), None);
+        assert_eq!(brain.get_parameter(0, "500".into()), Some(8.0));
+
+        assert!(!brain.set_persistent_parameter(100, 1.0));
+        assert!(brain.import_persistent_parameters(r#"{"501": 2.5}"#.into()));
+        assert_eq!(brain.get_parameter(0, "500".into()), None);
+        assert_eq!(brain.get_parameter(1, "501".into()), Some(2.5));
+        assert!(!brain.import_persistent_parameters(r#"{"100": 1}"#.into()));
+        brain.undo();
+        assert_eq!(brain.get_parameter(1, "500".into()), Some(8.0));
+    }
+
+    #[test]
+    fn expressions_follow_fanuc_and_linuxcnc_rules() {
+        let params: HashMap<String, f64> = [("1", 1.2), ("2", -1.2), ("24", 10.0), ("25", 0.0)]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        let eval = |text: &str, dialect| eval_expression(text, &params, dialect);
+        let fanuc = [
+            // Operators, precedence and brackets.
+            ("[1 + 2 * 3]", 7.0),
+            ("[[1 + 2] * 3]", 9.0),
+            ("[12 AND 10]", 8.0),
+            ("[12 OR 3 * 1]", 15.0),
+            ("[6 XOR 3]", 5.0),
+            ("[#24 GT 5]", 1.0),
+            ("[[#24 GT 5] AND [#25 EQ 0]]", 1.0),
+            ("[#24 NE 10]", 0.0),
+            // Vacant parameters: #0 and unset numbers only equal each other.
+            ("[#0 EQ 0]", 0.0),
+            ("[#26 EQ #0]", 1.0),
+            ("[#25 EQ #0]", 0.0),
+            ("[#26 NE #0]", 0.0),
+            ("[#26 + 1]", 1.0),
+            ("[#26 LT 1]", 1.0),
+            // Functions.
+            ("FIX[#1]", 1.0),
+            ("FIX[#2]", -1.0),
+            ("FUP[#1]", 2.0),
+            ("FUP[#2]", -2.0),
+            ("ROUND[2.5]", 3.0),
+            ("RND[-2.5]", -3.0),
+            ("ATAN[1]/[1]", 45.0),
+            ("ATAN[-1,-1]", 225.0),
+            ("ATN[1]", 45.0),
+            ("SQR[16]", 4.0),
+            ("POW[2,10]", 1024.0),
+            ("BCD[25]", 37.0),
+            ("BIN[37]", 25.0),
+            ("[10 MOD 4]", 2.0),
+            // A typical probing macro tolerance check.
+            ("[ABS[[#24 - 9.98] / 2] GT 0.005]", 1.0),
+        ];
+        for (text, want) in fanuc {
+            match eval(text, Dialect::Fanuc) {
+                Ok(got) => assert!((got - want).abs() < 1e-9, "{} = {} (want {})", text, got, want),
+                Err(e) => panic!("{}: {}", text, e),
+            }
+        }
+        let linuxcnc = [
+            ("[1 EQ 1 AND 2 EQ 2]", 1.0),
+            ("[FIX[#2]]", -2.0),
+            ("[FUP[#2]]", -1.0),
+            ("ATAN[-1]/[-1]", -135.0),
+            ("[#0 EQ 0]", 1.0),
+        ];
+        for (text, want) in linuxcnc {
+            assert_eq!(eval(text, Dialect::LinuxCnc), Ok(want), "{}", text);
+        }
+        for bad in ["[1.5 AND 1]", "SQRT[-1]", "ACOS[2]", "[[[[[[1]]]]]]", "POW[2]", "BIN[26]"] {
+            assert!(eval(bad, Dialect::Fanuc).is_err(), "{}", bad);
+        }
+        assert!(eval("[[[[[[1]]]]]]", Dialect::LinuxCnc).is_ok());
+
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "#1=5\n#2=#1\n#1=#0".to_string());
+        brain.run_channel_instant(0, 10, &mut Vec::new(), &mut 0.0);
+        assert_eq!(brain.get_parameter(0, "2".into()), Some(5.0));
+        assert_eq!(brain.get_parameter(0, "1".into()), None);
+    }
+
+    #[test]
+    #[cfg(feature = "macros")]
+    fn coverage_records_branches_and_subprogram_calls() {
+        let mut brain = make_xyz_brain();
+        let code = "#1=0\no10 while [#1 LT 2]\no20 call\n#1=[#1+1]\no10 endwhile\n\
+            o30 if [#1 EQ 5]\nG0 X1\no30 elseif [#1 EQ 2]\nG0 X2\no30 else\nG0 X3\no30 endif\nM30\n\
+            o20 sub\nG1 X5 F100\no20 endsub\n";
+        let run = brain.run_verification(0, code).unwrap();
+        assert!(run.completed);
+        let branches: Vec<(usize, u32, u32)> = run.coverage.branches.iter().map(|b| (b.line, b.taken, b.not_taken)).collect();
+        assert_eq!(branches, vec![(1, 2, 1), (5, 0, 1), (7, 1, 0)]);
+        let calls: Vec<(&str, u32)> = run.coverage.subprograms.iter().map(|p| (p.program.as_str(), p.calls)).collect();
+        assert_eq!(calls, vec![("20", 2)]);
+
+        brain.set_dialect("fanuc".to_string());
+        let code = "#1=0\nN10 #1=[#1+1]\nIF [#1 LT 3] GOTO 10\nM98 P100\nM30\nO100\nG0 X1\nM99\n";
+        let run = brain.run_verification(0, code).unwrap();
+        assert!(run.completed);
+        let branches: Vec<(usize, u32, u32)> = run.coverage.branches.iter().map(|b| (b.line, b.taken, b.not_taken)).collect();
+        assert_eq!(branches, vec![(2, 2, 1)]);
+        assert_eq!(run.coverage.subprograms[0].program, "100");
+    }
+
+    #[test]
+    #[cfg(feature = "macros")]
+    fn fanuc_control_flow_branches_loops_and_calls_subprograms() {
+        let mut brain = make_xyz_brain();
+        brain.set_dialect("fanuc".to_string());
+        let code = "O0001\n#1=3\n#2=0\nIF [#1 GT 5] THEN #2=7\nIF [#1 LT 5] THEN #3=4\n#10=0\n\
+            WHILE [#10 LT 3] DO1\n#10=#10+1\nG91 G1 X1 F1000\nEND1\nIF [#10 EQ 3] GOTO 50\nG90 G1 Y99\n\
+            N50 #20=0\nN60 #20=[#20+1]\nIF [#20 LT 4] GOTO 60\nM98 P1000 L2\nG65 P9000 A2.5\nM30\n\
+            O1000\nG91 G1 Z-1\nM99\nO9000\nG90 G1 Y#1\nM99\n";
+        brain.load_program(0, code.to_string());
+        assert!(brain.run_channel_instant(0, 500, &mut Vec::new(), &mut 0.0));
+        assert!(brain.alarms.is_empty(), "{:?}", brain.alarms.iter().map(|a| &a.message).collect::<Vec<_>>());
+        // A false THEN leaves its parameter alone and raises nothing.
+        assert_eq!(brain.get_parameter(0, "2".into()), Some(0.0));
+        assert_eq!(brain.get_parameter(0, "3".into()), Some(4.0));
+        assert_eq!(brain.get_parameter(0, "20".into()), Some(4.0));
+        assert_eq!(brain.get_parameter(0, "1".into()), Some(3.0));
+        approx_eq(brain.axes[0].position, 3.0);
+        approx_eq(brain.axes[1].position, 2.5);
+        approx_eq(brain.axes[2].position, -2.0);
+
+        for (bad, message) in [("GOTO 99", "N99 not found"), ("WHILE [1 EQ 2] DO2\nG0 X1", "END2 not found"), ("M98 P77", "unknown program O77")] {
+            let mut brain = make_xyz_brain();
+            brain.load_program(0, bad.to_string());
+            brain.run_channel_instant(0, 10, &mut Vec::new(), &mut 0.0);
+            assert!(brain.alarms.iter().any(|a| a.code == "O_WORD" && a.message.contains(message)), "{}", bad);
+        }
+    }
+
+
+    #[test]
+    fn channel_defaults_come_from_the_machine_config() {
+        let mut brain = MachineBrain::new();
+        assert!(brain.load_machine_template("millturn".to_string()));
+        let (mill, lathe) = (&brain.channels[0], &brain.channels[1]);
+        assert_eq!((mill.plane, mill.diameter_mode), (17, false));
+        assert_eq!((lathe.plane, lathe.diameter_mode), (18, true));
+        for ax in brain.axes.iter_mut() {
+            ax.homed = true;
+        }
+
+        // X is a diameter on the lathe channel, a radius on the mill.
+        brain.load_program(1, "G0 X40 Z-10\nG91\nG0 X-10".to_string());
+        brain.run_channel_instant(1, 100, &mut Vec::new(), &mut 0.0);
+        let x2 = brain.axes.iter().find(|a| a.physical_name == "X2").unwrap().id;
+        approx_eq(brain.channels[1].programmed_work[&x2], 15.0);
+        assert!(!brain.channels[1].abs_mode);
+        brain.reset_program(1);
+        assert!(brain.channels[1].abs_mode);
+        assert_eq!(brain.channels[1].plane, 18);
+
+        // The config can change them; an idle channel switches at once.
+        let report = brain.reconfigure_json(
+            r#"{"axes": [{"name": "X", "kind": "Linear", "min": -100, "max": 100}, {"name": "Z", "kind": "Linear", "min": -100, "max": 0}],
+                "channels": [{"id": 0, "axes": [{"axis": "X", "label": "X"}, {"axis": "Z", "label": "Z"}],
+                              "defaults": {"plane": 18, "units_mm": false}}]}"#,
+        );
+        assert!(report.error.is_none());
+        assert_eq!(brain.channels[0].defaults, ChannelDefaults { units_mm: false, plane: 18, ..ChannelDefaults::default() });
+        assert!(!brain.channels[0].units_mm);
+        assert_eq!(brain.channels[0].plane, 18);
+    }
+
+
+    #[test]
+    fn g7_programs_x_as_a_diameter() {
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "G7 G0 X50 Z2\nG91 G1 X-10 F200".to_string());
+        brain.run_channel_instant(0, 100, &mut Vec::new(), &mut 0.0);
+        assert!(brain.channels[0].diameter_mode);
+        approx_eq(brain.axes[0].position, 20.0);
+        assert_eq!(brain.block_state_value(0, "X"), Some(40.0));
+        brain.load_program(0, "G90 G8 G1 X10 F200".to_string());
+        brain.run_channel_instant(0, 100, &mut Vec::new(), &mut 0.0);
+        assert!(!brain.channels[0].diameter_mode);
+        approx_eq(brain.channels[0].programmed_work[&0], 10.0);
+        approx_eq(brain.axes[0].position, 10.0);
+        // G7 and G8 share a modal group.
+        assert_eq!(block_modal_conflicts("G7 G8 X1").len(), 1);
+    }
+
+
+    #[test]
+    fn g51_1_mirrors_moves_arcs_and_comp_side() {
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "G17 G90 G51.1 X0\nG1 X10 Y5 F500\nG2 X20 Y5 I5 J0".to_string());
+        let mut path = Vec::new();
+        brain.run_channel_instant(0, 200, &mut path, &mut 0.0);
+        // Mirrored about X0 the clockwise arc runs counter-clockwise, still
+        // bulging towards +Y.
+        approx_eq(brain.axes[0].position, -20.0);
+        approx_eq(brain.axes[1].position, 5.0);
+        let top = path.iter().filter_map(|p| p.position.get(1).map(|o| o.value)).fold(f64::MIN, f64::max);
+        assert!((top - 10.0).abs() < 0.05, "{top}");
+        assert_eq!(brain.channels[0].current_motion, 3);
+        assert!(!brain.block_moves_axes(0, "G51.1 X0"));
+
+        // Cancelling swaps the modal arc back; G41 runs as G42 while mirrored.
+        brain.load_program(0, "G51.1 Y0\nG41 D1\nG50.1\nG91 G1 X0".to_string());
+        brain.run_channel_instant(0, 2, &mut Vec::new(), &mut 0.0);
+        assert_eq!(brain.channels[0].cutter_comp, 42);
+        brain.run_channel_instant(0, 200, &mut Vec::new(), &mut 0.0);
+        assert_eq!(brain.channels[0].cutter_comp, 41);
+        assert!(brain.channels[0].mirror.is_empty());
+    }
+
+
+    #[test]
+    fn rear_turret_reverses_g18_arcs_and_nose_comp() {
+        let mut front = make_xyz_brain();
+        front.load_program(0, "G18 G0 X10 Z0\nG2 X10 Z-20 R10 F200".to_string());
+        let mut front_path = Vec::new();
+        front.run_channel_instant(0, 200, &mut front_path, &mut 0.0);
+
+        let mut rear = make_xyz_brain();
+        assert!(rear.set_rear_turret(0, true));
+        rear.load_program(0, "G18 G0 X10 Z0\nG2 X10 Z-20 R10 F200\nG41".to_string());
+        let mut rear_path = Vec::new();
+        rear.run_channel_instant(0, 200, &mut rear_path, &mut 0.0);
+        assert_eq!(rear.channels[0].cutter_comp, 42);
+
+        // Same end point, bulging to opposite sides of X10.
+        approx_eq(rear.axes[2].position, -20.0);
+        let peak = |path: &[ToolpathPoint]| path.iter().map(|p| p.position[0].value - 10.0).fold(0.0_f64, |a, d| if d.abs() > a.abs() { d } else { a });
+        let (f, r) = (peak(&front_path), peak(&rear_path));
+        assert!((f + r).abs() < 0.05 && f.abs() > 9.9, "{f} {r}");
+        // The plane matters: G17 arcs are untouched.
+        assert!(!rear.mirror_reverses(0, 17));
+    }
+
+
+    #[test]
+    fn cutting_state_derives_surface_speed_and_chip_load() {
+        let mut brain = make_xyz_brain();
+        brain.set_tool_table_entry(0, 1, 50.0, 5.0);
+        assert!(brain.set_tool_flutes(0, 1, 3));
+        brain.load_program(0, "T1 M6\nS3000 M3\nG1 X10 F720".to_string());
+        brain.run_channel_instant(0, 100, &mut Vec::new(), &mut 0.0);
+        let (vc, fz) = brain.cutting_state(0);
+        approx_eq(vc, std::f64::consts::PI * 10.0 * 3000.0 / 1000.0);
+        approx_eq(fz, 720.0 / (3000.0 * 3.0));
+
+        // Turning: the part diameter at X and feed per revolution.
+        brain.load_program(0, "G18 G0 X20\nG1 F300".to_string());
+        brain.run_channel_instant(0, 100, &mut Vec::new(), &mut 0.0);
+        let (vc, fr) = brain.cutting_state(0);
+        approx_eq(vc, std::f64::consts::PI * 40.0 * 3000.0 / 1000.0);
+        approx_eq(fr, 0.1);
+        brain.load_program(0, "M5".to_string());
+        brain.run_channel_instant(0, 100, &mut Vec::new(), &mut 0.0);
+        assert_eq!(brain.cutting_state(0), (0.0, 0.0));
+    }
+
+
+    #[test]
+    fn capture_latches_positions_on_probe_and_index() {
+        let mut brain = make_xyz_brain();
+        assert!(brain.set_capture_input("probe".to_string(), true));
+        assert!(brain.set_index_capture(0, true));
+        brain.set_axis_accel(0, 1_000_000.0);
+        brain.load_program(0, "S600 M3\nG1 X100 F6000".to_string());
+        // 600 rpm: one index every 100 ms, while X runs at 100 mm/s.
+        for _ in 0..25 {
+            brain.tick(20.0);
+        }
+        brain.set_input("probe".to_string(), true);
+        brain.set_input("probe".to_string(), true);
+        brain.capture_positions("manual".to_string());
+
+        let index: Vec<&PositionCapture> = brain.captures.iter().filter(|c| c.source == "index").collect();
+        assert_eq!(index.len(), 5);
+        approx_eq(index[1].timestamp_s - index[0].timestamp_s, 0.1);
+        assert_eq!(index[0].channel, Some(0));
+        let sources: Vec<&str> = brain.captures.iter().skip(5).map(|c| c.source.as_str()).collect();
+        assert_eq!(sources, vec!["input:probe", "manual"]);
+        let probe = &brain.captures[5];
+        approx_eq(probe.timestamp_s, brain.get_sim_time());
+        approx_eq(probe.positions[0].value, brain.axes[0].position);
+        brain.clear_capture_log();
+        assert!(brain.captures.is_empty());
+    }
+
+
+    #[test]
+    fn block_processing_time_starves_short_blocks() {
+        let program: String = (1..=100).map(|i| format!("G1 X{} F6000\n", i as f64 * 0.1)).collect();
+        let mut fast = make_xyz_brain();
+        fast.load_program(0, program.clone());
+        let mut fast_s = 0.0;
+        fast.run_channel_instant(0, 1000, &mut Vec::new(), &mut fast_s);
+        approx_eq(fast_s, 0.1);
+
+        // 0.1 mm at 100 mm/s is 1 ms; an 8 ms control runs each block in 8 ms.
+        let mut slow = make_xyz_brain();
+        assert!(slow.set_block_processing_time(8.0));
+        assert!(!slow.set_block_processing_time(500.0));
+        slow.load_program(0, program);
+        let mut slow_s = 0.0;
+        slow.run_channel_instant(0, 1000, &mut Vec::new(), &mut slow_s);
+        assert!((slow_s - 0.8).abs() < 0.01, "{slow_s}");
+
+        // Ticking shows the same starvation.
+        let ticks_for = |block_ms: f64| {
+            let mut brain = make_xyz_brain();
+            brain.set_axis_accel(0, 1_000_000.0);
+            brain.set_block_processing_time(block_ms);
+            brain.load_program(0, "G1 X0.1 F6000\nG1 X0.2\nG1 X0.3\nG1 X0.4".to_string());
+            let mut ticks = 0;
+            while brain.channels[0].is_running && ticks < 1000 {
+                brain.tick(1.0);
+                ticks += 1;
+            }
+            approx_eq(brain.axes[0].position, 0.4);
+            ticks
+        };
+        let (plain, starved) = (ticks_for(0.0), ticks_for(8.0));
+        assert!(starved >= plain + 20, "{plain} {starved}");
+    }
+
+
+    #[test]
+    fn diameter_mode_arcs_keep_radius_centers() {
+        // Incremental I is a radius offset, an absolute (G90.1) I a diameter.
+        for (centers, i) in [("G91.1", "I0"), ("G90.1", "I20")] {
+            let mut brain = make_xyz_brain();
+            let program = format!("G18 G7 {centers} G0 X20 Z0\nG3 X20 Z-20 {i} K-10 F200");
+            brain.load_program(0, program);
+            let mut path = Vec::new();
+            brain.run_channel_instant(0, 200, &mut path, &mut 0.0);
+            approx_eq(brain.axes[0].position, 10.0);
+            approx_eq(brain.axes[2].position, -20.0);
+            let peak = path.iter().map(|p| (p.position[0].value - 10.0).abs()).fold(0.0_f64, f64::max);
+            assert!((peak - 10.0).abs() < 0.05, "{centers}: {peak}");
+        }
+    }
+
+
+    #[test]
+    fn stock_path_cut_matches_segment_by_segment() {
+        let stock = StockBox { min: [0.0, 0.0, -20.0], max: [100.0, 80.0, 0.0] };
+        let cuts: Vec<([f64; 3], [f64; 3], f64)> = (0..40)
+            .map(|i| {
+                let y = i as f64 * 2.0;
+                ([-5.0, y, -1.0 - i as f64 * 0.2], [105.0, 80.0 - y, -2.0], 3.0 + (i % 3) as f64)
+            })
+            .collect();
+        let mut one_by_one = StockGrid::new(stock);
+        for &(from, to, radius) in &cuts {
+            one_by_one.cut_segment(from, to, radius);
+        }
+        let mut whole = StockGrid::new(stock);
+        whole.cut_path(&cuts);
+        assert_eq!(whole.heights, one_by_one.heights);
+        assert!(whole.removed_volume() > 0.0);
+    }
+
+
+    #[test]
+    fn g76_cuts_spindle_synchronized_passes() {
+        let cycle = ThreadCycle { finish_passes: 2, angle_deg: 60.0, min_depth: 0.1, allowance: 0.05 };
+        let depths = thread_pass_depths(1.0, 0.5, &cycle);
+        assert_eq!(depths.len(), 6);
+        approx_eq(depths[1], 0.5 * 2.0_f64.sqrt());
+        assert_eq!(&depths[3..], &[0.95, 1.0, 1.0]);
+
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "G0 X12 Z5 S600 M3\nG76 P020060 Q100 R0.05\nG76 X9 Z-20 P1000 Q500 F2\nG0 X30".to_string());
+        let mut path = Vec::new();
+        let mut time_s = 0.0;
+        assert!(brain.run_channel_instant(0, 500, &mut path, &mut time_s));
+        assert!(brain.alarms.is_empty(), "{}", brain.alarms[0].message);
+        let passes: Vec<&ToolpathPoint> = path.iter().filter(|p| p.motion == 33).collect();
+        assert_eq!(passes.len(), 6);
+        // Each pass goes deeper and slides along the 30° flank; the last two
+        // finish at the root X9.
+        let x_of = |p: &ToolpathPoint| p.position[0].value;
+        let z_of = |p: &ToolpathPoint| p.position[2].value;
+        approx_eq(x_of(passes[0]), 9.5);
+        assert!((z_of(passes[0]) - (-20.0 + 0.5 * 30.0_f64.to_radians().tan())).abs() < 1e-4);
+        approx_eq(x_of(passes[5]), 9.0);
+        approx_eq(brain.axes[0].position, 30.0);
+        assert_eq!(brain.channels[0].current_motion, 0);
+
+        // In G20, P and Q count 0.0001": P394 is a 1 mm thread, Q197 a 0.5 mm first pass.
+        let mut brain = make_xyz_brain();
+        brain.load_program(0, "G20 G0 X0.5 Z0.2 S600 M3\nG76 P010060 Q10\nG76 X0.4 Z-0.8 P394 Q197 F0.08\nG0 X1".to_string());
+        let mut path = Vec::new();
+        assert!(brain.run_channel_instant(0, 500, &mut path, &mut 0.0));
+        assert!(brain.alarms.is_empty(), "{}", brain.alarms[0].message);
+        let passes: Vec<&ToolpathPoint> = path.iter().filter(|p| p.motion == 33).collect();
+        assert!((x_of(passes[0]) - (10.16 + 394.0 * 0.00254 - 197.0 * 0.00254)).abs() < 1e-3, "{}", x_of(passes[0]));
+        approx_eq(x_of(passes.last().unwrap()), 10.16);
+
+        // Passes wait for the spindle angle, so every one starts in phase.
+        let mut brain = make_xyz_brain();
+        brain.parse_line(0, "G0 X0 Z0 S600 M3");
+        brain.channels[0].spindle_revs = 0.25;
+        brain.parse_line(0, "G33 Z-10 K2 Q180");
+        match brain.channels[0].pending.front() {
+            Some(PendingEntry::Dwell(wait)) => approx_eq(*wait, 0.025),
+            _ => panic!("no spindle sync"),
+        }
+    }
+
+    #[test]
+    fn log_records_follow_subsystem_thresholds() {
+        let mut brain = make_xyz_brain();
+        brain.set_log_console(false);
+        assert!(brain.logger.buffer.iter().any(|r| r.subsystem == "core"));
+        brain.clear_log_records();
+
+        // Interpreter tracing is off at the default Info threshold.
+        brain.load_program(0, "G0 X1\nG0 X2".to_string());
+        brain.run_channel_instant(0, 10, &mut Vec::new(), &mut 0.0);
+        assert!(brain.logger.buffer.is_empty());
+
+        assert!(brain.set_log_level("Interpreter".to_string(), "trace".to_string()));
+        assert!(brain.set_log_level(String::new(), "warn".to_string()));
+        assert!(!brain.set_log_level("alarms".to_string(), "loud".to_string()));
+        brain.load_program(0, "G0 X1\nG0 X2".to_string());
+        brain.run_channel_instant(0, 10, &mut Vec::new(), &mut 0.0);
+        brain.home_all();
+        brain.raise_alarm("TEST", "logged", AlarmSeverity::Warning, Some(0));
+        let lines: Vec<(LogLevel, &str, &str)> =
+            brain.logger.buffer.iter().map(|r| (r.level, r.subsystem.as_str(), r.message.as_str())).collect();
+        assert_eq!(
+            lines,
+            [
+                (LogLevel::Trace, "interpreter", "channel 0 line 1: G0 X1"),
+                (LogLevel::Trace, "interpreter", "channel 0 line 2: G0 X2"),
+                (LogLevel::Debug, "interpreter", "Channel 0: program end"),
+                (LogLevel::Warn, "alarms", "TEST: logged"),
+            ]
+        );
+
+        // Offline runs log nowhere; a small buffer keeps the newest records.
+        assert!(brain.verification_report(0, "G0 X5").is_some());
+        brain.set_log_buffer(1);
+        assert_eq!(brain.logger.buffer.len(), 1);
+        assert!(brain.set_log_level("alarms".to_string(), "off".to_string()));
+        brain.raise_alarm("TEST2", "muted", AlarmSeverity::Warning, Some(0));
+        assert_eq!(brain.logger.buffer[0].message, "TEST: logged");
+    }
+
+    #[test]
+    fn block_trace_records_words_modes_and_comp_decisions() {
+        let mut brain = make_xyz_brain();
+        brain.set_log_console(false);
+        let program = "G90 G21 G40 F500\nG1 X0 Y0\nG41 D2 G1 X10 Y0\nG1 X10 Y10\nG1 X0 Y10\nG40 G1 X0 Y20";
+        brain.load_program(0, program.to_string());
+        brain.run_channel_instant(0, 50, &mut Vec::new(), &mut 0.0);
+        assert!(brain.block_trace_range(0, 1, 6).is_empty());
+
+        assert!(brain.set_block_trace(0, true));
+        assert!(!brain.set_block_trace(7, true));
+        brain.load_program(0, program.to_string());
+        brain.run_channel_instant(0, 50, &mut Vec::new(), &mut 0.0);
+        let traced = brain.block_trace_range(0, 3, 4);
+        assert_eq!(traced.iter().map(|r| r.line).collect::<Vec<_>>(), [3, 4]);
+
+        let entry = &traced[0];
+        assert_eq!(entry.block, "G41 D2 G1 X10 Y0");
+        assert!(!entry.synthesized);
+        assert_eq!(entry.words.iter().map(|w| w.address.as_str()).collect::<Vec<_>>(), ["G", "D", "G", "X", "Y"]);
+        assert_eq!(entry.modal_changes, ["cutter comp G40 -> G41"]);
+        assert_eq!(
+            entry.decisions,
+            [
+                "trim: inside corner, end cut back 2.0000 to the next offset line",
+                "entry: ramp onto the offset path along this block",
+            ]
+        );
+        let ends: Vec<Vec<f64>> = traced.iter().map(|r| r.segments.last().unwrap().iter().map(|o| o.value).collect()).collect();
+        assert_eq!(ends, [[8.0, 2.0, 0.0], [8.0, 8.0, 0.0]]);
+        assert!(traced[1].decisions.contains(&"corner: inside, miter at the offset-line intersection".to_string()));
+
+        brain.clear_block_trace(0);
+        assert!(brain.block_trace_range(0, 1, 6).is_empty());
+        assert!(brain.set_block_trace(0, false));
+        assert!(brain.channels[0].block_trace.is_none());
+    }
+
+    #[test]
+    fn capabilities_list_compiled_subsystems() {
+        let caps = capabilities();
+        let compiled = [
+            ("material-removal", cfg!(feature = "material-removal")),
+            ("collision", cfg!(feature = "collision")),
+            ("macros", cfg!(feature = "macros")),
+            ("dialects", cfg!(feature = "dialects")),
+        ];
+        for (feature, enabled) in compiled {
+            assert_eq!(caps.features.iter().any(|f| f == feature), enabled, "{}", feature);
+        }
+        assert!(caps.dialects.iter().any(|d| d == "fanuc"));
+        assert_eq!(caps.machine_templates.len(), MACHINE_TEMPLATES.len());
+
+        // The stock grid is only built once a cut reaches the stock box.
+        let mut brain = make_xyz_brain();
+        assert_eq!(brain.set_stock_box(0.0, 0.0, -10.0, 50.0, 50.0, 0.0), MATERIAL_REMOVAL);
+        brain.load_program(0, "G0 X10 Y10 Z5\nG1 Z-1 F600\nX30".to_string());
+        brain.tick(10.0);
+        assert!(brain.channels[0].run_usage.stock.is_none());
+        for _ in 0..2000 {
+            brain.tick(10.0);
+        }
+        assert_eq!(brain.channels[0].run_usage.stock.is_some(), MATERIAL_REMOVAL);
+    }
+
+    #[test]
+    fn verification_report_gates_on_alarms_and_completion() {
+        let mut brain = MachineBrain::new();
+        assert!(brain.apply_config_json("not json").error.is_some());
+        assert!(brain.load_machine_template("vmc3".to_string()));
+        assert!(brain.verification_report(9, "G0 X0").is_none());
+
+        let clean = brain.verification_report(0, "G21 G90\nG0 X10 Y10\nG1 X20 F500\nM30").unwrap();
+        assert!(clean.completed && clean.alarms.is_empty());
+        assert!(clean.passed());
+
+        let bad = brain.verification_report(0, "G21 G90\nM5\nG33 Z-10 K1.5\nM30").unwrap();
+        assert!(!bad.alarms.is_empty());
+        assert!(!bad.passed());
+    }
+}
+#[wasm_bindgen]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AxisType { Linear, Rotary }
+
+impl AxisType {
+    fn units(self) -> &'static str {
+        match self {
+            AxisType::Linear => "mm",
+            AxisType::Rotary => "deg",
+        }
+    }
+}
+
+// How a rotary axis treats its range: continuous axes wrap to ±180°, limited
+// ones (trunnions, cable-bound tables) clamp to min/max without wrapping.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum RotaryMode {
+    Continuous,
+    Limited,
+}
+
+// Position feedback of an axis. An absolute encoder still knows where the
+// axis is after a power cycle, so the axis comes up homed and a restored home
+// reference stays valid; an incremental one has to be homed again.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum EncoderType {
+    Absolute,
+    Incremental,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Axis {
+    pub id: u32,
+    pub physical_name: String,
+    pub position: f64,
+    pub target: f64,
+    pub axis_type: AxisType,
+    pub min_range: f64,
+    pub max_range: f64,
+    pub homed: bool,
+    pub velocity: f64,   // current speed, mm/min (deg/min for rotary)
+    pub accel: f64,      // mm/min per second (deg/min per second for rotary)
+    pub invert: bool,    // flip direction in 3D view
+    pub machine_zero: f64,
+    pub side: AxisSide,          // which kinematic chain the axis moves
+    pub link_axis: Option<char>, // X/Y/Z (linear) or A/B/C (rotary) direction it drives
+    pub rotary_mode: RotaryMode, // rotary axes only
+    pub encoder: EncoderType,
+    pub feed: f64, // actual feed over the last tick, mm/min (deg/min for rotary)
+    pub load: f64, // simulated drive load, percent of rated
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum AxisSide {
+    Tool,  // moves the head/spindle
+    Table, // moves the workpiece
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChannelAxisMap {
+    pub axis_id: u32,
+    pub display_label: String,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct ProgramCoverage {
+    pub total_blocks: usize, // executable lines (blank and comment-only lines excluded)
+    pub executed_blocks: usize,
+    pub percent: f64,
+    pub hits: Vec<u32>, // execution count per program line
+    pub unexecuted_lines: Vec<usize>,
+    pub branches: Vec<BranchCoverage>,         // IF/ELSEIF/WHILE lines evaluated, in line order
+    pub subprograms: Vec<SubprogramCoverage>, // programs and macros called, by label
+}
+
+/// How often the condition of an IF, ELSEIF or WHILE line held.
+#[derive(Serialize, Clone, Default)]
+pub struct BranchCoverage {
+    pub line: usize,
+    pub taken: u32,
+    pub not_taken: u32,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct SubprogramCoverage {
+    pub program: String, // O-word label or Fanuc program number
+    pub calls: u32,
+}
+
+// ── Live trace ─────────────────────────────────────────────────────────────
+
+const TRACE_DEFAULT_TOLERANCE_MM: f64 = 0.01;
+const TRACE_MAX_POINTS: usize = 1_000_000; // oldest points are dropped beyond this
+const TRACE_MAX_SPAN: usize = 4096;        // intermediate points checked per chord
+
+#[derive(Clone)]
+struct TracePoint {
+    position: Vec<f64>, // machine coordinates in the channel's axis order
+    motion: i32,
+    tool: i32,
+    operation: Option<usize>, // index into the program outline's operations
+}
+
+// Default per-tool trace colors (cycled by tool number).
+const TRACE_TOOL_PALETTE: [&str; 8] = [
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#bfef45",
+];
+const TRACE_RAPID_COLOR: &str = "#9e9e9e";
+
+/// Polyline of the executed path with one tool, operation and motion mode.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TraceSegment {
+    pub tool: i32,
+    pub operation: Option<usize>,
+    pub operation_name: Option<String>,
+    pub motion: i32,
+    pub rapid: bool,
+    pub color: String,
+    pub points: Vec<Vec<f64>>, // machine coordinates in the channel's axis order
+}
+
+// Executed path of a channel, decimated by chord tolerance while it is recorded.
+// The last point is provisional: it follows the tool until the path bends by more
+// than the tolerance, then it is frozen and a new provisional point starts.
+#[derive(Clone, Default)]
+struct PathTrace {
+    points: Vec<TracePoint>,
+    dropped: usize,             // points removed from the front; indices stay absolute
+    span: Vec<Vec<f64>>,        // positions between the last frozen point and the tail
+}
+
+impl PathTrace {
+    fn len(&self) -> usize {
+        self.dropped + self.points.len()
+    }
+
+    fn clear(&mut self) {
+        self.dropped += self.points.len();
+        self.points.clear();
+        self.span.clear();
+    }
+
+    fn push(&mut self, point: TracePoint, tolerance: f64) {
+        let n = self.points.len();
+        let Some(tail) = self.points.last() else {
+            self.points.push(point);
+            return;
+        };
+        // Mode changes without motion show up with the next moved point.
+        if tail.position == point.position {
+            return;
+        }
+        let same_kind = tail.motion == point.motion && tail.tool == point.tool && tail.operation == point.operation;
+        // The first point after a freeze, motion/tool/operation changes and long spans start a new chord.
+        let keep_tail = n < 2
+            || !same_kind
+            || self.span.len() >= TRACE_MAX_SPAN
+            || {
+                let anchor = &self.points[n - 2].position;
+                std::iter::once(&tail.position)
+                    .chain(self.span.iter())
+                    .any(|p| point_polyline_deviation(p, anchor, &point.position) > tolerance)
+            };
+        if keep_tail {
+            self.span.clear();
+            self.points.push(point);
+            if self.points.len() > TRACE_MAX_POINTS {
+                let excess = self.points.len() - TRACE_MAX_POINTS;
+                self.points.drain(..excess);
+                self.dropped += excess;
+            }
+        } else {
+            let old = std::mem::replace(&mut self.points[n - 1], point);
+            self.span.push(old.position);
+        }
+    }
+
+    // Splits the trace into polylines of one tool/operation/motion; each segment
+    // starts at the previous one's last point so the path stays connected.
+    fn segments(&self, outline: &ProgramOutline, colors: &HashMap<i32, String>) -> Vec<TraceSegment> {
+        let mut out: Vec<TraceSegment> = Vec::new();
+        let mut prev: Option<&TracePoint> = None;
+        for p in &self.points {
+            let continues = out
+                .last()
+                .is_some_and(|s| s.tool == p.tool && s.operation == p.operation && s.motion == p.motion);
+            if !continues {
+                let rapid = p.motion == 0;
+                let color = if rapid {
+                    TRACE_RAPID_COLOR.to_string()
+                } else {
+                    colors.get(&p.tool).cloned().unwrap_or_else(|| {
+                        TRACE_TOOL_PALETTE[p.tool.unsigned_abs() as usize % TRACE_TOOL_PALETTE.len()].to_string()
+                    })
+                };
+                out.push(TraceSegment {
+                    tool: p.tool,
+                    operation: p.operation,
+                    operation_name: p.operation.and_then(|i| outline.operations.get(i)).map(|op| op.name.clone()),
+                    motion: p.motion,
+                    rapid,
+                    color,
+                    points: prev.map(|q| vec![q.position.clone()]).unwrap_or_default(),
+                });
+            }
+            if let Some(seg) = out.last_mut() {
+                seg.points.push(p.position.clone());
+            }
+            prev = Some(p);
+        }
+        // A lone start point is not a polyline.
+        out.retain(|s| s.points.len() >= 2);
+        out
+    }
+
+    // Flat [axis positions..., motion, tool] per point, from absolute index `start`.
+    fn chunk(&self, start: usize, max_points: usize) -> Vec<f64> {
+        let from = start.saturating_sub(self.dropped).min(self.points.len());
+        let to = from.saturating_add(max_points).min(self.points.len());
+        let mut out = Vec::new();
+        for p in &self.points[from..to] {
+            out.extend_from_slice(&p.position);
+            out.push(p.motion as f64);
+            out.push(p.tool as f64);
+        }
+        out
+    }
+}
+
+// Oscilloscope-style axis samples in a fixed-size ring buffer. Every tick
+// updates the measured velocity (mm/min or deg/min) and acceleration (per
+// second); every `decimation`-th tick is stored.
+#[derive(Clone, Default)]
+struct Telemetry {
+    capacity: usize, // samples kept, 0 = recording off
+    decimation: u32,
+    ticks: u32,
+    time: VecDeque<f64>,
+    axes: Vec<AxisTelemetry>,
+}
+
+#[derive(Clone, Default)]
+struct AxisTelemetry {
+    axis_id: u32,
+    velocity_now: f64,
+    position: VecDeque<f64>,
+    velocity: VecDeque<f64>,
+    acceleration: VecDeque<f64>,
+}
+
+impl Telemetry {
+    fn configure(&mut self, capacity: usize, decimation: u32, axes: &[Axis]) {
+        *self = Telemetry {
+            capacity,
+            decimation: decimation.max(1),
+            axes: axes.iter().map(|ax| AxisTelemetry { axis_id: ax.id, ..AxisTelemetry::default() }).collect(),
+            ..Telemetry::default()
+        };
+    }
+
+    fn record(&mut self, axes: &[Axis], before: &[f64], dt_sec: f64, now_s: f64) {
+        if self.capacity == 0 || dt_sec <= 0.0 {
+            return;
+        }
+        self.ticks += 1;
+        let keep = self.ticks.is_multiple_of(self.decimation);
+        if keep {
+            if self.time.len() == self.capacity {
+                self.time.pop_front();
+            }
+            self.time.push_back(now_s);
+        }
+        for track in &mut self.axes {
+            // Removed axes keep their history and record gaps.
+            let sample = axes.iter().zip(before).find(|(ax, _)| ax.id == track.axis_id).map(|(ax, prev)| {
+                let velocity = (ax.position - prev) / dt_sec * 60.0;
+                (ax.position, velocity, (velocity - track.velocity_now) / dt_sec)
+            });
+            let (position, velocity, acceleration) = sample.unwrap_or((f64::NAN, 0.0, f64::NAN));
+            track.velocity_now = velocity;
+            if !keep {
+                continue;
+            }
+            for (buf, value) in [
+                (&mut track.position, position),
+                (&mut track.velocity, if sample.is_some() { velocity } else { f64::NAN }),
+                (&mut track.acceleration, acceleration),
+            ] {
+                if buf.len() == self.capacity {
+                    buf.pop_front();
+                }
+                buf.push_back(value);
+            }
+        }
+    }
+
+    fn signal(&self, axis_id: u32, name: &str) -> Option<&VecDeque<f64>> {
+        let track = self.axes.iter().find(|t| t.axis_id == axis_id)?;
+        match name {
+            "position" => Some(&track.position),
+            "velocity" => Some(&track.velocity),
+            "acceleration" => Some(&track.acceleration),
+            _ => None,
+        }
+    }
+}
+
+// Distance of `p` from the segment a-b in any number of dimensions.
+fn point_polyline_deviation(p: &[f64], a: &[f64], b: &[f64]) -> f64 {
+    let ab: Vec<f64> = a.iter().zip(b).map(|(a, b)| b - a).collect();
+    let ap: Vec<f64> = a.iter().zip(p).map(|(a, p)| p - a).collect();
+    let len_sq: f64 = ab.iter().map(|d| d * d).sum();
+    let t = if len_sq > 1e-18 {
+        (ap.iter().zip(&ab).map(|(x, y)| x * y).sum::<f64>() / len_sq).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    ap.iter().zip(&ab).map(|(x, y)| (x - t * y).powi(2)).sum::<f64>().sqrt()
+}
+
+#[derive(Serialize, Clone)]
+pub struct ToolpathPoint {
+    pub block: usize,
+    pub motion: i32,
+    pub tool: i32,
+    pub feed: f64,
+    pub spindle_rpm: f64, // programmed S
+    pub duration_s: f64, // motion time of the move ending here
+    pub tolerance: f64,  // path deviation allowed for the move (G64 P or profile)
+    pub cutter_comp: i32, // 41/42 when the move ran compensated, else 40
+    pub wcs: usize,       // index of the active work offset
+    pub position: Vec<AxisOffset>, // machine coordinates of the channel's axes
+    pub length_comp: [f64; 3], // X/Y/Z tool offset included in `position`
+}
+
+/// Preview point under the cursor: where the tool is at parameter `t` of a
+/// block (0 = block start, 1 = block end, by path length) and the modal
+/// state the block runs in.
+#[derive(Serialize, Clone)]
+pub struct PathPointInfo {
+    pub block: usize, // program line, 0-based as in `ToolpathPoint`
+    pub t: f64,
+    pub machine: Vec<AxisOffset>,
+    pub work: Vec<AxisOffset>, // active WCS, tool offset removed (programmed position)
+    pub feed: f64,             // mm/min the move runs at: the rapid rate in G0
+    pub modal: ModalSnapshot,
+}
+
+/// Compensated tool-centre contour in the XY plane: one run of G41/G42 moves
+/// including the entry and exit moves, in work coordinates. `blocks[i]` is
+/// the program line that produced `points[i]`.
+#[derive(Serialize, Clone)]
+pub struct CompContour {
+    pub operation: Option<usize>,
+    pub operation_name: Option<String>,
+    pub tool: i32,
+    pub side: i32, // 41 or 42
+    pub points: Vec<[f64; 2]>,
+    pub blocks: Vec<usize>,
+}
+
+#[derive(Serialize)]
+pub struct VerificationReport {
+    pub completed: bool,
+    pub path_points: usize,
+    pub cycle_time_s: f64, // motion time at effective feeds, without ramps
+    pub coverage: ProgramCoverage,
+    pub alarms: Vec<Alarm>,
+}
+
+struct VerificationRun {
+    completed: bool,
+    part_count: u32, // parts counted during the run
+    coverage: ProgramCoverage,
+    path: Vec<ToolpathPoint>,
+    alarms: Vec<Alarm>,
+    cycle_time_s: f64,
+}
+
+#[derive(Clone, Default)]
+struct InvariantTrack {
+    pc: usize,
+    pending_len: usize,
+    pending_queued: usize,
+    cutter_comp: i32,
+    programmed_work: HashMap<u32, f64>,
+    // Axes reprogrammed by an uncompensated block, checked once its segments are consumed.
+    unverified_axes: Vec<u32>,
+}
+
+// Slack allowed on soft limits and path equality before an invariant fails.
+const INVARIANT_EPS: f64 = 1e-6;
+
+// Guard against runaway programs during offline verification.
+const VERIFY_MAX_STEPS: usize = 2_000_000;
+
+// Digest format version; bump when the hashed fields or quantization change.
+const TOOLPATH_DIGEST_VERSION: &str = "tp1";
+
+// FNV-1a over tolerance-bucketed path geometry, motion mode, tool and feed.
+// Block numbers are not hashed so comment or renumbering edits keep the digest.
+fn toolpath_digest(path: &[ToolpathPoint], tolerance: f64) -> String {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+    let tol = if tolerance.is_finite() && tolerance > 0.0 { tolerance } else { 1e-3 };
+    let bucket = |v: f64| -> i64 { (v / tol).round() as i64 };
+
+    let mut hash = FNV_OFFSET;
+    let mut feed_bytes = |bytes: &[u8]| {
+        for b in bytes {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    };
+    let mut last: Option<Vec<i64>> = None;
+    for p in path {
+        let mut key: Vec<i64> = vec![p.motion as i64, p.tool as i64, bucket(p.feed)];
+        for v in &p.position {
+            key.push(v.axis_id as i64);
+            key.push(bucket(v.value));
+        }
+        if last.as_ref() == Some(&key) {
+            continue;
+        }
+        for k in &key {
+            feed_bytes(&k.to_le_bytes());
+        }
+        last = Some(key);
+    }
+    format!("{}-{:016x}", TOOLPATH_DIGEST_VERSION, hash)
+}
+
+#[derive(Serialize, Clone)]
+pub struct PathDiffRegion {
+    pub start_block: usize,
+    pub end_block: usize,
+    pub start_point: usize,
+    pub end_point: usize,
+    pub max_deviation: f64,
+    pub allowed_deviation: f64, // largest per-block tolerance in the region
+}
+
+#[derive(Serialize, Clone)]
+pub struct ProgramDiff {
+    pub identical: bool,
+    pub max_deviation: f64,
+    pub regions_a: Vec<PathDiffRegion>, // parts of A not on B's path
+    pub regions_b: Vec<PathDiffRegion>, // parts of B not on A's path
+}
+
+fn point_coords(p: &ToolpathPoint) -> Vec<f64> {
+    p.position.iter().map(|a| a.value).collect()
+}
+
+fn point_segment_distance(p: &[f64], a: &[f64], b: &[f64]) -> f64 {
+    let mut ab2 = 0.0;
+    let mut ap_ab = 0.0;
+    for k in 0..p.len().min(a.len()).min(b.len()) {
+        let ab = b[k] - a[k];
+        ab2 += ab * ab;
+        ap_ab += (p[k] - a[k]) * ab;
+    }
+    let t = if ab2 > 1e-18 { (ap_ab / ab2).clamp(0.0, 1.0) } else { 0.0 };
+    let mut d2 = 0.0;
+    for k in 0..p.len().min(a.len()).min(b.len()) {
+        let q = a[k] + (b[k] - a[k]) * t;
+        d2 += (p[k] - q).powi(2);
+    }
+    d2.sqrt()
+}
+
+// Deviation regions of `from` measured against the polyline `to`. A point may
+// deviate by its own block tolerance when that is looser than `tolerance`.
+fn path_deviation_regions(from: &[ToolpathPoint], to: &[ToolpathPoint], tolerance: f64) -> (Vec<PathDiffRegion>, f64) {
+    const WINDOW: usize = 64;
+    let to_pts: Vec<Vec<f64>> = to.iter().map(point_coords).collect();
+    let seg_dist = |p: &[f64], s: usize| -> f64 {
+        if to_pts.len() == 1 {
+            point_segment_distance(p, &to_pts[0], &to_pts[0])
+        } else {
+            point_segment_distance(p, &to_pts[s], &to_pts[s + 1])
+        }
+    };
+    let seg_count = to_pts.len().saturating_sub(1).max(1);
+
+    let mut regions: Vec<PathDiffRegion> = Vec::new();
+    let mut max_dev: f64 = 0.0;
+    let mut cursor = 0usize;
+    let mut open: Option<PathDiffRegion> = None;
+    for (idx, p) in from.iter().enumerate() {
+        let coords = point_coords(p);
+        let dev = if to_pts.is_empty() {
+            f64::INFINITY
+        } else {
+            // Paths are mostly in step: search near the last match, then fall back to a full scan.
+            let lo = cursor.saturating_sub(WINDOW);
+            let hi = (cursor + WINDOW).min(seg_count - 1);
+            let (mut best, mut best_s) = (f64::INFINITY, cursor);
+            for s in lo..=hi {
+                let d = seg_dist(&coords, s);
+                if d < best {
+                    best = d;
+                    best_s = s;
+                }
+            }
+            if best > tolerance {
+                for s in 0..seg_count {
+                    let d = seg_dist(&coords, s);
+                    if d < best {
+                        best = d;
+                        best_s = s;
+                    }
+                }
+            }
+            cursor = best_s;
+            best
+        };
+        let allowed = tolerance.max(p.tolerance);
+        if dev > allowed {
+            max_dev = max_dev.max(dev);
+            match open.as_mut() {
+                Some(r) => {
+                    r.end_block = p.block;
+                    r.end_point = idx;
+                    r.max_deviation = r.max_deviation.max(dev);
+                    r.allowed_deviation = r.allowed_deviation.max(allowed);
+                }
+                None => {
+                    open = Some(PathDiffRegion {
+                        start_block: p.block,
+                        end_block: p.block,
+                        start_point: idx,
+                        end_point: idx,
+                        max_deviation: dev,
+                        allowed_deviation: allowed,
+                    })
+                }
+            }
+        } else if let Some(r) = open.take() {
+            regions.push(r);
+        }
+    }
+    if let Some(r) = open {
+        regions.push(r);
+    }
+    (regions, max_dev)
+}
+
+// Straight-line distance between two points of one channel's path.
+fn toolpath_point_distance(a: &ToolpathPoint, b: &ToolpathPoint) -> f64 {
+    a.position.iter().zip(&b.position).map(|(p, q)| (q.value - p.value).powi(2)).sum::<f64>().sqrt()
+}
+
+fn compare_toolpaths(a: &[ToolpathPoint], b: &[ToolpathPoint], tolerance: f64) -> ProgramDiff {
+    let tol = if tolerance.is_finite() && tolerance > 0.0 { tolerance } else { 1e-3 };
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    let ((regions_a, dev_a), (regions_b, dev_b)) =
+        rayon::join(|| path_deviation_regions(a, b, tol), || path_deviation_regions(b, a, tol));
+    #[cfg(not(all(feature = "parallel", not(target_arch = "wasm32"))))]
+    let ((regions_a, dev_a), (regions_b, dev_b)) = (path_deviation_regions(a, b, tol), path_deviation_regions(b, a, tol));
+    ProgramDiff {
+        identical: regions_a.is_empty() && regions_b.is_empty(),
+        max_deviation: dev_a.max(dev_b),
+        regions_a,
+        regions_b,
+    }
+}
+
+fn is_executable_line(line: &str) -> bool {
+    let mut depth = 0;
+    for ch in line.chars() {
+        match ch {
+            ';' if depth == 0 => break,
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            '%' => {}
+            c if depth == 0 && !c.is_whitespace() => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+#[derive(Serialize)]
+pub struct ChannelStatus {
+    pub id: u32,
+    pub is_running: bool,
+    pub paused: bool,
+    pub run_state: String, // RUNNING / PAUSED / PROGRAM_STOP / OPTIONAL_STOP / FINISHED / STOPPED
+    pub active_pc: i32,
+    pub pc: usize,
+    pub block_number: Option<u32>, // N of the last numbered block read (or searched)
+    pub axis_map: Vec<ChannelAxisMap>,
+    pub current_motion: i32,
+    pub exact_stop: bool,
+    pub path_tolerance: f64, // effective G64 P (or profile) value
+    pub cutter_comp: i32,
+    pub tool_radius: f64,
+    pub length_comp_active: bool,
+    pub tool_length: f64,
+    pub active_tool: i32,
+    pub active_d: i32,
+    pub active_h: i32,
+    pub spindle_rpm: f64,
+    pub spindle_mode: i32,
+    pub coolant_on: bool,
+    pub feed_rate: f64,
+    pub feed_override: f64,
+    pub spindle_override: f64,
+    pub feed_override_enabled: bool,
+    pub spindle_override_enabled: bool,
+    pub overrides_program_locked: bool,
+    pub single_block: bool,
+    pub dry_geometric: bool,
+    pub arc_center_absolute: bool,
+    pub thread_lead: f64,
+    pub thread_lead_change: f64,
+    pub thread_start_angle: f64,
+    pub rigid_tapping: bool,     // G33.1 / G84 / G74 feed: spindle locked to Z at the pitch
+    pub spindle_actual_rpm: f64, // measured; slows and reverses with Z while rigid tapping
+    pub spindle_angle: f64,      // deg since the last index pulse
+    pub retracing: bool,
+    pub auto_hold: bool,
+    pub turret_station: u32, // 0 without a turret
+    pub turret_angle: f64,
+    pub pending_tool: Option<i32>, // T preselected for the next M6
+    pub tool_change: Option<ToolChangeEvent>,
+    pub part_count: u32,
+    pub parts_total: u32,
+    pub part_target: u32,
+    pub parts_target_reached: bool,
+    pub programmed_work: Vec<AxisOffset>,
+    pub surface_speed: f64, // m/min at the cutter (G17/G19) or part (G18) diameter
+    pub chip_load: f64,     // mm per tooth; mm/rev when turning
+}
+
+// ── Block hooks ───────────────────────────────────────────────────────────
+
+/// One address word of a block, or an axis position by label.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct BlockWord {
+    pub address: String,
+    pub value: f64,
+}
+
+/// Modal state of a channel as seen by block hooks.
+#[derive(Serialize, Clone, Debug)]
+pub struct ModalSnapshot {
+    pub motion: i32,
+    pub absolute: bool,
+    pub units_mm: bool,
+    pub diameter_mode: bool,
+    pub wcs: String,
+    pub cutter_comp: i32,
+    pub length_comp_active: bool,
+    pub tool: i32,
+    pub feed_rate: f64,
+    pub spindle_rpm: f64,
+    pub spindle_mode: i32,
+    pub coolant_on: bool,
+    pub position: Vec<BlockWord>, // programmed work position per axis label
+}
+
+/// What a block hook passes in: the block about to run (or just run) and
+/// the channel's modal state at that point.
+#[derive(Serialize, Clone, Debug)]
+pub struct BlockContext {
+    pub channel_id: u32,
+    pub line: usize, // 1-based
+    pub block: String,
+    pub words: Vec<BlockWord>,
+    pub modal: ModalSnapshot,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockHookAction {
+    Run,
+    Replace(String), // run this block text instead
+    Veto(String),    // fault with this reason, block not run
+}
+
+/// Policy hook called around every program block, for site rules that
+/// should not live in the interpreter.
+pub trait BlockHook {
+    fn before(&self, ctx: &BlockContext) -> BlockHookAction;
+    fn after(&self, _ctx: &BlockContext) {}
+}
+
+// JS callbacks `before(ctx)` returning nothing/true (run), a string (replace),
+// false or `{ veto: reason }` (veto), and `after(ctx)` for logging. A throwing
+// `before` vetoes the block.
+struct JsBlockHook {
+    before: Option<js_sys::Function>,
+    after: Option<js_sys::Function>,
+}
+
+impl BlockHook for JsBlockHook {
+    fn before(&self, ctx: &BlockContext) -> BlockHookAction {
+        let Some(before) = &self.before else { return BlockHookAction::Run; };
+        let arg = serde_wasm_bindgen::to_value(ctx).unwrap_or(JsValue::NULL);
+        let Ok(result) = before.call1(&JsValue::NULL, &arg) else {
+            return BlockHookAction::Veto("block hook failed".to_string());
+        };
+        if let Some(text) = result.as_string() {
+            return BlockHookAction::Replace(text);
+        }
+        if result.as_bool() == Some(false) {
+            return BlockHookAction::Veto("vetoed by block hook".to_string());
+        }
+        let veto = if result.is_object() {
+            js_sys::Reflect::get(&result, &JsValue::from_str("veto")).ok().and_then(|v| v.as_string())
+        } else {
+            None
+        };
+        if let Some(reason) = veto {
+            return BlockHookAction::Veto(reason);
+        }
+        BlockHookAction::Run
+    }
+
+    fn after(&self, ctx: &BlockContext) {
+        if let Some(after) = &self.after {
+            let arg = serde_wasm_bindgen::to_value(ctx).unwrap_or(JsValue::NULL);
+            let _ = after.call1(&JsValue::NULL, &arg);
+        }
+    }
+}
+
+// ── Interpreter trace ─────────────────────────────────────────────────────
+
+const BLOCK_TRACE_MAX: usize = 5000; // per channel, oldest dropped
+
+/// Why the interpreter did what it did with one executed block.
+#[derive(Serialize, Clone, Default)]
+pub struct BlockTrace {
+    pub line: usize, // 1-based program line; synthesized blocks carry their origin's
+    pub block: String,
+    pub synthesized: bool, // injected by a cycle or the safe-start preamble
+    pub words: Vec<BlockWord>,
+    pub modal_changes: Vec<String>, // "motion G0 -> G1", ...
+    pub decisions: Vec<String>,     // comp entry/corner/trim choices and their reasons
+    pub segments: Vec<Vec<AxisOffset>>, // machine targets generated, in order
+}
+
+#[derive(Clone, Default)]
+struct BlockTraceLog {
+    records: VecDeque<BlockTrace>,
+    open: Option<BlockTrace>, // block being parsed; decisions land here
+}
+
+// State before a traced block, to diff against afterwards.
+struct BlockTraceStart {
+    modal: ModalSnapshot,
+    targets: Vec<(u32, f64)>,
+    pending: usize,
+}
+
+fn modal_changes(before: &ModalSnapshot, after: &ModalSnapshot) -> Vec<String> {
+    let g = |on: bool, yes: i32, no: i32| format!("G{}", if on { yes } else { no });
+    let changes = [
+        ("motion", format!("G{}", before.motion), format!("G{}", after.motion)),
+        ("distance", g(before.absolute, 90, 91), g(after.absolute, 90, 91)),
+        ("units", g(before.units_mm, 21, 20), g(after.units_mm, 21, 20)),
+        ("diameter", g(before.diameter_mode, 7, 8), g(after.diameter_mode, 7, 8)),
+        ("wcs", before.wcs.clone(), after.wcs.clone()),
+        ("cutter comp", format!("G{}", before.cutter_comp), format!("G{}", after.cutter_comp)),
+        ("length comp", g(before.length_comp_active, 43, 49), g(after.length_comp_active, 43, 49)),
+        ("tool", format!("T{}", before.tool), format!("T{}", after.tool)),
+        ("feed", format!("F{}", format_word_value(before.feed_rate)), format!("F{}", format_word_value(after.feed_rate))),
+        ("spindle", format!("S{}", format_word_value(before.spindle_rpm)), format!("S{}", format_word_value(after.spindle_rpm))),
+        ("spindle mode", format!("M{}", before.spindle_mode), format!("M{}", after.spindle_mode)),
+        ("coolant", format!("M{}", if before.coolant_on { 8 } else { 9 }), format!("M{}", if after.coolant_on { 8 } else { 9 })),
+    ];
+    changes.into_iter().filter(|(_, a, b)| a != b).map(|(name, a, b)| format!("{} {} -> {}", name, a, b)).collect()
+}
+
+// ── Program storage ───────────────────────────────────────────────────────
+
+// Lines fetched from a streamed source per request, and lines kept behind the pc
+// when evicting (backward jumps and look-ahead stay cheap).
+const PROGRAM_STREAM_CHUNK: usize = 256;
+const PROGRAM_STREAM_KEEP_BEHIND: usize = 64;
+
+// Blocks a dry geometric channel runs per tick at most.
+const DRY_BLOCKS_PER_TICK: usize = 10_000;
+
+/// Supplies program lines on demand for programs larger than the memory budget.
+pub trait ProgramSource {
+    /// Up to `count` raw lines starting at line `start` (0-based).
+    fn fetch(&self, start: usize, count: usize) -> Vec<String>;
+}
+
+// JS callback `(start, count) => string` returning newline-separated lines.
+struct JsProgramSource(js_sys::Function);
+
+impl ProgramSource for JsProgramSource {
+    fn fetch(&self, start: usize, count: usize) -> Vec<String> {
+        self.0
+            .call2(&JsValue::NULL, &JsValue::from(start as f64), &JsValue::from(count as f64))
+            .ok()
+            .and_then(|v| v.as_string())
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Program text of a channel: held in full, or (streamed) only a window of
+/// lines around the pc that stays within the memory budget.
+#[derive(Clone, Default)]
+pub struct ProgramStore {
+    lines: Vec<String>,
+    window_start: usize,
+    resident_bytes: usize,
+    total: usize,
+    source: Option<Rc<dyn ProgramSource>>,
+    linked: Vec<String>, // library macros appended past the program's last line
+}
+
+impl ProgramStore {
+    fn from_code(code: &str) -> Self {
+        let lines: Vec<String> = code.lines().map(|l| l.trim().to_uppercase()).collect();
+        Self {
+            total: lines.len(),
+            resident_bytes: lines.iter().map(String::len).sum(),
+            lines,
+            window_start: 0,
+            source: None,
+            linked: Vec::new(),
+        }
+    }
+
+    fn streamed(total: usize, source: Rc<dyn ProgramSource>) -> Self {
+        Self { total, source: Some(source), ..Self::default() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.total + self.linked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        self.source.is_some()
+    }
+
+    /// Line `idx` if it is resident (always the case for non-streamed programs).
+    pub fn get(&self, idx: usize) -> Option<&String> {
+        if idx >= self.total {
+            return self.linked.get(idx - self.total);
+        }
+        idx.checked_sub(self.window_start).and_then(|i| self.lines.get(i))
+    }
+
+    // Appends lines after the program (and any earlier links); they stay
+    // resident and out of coverage.
+    fn link(&mut self, lines: &[String]) {
+        self.linked.extend_from_slice(lines);
+    }
+
+    fn resident(&self) -> impl Iterator<Item = (usize, &String)> {
+        self.lines.iter().enumerate().map(move |(i, l)| (self.window_start + i, l))
+    }
+
+    // Makes line `idx` resident, fetching from the source and evicting lines
+    // further than PROGRAM_STREAM_KEEP_BEHIND before it while over `budget`.
+    // Returns false when the source could not supply the line.
+    fn ensure_resident(&mut self, idx: usize, budget: usize) -> bool {
+        if idx >= self.total || self.get(idx).is_some() {
+            return idx < self.len();
+        }
+        let Some(source) = self.source.clone() else { return false; };
+        let window_end = self.window_start + self.lines.len();
+        if idx != window_end || self.lines.is_empty() {
+            self.lines.clear();
+            self.resident_bytes = 0;
+            self.window_start = idx.saturating_sub(PROGRAM_STREAM_KEEP_BEHIND);
+        }
+        while self.window_start + self.lines.len() <= idx {
+            let start = self.window_start + self.lines.len();
+            let count = PROGRAM_STREAM_CHUNK.min(self.total - start);
+            let fetched = source.fetch(start, count);
+            if fetched.is_empty() {
+                return false;
+            }
+            for line in fetched.into_iter().take(count) {
+                let line = line.trim().to_uppercase();
+                self.resident_bytes += line.len();
+                self.lines.push(line);
+            }
+        }
+        let keep_from = idx.saturating_sub(PROGRAM_STREAM_KEEP_BEHIND);
+        let mut evict = 0;
+        while self.resident_bytes > budget && self.window_start + evict < keep_from {
+            self.resident_bytes -= self.lines[evict].len();
+            evict += 1;
+        }
+        if evict > 0 {
+            self.lines.drain(..evict);
+            self.window_start += evict;
+        }
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct Channel {
+    pub id: u32,
+    pub axis_map: Vec<ChannelAxisMap>,
+    pub is_running: bool,
+    pub paused: bool,
+    pub program_stop: Option<i32>, // M00/M01 that paused the channel, until the next block
+    pub program_end: Option<i32>,  // M02/M30 read; the program ends once its motion is done
+    pub finished: bool,            // ran to its end rather than being stopped
+    pub pc: usize,
+    pub active_pc: i32,
+    pub block_number: Option<u32>, // N of the last numbered block read
+    pub program: ProgramStore,
+    pub feed_rate: f64,
+    pub current_motion: i32, // last commanded motion mode (0/1/2/3)
+
+    // --- Simple modal state (per-channel) ---
+    pub abs_mode: bool,      // G90/G91
+    pub units_mm: bool,      // G21(true)/G20(false)
+    pub plane: u8,           // arc plane: 17=XY, 18=ZX, 19=YZ
+    pub diameter_mode: bool, // X words program diameters (lathe)
+    mirror: HashMap<u32, f64>, // G51.1 mirror centers (work), per axis
+    pub rear_turret: bool,   // X+ away from the operator (lathe)
+    index_capture: bool,     // latch positions on each spindle index pulse
+    block_prep_s: f64,       // time until the next block is prepared
+    spindle_revs: f64,       // spindle angle in revolutions since the last index
+    thread_cycle: ThreadCycle, // G76 first-block settings
+    rigid_tap: Option<RigidTap>, // G33.1 in progress
+    canned_cycle: Option<CannedCycle>, // G84/G74 until G80
+    cycle_return_r: bool,              // G99: return to R between holes (G98 = initial Z)
+    synthesizing: bool,                // an injected block is being interpreted
+    spindle_actual_rpm: f64, // measured over the last tick; follows Z while rigid tapping
+    defaults: ChannelDefaults, // power-on / reset modal state
+    pub exact_stop: bool,    // G61 exact-stop / G64 continuous
+    pub path_tolerance: Option<f64>, // G64 P: allowed path deviation, overrides the profile
+    pub comp_tolerance: Option<f64>, // G64 Q: comp segment join tolerance, overrides the profile
+    pub cutter_comp: i32,    // 40/41/42
+    pub tool_radius: f64,    // D value (mm)
+    pub length_comp_active: bool, // G43/G49
+    pub tool_length: f64,    // H value (mm)
+    pub active_tool: i32,    // T value
+    pub active_d: i32,       // Active D slot number
+    pub active_h: i32,       // Active H slot number
+    pub spindle_rpm: f64,    // S value (RPM)
+    pub spindle_mode: i32,   // M3/M4/M5
+    pub coolant_on: bool,    // M8/M9
+    pub run_timers: RunTimers, // current/last program run
+    run_usage: RunUsage,
+    pub assertions: AssertionReport, // (ASSERT ...) checks of the current/last run
+    pub feed_override: f64,  // 0.0..2.0 multiplier
+    pub spindle_override: f64, // 0.0..2.0 multiplier
+    pub feed_override_enabled: bool,    // setting: false locks feed override at 100%
+    pub spindle_override_enabled: bool, // setting: false locks spindle override at 100%
+    pub overrides_program_locked: bool, // M49 active (M48 releases)
+    pub single_block: bool,  // stop after each completed block
+    pub dry_geometric: bool, // blocks complete instantly, no axis dynamics
+    pub step_once: bool,     // run one block then hold
+    pub pause_pending: bool, // internal: wait block completion then pause
+    pub arc_center_absolute: bool, // G90.1 absolute IJK / G91.1 incremental IJK
+    pub arc_feed_limit: f64,       // mm/min cap for the current arc (0 = none)
+    pub descent_feed_limit: f64,   // mm/min cap for the current plunge/ramp (0 = none)
+    // --- Threading (G33/G34) ---
+    pub thread_lead: f64,                // mm/rev at the thread start
+    pub thread_lead_change: f64,         // G34 lead change per revolution
+    pub thread_start_angle: f64,         // spindle angle (deg) for multi-start threads
+    pub thread_origin: Vec<(u32, f64)>,  // machine positions where the thread started
+    // --- Retrace (backward run along the executed trace) ---
+    pub retracing: bool,
+    retrace: VecDeque<Vec<(u32, f64)>>,
+    // --- Interlocks ---
+    pub auto_hold: bool, // held by an input rule, resumes when it clears
+    policy_warned: [bool; 2], // cutting policy: spindle / coolant already reported
+    // --- Production counters ---
+    pub part_count: u32,          // resettable cycle counter
+    pub parts_total: u32,         // lifetime counter (not reset with part_count)
+    pub part_target: u32,         // required parts, 0 = no target
+    pub part_count_m_code: i32,   // M code that completes a cycle (default M30)
+    pub parts_target_reached: bool,
+    pub turret: Option<Turret>, // lathe turret; None for spindle/magazine tool changes
+    pub tool_changer: Option<ToolChanger>, // None: M6 swaps the tool at once
+    pub tool_change: Option<ToolChangeEvent>,
+    // T word waiting for M6.
+    pending_tool: Option<i32>,
+    // Tool compensation table, indexed by D/H number.
+    // Slot 0 is treated as the active/default tool.
+    tool_table: HashMap<i32, ToolTableEntry>,
+    // Last compensated linear segment for corner intersection smoothing.
+    comp_linear_prev: Option<CompLinearState>,
+    // True when G41/G42 was armed without an XY move and still needs first-entry transition.
+    comp_entry_pending: bool,
+    // Compensation applied to the current block's motion; a G40 exit move
+    // still runs on the previous side.
+    path_comp: i32,
+    // Synthesized blocks (safe-start preamble) run before the next program block.
+    injected: VecDeque<String>,
+    // Numbered (#1) and named (#<name>) parameters; they outlive program runs.
+    params: HashMap<String, f64>,
+    // Open subroutine calls and repeat loops of O-word flow control.
+    flow: Vec<FlowFrame>,
+    // Work offsets when the program started; fault recovery compares them.
+    offsets_at_start: Vec<WorkOffset>,
+    modal_call: Option<ModalMacroCall>, // G66 until G67
+    // Pending linear targets (expanded arcs) and G4 dwells.
+    pending: VecDeque<PendingEntry>,
+    // Entries the executor queued itself (retrace replay, tool changer), not a
+    // block; the invariant checker lets `pending` grow by these.
+    pending_queued: usize,
+    // Programmed work-coordinate position (uncompensated geometry), per axis.
+    programmed_work: HashMap<u32, f64>,
+    // Execution count per program line (coverage).
+    block_hits: Vec<u32>,
+    // Condition outcomes per branch line ([taken, not taken]) and calls per
+    // subprogram label (coverage).
+    branch_hits: BTreeMap<usize, [u32; 2]>,
+    call_hits: BTreeMap<String, u32>,
+    // N number per program line, for sequence search; empty when streamed.
+    block_numbers: Vec<Option<u32>>,
+    // Comments, operations and tool changes of the loaded program.
+    outline: ProgramOutline,
+    // Executed path recorded during ticks, for live backplot.
+    trace: PathTrace,
+    // Interpreter decisions per executed block; None while tracing is off.
+    block_trace: Option<BlockTraceLog>,
+    // Setup tool tip inside the stock or a fixture (faulted once on entry).
+    setup_crashed: bool,
+}
+
+impl Channel {
+    fn new(id: u32, axis_map: Vec<ChannelAxisMap>) -> Self {
+        Self {
+            id,
+            axis_map,
+            is_running: false,
+            paused: false,
+            program_stop: None,
+            program_end: None,
+            finished: false,
+            pc: 0,
+            active_pc: -1,
+            block_number: None,
+            program: ProgramStore::default(),
+            feed_rate: 1000.0,
+            current_motion: 0,
+            abs_mode: true,
+            units_mm: true,
+            plane: 17,
+            exact_stop: false,
+            path_tolerance: None,
+            comp_tolerance: None,
+            cutter_comp: 40,
+            tool_radius: 4.0,
+            length_comp_active: false,
+            tool_length: 50.0,
+            active_tool: 0,
+            active_d: 0,
+            active_h: 0,
+            spindle_rpm: 0.0,
+            spindle_mode: 5,
+            coolant_on: false,
+            run_timers: RunTimers::default(),
+            run_usage: RunUsage::default(),
+            assertions: AssertionReport::default(),
+            feed_override: 1.0,
+            spindle_override: 1.0,
+            feed_override_enabled: true,
+            spindle_override_enabled: true,
+            overrides_program_locked: false,
+            single_block: false,
+            dry_geometric: false,
+            step_once: false,
+            pause_pending: false,
+            arc_center_absolute: false,
+            arc_feed_limit: 0.0,
+            descent_feed_limit: 0.0,
+            thread_lead: 0.0,
+            thread_lead_change: 0.0,
+            thread_start_angle: 0.0,
+            thread_origin: Vec::new(),
+            retracing: false,
+            auto_hold: false,
+            policy_warned: [false; 2],
+            retrace: VecDeque::new(),
+            turret: None,
+            tool_changer: None,
+            tool_change: None,
+            pending_tool: None,
+            injected: VecDeque::new(),
+            params: HashMap::new(),
+            flow: Vec::new(),
+            offsets_at_start: Vec::new(),
+            modal_call: None,
+            part_count: 0,
+            parts_total: 0,
+            part_target: 0,
+            part_count_m_code: 30,
+            parts_target_reached: false,
+            tool_table: HashMap::from([
+                (0, ToolTableEntry { radius: 4.0, length: 50.0, ..ToolTableEntry::default() }),
+                (1, ToolTableEntry { radius: 4.0, length: 50.0, ..ToolTableEntry::default() }),
+            ]),
+            comp_linear_prev: None,
+            comp_entry_pending: false,
+            path_comp: 40,
+            pending: VecDeque::new(),
+            pending_queued: 0,
+            programmed_work: HashMap::new(),
+            block_hits: Vec::new(),
+            branch_hits: BTreeMap::new(),
+            call_hits: BTreeMap::new(),
+            block_numbers: Vec::new(),
+            outline: ProgramOutline::default(),
+            trace: PathTrace::default(),
+            block_trace: None,
+            setup_crashed: false,
+            diameter_mode: false,
+            mirror: HashMap::new(),
+            rear_turret: false,
+            index_capture: false,
+            block_prep_s: 0.0,
+            spindle_revs: 0.0,
+            thread_cycle: ThreadCycle::default(),
+            rigid_tap: None,
+            canned_cycle: None,
+            cycle_return_r: false,
+            synthesizing: false,
+            spindle_actual_rpm: 0.0,
+            defaults: ChannelDefaults::default(),
+        }
+    }
+
+    fn run_state(&self) -> &'static str {
+        match (self.is_running, self.paused, self.program_stop) {
+            (true, true, Some(0)) => "PROGRAM_STOP",
+            (true, true, Some(_)) => "OPTIONAL_STOP",
+            (true, true, None) => "PAUSED",
+            (true, false, _) => "RUNNING",
+            (false, ..) if self.finished => "FINISHED",
+            (false, ..) => "STOPPED",
+        }
+    }
+
+    // M30: back to the power-on modal state for the next cycle.
+    fn reset_modals_at_end(&mut self) {
+        self.current_motion = 0;
+        self.cutter_comp = 40;
+        self.comp_entry_pending = false;
+        self.comp_linear_prev = None;
+        self.length_comp_active = false;
+        self.overrides_program_locked = false;
+        self.path_tolerance = None;
+        self.comp_tolerance = None;
+        self.mirror.clear();
+        self.modal_call = None;
+        self.canned_cycle = None;
+        self.cycle_return_r = false;
+        self.apply_modal_defaults();
+    }
+
+    /// Returns units, distance mode, plane and diameter programming to the
+    /// channel's configured defaults.
+    fn apply_modal_defaults(&mut self) {
+        self.units_mm = self.defaults.units_mm;
+        self.abs_mode = self.defaults.abs_mode;
+        self.plane = self.defaults.plane;
+        self.diameter_mode = self.defaults.diameter_mode;
+    }
+
+    fn active_tool_direction(&self) -> Option<ToolDirection> {
+        self.tool_table.get(&self.active_tool).and_then(|e| e.direction)
+    }
+
+    fn feed_override_ratio(&self) -> f64 {
+        if self.feed_override_enabled && !self.overrides_program_locked { self.feed_override } else { 1.0 }
+    }
+
+    fn spindle_override_ratio(&self) -> f64 {
+        if self.spindle_override_enabled && !self.overrides_program_locked { self.spindle_override } else { 1.0 }
+    }
+
+    fn spindle_speed(&self) -> f64 {
+        self.spindle_rpm * self.spindle_override_ratio()
+    }
+
+    // Turns the spindle for `dt_sec`; returns the revolutions made.
+    fn advance_spindle(&mut self, dt_sec: f64) -> f64 {
+        if !matches!(self.spindle_mode, 3 | 4) {
+            return 0.0;
+        }
+        let revs = (self.spindle_speed() / 60.0 * dt_sec).max(0.0);
+        self.spindle_revs = (self.spindle_revs + revs).fract();
+        revs
+    }
+}
+
+// Controller dialect: selects defaults and rules that differ between controls.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Dialect {
+    Fanuc,
+    LinuxCnc,
+    Mach3, // legacy "IJ absolute" arc mode by default
+}
+
+impl Dialect {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "fanuc" => Some(Dialect::Fanuc),
+            "linuxcnc" | "linux_cnc" | "emc" if DIALECTS => Some(Dialect::LinuxCnc),
+            "mach3" | "mach" if DIALECTS => Some(Dialect::Mach3),
+            _ => None,
+        }
+    }
+
+    fn arc_center_absolute_default(self) -> bool {
+        matches!(self, Dialect::Mach3)
+    }
+
+    // Seconds per unit of a G4 P word: Fanuc counts milliseconds.
+    fn dwell_p_seconds(self) -> f64 {
+        if matches!(self, Dialect::Fanuc) { 0.001 } else { 1.0 }
+    }
+
+    // Fanuc takes the last code of a repeated modal group; the others reject the block.
+    fn modal_conflict_rejects_block(self) -> bool {
+        !matches!(self, Dialect::Fanuc)
+    }
+}
+
+// Modal groups: at most one code of each group per block.
+const G_MODAL_GROUPS: [(&str, &[i32]); 12] = [
+    ("motion", &[0, 1, 2, 3, 33, 34]),
+    ("plane", &[17, 18, 19]),
+    ("units", &[20, 21]),
+    ("distance", &[90, 91]),
+    ("cutter compensation", &[40, 41, 42]),
+    ("tool length", &[43, 44, 49]),
+    ("work offset", &[54, 55, 56, 57, 58, 59, 153]),
+    ("path mode", &[61, 64]),
+    ("feed mode", &[93, 94, 95]),
+    ("canned cycle", &[74, 76, 84]),
+    ("cycle return", &[98, 99]),
+    ("lathe diameter", &[7, 8]),
+];
+const G_DECIMAL_MODAL_GROUPS: [(&str, &[i32]); 2] = [("arc distance", &[901, 911]), ("mirror image", &[501, 511])]; // tenths
+// Machine bounds for F/S/T/D/H words; 0 leaves a bound unchecked.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct WordLimits {
+    pub spindle_max_rpm: f64,
+    pub magazine_pockets: u32, // valid T: 0 (unload) ..= pockets
+    pub offset_slots: u32,     // valid D/H: 0 ..= slots
+}
+
+const M_MODAL_GROUPS: [(&str, &[i32]); 4] = [
+    ("stopping", &[0, 1, 2, 30]),
+    ("spindle", &[3, 4, 5]),
+    ("coolant", &[7, 8, 9]),
+    ("override", &[48, 49]),
+];
+
+// Allowed difference between start and end radius of an IJK arc.
+const ARC_RADIUS_TOLERANCE_MM: f64 = 0.01;
+const ARC_RADIUS_TOLERANCE_REL: f64 = 0.001;
+
+// Program-quality checks for teaching: cutting moves (G1/G2/G3) with the
+// spindle stopped or coolant off are reported as events, once until the
+// condition clears. Off by default.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct CuttingPolicy {
+    pub spindle_before_feed: bool,
+    pub coolant_before_cut: bool,
+    pub dry_tools: Vec<i32>, // tools allowed to cut without coolant (dry materials, air blast)
+}
+
+// Feed caps for G1 moves that descend along the tool: a straight plunge is
+// held to `plunge_feed`, a ramp (sideways travel while descending) to
+// `ramp_feed`. Ramps steeper than `ramp_angle_deg` are treated as plunges.
+// 0 leaves a limit off.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct DescentLimits {
+    pub plunge_feed: f64,    // mm/min
+    pub ramp_feed: f64,      // mm/min
+    pub ramp_angle_deg: f64, // steepest ramp below horizontal
+}
+
+// Comparison tolerances used by the executor and interpreter. Defaults suit
+// ordinary milling; tighten for micron work, loosen for very large machines.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct ToleranceProfile {
+    pub position_mm: f64,    // axis counts as at target
+    pub crawl_snap_mm: f64,  // remaining distance snapped when the ramp stalls
+    pub angle_rad: f64,      // smallest arc segment angle
+    pub arc_chord_mm: f64,   // chord error when segmenting arcs
+    pub arc_radius_mm: f64,  // IJK start/end radius mismatch, full-circle detection
+    pub arc_radius_rel: f64, // ... plus this fraction of the radius
+    pub comp_join_mm: f64,   // cutter comp: segment start joins the previous end
+}
+
+impl Default for ToleranceProfile {
+    fn default() -> Self {
+        Self {
+            position_mm: 0.0005,
+            crawl_snap_mm: 0.05,
+            angle_rad: 1e-6,
+            arc_chord_mm: 0.005,
+            arc_radius_mm: ARC_RADIUS_TOLERANCE_MM,
+            arc_radius_rel: ARC_RADIUS_TOLERANCE_REL,
+            comp_join_mm: 1e-4,
+        }
+    }
+}
+
+impl ToleranceProfile {
+    fn is_valid(&self) -> bool {
+        [
+            self.position_mm,
+            self.crawl_snap_mm,
+            self.angle_rad,
+            self.arc_chord_mm,
+            self.arc_radius_mm,
+            self.arc_radius_rel,
+            self.comp_join_mm,
+        ]
+        .iter()
+        .all(|v| v.is_finite() && *v > 0.0)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Default)]
+struct ToolTableEntry {
+    radius: f64,
+    length: f64,
+    radius_wear: f64, // added to the geometry values
+    length_wear: f64,
+    direction: Option<ToolDirection>, // aggregate head; None = along the spindle
+    plate_offset: [f64; 2], // gang plate X/Y position, applied while selected
+    flutes: u32, // cutting edges; 0 = unknown, cutting data assumes 2
+    setup: Option<SetupToolKind>, // non-cutting setup tool; radius = effective radius
+}
+
+/// Non-cutting tool used to practice setups. It never removes stock; driving
+/// its tip into the stock or a fixture is a crash.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SetupToolKind {
+    EdgeFinder, // stylus kicks out on touching an edge while the spindle turns
+    Indicator,  // coaxial indicator: the dial shows runout of a Z bore or boss it is over
+}
+
+impl SetupToolKind {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().replace([' ', '-'], "_").as_str() {
+            "edge_finder" | "edgefinder" => Some(SetupToolKind::EdgeFinder),
+            "indicator" | "coaxial_indicator" => Some(SetupToolKind::Indicator),
+            _ => None,
+        }
+    }
+}
+
+const EDGE_FINDER_TRIP_MM: f64 = 0.005; // stylus gap at which it kicks out
+
+/// What the active setup tool of a channel shows at the current position.
+#[derive(Serialize, Clone, Default)]
+pub struct SetupToolReading {
+    pub kind: Option<SetupToolKind>, // None when the active tool cuts
+    pub gap: Option<f64>,            // stylus surface to the nearest stock/fixture, mm
+    pub contact: Option<String>,     // "stock" or the zone name the gap is measured to
+    pub tripped: bool,               // edge finder kicked out
+    pub indicated: Option<String>,   // bore/boss zone the indicator sweeps
+    pub tir: Option<f64>,            // total indicated runout, mm (twice the misalignment)
+}
+
+/// Machine direction a tool points from its gauge point to its tip.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ToolDirection {
+    PlusX,
+    MinusX,
+    PlusY,
+    MinusY,
+    PlusZ,
+    MinusZ,
+}
+
+impl ToolDirection {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_uppercase().as_str() {
+            "+X" | "X" => Some(ToolDirection::PlusX),
+            "-X" => Some(ToolDirection::MinusX),
+            "+Y" | "Y" => Some(ToolDirection::PlusY),
+            "-Y" => Some(ToolDirection::MinusY),
+            "+Z" | "Z" => Some(ToolDirection::PlusZ),
+            "-Z" => Some(ToolDirection::MinusZ),
+            _ => None,
+        }
+    }
+
+    fn vector(self) -> [f64; 3] {
+        match self {
+            ToolDirection::PlusX => [1.0, 0.0, 0.0],
+            ToolDirection::MinusX => [-1.0, 0.0, 0.0],
+            ToolDirection::PlusY => [0.0, 1.0, 0.0],
+            ToolDirection::MinusY => [0.0, -1.0, 0.0],
+            ToolDirection::PlusZ => [0.0, 0.0, 1.0],
+            ToolDirection::MinusZ => [0.0, 0.0, -1.0],
+        }
+    }
+
+    // X/Y/Z indices (a, b) of the cutter-comp plane, ordered so that G41 is
+    // left of travel when looking along the tool (a × b = -direction).
+    fn comp_plane(self) -> (usize, usize) {
+        match self {
+            ToolDirection::MinusZ => (0, 1),
+            ToolDirection::PlusZ => (1, 0),
+            ToolDirection::PlusX => (2, 1),
+            ToolDirection::MinusX => (1, 2),
+            ToolDirection::PlusY => (0, 2),
+            ToolDirection::MinusY => (2, 0),
+        }
+    }
+}
+
+// G76 settings from its first block (P mmrraa Q R): finishing passes m,
+// tool angle aa, minimum cut depth and finishing allowance (mm).
+#[derive(Clone, Copy, Debug)]
+struct ThreadCycle {
+    finish_passes: u32,
+    angle_deg: f64,
+    min_depth: f64,
+    allowance: f64,
+}
+
+impl Default for ThreadCycle {
+    fn default() -> Self {
+        Self { finish_passes: 1, angle_deg: 60.0, min_depth: 0.0, allowance: 0.0 }
+    }
+}
+
+// Active tapping cycle (G84/G74 until G80 or a G0-G3 block): every further
+// X/Y block taps again with these words. `initial_z` (work) is where the
+// cycle was called, the G98 return level.
+#[derive(Clone, Copy, Debug)]
+struct CannedCycle {
+    code: i32,
+    z: Option<f64>,
+    r: Option<f64>,
+    pitch: Option<f64>,
+    initial_z: f64,
+}
+
+// G33.1 rigid tap in progress: the spindle is slaved to the tap axis at the
+// pitch, so it slows, stops and reverses with the axis at the bottom.
+#[derive(Clone, Copy, Debug)]
+struct RigidTap {
+    axis_id: u32,
+    pitch: f64,        // mm/rev
+    restore_mode: i32, // spindle direction (M3/M4) before the tap
+}
+
+// Cut depths of the G76 passes: the first pass `first` deep, later ones
+// `first`·√n (constant chip area, at least `min_depth` more each) up to
+// the allowance, then the allowance and the finishing passes at `height`.
+fn thread_pass_depths(height: f64, first: f64, cycle: &ThreadCycle) -> Vec<f64> {
+    let rough_to = height - cycle.allowance.max(0.0);
+    let mut depths: Vec<f64> = Vec::new();
+    for n in 1..=THREAD_MAX_PASSES {
+        let d = (first * (n as f64).sqrt()).max(depths.last().map_or(0.0, |p| p + cycle.min_depth));
+        if d >= rough_to - 1e-9 {
+            break;
+        }
+        depths.push(d);
+    }
+    if cycle.allowance > 0.0 && rough_to > 0.0 {
+        depths.push(rough_to);
+    }
+    depths.extend(std::iter::repeat_n(height, cycle.finish_passes.max(1) as usize));
+    depths
+}
+
+const THREAD_MAX_PASSES: usize = 200;
+
+/// Lathe turret: stations spaced evenly around the index axis, each holding a
+/// tool with its own X/Z offset. T words index it (T0303 style selects
+/// station 3).
+#[derive(Serialize, Clone, Debug)]
+pub struct Turret {
+    pub stations: u32,
+    pub index_time_s: f64,      // per station stepped
+    pub bidirectional: bool,    // index the shorter way round
+    pub offsets: Vec<[f64; 2]>, // X/Z per station: tool tip to turret reference
+    pub station: u32,           // selected station (1-based)
+    pub angle_deg: f64,         // current angle, moves during an index
+    pub index_remaining_s: f64,
+    #[serde(skip)]
+    index_travel_deg: f64, // signed travel of the index in progress
+    #[serde(skip)]
+    index_total_s: f64,
+}
+
+impl Turret {
+    fn new(stations: u32, index_time_s: f64, bidirectional: bool) -> Self {
+        Self {
+            stations,
+            index_time_s,
+            bidirectional,
+            offsets: vec![[0.0; 2]; stations as usize],
+            station: 1,
+            angle_deg: 0.0,
+            index_remaining_s: 0.0,
+            index_travel_deg: 0.0,
+            index_total_s: 0.0,
+        }
+    }
+
+    fn station_for(t: i32) -> u32 {
+        let t = t.max(0) as u32;
+        if t >= 100 { t / 100 } else { t }
+    }
+
+    fn station_angle(&self, station: u32) -> f64 {
+        360.0 * station.saturating_sub(1) as f64 / self.stations as f64
+    }
+
+    fn offset(&self) -> [f64; 2] {
+        self.offsets.get(self.station as usize - 1).copied().unwrap_or_default()
+    }
+
+    // Starts indexing to `station`, forward unless the turret may take the
+    // shorter way back.
+    fn index_to(&mut self, station: u32) {
+        if station == 0 || station > self.stations || station == self.station {
+            return;
+        }
+        let n = self.stations as i64;
+        let forward = (station as i64 - self.station as i64).rem_euclid(n);
+        let steps = if self.bidirectional && n - forward < forward { forward - n } else { forward };
+        self.station = station;
+        self.index_travel_deg = 360.0 * steps as f64 / n as f64;
+        self.index_total_s = steps.unsigned_abs() as f64 * self.index_time_s;
+        self.index_remaining_s = self.index_total_s;
+        if self.index_remaining_s <= 0.0 {
+            self.angle_deg = self.station_angle(station);
+        }
+    }
+
+    fn advance(&mut self, dt_sec: f64) {
+        self.index_remaining_s = (self.index_remaining_s - dt_sec).max(0.0);
+        let left = if self.index_total_s > 0.0 { self.index_remaining_s / self.index_total_s } else { 0.0 };
+        self.angle_deg = (self.station_angle(self.station) - self.index_travel_deg * left).rem_euclid(360.0);
+    }
+}
+
+/// Automatic tool changer of a machining center. T words only preselect a
+/// tool; M6 travels to the change position (machine coordinates, Z first)
+/// and holds for `change_time_s` while the arm swaps it into the spindle.
+#[derive(Serialize, Clone, PartialEq)]
+pub struct ToolChanger {
+    pub change_time_s: f64,
+    pub position: Vec<AxisOffset>, // machine position per axis; other axes stay put
+}
+
+/// Last (or in-progress) M6 of a channel, for animating the change.
+#[derive(Serialize, Clone)]
+pub struct ToolChangeEvent {
+    pub from_tool: i32,
+    pub to_tool: i32,
+    pub phase: String,        // APPROACH / CHANGING / DONE
+    pub phase_started_s: f64, // sim time the phase began
+    pub change_time_s: f64,
+    pub position: Vec<AxisOffset>,
+}
+
+impl ToolChangeEvent {
+    fn enter(&mut self, phase: &str, now_s: f64) {
+        self.phase = phase.to_string();
+        self.phase_started_s = now_s;
+    }
+}
+
+impl ToolTableEntry {
+    fn total_radius(&self) -> f64 {
+        (self.radius + self.radius_wear).abs()
+    }
+
+    fn total_length(&self) -> f64 {
+        self.length + self.length_wear
+    }
+}
+
+// Queued work the executor finishes before the next block.
+#[derive(Clone, Debug, PartialEq)]
+enum PendingEntry {
+    Move(Vec<(u32, f64)>), // (axis_id, machine_target) per axis
+    Dwell(f64),            // seconds left; counted down by tick
+    ToolChange(i32),       // at the change position: start the changer
+    ToolLoad(i32),         // changer done: the tool is in the spindle
+    TapReverse,            // rigid tap at the bottom: reverse the spindle
+    TapEnd,                // rigid tap back out: restore the spindle direction
+}
+
+impl PendingEntry {
+    fn targets(&self) -> &[(u32, f64)] {
+        match self {
+            PendingEntry::Move(targets) => targets,
+            PendingEntry::Dwell(_)
+            | PendingEntry::TapReverse
+            | PendingEntry::TapEnd
+            | PendingEntry::ToolChange(_)
+            | PendingEntry::ToolLoad(_) => &[],
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CompLinearState {
+    end_prog_x: f64,
+    end_prog_y: f64,
+    end_off_x: f64,
+    end_off_y: f64,
+    dir_x: f64,
+    dir_y: f64,
+    mode: i32,
+    radius: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct AxisOffset {
+    pub axis_id: u32,
+    pub value: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct WorkOffset {
+    pub label: String,
+    pub offsets: Vec<AxisOffset>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum AlarmSeverity {
+    Info,
+    Warning,
+    Fault, // stops the affected channel (or all channels when machine-level)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Alarm {
+    pub id: u32,
+    pub code: String,
+    pub message: String,
+    pub severity: AlarmSeverity,
+    pub channel: Option<u32>,
+    pub active: bool,       // condition still present
+    pub acknowledged: bool, // operator has seen it
+    pub raised_at_s: f64,
+    pub last_raised_at_s: f64,
+    pub acknowledged_at_s: Option<f64>,
+    pub cleared_at_s: Option<f64>,
+    pub occurrences: u32,
+}
+
+// Closed alarms kept for export; oldest are dropped beyond this.
+const ALARM_HISTORY_MAX: usize = 500;
+
+// Informational notices (no condition to clear), drained by the UI.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MachineEvent {
+    pub id: u32,
+    pub code: String,
+    pub message: String,
+    pub channel: Option<u32>,
+    pub at_s: f64,
+    #[serde(default)]
+    pub interruption: Option<InterruptionReport>, // INTERRUPTED events only
+}
+
+/// What a fault (estop or Fault alarm) cut short in one channel.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InterruptedChannel {
+    pub channel: u32,
+    pub running: bool,
+    pub line: Option<usize>, // 1-based block being executed
+    pub block: Option<String>,
+    pub distance_remaining: f64, // mm of queued linear motion left
+    pub tool: i32,
+    pub spindle_on: bool,
+    pub coolant_on: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AxisDigest {
+    pub name: String,
+    pub position: f64,
+    pub homed: bool,
+}
+
+/// Snapshot taken as a fault stops the machine, with recovery hints for a
+/// guided-recovery UI.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InterruptionReport {
+    pub cause: String, // alarm code
+    pub message: String,
+    pub at_s: f64,
+    pub channels: Vec<InterruptedChannel>,
+    pub axes: Vec<AxisDigest>,
+    pub active_wcs: String,
+    pub rehome_required: bool,
+    pub offsets_intact: bool, // work offsets unchanged since the programs started
+    pub recovery: Vec<String>, // suggested steps, in order
+}
+
+const EVENT_LOG_MAX: usize = 200;
+
+#[derive(Serialize)]
+pub struct MachineState {
+    pub axes: Vec<Axis>,
+    pub channels: Vec<ChannelStatus>,
+    pub estop: bool,
+    pub feed_hold: bool,
+    pub block_delete: bool,  // "/" blocks are skipped
+    pub optional_stop: bool, // M01 stops
+    pub active_wcs: usize,
+    pub work_offsets: Vec<WorkOffset>,
+    pub is_homing: bool,
+    pub alarms: Vec<Alarm>,
+    pub dialect: Dialect,
+    pub jogs: Vec<JogState>, // axes moving under a jog command
+    pub local_shift: Vec<AxisOffset>, // G52 shift on top of the active work offset
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum MachineActivity {
+    Running,
+    Paused,
+    Alarm,
+    Idle,
+    Setup,
+}
+
+#[derive(Serialize, Clone)]
+pub struct UtilizationSpan {
+    pub state: MachineActivity,
+    pub start_s: f64,
+    pub end_s: f64,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct UtilizationSummary {
+    pub total_s: f64,
+    pub running_s: f64,
+    pub paused_s: f64,
+    pub alarm_s: f64,
+    pub idle_s: f64,
+    pub setup_s: f64,
+    pub utilization: f64, // running / total, 0..1
+}
+
+// Split timers of one program run. Spindle and coolant time overlap the
+// motion timers. Dwell counts G4 and other timed waits; tool change counts
+// turret indexing and changer swaps.
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+pub struct RunTimers {
+    pub total_s: f64,
+    pub cutting_s: f64,
+    pub rapid_s: f64,
+    pub dwell_s: f64,
+    pub tool_change_s: f64,
+    pub idle_s: f64, // running but not moving: paused, held, waiting
+    pub spindle_on_s: f64,
+    pub coolant_on_s: f64,
+}
+
+// Tolerance of an (ASSERT ...) comment without TOL=.
+const ASSERT_DEFAULT_TOL: f64 = 1e-3;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AssertionFailure {
+    pub line: usize, // 1-based program line
+    pub key: String, // axis label or F/S/T/D/H
+    pub expected: Option<f64>,
+    pub actual: Option<f64>,
+    pub tolerance: f64,
+    pub message: String,
+}
+
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct AssertionReport {
+    pub checked: u32,
+    pub failures: Vec<AssertionFailure>,
+}
+
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct ToolUsage {
+    pub tool: i32,
+    pub cutting_s: f64,
+    pub rapid_s: f64,
+    pub cut_length: f64,
+    pub rapid_length: f64,
+}
+
+fn tool_usage(tools: &mut Vec<ToolUsage>, tool: i32) -> &mut ToolUsage {
+    let idx = match tools.iter().position(|t| t.tool == tool) {
+        Some(idx) => idx,
+        None => {
+            tools.push(ToolUsage { tool, ..ToolUsage::default() });
+            tools.len() - 1
+        }
+    };
+    &mut tools[idx]
+}
+
+// Per-run usage gathered alongside `RunTimers` for the job report.
+#[derive(Clone, Default)]
+struct RunUsage {
+    started_at_s: f64,
+    tools: Vec<ToolUsage>,
+    max_cut_feed: f64,
+    max_rapid_feed: f64,
+    stock_box: Option<StockBox>,
+    stock: Option<StockGrid>, // built on the first cut into `stock_box`
+}
+
+/// Structured summary of a program run (live) or verification (offline).
+#[derive(Serialize, Clone)]
+pub struct JobReport {
+    pub source: String, // "run" / "verification"
+    pub channel_id: u32,
+    pub program_lines: usize,
+    pub lines_executed: usize,
+    pub running: bool,
+    pub completed: bool,
+    pub part_count: u32,
+    pub timers: RunTimers,
+    pub tools: Vec<ToolUsage>, // in order of first use
+    pub cut_length: f64,
+    pub rapid_length: f64,
+    pub max_cut_feed: f64,   // mm/min, actual
+    pub max_rapid_feed: f64, // mm/min, actual
+    pub alarms: Vec<Alarm>,
+    pub travel_exceeded: Vec<EnvelopeExcess>, // axes the program drives past their travel (verification)
+    pub stock_removed_mm3: Option<f64>, // needs a stock box
+    pub generated_at_s: f64,            // simulation time
+}
+
+// ── Setup sheet ──────────────────────────────────────────────────────────
+
+const SETUP_SAMPLE_STEP: f64 = 1.0; // mm between clearance samples along a move
+
+/// A work offset used by the program: its origin in machine coordinates and
+/// the tool tip extents in its own work coordinates.
+#[derive(Serialize, Clone)]
+pub struct SetupWcs {
+    pub index: usize,
+    pub label: String,
+    pub origin: Vec<AxisOffset>,
+    pub min: Vec<AxisOffset>,
+    pub max: Vec<AxisOffset>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct SetupTool {
+    pub tool: i32,
+    pub first_line: usize, // 1-based
+    pub radius: f64,
+    pub length: f64,
+    pub max_depth: Option<f64>, // deepest cut below work Z0; None without cutting moves
+}
+
+/// Closest approach of the tool to a protected zone, when under the
+/// requested clearance.
+#[derive(Serialize, Clone)]
+pub struct ClampWarning {
+    pub zone_id: u32,
+    pub zone_name: String,
+    pub clearance: f64, // tool surface to zone; negative when it cuts into the zone
+    pub line: usize,    // 1-based
+    pub at: [f64; 3],   // machine X/Y/Z of the closest sample
+}
+
+/// Printable setup data for a program, from an offline run.
+#[derive(Serialize, Clone)]
+pub struct SetupSheet {
+    pub channel_id: u32,
+    pub completed: bool,
+    pub wcs: Vec<SetupWcs>, // in order of first use
+    pub tools: Vec<SetupTool>, // in order of first use
+    pub min_clearance: f64,
+    pub clamp_warnings: Vec<ClampWarning>,
+    pub alarms: Vec<Alarm>,
+    pub generated_at_s: f64, // simulation time
+}
+
+// Raw stock as a box in the channel's X/Y/Z axis coordinates (the toolpath's
+// coordinates); the tool tip is at the Z position.
+#[derive(Clone, Copy, Debug)]
+struct StockBox {
+    min: [f64; 3],
+    max: [f64; 3],
+}
+
+const STOCK_GRID_CELLS: f64 = 200.0; // along the longer XY side
+
+// Height map of the stock top, lowered by flat-end cuts.
+#[derive(Clone)]
+struct StockGrid {
+    stock: StockBox,
+    cell: f64,
+    nx: usize,
+    ny: usize,
+    heights: Vec<f64>,
+}
+
+impl StockGrid {
+    fn new(stock: StockBox) -> Self {
+        let (dx, dy) = (stock.max[0] - stock.min[0], stock.max[1] - stock.min[1]);
+        let cell = (dx.max(dy) / STOCK_GRID_CELLS).max(1e-3);
+        let nx = ((dx / cell).ceil() as usize).max(1);
+        let ny = ((dy / cell).ceil() as usize).max(1);
+        StockGrid { stock, cell, nx, ny, heights: vec![stock.max[2]; nx * ny] }
+    }
+
+    fn cut_segment(&mut self, from: [f64; 3], to: [f64; 3], radius: f64) {
+        let mut heights = std::mem::take(&mut self.heights);
+        self.cut_rows(&mut heights, 0..self.ny, from, to, radius);
+        self.heights = heights;
+    }
+
+    // Cuts a segment into `heights`, the cells of rows `rows` only.
+    fn cut_rows(&self, heights: &mut [f64], rows: std::ops::Range<usize>, from: [f64; 3], to: [f64; 3], radius: f64) {
+        if from[2].min(to[2]) >= self.stock.max[2] {
+            return;
+        }
+        let reach = radius.max(self.cell * 0.5);
+        let lo = self.stock.min[1] + rows.start as f64 * self.cell - reach;
+        let hi = self.stock.min[1] + rows.end as f64 * self.cell + reach;
+        if from[1].max(to[1]) < lo || from[1].min(to[1]) > hi {
+            return;
+        }
+        let len = ((to[0] - from[0]).powi(2) + (to[1] - from[1]).powi(2) + (to[2] - from[2]).powi(2)).sqrt();
+        let steps = ((len / (self.cell * 0.5)).ceil() as usize).max(1);
+        let first = rows.start * self.nx;
+        for i in 0..=steps {
+            let t = i as f64 / steps as f64;
+            let p = [0, 1, 2].map(|k| from[k] + (to[k] - from[k]) * t);
+            let z = p[2].max(self.stock.min[2]);
+            for (idx, _, _) in self.cells_near(p[0], p[1], radius) {
+                if rows.contains(&(idx / self.nx)) {
+                    let h = &mut heights[idx - first];
+                    *h = h.min(z);
+                }
+            }
+        }
+    }
+
+    // Cuts a whole path of (from, to, radius) segments. Cells only ever drop
+    // to their lowest cut, so order does not matter: with the `parallel`
+    // feature, bands of rows are cut on separate threads.
+    fn cut_path(&mut self, segments: &[([f64; 3], [f64; 3], f64)]) {
+        #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+        {
+            use rayon::prelude::*;
+            let band_rows = self.ny.div_ceil(rayon::current_num_threads() * 4).max(1);
+            let mut heights = std::mem::take(&mut self.heights);
+            let grid = &*self;
+            heights.par_chunks_mut(band_rows * grid.nx).enumerate().for_each(|(band, chunk)| {
+                let rows = band * band_rows..((band + 1) * band_rows).min(grid.ny);
+                for &(from, to, radius) in segments {
+                    grid.cut_rows(chunk, rows.clone(), from, to, radius);
+                }
+            });
+            self.heights = heights;
+        }
+        #[cfg(not(all(feature = "parallel", not(target_arch = "wasm32"))))]
+        for &(from, to, radius) in segments {
+            self.cut_segment(from, to, radius);
+        }
+    }
+
+    // Cells whose centre lies within `radius` (at least half a cell) of
+    // (x, y), as (index, dx, dy) from that point.
+    fn cells_near(&self, x: f64, y: f64, radius: f64) -> impl Iterator<Item = (usize, f64, f64)> + '_ {
+        let reach = radius.max(self.cell * 0.5);
+        let cell = self.cell;
+        let range = move |lo: f64, hi: f64, n: usize| {
+            let a = ((lo / cell).floor().max(0.0) as usize).min(n);
+            let b = ((hi / cell).ceil().max(0.0) as usize).min(n);
+            a..b
+        };
+        let (ox, oy) = (x - self.stock.min[0], y - self.stock.min[1]);
+        let cols = range(ox - reach, ox + reach, self.nx);
+        range(oy - reach, oy + reach, self.ny)
+            .flat_map(move |j| cols.clone().map(move |i| (i, j)))
+            .filter_map(move |(i, j)| {
+                let dx = (i as f64 + 0.5) * cell - ox;
+                let dy = (j as f64 + 0.5) * cell - oy;
+                (dx * dx + dy * dy <= reach * reach).then_some((j * self.nx + i, dx, dy))
+            })
+    }
+
+    // Highest stock point under a tool of `radius` centred at (x, y); None
+    // when the tool is clear of the stock.
+    fn top_under(&self, x: f64, y: f64, radius: f64) -> Option<f64> {
+        self.cells_near(x, y, radius).map(|(idx, _, _)| self.heights[idx]).reduce(f64::max)
+    }
+
+    fn height_at(&self, x: f64, y: f64) -> Option<f64> {
+        let (ox, oy) = (x - self.stock.min[0], y - self.stock.min[1]);
+        if ox < 0.0 || oy < 0.0 {
+            return None;
+        }
+        let (i, j) = ((ox / self.cell) as usize, (oy / self.cell) as usize);
+        (i < self.nx && j < self.ny).then(|| self.heights[j * self.nx + i])
+    }
+
+    // Material met by a tool at `p` moving along `dir` (XY unit vector, zero
+    // for a plunge), probed on the leading half of the cutter's edge: radial
+    // width (ae) between the outermost probes in material and axial depth
+    // (ap) of the highest one. None while cutting air. Probe the grid as it
+    // was before the move: the leading edge of a straight move only meets
+    // material earlier positions of the same move have not reached.
+    fn engagement(&self, p: [f64; 3], dir: [f64; 2], radius: f64) -> Option<(f64, f64)> {
+        const PROBES: usize = 90;
+        let plunge = dir == [0.0, 0.0];
+        let (dir, sweep) = if plunge { ([1.0, 0.0], 2.0) } else { (dir, 1.0) };
+        let (mut lo, mut hi, mut depth) = (f64::INFINITY, f64::NEG_INFINITY, 0.0f64);
+        for k in 0..=PROBES {
+            let angle = std::f64::consts::PI * sweep * (k as f64 / PROBES as f64 - 0.5);
+            let (along, across) = (radius * angle.cos(), radius * angle.sin()); // left of travel is positive
+            let q = [p[0] + along * dir[0] - across * dir[1], p[1] + along * dir[1] + across * dir[0]];
+            let Some(above) = self.height_at(q[0], q[1]).map(|h| h - p[2]).filter(|a| *a > 1e-9) else { continue };
+            lo = lo.min(across);
+            hi = hi.max(across);
+            depth = depth.max(above);
+        }
+        if depth <= 0.0 {
+            return None;
+        }
+        let width = if plunge { 2.0 * radius } else { hi - lo };
+        Some((width, depth))
+    }
+
+    fn removed_volume(&self) -> f64 {
+        let area = self.cell * self.cell;
+        self.heights.iter().map(|h| (self.stock.max[2] - h) * area).sum()
+    }
+}
+
+/// Time-weighted distribution of one engagement measure.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct DistributionStats {
+    pub min: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub max: f64,
+}
+
+impl DistributionStats {
+    // `samples` are (value, weight); sorted in place.
+    fn from_weighted(samples: &mut [(f64, f64)]) -> Self {
+        let total: f64 = samples.iter().map(|s| s.1).sum();
+        if samples.is_empty() || total <= 0.0 {
+            return DistributionStats::default();
+        }
+        samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let quantile = |q: f64| {
+            let mut acc = 0.0;
+            for (value, weight) in samples.iter() {
+                acc += weight;
+                if acc >= q * total {
+                    return *value;
+                }
+            }
+            samples[samples.len() - 1].0
+        };
+        DistributionStats {
+            min: samples[0].0,
+            mean: samples.iter().map(|(v, w)| v * w).sum::<f64>() / total,
+            p50: quantile(0.5),
+            p90: quantile(0.9),
+            max: samples[samples.len() - 1].0,
+        }
+    }
+}
+
+/// Tool engagement of one operation and tool, from the stock height map:
+/// radial width of cut (ae, mm), engagement angle (deg) and axial depth
+/// (ap, mm), weighted by cutting time while the tool is in material.
+#[derive(Serialize, Clone, Debug)]
+pub struct OperationEngagement {
+    pub operation: Option<usize>,
+    pub operation_name: Option<String>,
+    pub tool: i32,
+    pub cutting_s: f64, // feed-move time, including air cutting
+    pub engaged_s: f64, // feed-move time in material
+    pub radial_width: DistributionStats,
+    pub engagement_deg: DistributionStats,
+    pub axial_depth: DistributionStats,
+}
+
+// X/Y/Z of a channel from per-axis positions; missing X/Y read as 0.
+fn channel_xyz(axis_map: &[ChannelAxisMap], position_of: impl Fn(u32) -> Option<f64>) -> Option<[f64; 3]> {
+    let mut xyz = [None; 3];
+    for m in axis_map {
+        let k = match m.display_label.trim().to_uppercase().as_str() {
+            "X" => 0,
+            "Y" => 1,
+            "Z" => 2,
+            _ => continue,
+        };
+        xyz[k] = position_of(m.axis_id);
+    }
+    Some([xyz[0].unwrap_or(0.0), xyz[1].unwrap_or(0.0), xyz[2]?])
+}
+
+/// Axis positions latched by a capture trigger: "input:<name>" on a rising
+/// edge (probe trip), "index" once per spindle revolution, or the label given
+/// to `capture_positions`.
+#[derive(Serialize, Clone)]
+pub struct PositionCapture {
+    pub source: String,
+    pub channel: Option<u32>, // spindle channel of an index capture
+    pub timestamp_s: f64,     // simulation time, interpolated within the tick
+    pub positions: Vec<AxisOffset>, // machine coordinates of every axis
+}
+
+// Oldest captures are dropped beyond this.
+const CAPTURE_LOG_MAX: usize = 1000;
+
+// Feed hold while an input is at `hold_level` ("door_open" follows the door
+// switch). `channel: None` holds every channel.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HoldRule {
+    pub input: String,
+    pub hold_level: bool,
+    #[serde(default)]
+    pub channel: Option<u32>,
+}
+
+// MTConnect-style data items. Enumerated values use MTConnect spelling so
+// adapters can forward them unchanged.
+#[derive(Serialize, Clone)]
+pub struct ObservabilitySnapshot {
+    pub availability: String,   // AVAILABLE / UNAVAILABLE
+    pub emergency_stop: String, // ARMED / TRIGGERED
+    pub timestamp_s: f64,       // simulation time
+    pub paths: Vec<PathDataItems>,
+    pub axes: Vec<AxisDataItems>,
+    pub conditions: Vec<ConditionDataItem>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PathDataItems {
+    pub path_id: u32,
+    pub execution: String,       // READY / ACTIVE / FEED_HOLD / INTERRUPTED / STOPPED / PROGRAM_STOPPED / OPTIONAL_STOP / PROGRAM_COMPLETED
+    pub controller_mode: String, // AUTOMATIC / SEMI_AUTOMATIC / MANUAL
+    pub line: i32,
+    pub block: String,
+    pub path_feedrate: f64,           // actual, mm/s
+    pub path_feedrate_commanded: f64, // programmed, mm/s
+    pub path_feedrate_override: f64,  // percent
+    pub rotary_velocity: f64,         // spindle RPM
+    pub spindle_direction: String,    // CLOCKWISE / COUNTER_CLOCKWISE / STOPPED
+    pub tool_number: i32,
+    pub part_count: u32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct AxisDataItems {
+    pub name: String,
+    pub axis_type: String, // LINEAR / ROTARY
+    pub units: String,     // mm / deg
+    pub position: f64,     // actual machine position
+    pub position_commanded: f64,
+    pub axis_feedrate: f64, // mm/min or deg/min
+    pub load: f64,          // percent
+    pub homed: bool,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ConditionDataItem {
+    pub level: String, // NORMAL / WARNING / FAULT
+    pub condition_type: String,
+    pub native_code: String,
+    pub message: String,
+    pub path_id: Option<u32>,
+}
+
+// Oldest spans are dropped beyond this; the summary keeps full totals.
+const UTILIZATION_MAX_SPANS: usize = 10_000;
+// Upper bound for the simulated block-processing time.
+const BLOCK_TIME_MAX_MS: f64 = 100.0;
+
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct MachineBrain {
+    axes: Vec<Axis>,
+    channels: Vec<Channel>,
+    estop: bool,
+    feed_hold: bool,
+    block_delete: bool,
+    optional_stop: bool,
+    work_offsets: Vec<WorkOffset>,
+    active_wcs: usize,
+    local_shift: HashMap<u32, f64>, // G52, per axis; empty when cancelled
+    is_homing: bool,
+    homing_sequence: Vec<u32>,
+    homing_index: usize,
+    homing_feed: f64,
+    homing_rapid: bool,
+    jog: JogSelector,
+    jogs: Vec<JogState>,
+    jog_direction: HashMap<u32, f64>, // sign of each axis's last jog: the side a touch-off contacts
+    manual_feed: f64, // mm/min (deg/min for rotary)
+    sim_time_s: f64,  // simulation clock: sum of scaled tick time, timestamps everything
+    sim_speed: f64,   // tick dt multiplier
+    block_time_s: f64, // controller block-processing time per program block
+    utilization_spans: VecDeque<UtilizationSpan>,
+    utilization_totals: UtilizationSummary,
+    // Open alarms (active and/or unacknowledged) and closed alarm history.
+    alarms: Vec<Alarm>,
+    alarm_history: VecDeque<Alarm>,
+    next_alarm_id: u32,
+    // Per-channel bookkeeping for invariant checks (see `invariant_violations`).
+    invariant_track: Vec<InvariantTrack>,
+    dialect: Dialect,
+    program_memory_budget: usize, // bytes of program text held per channel, 0 = unlimited
+    trace_tolerance: f64,         // chord tolerance (mm) of the live trace
+    trace_tool_colors: HashMap<i32, String>,
+    spindle_direction: String, // tool direction in machine coordinates, e.g. "-Z"
+    spindle_offset: [f64; 3],  // spindle nose relative to the head chain (mm)
+    axis_calibration: HashMap<u32, AxisCalibration>, // rotary axis errors, ideal when absent
+    protected_zones: Vec<ProtectedZone>,
+    wcs_limits: Vec<WcsTravelLimit>,
+    events: VecDeque<MachineEvent>,
+    next_event_id: u32,
+    last_interruption: Option<InterruptionReport>,
+    macro_packages: Vec<MacroPackage>,
+    persistent_params: BTreeMap<u32, f64>, // #500..#999, mirrored into every channel
+    door_open: bool,
+    inputs: HashMap<String, bool>,
+    hold_rules: Vec<HoldRule>,
+    capture_inputs: Vec<String>,
+    captures: VecDeque<PositionCapture>,
+    word_limits: WordLimits,
+    tolerances: ToleranceProfile,
+    cutting_policy: CuttingPolicy,
+    descent_limits: DescentLimits,
+    workpiece_material: Option<WorkMaterial>,
+    undo_stack: Vec<EditRecord>,
+    redo_stack: Vec<EditRecord>,
+    audit_log: VecDeque<AuditEntry>,
+    next_audit_seq: u32,
+    edit_tag: String,
+    edit_depth: u32,
+    access_level: AccessLevel,
+    access_keys: HashMap<AccessLevel, String>,
+    permission_levels: HashMap<Permission, AccessLevel>,
+    next_axis_id: u32, // axis ids are never reused
+    stock: Option<StockBox>,
+    block_hook: Option<Rc<dyn BlockHook>>,
+    telemetry: Telemetry,
+    logger: Logger,
+}
+
+fn default_work_offsets() -> Vec<WorkOffset> {
+    vec![
+        WorkOffset { label: "G54".to_string(), offsets: Vec::new() },
+        WorkOffset { label: "G55".to_string(), offsets: Vec::new() },
+        WorkOffset { label: "G56".to_string(), offsets: Vec::new() },
+        WorkOffset { label: "G57".to_string(), offsets: Vec::new() },
+        WorkOffset { label: "G58".to_string(), offsets: Vec::new() },
+        WorkOffset { label: "G59".to_string(), offsets: Vec::new() },
+        WorkOffset { label: "G153".to_string(), offsets: Vec::new() },
+    ]
+}
+
+// Axes are looked up by their stable id, not by position in `axes`: ids survive
+// removal and reordering.
+fn find_axis(axes: &[Axis], id: u32) -> Option<&Axis> {
+    axes.iter().find(|ax| ax.id == id)
+}
+
+fn find_axis_mut(axes: &mut [Axis], id: u32) -> Option<&mut Axis> {
+    axes.iter_mut().find(|ax| ax.id == id)
+}
+
+fn normalize_rotary_target(value: f64) -> f64 {
+    let mut wrapped = value % 360.0;
+    if wrapped > 180.0 {
+        wrapped -= 360.0;
+    } else if wrapped <= -180.0 {
+        wrapped += 360.0;
+    }
+    wrapped
+}
+
+// Axis load meters have no force model: load follows speed and acceleration
+// demand against the drive's rapid and acceleration limits. Feed and load are
+// smoothed like a real meter so tick-to-tick velocity ripple does not show.
+const AXIS_LOAD_SPEED_SHARE: f64 = 25.0;
+const AXIS_LOAD_ACCEL_SHARE: f64 = 75.0;
+const AXIS_METER_SMOOTHING_S: f64 = 0.1;
+
+fn update_axis_meters(ax: &mut Axis, prev_position: f64, dt_sec: f64) {
+    let mut moved = ax.position - prev_position;
+    if ax.axis_type == AxisType::Rotary && ax.rotary_mode == RotaryMode::Continuous {
+        moved = normalize_rotary_target(moved);
+    }
+    let alpha = (dt_sec / AXIS_METER_SMOOTHING_S).min(1.0);
+    let prev_feed = ax.feed;
+    ax.feed += (moved.abs() / dt_sec * 60.0 - ax.feed) * alpha;
+    if ax.feed < 1e-6 {
+        ax.feed = 0.0;
+    }
+    let speed = (ax.feed / axis_rapid_feed(ax)).min(1.0);
+    let accel = ((ax.feed - prev_feed).abs() / dt_sec / ax.accel.max(1.0)).min(1.0);
+    let demand = AXIS_LOAD_SPEED_SHARE * speed + AXIS_LOAD_ACCEL_SHARE * accel;
+    ax.load += (demand - ax.load) * alpha;
+}
+
+// Commanded target after the axis' travel rules.
+fn limit_axis_target(ax: &Axis, target: f64) -> f64 {
+    match (ax.axis_type, ax.rotary_mode) {
+        (AxisType::Rotary, RotaryMode::Continuous) => normalize_rotary_target(target),
+        _ => target.clamp(ax.min_range, ax.max_range),
+    }
+}
+
+const RAPID_LINEAR_MIN_MM_MIN: f64 = 50_000.0; // 50 m/min
+const RAPID_LINEAR_MAX_MM_MIN: f64 = 80_000.0; // 80 m/min
+const RAPID_ROTARY_MIN_DEG_MIN: f64 = 6_000.0;
+const RAPID_ROTARY_MAX_DEG_MIN: f64 = 30_000.0;
+
+// Pendant jog speed: a table of rates in percent of each axis's rapid, the
+// selected entry, and the rapid key that jogs at full rapid instead.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct JogSelector {
+    pub feed_table: Vec<f64>, // percent of rapid, ascending
+    pub selected: usize,
+    pub rapid: bool,
+}
+
+impl Default for JogSelector {
+    fn default() -> Self {
+        JogSelector { feed_table: vec![1.0, 5.0, 25.0, 100.0], selected: 0, rapid: false }
+    }
+}
+
+impl JogSelector {
+    // Jog rate of `ax` in mm/min (deg/min for rotary).
+    fn feed_for(&self, ax: &Axis) -> f64 {
+        let percent = if self.rapid { 100.0 } else { self.feed_table.get(self.selected).copied().unwrap_or(100.0) };
+        axis_rapid_feed(ax) * percent / 100.0
+    }
+}
+
+// An axis travelling to a jog or API positioning target at its own rate,
+// independent of the channel's programmed feed and motion mode. Dropped on
+// arrival.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct JogState {
+    pub axis_id: u32,
+    pub feed: f64, // mm/min (deg/min for rotary)
+    pub rapid: bool,
+}
+
+const DEFAULT_MANUAL_FEED_MM_MIN: f64 = 1000.0; // move_to without a feed
+
+fn axis_rapid_feed(ax: &Axis) -> f64 {
+    match ax.axis_type {
+        AxisType::Linear => (ax.accel.max(1.0) * 30.0).clamp(RAPID_LINEAR_MIN_MM_MIN, RAPID_LINEAR_MAX_MM_MIN),
+        AxisType::Rotary => (ax.accel.max(1.0) * 20.0).clamp(RAPID_ROTARY_MIN_DEG_MIN, RAPID_ROTARY_MAX_DEG_MIN),
+    }
+}
+
+
+#[allow(clippy::too_many_arguments)]
+fn arc_center_matches(
+    sx: f64,
+    sy: f64,
+    ex: f64,
+    ey: f64,
+    cx: f64,
+    cy: f64,
+    cw: bool,
+    want_large: bool,
+) -> bool {
+    let a0 = (sy - cy).atan2(sx - cx);
+    let a1 = (ey - cy).atan2(ex - cx);
+    let mut da = a1 - a0;
+    if cw {
+        if da >= 0.0 { da -= std::f64::consts::TAU; }
+    } else {
+        if da <= 0.0 { da += std::f64::consts::TAU; }
+    }
+    let sweep = da.abs();
+    if want_large {
+        sweep >= std::f64::consts::PI - 1e-9
+    } else {
+        sweep <= std::f64::consts::PI + 1e-9
+    }
+}
+
+fn build_short_arc_points(
+    cx: f64,
+    cy: f64,
+    from: (f64, f64),
+    to: (f64, f64),
+    radius: f64,
+) -> Vec<(f64, f64)> {
+    if radius <= 1e-9 {
+        return vec![to];
+    }
+    let a0 = (from.1 - cy).atan2(from.0 - cx);
+    let a1 = (to.1 - cy).atan2(to.0 - cx);
+    let mut da = a1 - a0;
+    while da <= -std::f64::consts::PI {
+        da += std::f64::consts::TAU;
+    }
+    while da > std::f64::consts::PI {
+        da -= std::f64::consts::TAU;
+    }
+    let sweep = da.abs();
+    if sweep <= 1e-6 {
+        return vec![to];
+    }
+    let n = ((radius * sweep) / 1.2).ceil().clamp(4.0, 48.0) as usize;
+    let mut out = Vec::with_capacity(n);
+    for k in 1..=n {
+        let t = k as f64 / n as f64;
+        let a = a0 + da * t;
+        out.push((cx + radius * a.cos(), cy + radius * a.sin()));
+    }
+    out
+}
+
+fn line_intersection_2d(
+    p1: (f64, f64),
+    d1: (f64, f64),
+    p2: (f64, f64),
+    d2: (f64, f64),
+) -> Option<(f64, f64)> {
+    let cross = d1.0 * d2.1 - d1.1 * d2.0;
+    if cross.abs() <= 1e-9 {
+        return None;
+    }
+    let qmp = (p2.0 - p1.0, p2.1 - p1.1);
+    let t = (qmp.0 * d2.1 - qmp.1 * d2.0) / cross;
+    Some((p1.0 + t * d1.0, p1.1 + t * d1.1))
+}
+
+// ── Protected zones ────────────────────────────────────────────────────────
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ZoneShape {
+    Box { min: [f64; 3], max: [f64; 3] },
+    // Cylinder along machine axis `axis` (0/1/2 = X/Y/Z); `center` holds the
+    // other two coordinates in X, Y, Z order; `min`/`max` bound the axis.
+    Cylinder { axis: usize, center: [f64; 2], radius: f64, min: f64, max: f64 },
+}
+
+/// Prohibited region in machine coordinates (chuck guard, tailstock, fixture).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProtectedZone {
+    #[serde(default)]
+    pub id: u32,
+    pub name: String,
+    pub shape: ZoneShape,
+    #[serde(default)]
+    pub tool_length_aware: bool, // check the tool tip instead of the spindle gauge point
+}
+
+// Clips the parameter interval [t0, t1] of p + t·d to lo <= coordinate <= hi.
+fn clip_slab(p: f64, d: f64, lo: f64, hi: f64, t0: &mut f64, t1: &mut f64) -> bool {
+    if d.abs() < 1e-12 {
+        return p >= lo && p <= hi;
+    }
+    let (a, b) = ((lo - p) / d, (hi - p) / d);
+    *t0 = t0.max(a.min(b));
+    *t1 = t1.min(a.max(b));
+    t0 <= t1
+}
+
+impl ZoneShape {
+    // Distance from `p` to the shape; 0 inside.
+    fn distance(&self, p: [f64; 3]) -> f64 {
+        let outside = |v: f64, lo: f64, hi: f64| (lo - v).max(v - hi).max(0.0);
+        match self {
+            ZoneShape::Box { min, max } => (0..3).map(|k| outside(p[k], min[k], max[k]).powi(2)).sum::<f64>().sqrt(),
+            ZoneShape::Cylinder { axis, center, radius, min, max } => {
+                let ax = (*axis).min(2);
+                let (u, v) = match ax {
+                    0 => (1, 2),
+                    1 => (0, 2),
+                    _ => (0, 1),
+                };
+                let radial = ((p[u] - center[0]).hypot(p[v] - center[1]) - radius).max(0.0);
+                radial.hypot(outside(p[ax], *min, *max))
+            }
+        }
+    }
+
+    // First parameter t in [0, 1] where segment a→b is inside the shape.
+    fn segment_entry(&self, a: [f64; 3], b: [f64; 3]) -> Option<f64> {
+        let d = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+        let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+        match self {
+            ZoneShape::Box { min, max } => {
+                for k in 0..3 {
+                    if !clip_slab(a[k], d[k], min[k], max[k], &mut t0, &mut t1) {
+                        return None;
+                    }
+                }
+            }
+            ZoneShape::Cylinder { axis, center, radius, min, max } => {
+                let ax = (*axis).min(2);
+                if !clip_slab(a[ax], d[ax], *min, *max, &mut t0, &mut t1) {
+                    return None;
+                }
+                let (u, v) = match ax {
+                    0 => (1, 2),
+                    1 => (0, 2),
+                    _ => (0, 1),
+                };
+                let (pu, pv) = (a[u] - center[0], a[v] - center[1]);
+                let qa = d[u] * d[u] + d[v] * d[v];
+                let qb = 2.0 * (pu * d[u] + pv * d[v]);
+                let qc = pu * pu + pv * pv - radius * radius;
+                if qa < 1e-18 {
+                    if qc > 0.0 {
+                        return None;
+                    }
+                } else {
+                    let disc = qb * qb - 4.0 * qa * qc;
+                    if disc < 0.0 {
+                        return None;
+                    }
+                    let s = disc.sqrt();
+                    t0 = t0.max((-qb - s) / (2.0 * qa));
+                    t1 = t1.min((-qb + s) / (2.0 * qa));
+                    if t0 > t1 {
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(t0)
+    }
+}
+
+// ── WCS travel limits ──────────────────────────────────────────────────────
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WcsTravelLimit {
+    pub wcs: usize,
+    pub axis_id: u32,
+    pub min: Option<f64>, // work coordinates of `wcs`
+    pub max: Option<f64>,
+}
+
+fn wcs_name(idx: usize) -> String {
+    if idx < 6 { format!("G{}", 54 + idx) } else { format!("WCS{}", idx + 1) }
+}
+
+// ── Retrace ────────────────────────────────────────────────────────────────
+
+// Motion modes whose reverse is physically meaningless: spindle-synchronized
+// moves would cross the cut thread. Rigid taps (G33.1) and tapping cycles
+// (G74/G84) run their feeds as G33 moves, so they are covered too.
+fn non_reversible_motion(motion: i32) -> Option<&'static str> {
+    match motion {
+        33 => Some("spindle-synchronized threading (G33)"),
+        34 => Some("variable-lead threading (G34)"),
+        _ => None,
+    }
+}
+
+// ── Cycle start ────────────────────────────────────────────────────────────
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct StartInhibit {
+    pub code: String,    // ESTOP, ALARM, NOT_HOMED, HOMING, DOOR_OPEN, NO_PROGRAM, ...
+    pub message: String, // operator-facing reason
+}
+
+fn start_inhibit(code: &str, message: String) -> StartInhibit {
+    StartInhibit { code: code.to_string(), message }
+}
+
+// ── Access levels ──────────────────────────────────────────────────────────
+
+// Key-switch positions, lowest first.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum AccessLevel {
+    Operator,
+    Setter,
+    Admin,
+}
+
+impl AccessLevel {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "operator" => Some(AccessLevel::Operator),
+            "setter" => Some(AccessLevel::Setter),
+            "admin" => Some(AccessLevel::Admin),
+            _ => None,
+        }
+    }
+}
+
+// Groups of protected edits, each requiring a minimum access level.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Permission {
+    Offsets,
+    ToolGeometry,
+    ToolWear,
+    Settings,
+    Programs,
+    Setup, // protected zones and WCS travel limits
+}
+
+impl Permission {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "offsets" => Some(Permission::Offsets),
+            "tool_geometry" => Some(Permission::ToolGeometry),
+            "tool_wear" => Some(Permission::ToolWear),
+            "settings" => Some(Permission::Settings),
+            "programs" => Some(Permission::Programs),
+            "setup" => Some(Permission::Setup),
+            _ => None,
+        }
+    }
+
+    fn default_level(self) -> AccessLevel {
+        match self {
+            Permission::ToolWear => AccessLevel::Operator,
+            Permission::Offsets | Permission::ToolGeometry | Permission::Programs | Permission::Setup => AccessLevel::Setter,
+            Permission::Settings => AccessLevel::Admin,
+        }
+    }
+}
+
+// ── Edit history ───────────────────────────────────────────────────────────
+
+// Offsets, tool data and settings covered by undo/redo.
+#[derive(Clone, PartialEq)]
+struct ConfigSnapshot {
+    work_offsets: Vec<WorkOffset>,
+    channels: Vec<ChannelEditData>,
+    dialect: Dialect,
+    word_limits: WordLimits,
+    tolerances: ToleranceProfile,
+    persistent_params: BTreeMap<u32, f64>,
+}
+
+#[derive(Clone, PartialEq)]
+struct ChannelEditData {
+    id: u32,
+    tool_table: HashMap<i32, ToolTableEntry>,
+    tool_length: f64,
+    tool_radius: f64,
+    active_d: i32,
+    active_h: i32,
+    turret_offsets: Option<Vec<[f64; 2]>>,
+    tool_changer: Option<ToolChanger>,
+}
+
+#[derive(Clone)]
+struct EditRecord {
+    action: String,
+    permissions: Vec<Permission>, // needed again to undo or redo it
+    before: ConfigSnapshot,
+    after: ConfigSnapshot,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub seq: u32,
+    pub action: String, // API name, or "undo <action>" / "redo <action>"
+    pub tag: String,    // caller-supplied who/when
+    pub at_s: f64,
+}
+
+const EDIT_HISTORY_MAX: usize = 100;
+const AUDIT_LOG_MAX: usize = 1000;
+
+// ── Batch commands ─────────────────────────────────────────────────────────
+
+// One configuration change of an `apply_batch` list, tagged by "op".
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchCommand {
+    SetWorkZero { wcs: usize, axis_id: u32, value: f64 },
+    ShiftWorkOffset { wcs: Option<usize>, axis_id: u32, delta: f64 }, // no wcs = all
+    CopyWorkOffset { from: usize, to: usize },
+    SetToolEntry { channel: usize, slot: i32, length: f64, radius: f64 },
+    SetToolWear { channel: usize, slot: i32, length_wear: f64, radius_wear: f64 },
+    SetDialect { name: String },
+    SetWordLimits { spindle_max_rpm: f64, magazine_pockets: u32, offset_slots: u32 },
+    SetTolerances { profile: ToleranceProfile },
+}
+
+impl BatchCommand {
+    fn permission(&self) -> Permission {
+        match self {
+            BatchCommand::SetWorkZero { .. } | BatchCommand::ShiftWorkOffset { .. } | BatchCommand::CopyWorkOffset { .. } => {
+                Permission::Offsets
+            }
+            BatchCommand::SetToolEntry { .. } => Permission::ToolGeometry,
+            BatchCommand::SetToolWear { .. } => Permission::ToolWear,
+            BatchCommand::SetDialect { .. } | BatchCommand::SetWordLimits { .. } | BatchCommand::SetTolerances { .. } => {
+                Permission::Settings
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BatchCommandResult {
+    pub index: usize,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct BatchOutcome {
+    pub applied: bool,          // all commands succeeded and were kept
+    pub error: Option<String>, // the list itself could not be read
+    pub results: Vec<BatchCommandResult>,
+}
+
+// ── Capabilities ───────────────────────────────────────────────────────────
+
+// Optional subsystems are cargo features (all on by default). A disabled one
+// folds to a constant `false`, so its code drops out of a minimal wasm build.
+const MATERIAL_REMOVAL: bool = cfg!(feature = "material-removal");
+const COLLISION: bool = cfg!(feature = "collision");
+const MACROS: bool = cfg!(feature = "macros");
+const DIALECTS: bool = cfg!(feature = "dialects");
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Capabilities {
+    pub version: String,
+    pub features: Vec<String>, // compiled-in optional subsystems
+    pub dialects: Vec<String>, // accepted by set_dialect
+    pub machine_templates: Vec<String>,
+}
+
+fn capabilities() -> Capabilities {
+    let features = [
+        ("material-removal", MATERIAL_REMOVAL),
+        ("collision", COLLISION),
+        ("macros", MACROS),
+        ("dialects", DIALECTS),
+        ("parallel", cfg!(all(feature = "parallel", not(target_arch = "wasm32")))),
+        ("invariants", cfg!(feature = "invariants")),
+    ];
+    let dialects: &[&str] = if DIALECTS { &["fanuc", "linuxcnc", "mach3"] } else { &["fanuc"] };
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: features.iter().filter(|(_, on)| *on).map(|(name, _)| name.to_string()).collect(),
+        dialects: dialects.iter().map(|d| d.to_string()).collect(),
+        machine_templates: MACHINE_TEMPLATES.iter().map(|t| t.to_string()).collect(),
+    }
+}
+
+// ── Machine configuration ──────────────────────────────────────────────────
+
+// Declarative machine description for `reconfigure`. Axes are matched by name,
+// channels by id.
+#[derive(Deserialize, Clone)]
+pub struct MachineConfig {
+    pub axes: Vec<AxisConfig>,
+    #[serde(default)]
+    pub channels: Vec<ChannelConfig>,
+    #[serde(default)]
+    pub references: Vec<AxisReference>, // saved reference state, restored last
+    #[serde(default)]
+    pub persistent_params: BTreeMap<u32, f64>, // #500.. values; empty keeps the current ones
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AxisConfig {
+    pub name: String,
+    pub kind: AxisType,
+    pub min: f64,
+    pub max: f64,
+    #[serde(default = "default_axis_accel")]
+    pub accel: f64,
+    #[serde(default)]
+    pub invert: bool,
+    #[serde(default)]
+    pub machine_zero: f64,
+    #[serde(default)]
+    pub rotary_mode: Option<RotaryMode>,
+    #[serde(default)]
+    pub side: Option<AxisSide>,
+    #[serde(default)]
+    pub link: Option<char>, // X/Y/Z/A/B/C direction driven
+    #[serde(default)]
+    pub encoder: Option<EncoderType>,
+}
+
+fn default_axis_accel() -> f64 {
+    2000.0
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ChannelConfig {
+    pub id: u32,
+    pub axes: Vec<ChannelAxisConfig>,
+    #[serde(default)]
+    pub tools: Vec<ToolConfig>, // merged into the tool table
+    #[serde(default)]
+    pub defaults: ChannelDefaults,
+    #[serde(default)]
+    pub rear_turret: bool,
+}
+
+/// Modal state a channel powers on and resets to. A lathe channel can start
+/// in G18 with diameter programming while a mill channel starts in G17.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct ChannelDefaults {
+    pub units_mm: bool,     // G21 (true) or G20
+    pub abs_mode: bool,     // G90 (true) or G91
+    pub plane: u8,          // 17, 18 or 19
+    pub diameter_mode: bool, // X words are diameters
+}
+
+impl Default for ChannelDefaults {
+    fn default() -> Self {
+        Self { units_mm: true, abs_mode: true, plane: 17, diameter_mode: false }
+    }
+}
+
+impl ChannelDefaults {
+    fn is_valid(&self) -> bool {
+        matches!(self.plane, 17..=19)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ToolConfig {
+    pub slot: i32,
+    pub length: f64,
+    pub radius: f64,
+    #[serde(default)]
+    pub plate_offset: [f64; 2], // gang-tool X/Y offset
+    #[serde(default)]
+    pub flutes: u32,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ChannelAxisConfig {
+    pub axis: String,  // axis name
+    pub label: String, // program letter in this channel
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ReconfigureReport {
+    pub applied: bool,
+    pub error: Option<String>,
+    pub added_axes: Vec<String>,
+    pub removed_axes: Vec<String>,
+    pub changed_axes: Vec<String>, // settings updated, position and offsets kept
+    pub added_channels: Vec<u32>,
+    pub removed_channels: Vec<u32>,
+    pub remapped_channels: Vec<u32>, // queue and trace reset, program kept
+    pub rehome_axes: Vec<String>, // restored references that need homing again
+}
+
+// Home reference of one axis as saved with a session; matched by axis name.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AxisReference {
+    pub name: String,
+    pub homed: bool,
+    pub machine_zero: f64,
+    pub position: f64, // machine position when saved
+    #[serde(default)]
+    pub encoder: Option<EncoderType>, // informational; the axis setting decides
+}
+
+pub const MACHINE_TEMPLATES: &[&str] = &["vmc3", "lathe", "trunnion5", "millturn"];
+
+fn template_axis(name: &str, kind: AxisType, min: f64, max: f64, side: AxisSide) -> AxisConfig {
+    AxisConfig {
+        name: name.to_string(),
+        kind,
+        min,
+        max,
+        accel: default_axis_accel(),
+        invert: false,
+        machine_zero: 0.0,
+        rotary_mode: (kind == AxisType::Rotary).then_some(RotaryMode::Continuous),
+        side: Some(side),
+        link: name.chars().next(),
+        encoder: None,
+    }
+}
+
+fn template_channel(id: u32, axes: &[&str], tools: &[(i32, f64, f64)]) -> ChannelConfig {
+    ChannelConfig {
+        id,
+        axes: axes
+            .iter()
+            .map(|a| ChannelAxisConfig { axis: a.to_string(), label: a.chars().take(1).collect() })
+            .collect(),
+        tools: tools.iter().map(|&(slot, length, radius)| ToolConfig { slot, length, radius, plate_offset: [0.0; 2], flutes: 0 }).collect(),
+        defaults: ChannelDefaults::default(),
+        rear_turret: false,
+    }
+}
+
+fn template_lathe_channel(id: u32, axes: &[&str]) -> ChannelConfig {
+    let mut chan = template_channel(id, axes, LATHE_TOOLS);
+    chan.defaults = ChannelDefaults { plane: 18, diameter_mode: true, ..ChannelDefaults::default() };
+    chan
+}
+
+// Starter tool tables: (slot, length, radius).
+const MILL_TOOLS: &[(i32, f64, f64)] = &[(1, 50.0, 5.0), (2, 60.0, 3.0), (3, 45.0, 1.5), (4, 70.0, 8.0)];
+const LATHE_TOOLS: &[(i32, f64, f64)] = &[(1, 0.0, 0.4), (2, 0.0, 0.8), (3, 0.0, 0.2)];
+
+/// Built-in machine by name, see `MACHINE_TEMPLATES`.
+fn machine_template(name: &str) -> Option<MachineConfig> {
+    use AxisSide::{Table, Tool};
+    use AxisType::{Linear, Rotary};
+    let config = match name.trim().to_ascii_lowercase().as_str() {
+        "vmc3" | "vmc" => MachineConfig {
+            axes: vec![
+                template_axis("X", Linear, -400.0, 400.0, Table),
+                template_axis("Y", Linear, -250.0, 250.0, Table),
+                template_axis("Z", Linear, -400.0, 0.0, Tool),
+            ],
+            channels: vec![template_channel(0, &["X", "Y", "Z"], MILL_TOOLS)],
+            references: Vec::new(),
+            persistent_params: BTreeMap::new(),
+        },
+        "lathe" => MachineConfig {
+            axes: vec![
+                template_axis("X", Linear, -10.0, 200.0, Tool),
+                template_axis("Z", Linear, -500.0, 0.0, Tool),
+            ],
+            channels: vec![template_lathe_channel(0, &["X", "Z"])],
+            references: Vec::new(),
+            persistent_params: BTreeMap::new(),
+        },
+        "trunnion5" | "5axis" => {
+            let mut a = template_axis("A", Rotary, -120.0, 30.0, Table);
+            a.rotary_mode = Some(RotaryMode::Limited);
+            MachineConfig {
+                axes: vec![
+                    template_axis("X", Linear, -300.0, 300.0, Tool),
+                    template_axis("Y", Linear, -250.0, 250.0, Tool),
+                    template_axis("Z", Linear, -400.0, 0.0, Tool),
+                    a,
+                    template_axis("C", Rotary, -360.0, 360.0, Table),
+                ],
+                channels: vec![template_channel(0, &["X", "Y", "Z", "A", "C"], MILL_TOOLS)],
+                references: Vec::new(),
+                persistent_params: BTreeMap::new(),
+            }
+        }
+        "millturn" | "mill-turn" => {
+            let mut sub_z = template_axis("Z2", Linear, -500.0, 0.0, Tool);
+            sub_z.link = Some('Z');
+            let mut sub_x = template_axis("X2", Linear, -10.0, 200.0, Tool);
+            sub_x.link = Some('X');
+            let mut mill = template_channel(0, &["X", "Y", "Z", "C"], MILL_TOOLS);
+            mill.tools.extend(LATHE_TOOLS.iter().map(|&(slot, length, radius)| ToolConfig { slot: slot + 10, length, radius, plate_offset: [0.0; 2], flutes: 0 }));
+            MachineConfig {
+                axes: vec![
+                    template_axis("X", Linear, -10.0, 250.0, Tool),
+                    template_axis("Y", Linear, -80.0, 80.0, Tool),
+                    template_axis("Z", Linear, -600.0, 0.0, Tool),
+                    template_axis("C", Rotary, -360.0, 360.0, Table),
+                    sub_x,
+                    sub_z,
+                ],
+                channels: vec![mill, template_lathe_channel(1, &["X2", "Z2"])],
+                references: Vec::new(),
+                persistent_params: BTreeMap::new(),
+            }
+        }
+        _ => return None,
+    };
+    Some(config)
+}
+
+fn validate_machine_config(config: &MachineConfig) -> Result<(), String> {
+    let mut names: Vec<&str> = config.axes.iter().map(|a| a.name.as_str()).collect();
+    names.sort_unstable();
+    names.dedup();
+    if names.len() != config.axes.len() {
+        return Err("axis names must be unique".to_string());
+    }
+    if let Some(a) = config.axes.iter().find(|a| !(a.min.is_finite() && a.max.is_finite() && a.min <= a.max)) {
+        return Err(format!("axis {} has invalid limits", a.name));
+    }
+    for chan in &config.channels {
+        if config.channels.iter().filter(|c| c.id == chan.id).count() > 1 {
+            return Err(format!("channel {} is defined twice", chan.id));
+        }
+        if let Some(a) = chan.axes.iter().find(|a| !config.axes.iter().any(|ax| ax.name == a.axis)) {
+            return Err(format!("channel {} maps unknown axis {}", chan.id, a.axis));
+        }
+        let mut labels: Vec<String> = chan.axes.iter().map(|a| a.label.trim().to_uppercase()).collect();
+        labels.sort();
+        labels.dedup();
+        if labels.len() != chan.axes.len() || labels.iter().any(|l| l.is_empty()) {
+            return Err(format!("channel {} has empty or duplicate labels", chan.id));
+        }
+    }
+    Ok(())
+}
+
+// Clearance above the entry point for a safe-start rapid (mm).
+const SAFE_START_CLEARANCE_MM: f64 = 5.0;
+
+fn format_word_value(v: f64) -> String {
+    let s = format!("{:.4}", v);
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" { "0".to_string() } else { s.to_string() }
+}
+
+// Safe-start preamble from a machine scanned up to the resume line: retract Z
+// to machine zero, select tool, spindle and coolant, traverse to the entry
+// point, then rapid above it and plunge at feed. Positions are written in mm
+// and absolute, then the program's units and distance mode are restored. Arc
+// (G2/G3) modal motion is left at G1.
+fn safe_start_preamble(scan: &MachineBrain, c_idx: usize) -> Vec<String> {
+    let chan = &scan.channels[c_idx];
+    let wcs = scan.work_offsets.get(scan.active_wcs).map(|w| w.label.clone()).unwrap_or_else(|| "G54".to_string());
+    let mut lines = vec![format!("G90 G21 G40 {}", wcs)];
+    let has_z = chan.axis_map.iter().any(|m| m.display_label.eq_ignore_ascii_case("Z"));
+    if has_z {
+        lines.push("G53 G0 Z0".to_string());
+    }
+    if chan.active_tool > 0 {
+        let comp = if chan.length_comp_active { format!("G43 H{}", chan.active_h) } else { "G49".to_string() };
+        let change = if chan.turret.is_some() { "" } else { " M6" };
+        lines.push(format!("T{}{} {}", chan.active_tool, change, comp));
+    }
+    if matches!(chan.spindle_mode, 3 | 4) && chan.spindle_rpm > 0.0 {
+        lines.push(format!("S{} M{}", format_word_value(chan.spindle_rpm), chan.spindle_mode));
+    }
+    if chan.coolant_on {
+        lines.push("M8".to_string());
+    }
+    let entry: Vec<(String, f64)> = chan
+        .axis_map
+        .iter()
+        .filter_map(|m| {
+            let label = m.display_label.to_uppercase();
+            let value = scan.block_state_value(c_idx, &label)?;
+            Some((label, value))
+        })
+        .collect();
+    let z = entry.iter().find(|(label, _)| label == "Z").map(|(_, v)| *v);
+    let others: Vec<String> = entry
+        .iter()
+        .filter(|(label, _)| label != "Z")
+        .map(|(label, v)| format!("{}{}", label, format_word_value(*v)))
+        .collect();
+    if !others.is_empty() {
+        lines.push(format!("G0 {}", others.join(" ")));
+    }
+    if let Some(z) = z {
+        lines.push(format!("G0 Z{}", format_word_value(z + SAFE_START_CLEARANCE_MM)));
+        lines.push(format!("G1 Z{} F{}", format_word_value(z), format_word_value(chan.feed_rate)));
+    }
+    let motion = if chan.current_motion == 0 { "G0" } else { "G1" };
+    let distance = if chan.abs_mode { "G90" } else { "G91" };
+    let units = if chan.units_mm { "G21" } else { "G20" };
+    lines.push(format!("{} {} {}", motion, distance, units));
+    lines
+}
+
+// ── Kinematic poses ────────────────────────────────────────────────────────
+
+/// World transform of one machine component, in machine coordinates (mm).
+#[derive(Serialize, Clone)]
+pub struct ComponentPose {
+    pub name: String,
+    pub kind: String,          // "base", "axis", "table", "head", "spindle" or "tool"
+    pub axis_id: Option<u32>,
+    pub matrix: [f64; 16],     // 4x4 column-major (three.js Matrix4.fromArray order)
+}
+
+/// Centerline of a rotary axis as a drawable line, in machine coordinates.
+/// `origin`/`direction` include the calibration errors; the nominal pair is
+/// where the configured kinematics put it.
+#[derive(Serialize, Clone)]
+pub struct RotaryAxisLine {
+    pub axis_id: u32,
+    pub name: String,
+    pub side: AxisSide,
+    pub angle_deg: f64,
+    pub origin: [f64; 3],
+    pub direction: [f64; 3], // unit; positive rotation is right-handed about it
+    pub nominal_origin: [f64; 3],
+    pub nominal_direction: [f64; 3],
+    pub start: [f64; 3], // origin ± ROTARY_LINE_HALF_LENGTH along the direction
+    pub end: [f64; 3],
+    pub tool_distance: f64, // tool tip to the centerline (pivot distance)
+}
+
+const ROTARY_LINE_HALF_LENGTH: f64 = 250.0; // mm
+
+type Mat4 = [f64; 16];
+
+const MAT4_IDENTITY: Mat4 = [
+    1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+];
+
+fn mat4_mul(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut out = [0.0; 16];
+    for col in 0..4 {
+        for row in 0..4 {
+            out[col * 4 + row] = (0..4).map(|k| a[k * 4 + row] * b[col * 4 + k]).sum();
+        }
+    }
+    out
+}
+
+fn mat4_translation(v: [f64; 3]) -> Mat4 {
+    let mut m = MAT4_IDENTITY;
+    m[12] = v[0];
+    m[13] = v[1];
+    m[14] = v[2];
+    m
+}
+
+// Rotation by `deg` about machine axis 0 = X, 1 = Y, 2 = Z (right-handed).
+fn mat4_rotation(axis: usize, deg: f64) -> Mat4 {
+    let (s, c) = deg.to_radians().sin_cos();
+    let mut m = MAT4_IDENTITY;
+    let (i, j) = match axis {
+        0 => (1, 2),
+        1 => (2, 0),
+        _ => (0, 1),
+    };
+    m[i * 4 + i] = c;
+    m[i * 4 + j] = s;
+    m[j * 4 + i] = -s;
+    m[j * 4 + j] = c;
+    m
+}
+
+// Inverse of a rotation + translation transform.
+fn mat4_rigid_inverse(m: &Mat4) -> Mat4 {
+    let mut out = MAT4_IDENTITY;
+    for col in 0..3 {
+        for row in 0..3 {
+            out[col * 4 + row] = m[row * 4 + col];
+        }
+    }
+    for row in 0..3 {
+        out[12 + row] = -(0..3).map(|k| out[k * 4 + row] * m[12 + k]).sum::<f64>();
+    }
+    out
+}
+
+fn mat4_apply(m: &Mat4, p: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|row| (0..3).map(|k| m[k * 4 + row] * p[k]).sum::<f64>() + m[12 + row])
+}
+
+// Direction `v` under `m`, ignoring the translation.
+fn mat4_apply_vector(m: &Mat4, v: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|row| (0..3).map(|k| m[k * 4 + row] * v[k]).sum::<f64>())
+}
+
+/// Geometric errors of a rotary axis: where its centerline really is relative
+/// to the nominal one, and how far it is tilted about the two perpendicular
+/// directions (e.g. about Y then Z for an A axis).
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct AxisCalibration {
+    pub center_offset: [f64; 3], // mm
+    pub tilt_deg: [f64; 2],
+}
+
+impl AxisCalibration {
+    // Rotation of `deg` about the misplaced, tilted centerline.
+    fn rotation(&self, dir: usize, deg: f64) -> Mat4 {
+        let tilt = mat4_mul(
+            &mat4_rotation((dir + 1) % 3, self.tilt_deg[0]),
+            &mat4_rotation((dir + 2) % 3, self.tilt_deg[1]),
+        );
+        let about = mat4_mul(&mat4_mul(&tilt, &mat4_rotation(dir, deg)), &mat4_rigid_inverse(&tilt));
+        let c = self.center_offset;
+        mat4_mul(&mat4_mul(&mat4_translation(c), &about), &mat4_translation([-c[0], -c[1], -c[2]]))
+    }
+
+    // Point on and direction of the real centerline, in the axis's parent frame.
+    fn centerline(&self, dir: usize) -> ([f64; 3], [f64; 3]) {
+        let tilt = mat4_mul(
+            &mat4_rotation((dir + 1) % 3, self.tilt_deg[0]),
+            &mat4_rotation((dir + 2) % 3, self.tilt_deg[1]),
+        );
+        let mut axis = [0.0; 3];
+        axis[dir] = 1.0;
+        (self.center_offset, mat4_apply_vector(&tilt, axis))
+    }
+}
+
+// Direction index (0..3) an axis drives: explicit link, else its name's letter
+// (U/V/W and A/B/C map onto X/Y/Z, "Z3" onto Z).
+fn axis_direction_index(ax: &Axis) -> Option<usize> {
+    let letter = ax.link_axis.or_else(|| ax.physical_name.to_uppercase().chars().next())?;
+    match letter {
+        'X' | 'U' | 'A' => Some(0),
+        'Y' | 'V' | 'B' => Some(1),
+        'Z' | 'W' | 'C' => Some(2),
+        _ => None,
+    }
+}
+
+// Orientation taking the spindle's local tool direction (-Z) onto `direction`.
+fn spindle_orientation(direction: &str) -> Option<Mat4> {
+    match direction {
+        "-Z" => Some(MAT4_IDENTITY),
+        "+Z" => Some(mat4_rotation(0, 180.0)),
+        "+X" => Some(mat4_rotation(1, -90.0)),
+        "-X" => Some(mat4_rotation(1, 90.0)),
+        "+Y" => Some(mat4_rotation(0, 90.0)),
+        "-Y" => Some(mat4_rotation(0, -90.0)),
+        _ => None,
+    }
+}
+
+impl Default for MachineBrain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ── Block tokenizer ────────────────────────────────────────────────────────
+
+#[derive(Clone, Debug, PartialEq)]
+enum BlockToken {
+    Word(char, String), // address letter (uppercase) and value text as written
+    Comment(String),    // ( ... ) or ; ... including delimiters
+    Other(String),      // block delete '/', '%', or unrecognized text (expressions) as written
+}
+
+fn tokenize_block(line: &str) -> Vec<BlockToken> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == ';' {
+            out.push(BlockToken::Comment(chars[i..].iter().collect()));
+            break;
+        }
+        if c == '(' {
+            let start = i;
+            while i < chars.len() && chars[i] != ')' {
+                i += 1;
+            }
+            i = (i + 1).min(chars.len());
+            out.push(BlockToken::Comment(chars[start..i].iter().collect()));
+            continue;
+        }
+        if c.is_ascii_alphabetic() {
+            let mut j = i + 1;
+            while j < chars.len() && chars[j] == ' ' {
+                j += 1;
+            }
+            let v_start = j;
+            if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                j += 1;
+            }
+            while j < chars.len() && (chars[j].is_ascii_digit() || chars[j] == '.') {
+                j += 1;
+            }
+            let value: String = chars[v_start..j].iter().collect();
+            if value.chars().any(|ch| ch.is_ascii_digit()) {
+                out.push(BlockToken::Word(c.to_ascii_uppercase(), value));
+                i = j;
+                continue;
+            }
+        }
+        if c == '/' || c == '%' {
+            out.push(BlockToken::Other(c.to_string()));
+            i += 1;
+            continue;
+        }
+        // Parameter assignments, bracketed expressions and keywords run to the
+        // next space outside brackets and are kept verbatim.
+        let start = i;
+        let mut depth = 0usize;
+        while i < chars.len() {
+            match chars[i] {
+                '[' => depth += 1,
+                ']' => depth = depth.saturating_sub(1),
+                ch if depth == 0 && (ch.is_whitespace() || ch == '(' || ch == ';') => break,
+                _ => {}
+            }
+            i += 1;
+        }
+        out.push(BlockToken::Other(chars[start..i].iter().collect()));
+    }
+    out
+}
+
+fn render_block(tokens: &[BlockToken]) -> String {
+    tokens
+        .iter()
+        .map(|t| match t {
+            BlockToken::Word(letter, value) => format!("{}{}", letter, value),
+            BlockToken::Comment(text) | BlockToken::Other(text) => text.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Modal group conflicts in one block, as (group, first word, conflicting word).
+fn block_modal_conflicts(line: &str) -> Vec<(&'static str, String, String)> {
+    let mut seen: Vec<(&'static str, i32, String)> = Vec::new();
+    let mut conflicts = Vec::new();
+    for token in tokenize_block(line) {
+        let BlockToken::Word(letter, value) = token else { continue };
+        let Ok(v) = value.parse::<f64>() else { continue };
+        let (code, groups): (i32, &[(&'static str, &[i32])]) = match letter {
+            'G' => {
+                let g10 = (v * 10.0).round() as i32;
+                if g10 % 10 == 0 { (g10 / 10, &G_MODAL_GROUPS) } else { (g10, &G_DECIMAL_MODAL_GROUPS) }
+            }
+            'M' => (v.round() as i32, &M_MODAL_GROUPS),
+            _ => continue,
+        };
+        let Some((group, _)) = groups.iter().find(|(_, codes)| codes.contains(&code)) else { continue };
+        let word = format!("{}{}", letter, value);
+        match seen.iter().find(|(g, _, _)| g == group) {
+            Some((_, first, first_word)) if *first != code => {
+                conflicts.push((*group, first_word.clone(), word));
+            }
+            Some(_) => {}
+            None => seen.push((group, code, word)),
+        }
+    }
+    conflicts
+}
+
+// ── Fast path scan ─────────────────────────────────────────────────────────
+
+// Adaptive/trochoidal clearing: many small arcs. Such programs are estimated
+// from the block geometry directly instead of running the interpreter.
+const ADAPTIVE_MIN_ARCS: usize = 500;
+const ADAPTIVE_SHORT_ARC_MM: f64 = 15.0;
+
+#[derive(Serialize, Clone, Default)]
+pub struct PathStatistics {
+    pub blocks: usize,
+    pub rapid_moves: usize,
+    pub linear_moves: usize,
+    pub arc_count: usize,
+    pub short_arc_count: usize, // arcs shorter than ADAPTIVE_SHORT_ARC_MM
+    pub adaptive: bool,         // recognized as adaptive/trochoidal clearing
+    pub average_arc_radius: f64,
+    pub average_stepover: f64,         // mean distance between successive loop centers
+    pub average_engagement_deg: f64,   // radial engagement angle from stepover and tool radius
+    pub cut_length: f64,
+    pub rapid_length: f64,
+    pub estimated_time_s: f64,
+    pub bounds_min: Option<[f64; 3]>, // X/Y/Z work coordinates reached by moves
+    pub bounds_max: Option<[f64; 3]>,
+}
+
+fn extend_bounds(stats: &mut PathStatistics, p: [f64; 3]) {
+    let min = stats.bounds_min.get_or_insert(p);
+    for k in 0..3 {
+        min[k] = min[k].min(p[k]);
+    }
+    let max = stats.bounds_max.get_or_insert(p);
+    for k in 0..3 {
+        max[k] = max[k].max(p[k]);
+    }
+}
+
+// Machine data the scan needs; taken from the channel at call time.
+struct PathScanParams {
+    start: [f64; 3], // X/Y/Z work position
+    units_mm: bool,
+    abs_mode: bool,
+    arc_center_absolute: bool,
+    feed_rate: f64,
+    feed_override: f64,
+    rapid_feed: f64,
+    xy_accel: f64, // mm/min per second, for the centripetal arc clamp
+    tool_radius: f64,
+}
+
+// Single pass over the block words: positions, arc geometry and feed-based
+// time, without cutter comp, WCS changes or axis dynamics.
+fn scan_path_statistics(code: &str, params: &PathScanParams) -> PathStatistics {
+    let mut stats = PathStatistics::default();
+    let mut pos = params.start;
+    let mut motion = 0;
+    let mut units_mm = params.units_mm;
+    let mut abs_mode = params.abs_mode;
+    let mut arc_center_absolute = params.arc_center_absolute;
+    let mut feed = params.feed_rate;
+    let mut radius_sum = 0.0;
+    let mut stepover_sum = 0.0;
+    let mut stepover_count = 0usize;
+    let mut last_arc: Option<(f64, f64, f64, bool)> = None; // center x/y, radius, cw
+
+    for line in code.lines() {
+        let mut words: HashMap<char, f64> = HashMap::new();
+        let mut g_codes: Vec<i32> = Vec::new();
+        for token in tokenize_block(line) {
+            let BlockToken::Word(letter, value) = token else { continue };
+            let Ok(v) = value.parse::<f64>() else { continue };
+            if letter == 'G' {
+                g_codes.push((v * 10.0).round() as i32);
+            } else {
+                words.insert(letter, v);
+            }
+        }
+        if words.is_empty() && g_codes.is_empty() {
+            continue;
+        }
+        stats.blocks += 1;
+        for g in &g_codes {
+            match *g {
+                0 | 10 | 20 | 30 => motion = *g / 10,
+                200 => units_mm = false,
+                210 => units_mm = true,
+                900 => abs_mode = true,
+                910 => abs_mode = false,
+                901 => arc_center_absolute = true,
+                911 => arc_center_absolute = false,
+                _ => {}
+            }
+        }
+        let unit = if units_mm { 1.0 } else { 25.4 };
+        if let Some(f) = words.get(&'F') {
+            feed = f * unit;
+        }
+        let mut end = pos;
+        for (k, letter) in ['X', 'Y', 'Z'].iter().enumerate() {
+            if let Some(v) = words.get(letter) {
+                end[k] = if abs_mode { v * unit } else { pos[k] + v * unit };
+            }
+        }
+        let moved = (0..3).any(|k| (end[k] - pos[k]).abs() > 1e-9);
+        let cut_feed = feed * params.feed_override;
+        if moved && matches!(motion, 0..=3) {
+            extend_bounds(&mut stats, pos);
+            extend_bounds(&mut stats, end);
+        }
+
+        match motion {
+            0 if moved => {
+                let len = (0..3).map(|k| (end[k] - pos[k]).powi(2)).sum::<f64>().sqrt();
+                stats.rapid_moves += 1;
+                stats.rapid_length += len;
+                if params.rapid_feed > 0.0 {
+                    stats.estimated_time_s += len / params.rapid_feed * 60.0;
+                }
+            }
+            1 if moved => {
+                let len = (0..3).map(|k| (end[k] - pos[k]).powi(2)).sum::<f64>().sqrt();
+                stats.linear_moves += 1;
+                stats.cut_length += len;
+                if cut_feed > 0.0 {
+                    stats.estimated_time_s += len / cut_feed * 60.0;
+                }
+            }
+            2 | 3 => {
+                let cw = motion == 2;
+                let (sx, sy, ex, ey) = (pos[0], pos[1], end[0], end[1]);
+                let i_off = words.get(&'I').map(|v| v * unit);
+                let j_off = words.get(&'J').map(|v| v * unit);
+                let chord = ((ex - sx).powi(2) + (ey - sy).powi(2)).sqrt();
+                let ijk = i_off.is_some() || j_off.is_some();
+                let (cx, cy, r, sweep) = if ijk {
+                    let (cx, cy) = if arc_center_absolute {
+                        (i_off.unwrap_or(sx), j_off.unwrap_or(sy))
+                    } else {
+                        (sx + i_off.unwrap_or(0.0), sy + j_off.unwrap_or(0.0))
+                    };
+                    let r = ((sx - cx).powi(2) + (sy - cy).powi(2)).sqrt();
+                    let mut da = (ey - cy).atan2(ex - cx) - (sy - cy).atan2(sx - cx);
+                    if cw && da >= 0.0 {
+                        da -= std::f64::consts::TAU;
+                    } else if !cw && da <= 0.0 {
+                        da += std::f64::consts::TAU;
+                    }
+                    if chord <= ARC_RADIUS_TOLERANCE_MM {
+                        da = std::f64::consts::TAU;
+                    }
+                    let turns = words.get(&'P').map(|p| p.round().max(1.0)).unwrap_or(1.0);
+                    (cx, cy, r, da.abs() + std::f64::consts::TAU * (turns - 1.0))
+                } else if let Some(rw) = words.get(&'R').map(|v| v * unit) {
+                    let r = rw.abs();
+                    if r <= 1e-9 || chord <= 1e-9 || chord > 2.0 * r + 1e-9 {
+                        pos = end;
+                        continue;
+                    }
+                    let small = 2.0 * (chord / (2.0 * r)).min(1.0).asin();
+                    // Center left of the chord for CCW short arcs / CW long arcs.
+                    let h = (r * r - chord * chord / 4.0).max(0.0).sqrt();
+                    let side = if cw == (rw < 0.0) { 1.0 } else { -1.0 };
+                    let cx = (sx + ex) / 2.0 - side * h * (ey - sy) / chord;
+                    let cy = (sy + ey) / 2.0 + side * h * (ex - sx) / chord;
+                    (cx, cy, r, if rw < 0.0 { std::f64::consts::TAU - small } else { small })
+                } else {
+                    pos = end;
+                    continue;
+                };
+                if r <= 1e-9 {
+                    pos = end;
+                    continue;
+                }
+                // Quadrant points inside the sweep widen the bounds beyond the end points.
+                let a0 = (sy - cy).atan2(sx - cx);
+                for q in 0..4 {
+                    let qa = q as f64 * std::f64::consts::FRAC_PI_2;
+                    let from_start = if cw { a0 - qa } else { qa - a0 };
+                    if from_start.rem_euclid(std::f64::consts::TAU) <= sweep {
+                        extend_bounds(&mut stats, [cx + r * qa.cos(), cy + r * qa.sin(), pos[2]]);
+                    }
+                }
+                let planar = r * sweep;
+                let len = (planar * planar + (end[2] - pos[2]).powi(2)).sqrt();
+                stats.arc_count += 1;
+                if len < ADAPTIVE_SHORT_ARC_MM {
+                    stats.short_arc_count += 1;
+                }
+                radius_sum += r;
+                stats.cut_length += len;
+                let limit = (params.xy_accel.max(1.0) / 60.0 * r).sqrt() * 60.0;
+                let arc_feed = cut_feed.min(limit);
+                if arc_feed > 0.0 {
+                    stats.estimated_time_s += len / arc_feed * 60.0;
+                }
+                if let Some((px, py, pr, pcw)) = last_arc.filter(|_| ijk) {
+                    let step = ((cx - px).powi(2) + (cy - py).powi(2)).sqrt();
+                    // Successive loops of one trochoid: same direction, overlapping circles.
+                    if pcw == cw && step > 1e-6 && step < r.max(pr) * 2.0 {
+                        stepover_sum += step;
+                        stepover_count += 1;
+                    }
+                }
+                if ijk {
+                    last_arc = Some((cx, cy, r, cw));
+                }
+            }
+            _ => {}
+        }
+        pos = end;
+    }
+
+    if stats.arc_count > 0 {
+        stats.average_arc_radius = radius_sum / stats.arc_count as f64;
+    }
+    if stepover_count > 0 {
+        stats.average_stepover = stepover_sum / stepover_count as f64;
+        if params.tool_radius > 0.0 {
+            let ae = stats.average_stepover.min(2.0 * params.tool_radius);
+            stats.average_engagement_deg = (1.0 - ae / params.tool_radius).clamp(-1.0, 1.0).acos().to_degrees();
+        }
+    }
+    stats.adaptive = stats.arc_count >= ADAPTIVE_MIN_ARCS && stats.short_arc_count * 2 >= stats.arc_count;
+    stats
+}
+
+// True when `code` holds only what `scan_path_statistics` can time: no dwells,
+// tool changes, threading or canned cycles, no macro/O-word flow control, and
+// nothing that moves the XY plane or coordinate frame under it (G18/G19, G7,
+// work offsets, G52/G53/G92, G51.1 mirroring).
+fn scan_times_program(code: &str) -> bool {
+    for line in code.lines() {
+        for token in tokenize_block(line) {
+            match token {
+                BlockToken::Word(letter, value) => {
+                    let Ok(v) = value.parse::<f64>() else { return false };
+                    let code = (v * 10.0).round() as i32;
+                    match letter {
+                        'G' if matches!(code, 40 | 70 | 180 | 190 | 330 | 331 | 340 | 511 | 520 | 530 | 650 | 660 | 760 | 1530)
+                            || (540..=599).contains(&code)
+                            || (730..=890).contains(&code)
+                            || (920..=923).contains(&code) =>
+                        {
+                            return false
+                        }
+                        'M' if matches!(code, 60 | 980 | 990) => return false,
+                        'O' | '#' => return false,
+                        _ => {}
+                    }
+                }
+                BlockToken::Other(text) if text != "/" && text != "%" => return false,
+                _ => {}
+            }
+        }
+    }
+    true
+}
+
+// ── Work envelope ──────────────────────────────────────────────────────────
+
+/// Travel range of one axis: mm for linear axes, degrees (arc) for rotary ones.
+#[derive(Serialize, Clone)]
+pub struct AxisExtent {
+    pub axis_id: u32,
+    pub name: String,
+    pub rotary: bool,
+    pub side: AxisSide,
+    pub direction: Option<usize>, // 0/1/2 = X/Y/Z (rotation axis for rotary)
+    pub min: f64,                 // machine coordinates
+    pub max: f64,
+    pub work_min: f64,            // same limits in the active WCS
+    pub work_max: f64,
+}
+
+/// Renderable work envelope: per-axis extents plus the X/Y/Z travel box.
+#[derive(Serialize, Clone, Default)]
+pub struct WorkEnvelope {
+    pub active_wcs: usize,
+    pub axes: Vec<AxisExtent>,
+    pub box_min: [f64; 3], // machine coordinates
+    pub box_max: [f64; 3],
+    pub work_box_min: [f64; 3], // active WCS coordinates
+    pub work_box_max: [f64; 3],
+}
+
+#[derive(Serialize, Clone)]
+pub struct EnvelopeExcess {
+    pub axis_id: u32,
+    pub name: String,
+    pub below_by: f64, // mm the program goes past the minimum (0 = inside)
+    pub above_by: f64,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct EnvelopeCheck {
+    pub envelope: WorkEnvelope,
+    pub program_min: Option<[f64; 3]>, // program bounding box, machine coordinates
+    pub program_max: Option<[f64; 3]>,
+    pub within: bool,
+    pub exceeded: Vec<EnvelopeExcess>,
+}
+
+// ── Cutting data ───────────────────────────────────────────────────────────
+
+// Starting-point speeds and feeds for carbide end mills and drills, from
+// the workpiece material and the tool's diameter and flute count.
+
+// Programmed S or F further than this factor from the recommendation is an outlier.
+const CUTTING_DATA_OUTLIER_RATIO: f64 = 2.0;
+const CHIP_LOAD_RANGE: (f64, f64) = (0.005, 0.25); // mm per tooth
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WorkMaterial {
+    Aluminum,
+    Brass,
+    Plastic,
+    MildSteel,
+    StainlessSteel,
+    CastIron,
+    Titanium,
+}
+
+impl WorkMaterial {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().replace([' ', '-'], "_").as_str() {
+            "aluminum" | "aluminium" | "al" => Some(WorkMaterial::Aluminum),
+            "brass" | "bronze" => Some(WorkMaterial::Brass),
+            "plastic" | "pom" | "delrin" => Some(WorkMaterial::Plastic),
+            "steel" | "mild_steel" => Some(WorkMaterial::MildSteel),
+            "stainless" | "stainless_steel" => Some(WorkMaterial::StainlessSteel),
+            "cast_iron" => Some(WorkMaterial::CastIron),
+            "titanium" => Some(WorkMaterial::Titanium),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            WorkMaterial::Aluminum => "aluminum",
+            WorkMaterial::Brass => "brass",
+            WorkMaterial::Plastic => "plastic",
+            WorkMaterial::MildSteel => "mild_steel",
+            WorkMaterial::StainlessSteel => "stainless_steel",
+            WorkMaterial::CastIron => "cast_iron",
+            WorkMaterial::Titanium => "titanium",
+        }
+    }
+
+    // Roughing surface speed (m/min) and chip load per mm of tool diameter.
+    fn base_cutting_data(self) -> (f64, f64) {
+        match self {
+            WorkMaterial::Aluminum => (300.0, 0.008),
+            WorkMaterial::Brass => (150.0, 0.006),
+            WorkMaterial::Plastic => (250.0, 0.010),
+            WorkMaterial::MildSteel => (120.0, 0.004),
+            WorkMaterial::StainlessSteel => (80.0, 0.003),
+            WorkMaterial::CastIron => (100.0, 0.005),
+            WorkMaterial::Titanium => (50.0, 0.003),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CutOperation {
+    Roughing,
+    Finishing,
+    Slotting,
+    Drilling,
+}
+
+impl CutOperation {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "roughing" | "rough" | "" => Some(CutOperation::Roughing),
+            "finishing" | "finish" => Some(CutOperation::Finishing),
+            "slotting" | "slot" => Some(CutOperation::Slotting),
+            "drilling" | "drill" | "plunge" => Some(CutOperation::Drilling),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            CutOperation::Roughing => "roughing",
+            CutOperation::Finishing => "finishing",
+            CutOperation::Slotting => "slotting",
+            CutOperation::Drilling => "drilling",
+        }
+    }
+
+    // Factors on the roughing surface speed and chip load.
+    fn factors(self) -> (f64, f64) {
+        match self {
+            CutOperation::Roughing => (1.0, 1.0),
+            CutOperation::Finishing => (1.2, 0.6),
+            CutOperation::Slotting => (0.8, 0.7),
+            CutOperation::Drilling => (0.7, 1.0),
+        }
+    }
+}
+
+/// Suggested spindle speed and feed for one tool, material and operation.
+#[derive(Serialize, Clone, Debug)]
+pub struct CuttingRecommendation {
+    pub material: String,
+    pub operation: String,
+    pub diameter: f64,
+    pub flutes: u32,          // cutting edges used (drills always 2)
+    pub surface_speed: f64,   // m/min
+    pub chip_load: f64,       // mm per tooth
+    pub rpm: f64,
+    pub feed: f64,            // mm/min
+    pub rpm_limited: bool,    // held to the spindle maximum, feed scaled with it
+}
+
+// None for a tool without a diameter. `max_rpm` 0 leaves the speed unbounded.
+fn recommend_cutting(
+    material: WorkMaterial,
+    operation: CutOperation,
+    diameter: f64,
+    flutes: u32,
+    max_rpm: f64,
+) -> Option<CuttingRecommendation> {
+    if !diameter.is_finite() || diameter <= 1e-6 {
+        return None;
+    }
+    let (vc, chip_per_mm) = material.base_cutting_data();
+    let (vc_factor, chip_factor) = operation.factors();
+    let surface_speed = vc * vc_factor;
+    let chip_load = (chip_per_mm * diameter * chip_factor).clamp(CHIP_LOAD_RANGE.0, CHIP_LOAD_RANGE.1);
+    let flutes = match (operation, flutes) {
+        (CutOperation::Drilling, _) | (_, 0) => 2,
+        (_, n) => n,
+    };
+    let mut rpm = surface_speed * 1000.0 / (std::f64::consts::PI * diameter);
+    let rpm_limited = max_rpm > 0.0 && rpm > max_rpm;
+    if rpm_limited {
+        rpm = max_rpm;
+    }
+    Some(CuttingRecommendation {
+        material: material.name().to_string(),
+        operation: operation.name().to_string(),
+        diameter,
+        flutes,
+        surface_speed: rpm * std::f64::consts::PI * diameter / 1000.0,
+        chip_load,
+        rpm,
+        feed: rpm * flutes as f64 * chip_load,
+        rpm_limited,
+    })
+}
+
+/// Programmed spindle speed or feed far from the recommendation for the
+/// block's tool (more than `CUTTING_DATA_OUTLIER_RATIO` either way).
+#[derive(Serialize, Clone, Debug)]
+pub struct CuttingDataOutlier {
+    pub line: usize, // 1-based program line
+    pub tool: i32,
+    pub operation: String, // "roughing" for side cuts, "drilling" for plunges
+    pub quantity: String,  // "rpm" or "feed"
+    pub programmed: f64,
+    pub recommended: f64,
+}
+
+// ── O-word flow control ────────────────────────────────────────────────────
+
+// LinuxCNC-style structured blocks: `O100 SUB` .. `O100 ENDSUB` with
+// `O100 CALL [args]`, `IF`/`ELSEIF`/`ELSE`/`ENDIF`, `WHILE`/`ENDWHILE` with
+// `BREAK`/`CONTINUE`, and `REPEAT [n]`/`ENDREPEAT`. Conditions, arguments and
+// word values are `[...]` expressions over `#n` / `#<name>` parameters.
+
+const FLOW_MAX_DEPTH: usize = 64; // nested calls and repeats
+const CALL_ARG_PARAMS: usize = 30; // #1..#30 carry call arguments
+const MACRO_LOCAL_PARAMS: usize = 33; // G65/G66 local frame #1..#33
+
+// Memory behavior of a parameter, by number as on a Fanuc control.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ParamScope {
+    Local,      // #1..#33: per call frame, cleared when a program starts
+    Common,     // #100..#199: per channel, lost on power off
+    Persistent, // #500..#999: machine-wide, kept across power cycles
+    Other,      // named and remaining numbers: per channel
+}
+
+fn param_scope(key: &str) -> ParamScope {
+    match key.parse::<u32>() {
+        Ok(1..=33) => ParamScope::Local,
+        Ok(100..=199) => ParamScope::Common,
+        Ok(500..=999) => ParamScope::Persistent,
+        _ => ParamScope::Other,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OKeyword {
+    Sub,
+    EndSub,
+    Return,
+    Call,
+    If,
+    ElseIf,
+    Else,
+    EndIf,
+    While,
+    EndWhile,
+    Break,
+    Continue,
+    Repeat,
+    EndRepeat,
+}
+
+impl OKeyword {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "SUB" => OKeyword::Sub,
+            "ENDSUB" => OKeyword::EndSub,
+            "RETURN" => OKeyword::Return,
+            "CALL" => OKeyword::Call,
+            "IF" => OKeyword::If,
+            "ELSEIF" => OKeyword::ElseIf,
+            "ELSE" => OKeyword::Else,
+            "ENDIF" => OKeyword::EndIf,
+            "WHILE" => OKeyword::While,
+            "ENDWHILE" => OKeyword::EndWhile,
+            "BREAK" => OKeyword::Break,
+            "CONTINUE" => OKeyword::Continue,
+            "REPEAT" => OKeyword::Repeat,
+            "ENDREPEAT" => OKeyword::EndRepeat,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct OWord {
+    label: String, // "100" or "<NAME>"
+    keyword: OKeyword,
+    args: Vec<String>, // bracketed expressions, brackets included
+}
+
+#[derive(Clone, Debug)]
+enum FlowFrame {
+    // `passes`: further runs of the body left (M98 L).
+    Call { label: String, return_pc: usize, saved: Vec<(String, Option<f64>)>, passes: u64 },
+    Repeat { label: String, body_pc: usize, remaining: u64 },
+}
+
+// Fanuc G65 argument addresses and the parameters they set.
+const MACRO_ARG_LETTERS: [(char, usize); 21] = [
+    ('A', 1), ('B', 2), ('C', 3), ('I', 4), ('J', 5), ('K', 6), ('D', 7), ('E', 8), ('F', 9), ('H', 11), ('M', 13),
+    ('Q', 17), ('R', 18), ('S', 19), ('T', 20), ('U', 21), ('V', 22), ('W', 23), ('X', 24), ('Y', 25), ('Z', 26),
+];
+
+// G66 modal call: after each block that moves axes at call depth `depth`,
+// `label` runs with `args`. Moves inside the macro do not call it again.
+#[derive(Clone, Debug)]
+struct ModalMacroCall {
+    label: String,
+    args: Vec<(usize, f64)>,
+    depth: usize,
+}
+
+// The G65 (call), G66 (modal call) or G67 (cancel) word of a block.
+fn macro_call_code(line: &str) -> Option<i32> {
+    tokenize_block(line).iter().find_map(|t| match t {
+        BlockToken::Word('G', v) => {
+            let g: f64 = v.parse().ok()?;
+            [65, 66, 67].into_iter().find(|c| g == *c as f64)
+        }
+        _ => None,
+    })
+}
+
+// `G65 P<n> A.. B..` as a call of O<n> with (parameter, value) arguments.
+fn parse_macro_call(line: &str) -> Result<(String, Vec<(usize, f64)>), String> {
+    let mut label = None;
+    let mut args = Vec::new();
+    for token in tokenize_block(line) {
+        let BlockToken::Word(letter, text) = token else { continue };
+        let value: f64 = text.parse().map_err(|_| format!("macro call: bad {} value '{}'", letter, text))?;
+        match letter {
+            'G' | 'N' => {}
+            'P' => label = Some((value.round() as i64).to_string()),
+            _ => {
+                let (_, n) = MACRO_ARG_LETTERS
+                    .iter()
+                    .find(|(l, _)| *l == letter)
+                    .ok_or_else(|| format!("macro call cannot pass address {}", letter))?;
+                args.push((*n, value));
+            }
+        }
+    }
+    label.map(|l| (l, args)).ok_or_else(|| "macro call needs a P program number".to_string())
+}
+
+/// A named, versioned set of subroutines registered once for all channels.
+#[derive(Clone, Debug)]
+struct MacroPackage {
+    name: String,
+    version: String,
+    macros: Vec<(String, Vec<String>)>, // label, lines from SUB to ENDSUB
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MacroPackageInfo {
+    pub name: String,
+    pub version: String,
+    pub macros: Vec<String>, // labels, e.g. "9810" or "<PROBE_X>"
+}
+
+// Splits library source into its subroutines: LinuxCNC `O.. SUB` ... `O.. ENDSUB`
+// blocks and Fanuc `O9000` programs, which run to the next program number and
+// return with M99. Only blank lines, comments and `%` may sit between them.
+fn parse_macro_package(source: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mut macros: Vec<(String, Vec<String>)> = Vec::new();
+    let mut open: Option<(String, Vec<String>)> = None;
+    let mut fanuc: Option<(String, Vec<String>)> = None;
+    let close_fanuc = |macros: &mut Vec<(String, Vec<String>)>, body: Option<(String, Vec<String>)>| {
+        let Some((label, lines)) = body else { return Ok(()) };
+        if !lines.iter().any(|l| parse_fanuc_flow(l) == Some(FanucFlow::Return)) {
+            return Err(format!("O{} has no M99", label));
+        }
+        macros.push((label, lines));
+        Ok(())
+    };
+    for (i, raw) in source.lines().enumerate() {
+        let line = raw.trim().to_uppercase();
+        let word = parse_o_word(&line);
+        if let Some((label, lines)) = open.as_mut() {
+            lines.push(line);
+            if word.is_some_and(|w| w.keyword == OKeyword::EndSub && w.label == *label) {
+                macros.extend(open.take());
+            }
+            continue;
+        }
+        let number = program_number(&line);
+        if let Some((_, lines)) = fanuc.as_mut().filter(|_| word.is_none() && number.is_none()) {
+            lines.push(line);
+            continue;
+        }
+        close_fanuc(&mut macros, fanuc.take())?;
+        let label = word.as_ref().map(|w| w.label.clone()).or(number.map(|n| n.to_string()));
+        if label.as_ref().is_some_and(|l| macros.iter().any(|(m, _)| m == l)) {
+            return Err(format!("line {}: O{} defined twice", i + 1, label.unwrap_or_default()));
+        }
+        match (word, number) {
+            (Some(w), _) if w.keyword == OKeyword::Sub => open = Some((w.label, vec![line])),
+            (None, Some(n)) => fanuc = Some((n.to_string(), vec![line])),
+            _ if is_executable_line(&line) && line != "%" => {
+                return Err(format!("line {}: code outside a subroutine ({})", i + 1, line));
+            }
+            _ => {}
+        }
+    }
+    if let Some((label, _)) = open {
+        return Err(format!("O{} SUB has no ENDSUB", label));
+    }
+    close_fanuc(&mut macros, fanuc)?;
+    if macros.is_empty() {
+        return Err("no subroutines found".to_string());
+    }
+    Ok(macros)
+}
+
+// Leading N number of a block, after an optional block delete "/".
+fn block_number(line: &str) -> Option<u32> {
+    let rest = line.trim_start().trim_start_matches('/').trim_start();
+    let rest = rest.strip_prefix(['N', 'n'])?;
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    rest[..digits].parse().ok()
+}
+
+// Fanuc custom-macro control flow: `IF [c] GOTO n`, `IF [c] THEN #i=..`,
+// `GOTO n`, `WHILE [c] DOm` .. `ENDm` (a bare `DOm` loops forever), and
+// `M98 P.. L..` subprogram calls returning on `M99`. Conditions and targets
+// are expression text, evaluated when the block runs.
+#[derive(Clone, Debug, PartialEq)]
+enum FanucFlow {
+    Goto(String),
+    IfGoto(String, String),
+    IfThen(String, String),
+    While(Option<String>, u32),
+    End(u32),
+    Call,   // M98; its words are read after parameter substitution
+    Return, // M99
+}
+
+// Text after an optional block delete and N number.
+fn strip_block_number(line: &str) -> &str {
+    let rest = line.trim_start().trim_start_matches('/').trim_start();
+    match rest.strip_prefix(['N', 'n']) {
+        Some(after) if after.starts_with(|c: char| c.is_ascii_digit()) => {
+            after.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.').trim_start()
+        }
+        _ => rest,
+    }
+}
+
+// Splits `[...] rest` into the bracketed text (brackets included) and the rest.
+fn split_bracketed(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start();
+    if !text.starts_with('[') {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((&text[..=i], text[i + 1..].trim_start()));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Loop number of `DOm` / `ENDm` at the start of `text`.
+fn loop_number(text: &str, keyword: &str) -> Option<u32> {
+    let rest = text.get(..keyword.len()).filter(|k| k.eq_ignore_ascii_case(keyword)).map(|_| &text[keyword.len()..])?;
+    let digits = rest.trim_start();
+    let len = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+    digits[..len].parse().ok()
+}
+
+// Comment-free text of a GOTO target or THEN statement.
+fn statement_text(text: &str) -> String {
+    text.split(['(', ';']).next().unwrap_or("").trim().to_string()
+}
+
+fn parse_fanuc_flow(line: &str) -> Option<FanucFlow> {
+    let text = strip_block_number(line);
+    let upper = text.to_ascii_uppercase();
+    if let Some(rest) = upper.strip_prefix("IF") {
+        let (condition, rest) = split_bracketed(rest)?;
+        if let Some(target) = rest.strip_prefix("GOTO") {
+            return Some(FanucFlow::IfGoto(condition.to_string(), statement_text(target)));
+        }
+        let statement = rest.strip_prefix("THEN")?;
+        // Keep the statement's own text: comments and #<names> as written.
+        let at = text.len() - statement.len();
+        return Some(FanucFlow::IfThen(condition.to_string(), text[at..].trim().to_string()));
+    }
+    if let Some(target) = upper.strip_prefix("GOTO") {
+        return Some(FanucFlow::Goto(statement_text(target)));
+    }
+    if let Some(rest) = upper.strip_prefix("WHILE") {
+        let (condition, rest) = split_bracketed(rest)?;
+        return loop_number(rest, "DO").map(|m| FanucFlow::While(Some(condition.to_string()), m));
+    }
+    if let Some(m) = loop_number(&upper, "DO") {
+        return Some(FanucFlow::While(None, m));
+    }
+    if let Some(m) = loop_number(&upper, "END") {
+        return Some(FanucFlow::End(m));
+    }
+    let m_words: Vec<f64> = tokenize_block(text)
+        .iter()
+        .filter_map(|t| match t {
+            BlockToken::Word('M', v) => v.parse().ok(),
+            _ => None,
+        })
+        .collect();
+    if m_words.contains(&98.0) {
+        Some(FanucFlow::Call)
+    } else if m_words.contains(&99.0) {
+        Some(FanucFlow::Return)
+    } else {
+        None
+    }
+}
+
+// Program number of a bare `O1234` line (a Fanuc program or subprogram head).
+fn program_number(line: &str) -> Option<u64> {
+    let rest = strip_block_number(line).strip_prefix(['O', 'o'])?;
+    let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    (len > 0 && !is_executable_line(&rest[len..])).then(|| rest[..len].parse().ok())?
+}
+
+// Strips an optional N number; None unless the block is an O-word statement.
+fn parse_o_word(line: &str) -> Option<OWord> {
+    let mut rest = line.trim();
+    if rest.starts_with(['N', 'n']) {
+        rest = rest[1..].trim_start_matches(|c: char| c.is_ascii_digit() || c == '.').trim_start();
+    }
+    let rest = rest.strip_prefix(['O', 'o'])?.trim_start();
+    let label_len = if rest.starts_with('<') {
+        rest.find('>')? + 1
+    } else {
+        rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len())
+    };
+    if label_len == 0 {
+        return None;
+    }
+    let (label, rest) = rest.split_at(label_len);
+    let rest = rest.trim_start();
+    let name_len = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+    let keyword = OKeyword::from_name(&rest[..name_len].to_ascii_uppercase())?;
+    let mut args = Vec::new();
+    let mut chars = rest[name_len..].char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '(' | ';' => break,
+            '[' => {
+                let mut depth = 1;
+                let mut end = start + 1;
+                for (i, c) in chars.by_ref() {
+                    depth += match c {
+                        '[' => 1,
+                        ']' => -1,
+                        _ => 0,
+                    };
+                    end = i + 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                args.push(rest[name_len..][start..end].to_string());
+            }
+            _ => {}
+        }
+    }
+    Some(OWord { label: label.to_ascii_uppercase(), keyword, args })
+}
+
+// Parameter key: "1" for #1, "<NAME>" for #<name>.
+fn param_key(name: &str) -> String {
+    name.trim().to_ascii_uppercase()
+}
+
+// Recursive-descent evaluator for `[...]` expressions. Comparisons and logic
+// give 1.0 or 0.0; angles are in degrees. Precedence, lowest first:
+// - LinuxCNC/Mach3: AND/OR/XOR, comparisons (EQ NE GT GE LT LE), + -, * / MOD, **.
+// - Fanuc: comparisons, + - OR XOR, * / AND MOD, **, with AND/OR/XOR bitwise on
+//   integers and brackets nested at most FANUC_BRACKET_DEPTH deep.
+struct ExprParser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    params: &'a HashMap<String, f64>,
+    fanuc: bool,
+    depth: usize, // open brackets
+}
+
+const FANUC_BRACKET_DEPTH: usize = 5;
+
+impl<'a> ExprParser<'a> {
+    fn new(text: &str, params: &'a HashMap<String, f64>, dialect: Dialect) -> Self {
+        ExprParser { chars: text.chars().collect(), pos: 0, params, fanuc: dialect == Dialect::Fanuc, depth: 0 }
+    }
+
+    fn skip_ws(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_ws();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", c))
+        }
+    }
+
+    // Consumes `word` (case-insensitive) if it comes next.
+    fn keyword(&mut self, word: &str) -> bool {
+        self.skip_ws();
+        let end = self.pos + word.len();
+        let matches = end <= self.chars.len()
+            && self.chars[self.pos..end].iter().zip(word.chars()).all(|(a, b)| a.eq_ignore_ascii_case(&b));
+        if matches {
+            self.pos = end;
+        }
+        matches
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        if self.fanuc {
+            return self.comparison();
+        }
+        let mut value = self.comparison()?;
+        loop {
+            if self.keyword("AND") {
+                let rhs = self.comparison()?;
+                value = f64::from(value != 0.0 && rhs != 0.0);
+            } else if self.keyword("OR") {
+                let rhs = self.comparison()?;
+                value = f64::from(value != 0.0 || rhs != 0.0);
+            } else if self.keyword("XOR") {
+                let rhs = self.comparison()?;
+                value = f64::from((value != 0.0) != (rhs != 0.0));
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn comparison(&mut self) -> Result<f64, String> {
+        let (mut value, mut vacant) = self.operand()?;
+        loop {
+            let op = ["EQ", "NE", "GT", "GE", "LT", "LE"].into_iter().find(|op| self.keyword(op));
+            let Some(op) = op else { return Ok(value) };
+            let (rhs, rhs_vacant) = self.operand()?;
+            let result = match op {
+                // Fanuc: a vacant operand only equals another vacant one.
+                "EQ" if vacant || rhs_vacant => vacant && rhs_vacant,
+                "NE" if vacant || rhs_vacant => vacant != rhs_vacant,
+                "EQ" => value == rhs,
+                "NE" => value != rhs,
+                "GT" => value > rhs,
+                "GE" => value >= rhs,
+                "LT" => value < rhs,
+                _ => value <= rhs,
+            };
+            value = f64::from(result);
+            vacant = false;
+        }
+    }
+
+    // A comparison operand, and whether it is a lone vacant parameter (#0 or
+    // an unset number) under Fanuc rules.
+    fn operand(&mut self) -> Result<(f64, bool), String> {
+        self.skip_ws();
+        let start = self.pos;
+        let value = self.sum()?;
+        Ok((value, self.vacant_since(start)))
+    }
+
+    fn vacant_since(&self, start: usize) -> bool {
+        let text: String = self.chars[start..self.pos].iter().collect();
+        self.fanuc
+            && text.trim().strip_prefix('#').is_some_and(|n| {
+                !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()) && !self.params.contains_key(&param_key(n))
+            })
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut value = self.product()?;
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.pos += 1;
+                    value += self.product()?;
+                }
+                Some('-') => {
+                    self.pos += 1;
+                    value -= self.product()?;
+                }
+                _ if self.fanuc && self.keyword("OR") => value = bitwise("OR", value, self.product()?)?,
+                _ if self.fanuc && self.keyword("XOR") => value = bitwise("XOR", value, self.product()?)?,
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut value = self.power()?;
+        loop {
+            if self.peek() == Some('*') && self.chars.get(self.pos + 1) != Some(&'*') {
+                self.pos += 1;
+                value *= self.power()?;
+            } else if self.peek() == Some('/') {
+                self.pos += 1;
+                let rhs = self.power()?;
+                if rhs == 0.0 {
+                    return Err("division by zero".to_string());
+                }
+                value /= rhs;
+            } else if self.keyword("MOD") {
+                let rhs = self.power()?;
+                value = value.rem_euclid(rhs);
+            } else if self.fanuc && self.keyword("AND") {
+                value = bitwise("AND", value, self.power()?)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.unary()?;
+        if self.peek() == Some('*') && self.chars.get(self.pos + 1) == Some(&'*') {
+            self.pos += 2;
+            return Ok(base.powf(self.power()?));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(-self.unary()?)
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn bracketed(&mut self) -> Result<f64, String> {
+        let mut args = self.arguments()?;
+        if args.len() != 1 {
+            return Err(format!("expected one value, got {}", args.len()));
+        }
+        Ok(args.remove(0))
+    }
+
+    // `[a]` or `[a, b, ...]`.
+    fn arguments(&mut self) -> Result<Vec<f64>, String> {
+        self.expect('[')?;
+        self.depth += 1;
+        if self.fanuc && self.depth > FANUC_BRACKET_DEPTH {
+            return Err(format!("brackets nested deeper than {}", FANUC_BRACKET_DEPTH));
+        }
+        let mut args = vec![self.expression()?];
+        while self.peek() == Some(',') {
+            self.pos += 1;
+            args.push(self.expression()?);
+        }
+        self.expect(']')?;
+        self.depth -= 1;
+        Ok(args)
+    }
+
+    fn primary(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('[') => self.bracketed(),
+            Some('#') => self.parameter(),
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                text.parse().map_err(|_| format!("bad number '{}'", text))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_alphabetic()) {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect::<String>().to_ascii_uppercase();
+                let args = self.arguments()?;
+                let value = self.function(&name, &args)?;
+                if !value.is_finite() {
+                    return Err(format!("{}{:?} is undefined", name, args));
+                }
+                Ok(value)
+            }
+            other => Err(format!("unexpected {:?} in expression", other)),
+        }
+    }
+
+    fn function(&mut self, name: &str, args: &[f64]) -> Result<f64, String> {
+        let two = |args: &[f64]| match args {
+            [a, b] => Ok((*a, *b)),
+            _ => Err(format!("{} takes [a, b]", name)),
+        };
+        if matches!(name, "ATAN" | "ATN") {
+            // ATAN[y]/[x], ATAN[y, x] or ATAN[t]
+            let (y, x) = match args {
+                [y] if self.peek() == Some('/') => {
+                    self.pos += 1;
+                    (*y, self.bracketed()?)
+                }
+                [t] => (*t, 1.0),
+                _ => two(args)?,
+            };
+            let deg = y.atan2(x).to_degrees();
+            // Fanuc answers 0..360, LinuxCNC -180..180.
+            return Ok(if self.fanuc { deg.rem_euclid(360.0) } else { deg });
+        }
+        if name == "POW" {
+            let (a, b) = two(args)?;
+            return Ok(a.powf(b));
+        }
+        let [arg] = *args else { return Err(format!("{} takes one value", name)) };
+        Ok(match name {
+            "ABS" => arg.abs(),
+            "ACOS" => arg.acos().to_degrees(),
+            "ASIN" => arg.asin().to_degrees(),
+            "COS" => arg.to_radians().cos(),
+            "SIN" => arg.to_radians().sin(),
+            "TAN" => arg.to_radians().tan(),
+            "SQRT" | "SQR" => arg.sqrt(),
+            "EXP" => arg.exp(),
+            "LN" => arg.ln(),
+            "ROUND" | "RND" => arg.round(),
+            // Fanuc truncates toward zero (FIX) and away from it (FUP).
+            "FIX" if self.fanuc => arg.trunc(),
+            "FUP" if self.fanuc => arg.abs().ceil().copysign(arg),
+            "FIX" => arg.floor(),
+            "FUP" => arg.ceil(),
+            "BIN" | "BCD" => {
+                if arg < 0.0 || arg.fract() != 0.0 {
+                    return Err(format!("{} needs a non-negative integer", name));
+                }
+                let (from, to) = if name == "BIN" { (16, 10) } else { (10, 16) };
+                let (mut n, mut out, mut scale) = (arg as u64, 0u64, 1u64);
+                while n > 0 {
+                    let digit = n % from;
+                    if digit > 9 {
+                        return Err(format!("BIN[{}] is not BCD", arg));
+                    }
+                    out += digit * scale;
+                    n /= from;
+                    scale *= to;
+                }
+                out as f64
+            }
+            _ => return Err(format!("unknown function {}", name)),
+        })
+    }
+
+    // `#12` or `#<name>`; unset numbered parameters read as 0.
+    fn parameter(&mut self) -> Result<f64, String> {
+        self.expect('#')?;
+        let start = self.pos;
+        if self.chars.get(self.pos) == Some(&'<') {
+            while self.chars.get(self.pos).is_some_and(|c| *c != '>') {
+                self.pos += 1;
+            }
+            self.expect('>')?;
+            let name: String = self.chars[start..self.pos].iter().collect();
+            return self.params.get(&param_key(&name)).copied().ok_or_else(|| format!("parameter #{} is not set", name));
+        }
+        while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let number: String = self.chars[start..self.pos].iter().collect();
+        if number.is_empty() {
+            return Err("expected a parameter number or <name> after '#'".to_string());
+        }
+        Ok(self.params.get(&param_key(&number)).copied().unwrap_or(0.0))
+    }
+}
+
+// Fanuc AND/OR/XOR on the bits of integer operands.
+fn bitwise(op: &str, a: f64, b: f64) -> Result<f64, String> {
+    if a.fract() != 0.0 || b.fract() != 0.0 {
+        return Err(format!("{} needs integer operands", op));
+    }
+    let (a, b) = (a as i64, b as i64);
+    Ok(match op {
+        "AND" => a & b,
+        "OR" => a | b,
+        _ => a ^ b,
+    } as f64)
+}
+
+fn eval_expression(text: &str, params: &HashMap<String, f64>, dialect: Dialect) -> Result<f64, String> {
+    let mut parser = ExprParser::new(text, params, dialect);
+    let value = parser.expression()?;
+    match parser.peek() {
+        None => Ok(value),
+        Some(c) => Err(format!("unexpected '{}' in {}", c, text)),
+    }
+}
+
+// Takes the `#1 = [expr]` / `#<x> = 2` assignments out of a block, leaving
+// the other words. Every right-hand side is read with the old values.
+fn split_assignments(line: &str, params: &HashMap<String, f64>, dialect: Dialect) -> Result<(String, Vec<(String, f64)>), String> {
+    let mut parser = ExprParser::new(line, params, dialect);
+    let mut rest = String::with_capacity(line.len());
+    let mut assignments = Vec::new();
+    while let Some(&c) = parser.chars.get(parser.pos) {
+        match c {
+            '(' => {
+                while let Some(&c) = parser.chars.get(parser.pos) {
+                    rest.push(c);
+                    parser.pos += 1;
+                    if c == ')' {
+                        break;
+                    }
+                }
+            }
+            ';' => {
+                rest.extend(&parser.chars[parser.pos..]);
+                break;
+            }
+            '#' => {
+                let start = parser.pos;
+                parser.pos += 1;
+                if parser.chars.get(parser.pos) == Some(&'<') {
+                    while parser.chars.get(parser.pos).is_some_and(|c| *c != '>') {
+                        parser.pos += 1;
+                    }
+                    parser.pos += 1;
+                } else {
+                    while parser.chars.get(parser.pos).is_some_and(|c| c.is_ascii_digit()) {
+                        parser.pos += 1;
+                    }
+                }
+                let name_end = parser.pos.min(parser.chars.len());
+                if parser.peek() == Some('=') {
+                    let name: String = parser.chars[start + 1..name_end].iter().collect();
+                    if name.is_empty() {
+                        return Err("expected a parameter number or <name> after '#'".to_string());
+                    }
+                    if name == "0" {
+                        return Err("#0 is always vacant".to_string());
+                    }
+                    parser.pos += 1;
+                    parser.skip_ws();
+                    let rhs = parser.pos;
+                    let value = parser.expression()?;
+                    // Assigning a vacant value empties the parameter; NaN marks that.
+                    let value = if parser.vacant_since(rhs) { f64::NAN } else { value };
+                    assignments.push((param_key(&name), value));
+                } else {
+                    // A value reference; expanded later.
+                    rest.extend(&parser.chars[start..name_end]);
+                    parser.pos = name_end;
+                }
+            }
+            _ => {
+                rest.push(c);
+                parser.pos += 1;
+            }
+        }
+    }
+    Ok((rest, assignments))
+}
+
+// Replaces `#n`, `#<name>` and `[expr]` word values with numbers; comments
+// are left alone.
+fn expand_parameters(line: &str, params: &HashMap<String, f64>, dialect: Dialect) -> Result<String, String> {
+    if !line.contains(['#', '[']) {
+        return Ok(line.to_string());
+    }
+    let mut parser = ExprParser::new(line, params, dialect);
+    let mut out = String::with_capacity(line.len());
+    while let Some(&c) = parser.chars.get(parser.pos) {
+        match c {
+            '(' => {
+                while let Some(&c) = parser.chars.get(parser.pos) {
+                    out.push(c);
+                    parser.pos += 1;
+                    if c == ')' {
+                        break;
+                    }
+                }
+            }
+            ';' => {
+                out.extend(&parser.chars[parser.pos..]);
+                break;
+            }
+            '#' | '[' => {
+                let mut value = parser.primary()?;
+                // A sign written before the reference applies to its value.
+                if out.ends_with('-') {
+                    out.pop();
+                    value = -value;
+                } else if out.ends_with('+') {
+                    out.pop();
+                }
+                out.push_str(&format!("{}", value));
+            }
+            _ => {
+                out.push(c);
+                parser.pos += 1;
+            }
+        }
+    }
+    Ok(out)
+}
+
+// ── Program outline ────────────────────────────────────────────────────────
+
+#[derive(Serialize, Clone, Default)]
+pub struct BlockMetadata {
+    pub line: usize,
+    pub n_number: Option<u32>,
+    pub comments: Vec<String>, // comment text without delimiters, original case
+    pub tool_change: Option<i32>,
+    pub operation: Option<usize>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ProgramOperation {
+    pub index: usize,
+    pub name: String,
+    pub notes: Vec<String>,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub tool: Option<i32>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct ProgramOutline {
+    pub program_number: Option<u32>,
+    pub program_name: Option<String>, // comment on the O-number block
+    pub operations: Vec<ProgramOperation>,
+    pub blocks: Vec<BlockMetadata>, // only blocks carrying comments, N numbers or tool changes
+}
+
+impl ProgramOutline {
+    fn operation_at(&self, line: usize) -> Option<usize> {
+        self.operations.iter().find(|op| op.start_line <= line && line <= op.end_line).map(|op| op.index)
+    }
+}
+
+fn comment_text(raw: &str) -> String {
+    let t = raw.trim();
+    let t = t.strip_prefix(';').unwrap_or(t);
+    let t = t.strip_prefix('(').unwrap_or(t);
+    let t = t.strip_suffix(')').unwrap_or(t);
+    t.trim().to_string()
+}
+
+// Operations start at a comment-only block (its text names the operation; directly
+// following comment-only blocks become notes) or at a second tool change.
+fn build_program_outline(code: &str) -> ProgramOutline {
+    let mut outline = ProgramOutline::default();
+    let mut current: Option<usize> = None;
+    let mut last_was_comment_only = false;
+    let mut op_has_code = false;
+
+    for (line, raw) in code.lines().enumerate() {
+        let tokens = tokenize_block(raw.trim());
+        let comments: Vec<String> = tokens
+            .iter()
+            .filter_map(|t| match t {
+                BlockToken::Comment(c) => Some(comment_text(c)),
+                _ => None,
+            })
+            .filter(|c| !c.is_empty())
+            .collect();
+        let words: Vec<(char, f64)> = tokens
+            .iter()
+            .filter_map(|t| match t {
+                BlockToken::Word(l, v) => v.parse::<f64>().ok().map(|v| (*l, v)),
+                _ => None,
+            })
+            .collect();
+        let n_number = words.iter().find(|(l, _)| *l == 'N').map(|(_, v)| v.max(0.0).round() as u32);
+        let code_words = words.iter().filter(|(l, _)| *l != 'N' && *l != 'O').count();
+        let tool_change = words.iter().find(|(l, _)| *l == 'T').map(|(_, v)| v.round() as i32);
+
+        let o_number = words.iter().find(|(l, _)| *l == 'O').map(|(_, v)| v.max(0.0).round() as u32);
+        if o_number.is_some() && outline.program_number.is_none() {
+            outline.program_number = o_number;
+            outline.program_name = comments.first().cloned();
+            last_was_comment_only = false;
+        } else if code_words == 0 && !comments.is_empty() {
+            if last_was_comment_only && !op_has_code {
+                if let Some(op) = current.and_then(|i| outline.operations.get_mut(i)) {
+                    op.notes.extend(comments.iter().cloned());
+                }
+            } else {
+                if let Some(op) = current.and_then(|i| outline.operations.get_mut(i)) {
+                    op.end_line = line.saturating_sub(1).max(op.start_line);
+                }
+                let index = outline.operations.len();
+                outline.operations.push(ProgramOperation {
+                    index,
+                    name: comments[0].clone(),
+                    notes: comments[1..].to_vec(),
+                    start_line: line,
+                    end_line: line,
+                    tool: None,
+                });
+                current = Some(index);
+                op_has_code = false;
+            }
+            last_was_comment_only = true;
+        } else if code_words > 0 {
+            last_was_comment_only = false;
+            if let Some(t) = tool_change {
+                let needs_new = match current.and_then(|i| outline.operations.get(i)) {
+                    Some(op) => op.tool.is_some(),
+                    None => true,
+                };
+                if needs_new {
+                    if let Some(op) = current.and_then(|i| outline.operations.get_mut(i)) {
+                        op.end_line = line.saturating_sub(1).max(op.start_line);
+                    }
+                    let index = outline.operations.len();
+                    outline.operations.push(ProgramOperation {
+                        index,
+                        name: format!("T{}", t),
+                        notes: Vec::new(),
+                        start_line: line,
+                        end_line: line,
+                        tool: None,
+                    });
+                    current = Some(index);
+                }
+                if let Some(op) = current.and_then(|i| outline.operations.get_mut(i)) {
+                    op.tool = Some(t);
+                }
+            }
+            op_has_code = true;
+            if let Some(op) = current.and_then(|i| outline.operations.get_mut(i)) {
+                op.end_line = line;
+            }
+        }
+
+        if !comments.is_empty() || n_number.is_some() || tool_change.is_some() {
+            outline.blocks.push(BlockMetadata { line, n_number, comments, tool_change, operation: current });
+        }
+    }
+    outline
+}
+
+// ── Program formatter ──────────────────────────────────────────────────────
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct FormatOptions {
+    pub renumber: bool,
+    pub number_start: u32,
+    pub number_step: u32,
+    pub remove_redundant_modals: bool,
+    pub align_words: bool, // one space between words, uppercase addresses
+    pub strip_comments: bool,
+    pub strip_blank_lines: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            renumber: true,
+            number_start: 10,
+            number_step: 10,
+            remove_redundant_modals: true,
+            align_words: true,
+            strip_comments: false,
+            strip_blank_lines: false,
+        }
+    }
+}
+
+// Modal group of a G code for redundancy tracking, from the interpreter's
+// group tables; None for non-modal codes. Threading, rigid tapping, canned
+// cycles and G80 count as motion: each changes what a following block
+// without a G1/G0 does.
+fn g_modal_group(g: f64) -> Option<&'static str> {
+    let g10 = (g * 10.0).round() as i32;
+    if g10 == 331 {
+        return Some("motion");
+    }
+    if g10 % 10 != 0 {
+        return G_DECIMAL_MODAL_GROUPS.iter().find(|(_, codes)| codes.contains(&g10)).map(|(group, _)| *group);
+    }
+    let code = g10 / 10;
+    if code == 32 || (73..=89).contains(&code) {
+        return Some("motion");
+    }
+    let (group, _) = G_MODAL_GROUPS.iter().find(|(_, codes)| codes.contains(&code))?;
+    Some(if *group == "canned cycle" { "motion" } else { group })
+}
+
+// Lines control can reach from elsewhere or leave for elsewhere: O-word
+// statements, program heads, Fanuc branches, loops and calls, macro calls,
+// and N labels of a program that jumps to them.
+fn format_flow_boundary(line: &str, has_goto: bool) -> bool {
+    parse_o_word(line).is_some()
+        || program_number(line).is_some()
+        || parse_fanuc_flow(line).is_some()
+        || macro_call_code(line).is_some()
+        || (has_goto && block_number(line).is_some())
+}
+
+fn format_gcode(code: &str, opts: &FormatOptions) -> String {
+    let mut modal: HashMap<&'static str, f64> = HashMap::new();
+    let mut feed: Option<f64> = None;
+    let mut next_number = opts.number_start;
+    let mut out: Vec<String> = Vec::new();
+    let has_goto = code.to_ascii_uppercase().contains("GOTO");
+    let mut modal_macro = false; // G66: every move may run a macro
+    let mut reset_next = false;
+
+    for raw in code.lines() {
+        let trimmed = raw.trim();
+        if trimmed.is_empty() {
+            if !opts.strip_blank_lines {
+                out.push(String::new());
+            }
+            continue;
+        }
+        // Modal state is unknown across a flow boundary, so nothing on either
+        // side of one counts as redundant.
+        let boundary = modal_macro || format_flow_boundary(trimmed, has_goto);
+        if boundary || reset_next {
+            modal.clear();
+            feed = None;
+        }
+        reset_next = boundary;
+        match macro_call_code(trimmed) {
+            Some(66) => modal_macro = true,
+            Some(67) => modal_macro = false,
+            _ => {}
+        }
+        let mut tokens = tokenize_block(trimmed);
+        let original_len = tokens.len();
+        // A skipped block-delete line must not change what later lines inherit.
+        let optional = matches!(tokens.first(), Some(BlockToken::Other(s)) if s == "/");
+
+        if opts.renumber {
+            tokens.retain(|t| !matches!(t, BlockToken::Word('N', _)));
+        }
+        if opts.strip_comments {
+            tokens.retain(|t| !matches!(t, BlockToken::Comment(_)));
+        }
+        if opts.remove_redundant_modals {
+            let mut kept = Vec::with_capacity(tokens.len());
+            for t in tokens {
+                if let BlockToken::Word(letter, value) = &t {
+                    let v = value.parse::<f64>().unwrap_or(f64::NAN);
+                    if *letter == 'G' {
+                        if let Some(group) = g_modal_group(v) {
+                            if modal.get(group).is_some_and(|cur| (cur - v).abs() < 1e-9) {
+                                continue;
+                            }
+                            if optional {
+                                kept.push(t);
+                                continue;
+                            }
+                            if group == "units" {
+                                // F changes meaning with units; force the next F to be kept.
+                                feed = None;
+                            }
+                            modal.insert(group, v);
+                        }
+                    } else if *letter == 'F' {
+                        if feed.is_some_and(|cur| (cur - v).abs() < 1e-9) {
+                            continue;
+                        }
+                        if !optional {
+                            feed = Some(v);
+                        }
+                    }
+                }
+                kept.push(t);
+            }
+            tokens = kept;
+        }
+
+        let has_words = tokens.iter().any(|t| matches!(t, BlockToken::Word(..)));
+        let has_content = tokens.iter().any(|t| !matches!(t, BlockToken::Other(s) if s == "/"));
+        if !has_content {
+            // Block emptied by cleanup (only redundant modals or comments).
+            continue;
+        }
+        let is_tape_marker = tokens.iter().all(|t| matches!(t, BlockToken::Other(s) if s == "%"));
+        if opts.renumber && has_words && !is_tape_marker {
+            let pos = usize::from(matches!(tokens.first(), Some(BlockToken::Other(s)) if s == "/"));
+            tokens.insert(pos, BlockToken::Word('N', next_number.to_string()));
+            next_number = next_number.saturating_add(opts.number_step.max(1));
+        }
+
+        let changed = tokens.len() != original_len || opts.renumber;
+        if opts.align_words || changed {
+            out.push(render_block(&tokens));
+        } else {
+            out.push(trimmed.to_string());
+        }
+    }
+
+    let mut text = out.join("\n");
+    if code.ends_with('\n') {
+        text.push('\n');
+    }
+    text
+}
+
+/// What this build of the core supports (`Capabilities`); call at init so a
+/// UI can hide panels for subsystems that were not compiled in.
+#[wasm_bindgen]
+pub fn get_capabilities() -> JsValue {
+    serde_wasm_bindgen::to_value(&capabilities()).unwrap_or(JsValue::NULL)
+}
+
+/// Outline of a program without loading it: operations, comments and tool changes.
+#[wasm_bindgen]
+pub fn outline_program(code: String) -> JsValue {
+    serde_wasm_bindgen::to_value(&build_program_outline(&code)).unwrap_or(JsValue::NULL)
+}
+
+/// Formats a program: renumbering, redundant modal removal, word alignment and
+/// optional comment/blank-line stripping. `options` follows `FormatOptions`.
+#[wasm_bindgen]
+pub fn format_program(code: String, options: JsValue) -> String {
+    let opts: FormatOptions = serde_wasm_bindgen::from_value(options).unwrap_or_default();
+    format_gcode(&code, &opts)
+}
+
+#[wasm_bindgen]
+impl MachineBrain {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        let mut brain = Self {
+            axes: Vec::new(),
+            channels: Vec::new(),
+            estop: false,
+            feed_hold: false,
+            block_delete: false,
+            optional_stop: false,
+            active_wcs: 0,
+            local_shift: HashMap::new(),
+            is_homing: false,
+            work_offsets: default_work_offsets(),
+            homing_sequence: Vec::new(),
+            homing_index: 0,
+            homing_feed: 300.0,
+            homing_rapid: false,
+            jog: JogSelector::default(),
+            jogs: Vec::new(),
+            jog_direction: HashMap::new(),
+            manual_feed: DEFAULT_MANUAL_FEED_MM_MIN,
+            sim_time_s: 0.0,
+            sim_speed: 1.0,
+            block_time_s: 0.0,
+            utilization_spans: VecDeque::new(),
+            utilization_totals: UtilizationSummary::default(),
+            alarms: Vec::new(),
+            alarm_history: VecDeque::new(),
+            next_alarm_id: 1,
+            invariant_track: Vec::new(),
+            dialect: Dialect::Fanuc,
+            program_memory_budget: 0,
+            trace_tolerance: TRACE_DEFAULT_TOLERANCE_MM,
+            trace_tool_colors: HashMap::new(),
+            spindle_direction: "-Z".to_string(),
+            spindle_offset: [0.0; 3],
+            axis_calibration: HashMap::new(),
+            protected_zones: Vec::new(),
+            wcs_limits: Vec::new(),
+            events: VecDeque::new(),
+            next_event_id: 1,
+            last_interruption: None,
+            macro_packages: Vec::new(),
+            persistent_params: BTreeMap::new(),
+            door_open: false,
+            inputs: HashMap::new(),
+            hold_rules: Vec::new(),
+            capture_inputs: Vec::new(),
+            captures: VecDeque::new(),
+            word_limits: WordLimits::default(),
+            tolerances: ToleranceProfile::default(),
+            cutting_policy: CuttingPolicy::default(),
+            descent_limits: DescentLimits::default(),
+            workpiece_material: None,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            audit_log: VecDeque::new(),
+            next_audit_seq: 1,
+            edit_tag: String::new(),
+            edit_depth: 0,
+            access_level: AccessLevel::Admin,
+            access_keys: HashMap::new(),
+            permission_levels: HashMap::new(),
+            next_axis_id: 0,
+            stock: None,
+            block_hook: None,
+            logger: Logger::default(),
+            telemetry: Telemetry::default(),
+        };
+        log_at!(brain, Info, "core", "MachineBrain v6: Zero Control Ready");
+        brain
+    }
+
+    pub fn clear_config(&mut self) {
+        if !self.permitted(Permission::Settings, "clear_config") {
+            return;
+        }
+        self.axes.clear();
+        self.channels.clear();
+        self.feed_hold = false;
+        self.work_offsets = default_work_offsets();
+        self.active_wcs = 0;
+        self.is_homing = false;
+        self.homing_sequence.clear();
+        self.homing_index = 0;
+        self.homing_feed = 300.0;
+        self.homing_rapid = false;
+        self.next_axis_id = 0;
+    }
+
+    fn start_homing_sequence(&mut self, mut order: Vec<u32>, rapid: bool, feed: f64) {
+        if self.estop {
+            return;
+        }
+        order.retain(|id| find_axis(&self.axes, *id).is_some());
+        order.dedup();
+        if order.is_empty() {
+            self.is_homing = false;
+            self.homing_sequence.clear();
+            self.homing_index = 0;
+            return;
+        }
+        self.is_homing = true;
+        self.homing_sequence = order;
+        self.homing_index = 0;
+        self.homing_rapid = rapid;
+        self.homing_feed = feed.max(1.0);
+        // Start homing from a clean motion state so selected feed/rapid is respected.
+        for ax in self.axes.iter_mut() {
+            ax.velocity = 0.0;
+        }
+    }
+
+    fn machine_target_with_limits(&self, axis_id: u32, machine_target: f64) -> f64 {
+        let Some(ax) = find_axis(&self.axes, axis_id) else {
+            return machine_target;
+        };
+        limit_axis_target(ax, machine_target)
+    }
+
+    // Highest feed (mm/min) on an arc of radius `r` that keeps the centripetal
+    // acceleration v²/r within the plane axes' acceleration.
+    fn arc_centripetal_feed_limit(&self, x_id: u32, y_id: u32, r: f64) -> f64 {
+        let accel_mm_min_s = [x_id, y_id]
+            .iter()
+            .filter_map(|id| find_axis(&self.axes, *id))
+            .map(|ax| ax.accel.max(1.0))
+            .fold(f64::INFINITY, f64::min);
+        if !accel_mm_min_s.is_finite() || r <= 0.0 {
+            return 0.0;
+        }
+        let accel_mm_s2 = accel_mm_min_s / 60.0;
+        (accel_mm_s2 * r).sqrt() * 60.0
+    }
+
+    // Work-coordinate shift from tool tip to gauge point: under G43 the tool
+    // length back along the tool direction (+Z for a vertical tool), plus the
+    // X/Z offset of the selected turret station and the X/Y position of a
+    // gang-plate tool, which always apply.
+    fn length_comp_vector(&self, channel_index: usize) -> [f64; 3] {
+        let chan = &self.channels[channel_index];
+        let mut v = if chan.length_comp_active {
+            let d = chan.active_tool_direction().unwrap_or(ToolDirection::MinusZ).vector();
+            d.map(|v| -v * chan.tool_length)
+        } else {
+            [0.0; 3]
+        };
+        if let Some(turret) = &chan.turret {
+            let [x, z] = turret.offset();
+            v[0] += x;
+            v[2] += z;
+        }
+        if let Some(entry) = chan.tool_table.get(&chan.active_tool) {
+            v[0] += entry.plate_offset[0];
+            v[1] += entry.plate_offset[1];
+        }
+        v
+    }
+
+    // Allowed path deviation (arc chords) of the channel: G64 P or the profile.
+    fn path_tolerance(&self, channel_index: usize) -> f64 {
+        self.channels[channel_index].path_tolerance.unwrap_or(self.tolerances.arc_chord_mm)
+    }
+
+    fn comp_tolerance(&self, channel_index: usize) -> f64 {
+        self.channels[channel_index].comp_tolerance.unwrap_or(self.tolerances.comp_join_mm)
+    }
+
+    // Feed the executor uses for the channel's current motion, after override
+    // and the arc centripetal clamp.
+    fn effective_feed(&self, channel_index: usize) -> f64 {
+        let chan = &self.channels[channel_index];
+        if chan.current_motion == 0 {
+            return self.channel_rapid_feed(channel_index);
+        }
+        if matches!(chan.current_motion, 33 | 34) {
+            // Spindle-synchronized: feed override is locked at 100%.
+            let lead = Self::thread_lead_at(chan.thread_lead, chan.thread_lead_change, self.thread_progress(channel_index));
+            return chan.spindle_speed() * lead;
+        }
+        let feed = chan.feed_rate * chan.feed_override_ratio();
+        if matches!(chan.current_motion, 2 | 3) && chan.arc_feed_limit > 0.0 {
+            feed.min(chan.arc_feed_limit)
+        } else if chan.current_motion == 1 && chan.descent_feed_limit > 0.0 {
+            feed.min(chan.descent_feed_limit)
+        } else {
+            feed
+        }
+    }
+
+    // Path rapid (mm/min) of the channel's linear axes; deg/min for a rotary-only channel.
+    fn channel_rapid_feed(&self, channel_index: usize) -> f64 {
+        self.channel_rapid_feed_for(channel_index, AxisType::Linear)
+            .or_else(|| self.channel_rapid_feed_for(channel_index, AxisType::Rotary))
+            .unwrap_or(RAPID_LINEAR_MAX_MM_MIN)
+    }
+
+    // Slowest rapid among the channel's axes of one type (units of that type).
+    fn channel_rapid_feed_for(&self, channel_index: usize, kind: AxisType) -> Option<f64> {
+        let chan = self.channels.get(channel_index)?;
+        chan.axis_map
+            .iter()
+            .filter_map(|m| find_axis(&self.axes, m.axis_id))
+            .filter(|ax| ax.axis_type == kind)
+            .map(axis_rapid_feed)
+            .reduce(f64::min)
+    }
+
+    /// Adds an axis and returns its id; None without settings access.
+    pub fn add_axis(&mut self, name: String, kind: AxisType, min: f64, max: f64) -> Option<u32> {
+        if !self.permitted(Permission::Settings, "add_axis") {
+            return None;
+        }
+        let id = self.next_axis_id;
+        self.next_axis_id += 1;
+
+        for wcs in self.work_offsets.iter_mut() {
+            wcs.offsets.push(AxisOffset { axis_id: id, value: 0.0 });
+        }
+        // Same default chain assignment as the viewer: rotaries and Z3 carry the table.
+        let side = if matches!(name.to_uppercase().as_str(), "A" | "B" | "C" | "Z3") {
+            AxisSide::Table
+        } else {
+            AxisSide::Tool
+        };
+        self.axes.push(Axis {
+            id, physical_name: name, position: 0.0, target: 0.0, velocity: 0.0, accel: 0.0,
+            axis_type: kind, min_range: min, max_range: max, homed: false, invert: false, machine_zero: 0.0,
+            side, link_axis: None, rotary_mode: RotaryMode::Continuous, encoder: EncoderType::Incremental, feed: 0.0, load: 0.0,
+        });
+        Some(id)
+    }
+
+    /// Removes an axis and every reference to it (channel maps, work offsets,
+    /// WCS limits). Refused while homing or while a channel using it runs.
+    pub fn remove_axis(&mut self, axis_id: u32) -> bool {
+        let Some(pos) = self.axes.iter().position(|ax| ax.id == axis_id) else { return false; };
+        let users: Vec<usize> = (0..self.channels.len())
+            .filter(|c| self.channels[*c].axis_map.iter().any(|m| m.axis_id == axis_id))
+            .collect();
+        if self.is_homing || users.iter().any(|c| self.channels[*c].is_running) {
+            return false;
+        }
+        if !self.permitted(Permission::Settings, "remove_axis") {
+            return false;
+        }
+        for c in users {
+            let axis_map = self.channels[c].axis_map.iter().filter(|m| m.axis_id != axis_id).cloned().collect();
+            self.reassign_channel_axes(c, axis_map);
+        }
+        self.axes.remove(pos);
+        for wcs in self.work_offsets.iter_mut() {
+            wcs.offsets.retain(|o| o.axis_id != axis_id);
+        }
+        self.wcs_limits.retain(|l| l.axis_id != axis_id);
+        self.axis_calibration.remove(&axis_id);
+        true
+    }
+
+    /// Reorders `axes` (status and display order); `order` must list every
+    /// axis id once. Ids and references are unchanged.
+    pub fn reorder_axes(&mut self, order: Vec<u32>) -> bool {
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        let mut ids: Vec<u32> = self.axes.iter().map(|ax| ax.id).collect();
+        ids.sort_unstable();
+        if sorted != ids || !self.permitted(Permission::Settings, "reorder_axes") {
+            return false;
+        }
+        self.axes.sort_by_key(|ax| order.iter().position(|id| *id == ax.id));
+        true
+    }
+
+    pub fn add_channel(&mut self, id: u32, mappings: JsValue) {
+        let axis_map: Vec<ChannelAxisMap> = serde_wasm_bindgen::from_value(mappings).unwrap_or_default();
+        let mut chan = Channel::new(id, axis_map);
+        chan.arc_center_absolute = self.dialect.arc_center_absolute_default();
+        self.channels.push(chan);
+    }
+
+    /// Sets the modal state a channel powers on and resets to
+    /// (`ChannelDefaults` as JSON). An idle channel switches to it at once.
+    pub fn set_channel_defaults(&mut self, channel_index: usize, defaults: JsValue) -> bool {
+        let Ok(defaults) = serde_wasm_bindgen::from_value::<ChannelDefaults>(defaults) else { return false; };
+        if !defaults.is_valid() || channel_index >= self.channels.len() {
+            return false;
+        }
+        self.edit("channel_defaults", Permission::Settings, |brain| {
+            let chan = &mut brain.channels[channel_index];
+            chan.defaults = defaults;
+            if !chan.is_running {
+                chan.apply_modal_defaults();
+            }
+            true
+        })
+    }
+
+    /// Rear-turret lathe convention: X+ points away from the operator, so
+    /// G2/G3 and G41/G42 in the G18 plane run the other way round.
+    pub fn set_rear_turret(&mut self, channel_index: usize, rear: bool) -> bool {
+        if self.channels.get(channel_index).is_none_or(|c| c.is_running) {
+            return false;
+        }
+        self.edit("rear_turret", Permission::Settings, |brain| {
+            brain.channels[channel_index].rear_turret = rear;
+            true
+        })
+    }
+
+    pub fn get_channel_defaults(&self, channel_index: usize) -> JsValue {
+        match self.channels.get(channel_index) {
+            Some(chan) => serde_wasm_bindgen::to_value(&chan.defaults).unwrap_or(JsValue::NULL),
+            None => JsValue::NULL,
+        }
+    }
+
+    /// Removes a channel. A running channel is stopped first: its axes hold
+    /// position, its queue is dropped and its alarm conditions are cleared.
+    pub fn remove_channel(&mut self, channel_index: usize) -> bool {
+        if channel_index >= self.channels.len() || !self.permitted(Permission::Settings, "remove_channel") {
+            return false;
+        }
+        self.stop_channel_motion(channel_index);
+        let chan = self.channels.remove(channel_index);
+        let codes: Vec<String> = self.alarms.iter().filter(|a| a.channel == Some(chan.id)).map(|a| a.code.clone()).collect();
+        for code in codes {
+            self.clear_alarm_condition(&code, Some(chan.id));
+        }
+        true
+    }
+
+    /// Replaces a channel's axis assignment. Only while the channel is not
+    /// running; labels must be unique and axes must exist.
+    pub fn set_channel_axis_map(&mut self, channel_index: usize, mappings: JsValue) -> bool {
+        let Ok(axis_map) = serde_wasm_bindgen::from_value::<Vec<ChannelAxisMap>>(mappings) else { return false; };
+        if !self.permitted(Permission::Settings, "set_channel_axis_map") {
+            return false;
+        }
+        self.reassign_channel_axes(channel_index, axis_map)
+    }
+
+    pub fn relabel_channel_axis(&mut self, channel_index: usize, axis_id: u32, label: String) -> bool {
+        if !self.permitted(Permission::Settings, "relabel_channel_axis") {
+            return false;
+        }
+        let Some(chan) = self.channels.get(channel_index) else { return false; };
+        let mut axis_map = chan.axis_map.clone();
+        let Some(m) = axis_map.iter_mut().find(|m| m.axis_id == axis_id) else { return false; };
+        m.display_label = label;
+        self.reassign_channel_axes(channel_index, axis_map)
+    }
+
+    fn reassign_channel_axes(&mut self, channel_index: usize, axis_map: Vec<ChannelAxisMap>) -> bool {
+        let Some(chan) = self.channels.get(channel_index) else { return false; };
+        if chan.is_running {
+            return false;
+        }
+        let mut labels: Vec<String> = axis_map.iter().map(|m| m.display_label.trim().to_uppercase()).collect();
+        let valid = labels.iter().all(|l| !l.is_empty())
+            && axis_map.iter().all(|m| find_axis(&self.axes, m.axis_id).is_some());
+        labels.sort();
+        labels.dedup();
+        if !valid || labels.len() != axis_map.len() {
+            return false;
+        }
+        let chan = &mut self.channels[channel_index];
+        chan.axis_map = axis_map;
+        // Queued targets, cached geometry and the trace refer to the old axis order.
+        chan.pending.clear();
+        chan.retrace.clear();
+        chan.retracing = false;
+        chan.programmed_work.clear();
+        chan.comp_linear_prev = None;
+        chan.comp_entry_pending = false;
+        chan.trace.clear();
+        true
+    }
+
+    // Ends execution on a channel: program stopped, queues dropped, axes held.
+    fn stop_channel_motion(&mut self, c_idx: usize) {
+        let chan = &mut self.channels[c_idx];
+        chan.is_running = false;
+        chan.paused = false;
+        chan.pause_pending = false;
+        chan.pending.clear();
+        chan.rigid_tap = None;
+        chan.retrace.clear();
+        chan.retracing = false;
+        if chan.tool_change.as_ref().is_some_and(|e| e.phase != "DONE") {
+            chan.tool_change = None;
+        }
+        self.hold_channel_axes(c_idx);
+    }
+
+    /// Selects the controller dialect ("fanuc", "linuxcnc", "mach3") and applies
+    /// its defaults to all channels. Returns false for unknown names.
+    pub fn set_dialect(&mut self, name: String) -> bool {
+        self.edit("set_dialect", Permission::Settings, |brain| {
+            let Some(dialect) = Dialect::from_name(&name) else { return false; };
+            brain.dialect = dialect;
+            for chan in brain.channels.iter_mut() {
+                chan.arc_center_absolute = dialect.arc_center_absolute_default();
+            }
+            true
+        })
+    }
+
+    pub fn set_arc_center_absolute(&mut self, channel_index: usize, absolute: bool) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.arc_center_absolute = absolute;
+        }
+    }
+
+    /// Bounds used to validate F, S, T and D/H words (0 = unchecked).
+    pub fn set_word_limits(&mut self, spindle_max_rpm: f64, magazine_pockets: u32, offset_slots: u32) {
+        self.edit("set_word_limits", Permission::Settings, |brain| {
+            brain.word_limits = WordLimits { spindle_max_rpm: spindle_max_rpm.max(0.0), magazine_pockets, offset_slots };
+        })
+    }
+
+    /// Replaces the tolerance profile; missing fields take defaults. Returns
+    /// false (keeping the current profile) unless every value is positive.
+    pub fn set_tolerance_profile(&mut self, profile: JsValue) -> bool {
+        match serde_wasm_bindgen::from_value::<ToleranceProfile>(profile) {
+            Ok(p) => self.apply_tolerance_profile(p),
+            Err(_) => false,
+        }
+    }
+
+    pub fn get_tolerance_profile(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.tolerances).unwrap_or(JsValue::NULL)
+    }
+
+    fn apply_tolerance_profile(&mut self, profile: ToleranceProfile) -> bool {
+        self.edit("set_tolerance_profile", Permission::Settings, |brain| {
+            if !profile.is_valid() {
+                return false;
+            }
+            brain.tolerances = profile;
+            true
+        })
+    }
+
+    /// Replaces the cutting policy (see `CuttingPolicy`); missing fields take
+    /// defaults. False if the value does not parse.
+    pub fn set_cutting_policy(&mut self, policy: JsValue) -> bool {
+        let Ok(policy) = serde_wasm_bindgen::from_value::<CuttingPolicy>(policy) else { return false; };
+        if !self.permitted(Permission::Settings, "set_cutting_policy") {
+            return false;
+        }
+        self.cutting_policy = policy;
+        true
+    }
+
+    /// Sets `#n` ("5") or `#<name>` ("<depth>") for O-word programs.
+    pub fn set_parameter(&mut self, channel_index: usize, name: String, value: f64) -> bool {
+        let Some(chan) = self.channels.get_mut(channel_index) else { return false };
+        let key = param_key(name.trim().trim_start_matches('#'));
+        if key.is_empty() || !value.is_finite() {
+            return false;
+        }
+        if param_scope(&key) == ParamScope::Persistent {
+            return self.edit("set_parameter", Permission::Settings, |brain| {
+                brain.share_persistent_params(&[(key, value)]);
+                true
+            });
+        }
+        chan.params.insert(key, value);
+        true
+    }
+
+    /// Value of `#n` / `#<name>`; None while unset.
+    pub fn get_parameter(&self, channel_index: usize, name: String) -> Option<f64> {
+        let key = param_key(name.trim().trim_start_matches('#'));
+        self.channels.get(channel_index)?.params.get(&key).copied()
+    }
+
+    /// Clears the channel's parameters; persistent #500.. values stay.
+    pub fn clear_parameters(&mut self, channel_index: usize) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.params.retain(|key, _| param_scope(key) == ParamScope::Persistent);
+        }
+    }
+
+    /// Sets persistent parameter `#number` (500..=999) for every channel.
+    pub fn set_persistent_parameter(&mut self, number: u32, value: f64) -> bool {
+        if param_scope(&number.to_string()) != ParamScope::Persistent || !value.is_finite() {
+            return false;
+        }
+        self.edit("set_persistent_parameter", Permission::Settings, |brain| {
+            brain.share_persistent_params(&[(number.to_string(), value)]);
+            true
+        })
+    }
+
+    /// Persistent parameters as a number -> value map.
+    pub fn get_persistent_parameters(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.persistent_params).unwrap_or(JsValue::NULL)
+    }
+
+    /// Persistent parameters as JSON (`{"500": 1.5}`) for saving with a session.
+    pub fn export_persistent_parameters(&self) -> String {
+        serde_json::to_string(&self.persistent_params).unwrap_or_default()
+    }
+
+    /// Replaces the persistent parameters with an exported set. False if the
+    /// JSON does not parse or holds a number outside 500..=999.
+    pub fn import_persistent_parameters(&mut self, json: String) -> bool {
+        let Ok(params) = serde_json::from_str::<BTreeMap<u32, f64>>(&json) else { return false };
+        if params.iter().any(|(n, v)| param_scope(&n.to_string()) != ParamScope::Persistent || !v.is_finite()) {
+            return false;
+        }
+        self.edit("import_persistent_parameters", Permission::Settings, |brain| {
+            brain.replace_persistent_params(params);
+            true
+        })
+    }
+
+    /// Parameters of the channel as a name -> value map.
+    pub fn get_parameters(&self, channel_index: usize) -> JsValue {
+        let params = self.channels.get(channel_index).map(|c| c.params.clone()).unwrap_or_default();
+        serde_wasm_bindgen::to_value(&params).unwrap_or(JsValue::NULL)
+    }
+
+    pub fn get_cutting_policy(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.cutting_policy).unwrap_or(JsValue::NULL)
+    }
+
+    /// Plunge and ramp feed caps (see `DescentLimits`); 0 turns a limit off.
+    pub fn set_descent_limits(&mut self, plunge_feed: f64, ramp_feed: f64, ramp_angle_deg: f64) -> bool {
+        if !self.permitted(Permission::Settings, "set_descent_limits") {
+            return false;
+        }
+        self.descent_limits = DescentLimits {
+            plunge_feed: plunge_feed.max(0.0),
+            ramp_feed: ramp_feed.max(0.0),
+            ramp_angle_deg: ramp_angle_deg.clamp(0.0, 90.0),
+        };
+        true
+    }
+
+    pub fn get_descent_limits(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.descent_limits).unwrap_or(JsValue::NULL)
+    }
+
+    /// Workpiece material for cutting-data recommendations ("aluminum",
+    /// "brass", "plastic", "mild_steel", "stainless_steel", "cast_iron",
+    /// "titanium"); "" clears it. False for an unknown name.
+    pub fn set_workpiece_material(&mut self, name: String) -> bool {
+        let material = if name.trim().is_empty() {
+            None
+        } else {
+            match WorkMaterial::from_name(&name) {
+                Some(m) => Some(m),
+                None => return false,
+            }
+        };
+        if !self.permitted(Permission::Settings, "set_workpiece_material") {
+            return false;
+        }
+        self.workpiece_material = material;
+        true
+    }
+
+    pub fn get_workpiece_material(&self) -> String {
+        self.workpiece_material.map(|m| m.name().to_string()).unwrap_or_default()
+    }
+
+    /// Cutting edges of a tool-table slot, used for feed recommendations.
+    pub fn set_tool_flutes(&mut self, channel_index: usize, slot: i32, flutes: u32) -> bool {
+        self.edit("set_tool_flutes", Permission::ToolGeometry, |brain| {
+            let Some(chan) = brain.channels.get_mut(channel_index) else { return false; };
+            chan.tool_table.entry(slot.max(0)).or_default().flutes = flutes;
+            true
+        })
+    }
+
+    /// Suggested RPM and feed (`CuttingRecommendation`) for a tool-table slot
+    /// in the workpiece material. `operation` is "roughing", "finishing",
+    /// "slotting" or "drilling". Null without a material or tool diameter.
+    pub fn recommend_cutting_data(&self, channel_index: usize, slot: i32, operation: String) -> JsValue {
+        let data = self.cutting_recommendation(channel_index, slot, &operation);
+        serde_wasm_bindgen::to_value(&data).unwrap_or(JsValue::NULL)
+    }
+
+    fn cutting_recommendation(&self, channel_index: usize, slot: i32, operation: &str) -> Option<CuttingRecommendation> {
+        let tool = self.channels.get(channel_index)?.tool_table.get(&slot.max(0))?;
+        recommend_cutting(
+            self.workpiece_material?,
+            CutOperation::from_name(operation)?,
+            tool.total_radius() * 2.0,
+            tool.flutes,
+            self.word_limits.spindle_max_rpm,
+        )
+    }
+
+    // Live (surface speed m/min, chip load) of a channel from the actual
+    // spindle speed and feed. A G18 channel turns: the diameter is the X
+    // position and the load is per revolution. Zeros with the spindle off.
+    fn cutting_state(&self, channel_index: usize) -> (f64, f64) {
+        let chan = &self.channels[channel_index];
+        let rpm = chan.spindle_speed();
+        if !matches!(chan.spindle_mode, 3 | 4) || rpm <= 0.0 {
+            return (0.0, 0.0);
+        }
+        let tool = chan.tool_table.get(&chan.active_tool).copied().unwrap_or_default();
+        let (diameter, edges) = if chan.plane == 18 {
+            let x = chan.axis_map.iter().find(|m| m.display_label.eq_ignore_ascii_case("X"));
+            let radius = x
+                .and_then(|m| find_axis(&self.axes, m.axis_id).map(|ax| self.programmed_work_at(channel_index, m.axis_id, ax.position)))
+                .unwrap_or(0.0);
+            (2.0 * radius.abs(), 1)
+        } else {
+            (tool.total_radius() * 2.0, if tool.flutes == 0 { 2 } else { tool.flutes })
+        };
+        let surface_speed = std::f64::consts::PI * diameter * rpm / 1000.0;
+        let chip_load = chan.feed_rate * chan.feed_override_ratio() / (rpm * edges as f64);
+        (surface_speed, chip_load)
+    }
+
+    // ── Program control ────────────────────────────────────────────────────
+
+    /// Upper bound (bytes) for program text held in memory; 0 = unlimited.
+    /// Larger programs must be loaded with `load_program_streamed`.
+    pub fn set_program_memory_budget(&mut self, bytes: usize) {
+        self.program_memory_budget = bytes;
+    }
+
+    pub fn load_program(&mut self, channel_index: usize, code: String) {
+        if !self.permitted(Permission::Programs, "load_program") {
+            return;
+        }
+        if self.program_memory_budget > 0 && code.len() > self.program_memory_budget {
+            if let Some(chan_id) = self.channels.get(channel_index).map(|c| c.id) {
+                let message = format!(
+                    "Program of {} bytes exceeds the {} byte memory budget; load it streamed",
+                    code.len(),
+                    self.program_memory_budget
+                );
+                self.raise_alarm("PROGRAM_MEMORY", &message, AlarmSeverity::Fault, Some(chan_id));
+            }
+            return;
+        }
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.program = ProgramStore::from_code(&code);
+            chan.block_hits = vec![0; chan.program.len()];
+            chan.branch_hits.clear();
+            chan.call_hits.clear();
+            chan.block_numbers = chan.program.resident().map(|(_, line)| block_number(line)).collect();
+            chan.outline = build_program_outline(&code);
+        }
+        self.start_loaded_program(channel_index);
+    }
+
+    /// Loads a program of `total_lines` lines whose text is fetched on demand by
+    /// `fetch(start, count)` returning newline-separated lines. Only a window
+    /// around the pc is kept, within the memory budget. Coverage and outline are
+    /// not tracked for streamed programs.
+    pub fn load_program_streamed(&mut self, channel_index: usize, total_lines: usize, fetch: js_sys::Function) {
+        if !self.permitted(Permission::Programs, "load_program_streamed") {
+            return;
+        }
+        self.load_program_source(channel_index, total_lines, Rc::new(JsProgramSource(fetch)));
+    }
+
+    /// Registers JS callbacks run before and after every program block (see
+    /// `BlockHook`). Passing neither removes the hook.
+    pub fn set_block_hooks(&mut self, before: Option<js_sys::Function>, after: Option<js_sys::Function>) -> bool {
+        if !self.permitted(Permission::Settings, "set_block_hooks") {
+            return false;
+        }
+        let hook: Option<Rc<dyn BlockHook>> = match (before, after) {
+            (None, None) => None,
+            (before, after) => Some(Rc::new(JsBlockHook { before, after })),
+        };
+        self.block_hook = hook;
+        true
+    }
+
+    fn load_program_source(&mut self, channel_index: usize, total_lines: usize, source: Rc<dyn ProgramSource>) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.program = ProgramStore::streamed(total_lines, source);
+            chan.block_hits = Vec::new();
+            chan.branch_hits.clear();
+            chan.call_hits.clear();
+            chan.block_numbers = Vec::new();
+            chan.outline = ProgramOutline::default();
+        }
+        self.start_loaded_program(channel_index);
+    }
+
+    fn start_loaded_program(&mut self, channel_index: usize) {
+        let run_usage = RunUsage {
+            started_at_s: self.now_s(),
+            stock_box: self.stock_box(),
+            ..RunUsage::default()
+        };
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            self.feed_hold = false;
+            chan.pc = 0;
+            chan.active_pc = -1;
+            // Required parts reached: keep the program loaded but inhibit cycle start.
+            chan.is_running = !chan.parts_target_reached;
+            chan.paused = false;
+            chan.program_stop = None;
+            chan.program_end = None;
+            chan.finished = false;
+            chan.block_number = None;
+            chan.current_motion = 0;
+            chan.step_once = false;
+            chan.pause_pending = false;
+            chan.programmed_work.clear();
+            chan.comp_linear_prev = None;
+            chan.comp_entry_pending = false;
+            chan.overrides_program_locked = false;
+            chan.path_tolerance = None;
+            chan.comp_tolerance = None;
+            chan.injected.clear();
+            chan.flow.clear();
+            chan.modal_call = None;
+            chan.mirror.clear();
+            chan.offsets_at_start = self.work_offsets.clone();
+            chan.params.retain(|key, _| param_scope(key) != ParamScope::Local);
+            chan.params.extend(self.persistent_params.iter().map(|(n, v)| (n.to_string(), *v)));
+            chan.policy_warned = [false; 2];
+            chan.run_timers = RunTimers::default();
+            chan.run_usage = run_usage;
+            chan.assertions = AssertionReport::default();
+        }
+    }
+
+    pub fn toggle_pause(&mut self, channel_index: usize) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.paused = !chan.paused;
+        }
+    }
+
+    pub fn reset_program(&mut self, channel_index: usize) {
+        if channel_index < self.channels.len() {
+            self.local_shift.clear();
+        }
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.pc = 0;
+            chan.active_pc = -1;
+            chan.is_running = false;
+            chan.paused = false;
+            chan.program_stop = None;
+            chan.program_end = None;
+            chan.finished = false;
+            chan.block_number = None;
+            chan.step_once = false;
+            chan.pause_pending = false;
+            chan.programmed_work.clear();
+            chan.comp_linear_prev = None;
+            chan.comp_entry_pending = false;
+            chan.overrides_program_locked = false;
+            chan.path_tolerance = None;
+            chan.comp_tolerance = None;
+            chan.injected.clear();
+            chan.flow.clear();
+            chan.modal_call = None;
+            chan.mirror.clear();
+            chan.apply_modal_defaults();
+        }
+    }
+
+    pub fn set_feed_override(&mut self, channel_index: usize, ratio: f64) {
+        let Some(chan) = self.channels.get_mut(channel_index) else { return };
+        if chan.feed_override_enabled && !chan.overrides_program_locked {
+            chan.feed_override = ratio.clamp(0.0, 5.0);
+        } else {
+            let chan_id = chan.id;
+            self.emit_event("OVERRIDE_LOCKED", "Feed override is locked at 100%; change ignored", Some(chan_id));
+        }
+    }
+
+    pub fn set_spindle_override(&mut self, channel_index: usize, ratio: f64) {
+        let Some(chan) = self.channels.get_mut(channel_index) else { return };
+        if chan.spindle_override_enabled && !chan.overrides_program_locked {
+            chan.spindle_override = ratio.clamp(0.0, 2.0);
+        } else {
+            let chan_id = chan.id;
+            self.emit_event("OVERRIDE_LOCKED", "Spindle override is locked at 100%; change ignored", Some(chan_id));
+        }
+    }
+
+    /// Override enable switches; a disabled override runs at 100% and rejects
+    /// changes. Programs can also lock both with M49 (released by M48).
+    pub fn set_override_enable(&mut self, channel_index: usize, feed: bool, spindle: bool) {
+        if !self.permitted(Permission::Settings, "set_override_enable") {
+            return;
+        }
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.feed_override_enabled = feed;
+            chan.spindle_override_enabled = spindle;
+        }
     }
 
     pub fn set_feed_hold(&mut self, hold: bool) {
@@ -827,518 +9451,5708 @@ impl MachineBrain {
         }
     }
 
-    pub fn set_single_block(&mut self, channel_index: usize, enabled: bool) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            chan.single_block = enabled;
+    /// Operator panel block delete: program lines starting with "/" are skipped.
+    pub fn set_block_delete(&mut self, enabled: bool) {
+        self.block_delete = enabled;
+    }
+
+    /// Operator panel optional stop: M01 pauses the channel once the block completes.
+    pub fn set_optional_stop(&mut self, enabled: bool) {
+        self.optional_stop = enabled;
+    }
+
+    pub fn set_single_block(&mut self, channel_index: usize, enabled: bool) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.single_block = enabled;
+        }
+    }
+
+    /// Dry geometric interpretation: each tick runs blocks back to back with
+    /// the axes jumping to their targets, so logic-heavy programs can be
+    /// stepped at full speed. Single block, pauses and holds still stop it.
+    pub fn set_dry_geometric(&mut self, channel_index: usize, enabled: bool) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.dry_geometric = enabled;
+        }
+    }
+
+    // ── Macro library ─────────────────────────────────────────────────────
+
+    /// Registers a named, versioned package of `O.. SUB` ... `O.. ENDSUB`
+    /// macros (a probing library, say) for every channel. Programs call them
+    /// with `O<label> CALL` or `G65 P<n>`; a program's own subroutine of the same
+    /// label takes precedence. Registering an existing name replaces it; runs
+    /// that already linked a macro keep the old text. Returns the macro labels,
+    /// empty (with a MACRO_LIBRARY event) when the source is rejected, and
+    /// always in builds without the `macros` feature.
+    pub fn register_macro_package(&mut self, name: String, version: String, source: String) -> Vec<String> {
+        if !MACROS || !self.permitted(Permission::Programs, "register_macro_package") {
+            return Vec::new();
+        }
+        let name = name.trim().to_string();
+        let result = parse_macro_package(&source).and_then(|macros| {
+            let clash = self.macro_packages.iter().filter(|p| p.name != name).find_map(|p| {
+                macros.iter().find(|(label, _)| p.macros.iter().any(|(l, _)| l == label)).map(|(l, _)| (l, &p.name))
+            });
+            match clash {
+                Some((label, other)) => Err(format!("O{} is already provided by {}", label, other)),
+                None => Ok(macros),
+            }
+        });
+        let macros = match result {
+            Ok(macros) => macros,
+            Err(message) => {
+                self.emit_event("MACRO_LIBRARY", &format!("{} {} rejected: {}", name, version, message), None);
+                return Vec::new();
+            }
+        };
+        let labels: Vec<String> = macros.iter().map(|(label, _)| label.clone()).collect();
+        let package = MacroPackage { name, version, macros };
+        match self.macro_packages.iter_mut().find(|p| p.name == package.name) {
+            Some(existing) => *existing = package,
+            None => self.macro_packages.push(package),
+        }
+        labels
+    }
+
+    pub fn remove_macro_package(&mut self, name: String) -> bool {
+        if !self.permitted(Permission::Programs, "remove_macro_package") {
+            return false;
+        }
+        let before = self.macro_packages.len();
+        self.macro_packages.retain(|p| p.name != name.trim());
+        self.macro_packages.len() != before
+    }
+
+    /// Registered packages with their versions and macro labels.
+    pub fn get_macro_packages(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.macro_package_list()).unwrap_or(JsValue::NULL)
+    }
+
+    fn macro_package_list(&self) -> Vec<MacroPackageInfo> {
+        self.macro_packages
+            .iter()
+            .map(|p| MacroPackageInfo {
+                name: p.name.clone(),
+                version: p.version.clone(),
+                macros: p.macros.iter().map(|(label, _)| label.clone()).collect(),
+            })
+            .collect()
+    }
+
+    // ── Production counters ───────────────────────────────────────────────
+
+    pub fn set_part_target(&mut self, channel_index: usize, target: u32) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.part_target = target;
+            chan.parts_target_reached = target > 0 && chan.part_count >= target;
+        }
+    }
+
+    pub fn set_part_count_m_code(&mut self, channel_index: usize, m_code: i32) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.part_count_m_code = m_code.max(0);
+        }
+    }
+
+    pub fn reset_part_count(&mut self, channel_index: usize) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.part_count = 0;
+            chan.parts_target_reached = false;
+        }
+    }
+
+    pub fn reset_parts_total(&mut self, channel_index: usize) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.parts_total = 0;
+        }
+    }
+
+    fn count_completed_part(&mut self, c_idx: usize) {
+        let chan = &mut self.channels[c_idx];
+        chan.part_count = chan.part_count.saturating_add(1);
+        chan.parts_total = chan.parts_total.saturating_add(1);
+        if chan.part_target > 0 && chan.part_count >= chan.part_target {
+            // Required parts reached: finish this cycle and stop like a real control.
+            chan.parts_target_reached = true;
+            chan.finished = true;
+            chan.is_running = false;
+            chan.pending.clear();
+            chan.pause_pending = false;
+            chan.step_once = false;
+            log_at!(self, Info, "production", "Channel {}: required parts reached ({})", chan.id, chan.part_count);
+        }
+    }
+
+    pub fn step_once(&mut self, channel_index: usize) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            if !chan.is_running { return; }
+            chan.step_once = true;
+            chan.paused = false;
+        }
+    }
+
+    pub fn jump_blocks(&mut self, channel_index: usize, delta: i32) {
+        let Some(chan) = self.channels.get_mut(channel_index) else { return; };
+        if chan.program.is_empty() { return; }
+
+        let len = chan.program.len() as i32;
+        let mut next_pc = chan.pc as i32 + delta;
+        if next_pc < 0 { next_pc = 0; }
+        if next_pc > len { next_pc = len; }
+
+        chan.pc = next_pc as usize;
+        chan.active_pc = if chan.pc == 0 { -1 } else { (chan.pc - 1) as i32 };
+        chan.pending.clear();
+        chan.rigid_tap = None;
+        chan.injected.clear();
+        chan.flow.clear();
+        chan.modal_call = None;
+        chan.program_stop = None;
+        chan.program_end = None;
+        chan.pause_pending = false;
+        chan.step_once = false;
+        chan.paused = true;
+        chan.is_running = true;
+
+        for m in &chan.axis_map {
+            if let Some(ax) = find_axis_mut(&mut self.axes, m.axis_id) {
+                ax.velocity = 0.0;
+            }
+        }
+    }
+
+    pub fn get_program_outline(&self, channel_index: usize) -> JsValue {
+        let Some(chan) = self.channels.get(channel_index) else { return JsValue::NULL; };
+        serde_wasm_bindgen::to_value(&chan.outline).unwrap_or(JsValue::NULL)
+    }
+
+    /// Moves the channel to the first block of an outline operation (paused, like jump_blocks).
+    pub fn jump_to_operation(&mut self, channel_index: usize, operation: usize) -> bool {
+        let Some(chan) = self.channels.get(channel_index) else { return false; };
+        let Some(op) = chan.outline.operations.get(operation) else { return false; };
+        let delta = op.start_line as i32 - chan.pc as i32;
+        self.jump_blocks(channel_index, delta);
+        true
+    }
+
+    /// Sequence number search: restarts the loaded program at the first block
+    /// numbered `n` as `run_from_line` does (without the safe-start preamble).
+    /// False when no block carries that N or the program is streamed.
+    pub fn goto_block_number(&mut self, channel_index: usize, n: u32) -> bool {
+        let Some(chan) = self.channels.get(channel_index) else { return false; };
+        let Some(line) = chan.block_numbers.iter().position(|b| *b == Some(n)) else { return false; };
+        if !self.run_from_line(channel_index, line, false) {
+            return false;
+        }
+        self.channels[channel_index].block_number = Some(n);
+        true
+    }
+
+    /// Resumes the loaded program at `line` (0-based, as in the outline),
+    /// paused like `jump_blocks`. The blocks before it are scanned for their
+    /// modal state (units, distance mode, WCS and G52 shift, tool and offsets,
+    /// feed, macro parameters), which the channel takes over. With
+    /// `safe_start` a preamble is run first: retract Z, select WCS and tool,
+    /// start spindle and coolant, traverse to the entry point, rapid above it
+    /// and plunge to it at feed. False if the scan faults.
+    pub fn run_from_line(&mut self, channel_index: usize, line: usize, safe_start: bool) -> bool {
+        let Some(scan) = self.scan_to_line(channel_index, line) else { return false; };
+        let preamble = if safe_start { safe_start_preamble(&scan, channel_index) } else { Vec::new() };
+        let delta = line as i32 - self.channels[channel_index].pc as i32;
+        self.jump_blocks(channel_index, delta);
+        self.active_wcs = scan.active_wcs;
+        self.local_shift = scan.local_shift.clone();
+        let from = &scan.channels[channel_index];
+        let chan = &mut self.channels[channel_index];
+        chan.params = from.params.clone();
+        chan.abs_mode = from.abs_mode;
+        chan.units_mm = from.units_mm;
+        chan.plane = from.plane;
+        chan.diameter_mode = from.diameter_mode;
+        chan.mirror = from.mirror.clone();
+        chan.current_motion = from.current_motion;
+        chan.feed_rate = from.feed_rate;
+        chan.exact_stop = from.exact_stop;
+        chan.path_tolerance = from.path_tolerance;
+        chan.comp_tolerance = from.comp_tolerance;
+        chan.arc_center_absolute = from.arc_center_absolute;
+        chan.active_tool = from.active_tool;
+        chan.active_d = from.active_d;
+        chan.active_h = from.active_h;
+        chan.tool_length = from.tool_length;
+        chan.tool_radius = from.tool_radius;
+        chan.length_comp_active = from.length_comp_active;
+        chan.tool_table = from.tool_table.clone();
+        chan.turret = from.turret.clone();
+        chan.tool_changer = from.tool_changer.clone();
+        chan.pending_tool = from.pending_tool;
+        chan.cutter_comp = 40;
+        chan.comp_linear_prev = None;
+        chan.comp_entry_pending = false;
+        chan.programmed_work.clear();
+        chan.injected = preamble.into();
+        true
+    }
+
+    /// The safe-start preamble `run_from_line` would run before `line`.
+    pub fn get_safe_start_preamble(&self, channel_index: usize, line: usize) -> Vec<String> {
+        self.scan_to_line(channel_index, line)
+            .map(|scan| safe_start_preamble(&scan, channel_index))
+            .unwrap_or_default()
+    }
+
+    // Interprets the program up to `line` on a copy of the machine, motion
+    // completing instantly, and returns the copy.
+    fn scan_to_line(&self, channel_index: usize, line: usize) -> Option<MachineBrain> {
+        let chan = self.channels.get(channel_index)?;
+        if line >= chan.program.len() {
+            return None;
+        }
+        let mut scan = self.clone();
+        scan.block_hook = None;
+        scan.logger.silence();
+        scan.start_loaded_program(channel_index);
+        let chan = &mut scan.channels[channel_index];
+        chan.single_block = false;
+        chan.is_running = true;
+        chan.block_trace = None;
+        while scan.channels[channel_index].is_running
+            && (scan.channels[channel_index].pc < line || !scan.channels[channel_index].pending.is_empty())
+        {
+            scan.complete_channel_motion(channel_index);
+            scan.advance_channel(channel_index);
+        }
+        scan.complete_channel_motion(channel_index);
+        (scan.channels[channel_index].pc == line).then_some(scan)
+    }
+
+    pub fn set_tool_length(&mut self, channel_index: usize, length: f64) {
+        self.edit("set_tool_length", Permission::ToolGeometry, |brain| {
+            if let Some(chan) = brain.channels.get_mut(channel_index) {
+                chan.tool_length = length;
+                chan.active_h = 0;
+                let entry = chan.tool_table.entry(0).or_insert(ToolTableEntry::default());
+                entry.length = length;
+            }
+        })
+    }
+
+    pub fn set_tool_length_comp(&mut self, channel_index: usize, active: bool) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.length_comp_active = active;
+        }
+    }
+
+    pub fn set_tool_radius(&mut self, channel_index: usize, radius: f64) {
+        self.edit("set_tool_radius", Permission::ToolGeometry, |brain| {
+            if let Some(chan) = brain.channels.get_mut(channel_index) {
+                chan.tool_radius = radius.abs();
+                chan.active_d = 0;
+                let entry = chan.tool_table.entry(0).or_insert(ToolTableEntry::default());
+                entry.radius = chan.tool_radius;
+            }
+        })
+    }
+
+    pub fn set_tool_table_entry(&mut self, channel_index: usize, slot: i32, length: f64, radius: f64) {
+        self.edit("set_tool_table_entry", Permission::ToolGeometry, |brain| {
+            if let Some(chan) = brain.channels.get_mut(channel_index) {
+                let idx = slot.max(0);
+                let entry = chan.tool_table.entry(idx).or_default();
+                entry.radius = radius.abs();
+                entry.length = length;
+                let entry = *entry;
+                if chan.active_tool == idx {
+                    chan.tool_length = entry.total_length();
+                    chan.tool_radius = entry.total_radius();
+                }
+            }
+        })
+    }
+
+    /// Declares a tool as mounted in an aggregate (right-angle) head pointing
+    /// along `direction` ("+X", "-Y", ...); "" returns it to the spindle axis.
+    /// G43 then compensates along that direction and cutter comp works in
+    /// the plane normal to it.
+    pub fn set_tool_direction(&mut self, channel_index: usize, slot: i32, direction: String) -> bool {
+        let direction = if direction.trim().is_empty() {
+            None
+        } else {
+            match ToolDirection::from_name(&direction) {
+                Some(d) => Some(d),
+                None => return false,
+            }
+        };
+        self.edit("set_tool_direction", Permission::ToolGeometry, |brain| {
+            let Some(chan) = brain.channels.get_mut(channel_index) else { return false; };
+            chan.tool_table.entry(slot.max(0)).or_default().direction = direction;
+            true
+        })
+    }
+
+    /// Wear offsets of an existing tool-table slot (added to its geometry).
+    /// Allowed at a lower access level than geometry edits.
+    /// Declares a tool-table slot a setup tool: "edge_finder" (its radius is
+    /// the stylus' effective radius) or "indicator"; "" makes it a cutter again.
+    pub fn set_setup_tool(&mut self, channel_index: usize, slot: i32, kind: String) -> bool {
+        let kind = if kind.trim().is_empty() {
+            None
+        } else {
+            match SetupToolKind::from_name(&kind) {
+                Some(k) => Some(k),
+                None => return false,
+            }
+        };
+        self.edit("set_setup_tool", Permission::ToolGeometry, |brain| {
+            let Some(chan) = brain.channels.get_mut(channel_index) else { return false; };
+            let idx = slot.max(0);
+            chan.tool_table.entry(idx).or_default().setup = kind;
+            if idx > 0 && chan.active_tool == idx {
+                chan.tool_table.entry(0).or_default().setup = kind;
+            }
+            true
+        })
+    }
+
+    /// Edge finder gap/trip state or indicator runout of the channel's active tool.
+    pub fn get_setup_tool_reading(&self, channel_index: usize) -> JsValue {
+        let Some(reading) = self.setup_tool_reading(channel_index) else { return JsValue::NULL; };
+        serde_wasm_bindgen::to_value(&reading).unwrap_or(JsValue::NULL)
+    }
+
+    fn active_setup_tool(&self, c_idx: usize) -> Option<(SetupToolKind, ToolTableEntry)> {
+        let chan = self.channels.get(c_idx)?;
+        let entry = chan.tool_table.get(&chan.active_tool).copied()?;
+        Some((entry.setup?, entry))
+    }
+
+    // Stock box and protected zones the setup tool interacts with.
+    fn setup_targets(&self) -> Vec<(String, ZoneShape)> {
+        let stock = self.stock_box().map(|b| ("stock".to_string(), ZoneShape::Box { min: b.min, max: b.max }));
+        let zones = self.protected_zones.iter().filter(|_| COLLISION).map(|z| (z.name.clone(), z.shape.clone()));
+        stock.into_iter().chain(zones).collect()
+    }
+
+    // Tool tip of the channel in machine X/Y/Z for a tool of `length`.
+    fn tool_tip(&self, c_idx: usize, length: f64) -> Option<[f64; 3]> {
+        let chan = &self.channels[c_idx];
+        let gauge = channel_xyz(&chan.axis_map, |id| find_axis(&self.axes, id).map(|ax| ax.position))?;
+        let direction = chan.active_tool_direction().unwrap_or(ToolDirection::MinusZ).vector();
+        Some([0, 1, 2].map(|k| gauge[k] + direction[k] * length))
+    }
+
+    fn setup_tool_reading(&self, c_idx: usize) -> Option<SetupToolReading> {
+        let chan = self.channels.get(c_idx)?;
+        let Some((kind, entry)) = self.active_setup_tool(c_idx) else { return Some(SetupToolReading::default()) };
+        let tip = self.tool_tip(c_idx, entry.total_length())?;
+        let mut reading = SetupToolReading { kind: Some(kind), ..SetupToolReading::default() };
+        let radius = entry.total_radius();
+        for (name, shape) in self.setup_targets() {
+            let gap = shape.distance(tip) - radius;
+            if reading.gap.is_none_or(|g| gap < g) {
+                reading.gap = Some(gap);
+                reading.contact = Some(name);
+            }
+        }
+        match kind {
+            SetupToolKind::EdgeFinder => {
+                let spinning = matches!(chan.spindle_mode, 3 | 4) && chan.spindle_rpm > 0.0;
+                reading.tripped = spinning && reading.gap.is_some_and(|g| g <= EDGE_FINDER_TRIP_MM);
+            }
+            SetupToolKind::Indicator => {
+                // The vertical bore/boss the spindle axis is closest to the centre of.
+                let swept = self.protected_zones.iter().filter(|_| COLLISION).filter_map(|z| match z.shape {
+                    ZoneShape::Cylinder { axis: 2, center, radius, .. } => {
+                        let offset = (tip[0] - center[0]).hypot(tip[1] - center[1]);
+                        (offset < radius).then(|| (z.name.clone(), offset))
+                    }
+                    _ => None,
+                });
+                if let Some((name, offset)) = swept.min_by(|a, b| a.1.total_cmp(&b.1)) {
+                    reading.indicated = Some(name);
+                    reading.tir = Some(2.0 * offset);
+                }
+            }
+        }
+        Some(reading)
+    }
+
+    // A setup tool whose tip enters the stock or a fixture has crashed: fault
+    // once per entry and hold the channel's axes.
+    fn check_setup_tool(&mut self, c_idx: usize) {
+        let Some((kind, entry)) = self.active_setup_tool(c_idx) else {
+            self.channels[c_idx].setup_crashed = false;
+            return;
+        };
+        let Some(tip) = self.tool_tip(c_idx, entry.total_length()) else { return };
+        let hit = self.setup_targets().into_iter().find(|(_, shape)| shape.distance(tip) <= 0.0).map(|(name, _)| name);
+        let crashed = hit.is_some();
+        if let Some(name) = hit.filter(|_| !self.channels[c_idx].setup_crashed) {
+            let what = match kind {
+                SetupToolKind::EdgeFinder => "Edge finder",
+                SetupToolKind::Indicator => "Indicator",
+            };
+            let chan_id = self.channels[c_idx].id;
+            self.raise_alarm("SETUP_TOOL", &format!("{} driven into {}", what, name), AlarmSeverity::Fault, Some(chan_id));
+            self.hold_channel_axes(c_idx);
+        }
+        self.channels[c_idx].setup_crashed = crashed;
+    }
+
+    pub fn set_tool_wear(&mut self, channel_index: usize, slot: i32, length_wear: f64, radius_wear: f64) -> bool {
+        self.edit("set_tool_wear", Permission::ToolWear, |brain| {
+            let Some(chan) = brain.channels.get_mut(channel_index) else { return false; };
+            let idx = slot.max(0);
+            let Some(entry) = chan.tool_table.get_mut(&idx) else { return false; };
+            entry.length_wear = length_wear;
+            entry.radius_wear = radius_wear;
+            let entry = *entry;
+            if idx > 0 && chan.active_tool == idx {
+                chan.tool_length = entry.total_length();
+                chan.tool_radius = entry.total_radius();
+                chan.tool_table.insert(0, entry);
+            }
+            true
+        })
+    }
+
+    /// Fixed X/Y position of a tool on a gang plate. Selecting the tool with T
+    /// applies it straight away, with no index motion.
+    pub fn set_tool_plate_offset(&mut self, channel_index: usize, slot: i32, x: f64, y: f64) -> bool {
+        if !x.is_finite() || !y.is_finite() || slot <= 0 {
+            return false;
+        }
+        self.edit("set_tool_plate_offset", Permission::ToolGeometry, |brain| {
+            let Some(chan) = brain.channels.get_mut(channel_index) else { return false; };
+            chan.tool_table.entry(slot).or_default().plate_offset = [x, y];
+            true
+        })
+    }
+
+    /// Gives the channel a turret of `stations` stations taking `index_time_s`
+    /// per station stepped; `bidirectional` lets it index the shorter way.
+    /// Zero stations removes it.
+    pub fn set_turret(&mut self, channel_index: usize, stations: u32, index_time_s: f64, bidirectional: bool) -> bool {
+        if !index_time_s.is_finite() || index_time_s < 0.0 || channel_index >= self.channels.len() {
+            return false;
+        }
+        if !self.permitted(Permission::Settings, "set_turret") {
+            return false;
+        }
+        self.channels[channel_index].turret = (stations > 0).then(|| Turret::new(stations, index_time_s, bidirectional));
+        true
+    }
+
+    /// X/Z offset of a turret station, applied whenever the station is selected.
+    pub fn set_turret_offset(&mut self, channel_index: usize, station: u32, x: f64, z: f64) -> bool {
+        self.edit("set_turret_offset", Permission::ToolGeometry, |brain| {
+            let Some(turret) = brain.channels.get_mut(channel_index).and_then(|c| c.turret.as_mut()) else {
+                return false;
+            };
+            let Some(offset) = station.checked_sub(1).and_then(|i| turret.offsets.get_mut(i as usize)) else {
+                return false;
+            };
+            *offset = [x, z];
+            true
+        })
+    }
+
+    pub fn get_turret(&self, channel_index: usize) -> JsValue {
+        let turret = self.channels.get(channel_index).and_then(|c| c.turret.as_ref());
+        serde_wasm_bindgen::to_value(&turret).unwrap_or(JsValue::NULL)
+    }
+
+    /// Gives the channel an automatic tool changer taking `change_time_s` per
+    /// swap; from then on M6 travels to the change position and waits for the
+    /// swap. A negative time removes it (M6 swaps at once again).
+    pub fn set_tool_changer(&mut self, channel_index: usize, change_time_s: f64) -> bool {
+        if !change_time_s.is_finite() || channel_index >= self.channels.len() {
+            return false;
+        }
+        self.edit("set_tool_changer", Permission::Settings, |brain| {
+            let chan = &mut brain.channels[channel_index];
+            chan.pending_tool = None;
+            chan.tool_changer = match chan.tool_changer.take() {
+                _ if change_time_s < 0.0 => None,
+                Some(changer) => Some(ToolChanger { change_time_s, ..changer }),
+                None => Some(ToolChanger { change_time_s, position: Vec::new() }),
+            };
+            true
+        })
+    }
+
+    /// Machine position of one axis for tool changes (NaN drops the axis,
+    /// leaving it where M6 finds it).
+    pub fn set_tool_change_position(&mut self, channel_index: usize, axis_id: u32, machine_pos: f64) -> bool {
+        if find_axis(&self.axes, axis_id).is_none() {
+            return false;
+        }
+        self.edit("set_tool_change_position", Permission::Settings, |brain| {
+            let Some(changer) = brain.channels.get_mut(channel_index).and_then(|c| c.tool_changer.as_mut()) else {
+                return false;
+            };
+            changer.position.retain(|p| p.axis_id != axis_id);
+            if !machine_pos.is_nan() {
+                changer.position.push(AxisOffset { axis_id, value: machine_pos });
+            }
+            true
+        })
+    }
+
+    pub fn get_tool_changer(&self, channel_index: usize) -> JsValue {
+        let changer = self.channels.get(channel_index).and_then(|c| c.tool_changer.as_ref());
+        serde_wasm_bindgen::to_value(&changer).unwrap_or(JsValue::NULL)
+    }
+
+    pub fn set_active_tool(&mut self, channel_index: usize, slot: i32) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            let idx = slot.max(0);
+            chan.active_tool = idx;
+            chan.active_d = 0;
+            chan.active_h = 0;
+            if idx == 0 {
+                chan.tool_length = 0.0;
+                chan.tool_radius = 0.0;
+                chan.length_comp_active = false;
+                chan.cutter_comp = 40;
+                chan.tool_table.insert(0, ToolTableEntry::default());
+            } else if let Some(entry) = chan.tool_table.get(&idx).copied() {
+                chan.tool_length = entry.total_length();
+                chan.tool_radius = entry.total_radius();
+                // Keep D0/H0 in sync with currently loaded tool.
+                chan.tool_table.insert(0, entry);
+            }
+        }
+    }
+
+    pub fn set_cutter_comp(&mut self, channel_index: usize, mode: i32) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.cutter_comp = match mode {
+                41 => 41,
+                42 => 42,
+                _ => 40,
+            };
+            chan.comp_linear_prev = None;
+            chan.comp_entry_pending = false;
+        }
+    }
+
+    fn resolve_d_radius(&self, channel_index: usize, d_raw: f64, d_scaled: f64) -> f64 {
+        let Some(chan) = self.channels.get(channel_index) else {
+            return d_scaled.abs();
+        };
+
+        let idx = d_raw.round() as i32;
+        if let Some(entry) = chan.tool_table.get(&idx).filter(|_| (d_raw - idx as f64).abs() <= 1e-9) {
+            return entry.total_radius();
+        }
+        d_scaled.abs()
+    }
+
+    fn resolve_table_slot_index(raw: f64) -> Option<i32> {
+        let idx = raw.round() as i32;
+        if (raw - idx as f64).abs() <= 1e-9 {
+            Some(idx.max(0))
+        } else {
+            None
+        }
+    }
+
+    fn resolve_h_length(&self, channel_index: usize, h_raw: f64, h_scaled: f64) -> f64 {
+        let Some(chan) = self.channels.get(channel_index) else {
+            return h_scaled;
+        };
+
+        let idx = h_raw.round() as i32;
+        if let Some(entry) = chan.tool_table.get(&idx).filter(|_| (h_raw - idx as f64).abs() <= 1e-9) {
+            return entry.total_length();
+        }
+        h_scaled
+    }
+
+    /// Positions an axis at the manual-motion feed (see `set_manual_feed`).
+    pub fn move_to(&mut self, axis_id: u32, target: f64) {
+        self.move_to_at_feed(axis_id, target, self.manual_feed);
+    }
+
+    /// Positions an axis at `feed` (mm/min, deg/min for rotary), capped at
+    /// the axis's rapid rate. The move ramps with the axis acceleration like
+    /// program motion and leaves channel modals alone.
+    pub fn move_to_at_feed(&mut self, axis_id: u32, target: f64, feed: f64) -> bool {
+        if !(target.is_finite() && feed.is_finite() && feed > 0.0) {
+            return false;
+        }
+        let Some(ax) = find_axis_mut(&mut self.axes, axis_id) else { return false };
+        ax.target = limit_axis_target(ax, target);
+        let feed = feed.min(axis_rapid_feed(ax));
+        self.track_axis_move(JogState { axis_id, feed, rapid: false });
+        true
+    }
+
+    /// Default feed of API positioning moves (`move_to`).
+    pub fn set_manual_feed(&mut self, feed: f64) -> bool {
+        if !(feed.is_finite() && feed > 0.0) {
+            return false;
+        }
+        if !self.permitted(Permission::Settings, "set_manual_feed") {
+            return false;
+        }
+        self.manual_feed = feed;
+        true
+    }
+
+    pub fn get_manual_feed(&self) -> f64 {
+        self.manual_feed
+    }
+
+    // ── Homing ────────────────────────────────────────────────────────────
+
+    /// Simulated power cycle: every axis stops where it is, absolute-encoder
+    /// axes come back homed and incremental ones lose their reference. Local
+    /// and common parameters are lost; persistent ones (#500..) stay.
+    /// Returns the names of the axes that need homing; null while a program
+    /// runs or homing is in progress.
+    pub fn power_cycle(&mut self) -> JsValue {
+        match self.power_cycle_axes() {
+            Some(unhomed) => serde_wasm_bindgen::to_value(&unhomed).unwrap_or(JsValue::NULL),
+            None => JsValue::NULL,
+        }
+    }
+
+    fn power_cycle_axes(&mut self) -> Option<Vec<String>> {
+        if self.is_homing || self.channels.iter().any(|c| c.is_running) {
+            return None;
+        }
+        for ax in self.axes.iter_mut() {
+            ax.target = ax.position;
+            ax.velocity = 0.0;
+            ax.feed = 0.0;
+            ax.homed = ax.encoder == EncoderType::Absolute;
+        }
+        for chan in self.channels.iter_mut() {
+            chan.params.retain(|key, _| !matches!(param_scope(key), ParamScope::Local | ParamScope::Common));
+            chan.apply_modal_defaults();
+        }
+        Some(self.axes.iter().filter(|ax| !ax.homed).map(|ax| ax.physical_name.clone()).collect())
+    }
+
+    pub fn home_all(&mut self) {
+        if self.estop { return; }
+        let mut order: Vec<u32> = Vec::with_capacity(self.axes.len());
+        if let Some(z) = self.axes.iter().find(|ax| ax.physical_name.eq_ignore_ascii_case("Z")) {
+            order.push(z.id);
+        }
+        for ax in self.axes.iter() {
+            if !order.contains(&ax.id) {
+                order.push(ax.id);
+            }
+        }
+        for ax in self.axes.iter_mut() {
+            ax.target = 0.0;
+            ax.homed = false;
+        }
+        self.start_homing_sequence(order, false, 300.0);
+        log_at!(self, Info, "homing", "Homing all axes");
+    }
+
+    pub fn home_all_ordered(&mut self, primary_axis_id: i32, rapid: bool, feed: f64) {
+        if self.estop { return; }
+        let mut order: Vec<u32> = Vec::with_capacity(self.axes.len());
+        if primary_axis_id >= 0 {
+            let pid = primary_axis_id as u32;
+            if find_axis(&self.axes, pid).is_some() {
+                order.push(pid);
+            }
+        }
+        for ax in self.axes.iter() {
+            if !order.contains(&ax.id) {
+                order.push(ax.id);
+            }
+        }
+        for ax in self.axes.iter_mut() {
+            ax.target = 0.0;
+            ax.homed = false;
+        }
+        self.start_homing_sequence(order, rapid, feed);
+        log_at!(
+            self,
+            Info,
+            "homing",
+            "Homing ordered: primary={}, rapid={}, feed={}",
+            primary_axis_id,
+            rapid,
+            feed
+        );
+    }
+
+    pub fn home_axis(&mut self, axis_id: u32) {
+        if self.estop { return; }
+        if find_axis(&self.axes, axis_id).is_none() { return; }
+        if let Some(ax) = find_axis_mut(&mut self.axes, axis_id) {
+            ax.target = 0.0;
+            ax.homed = false;
+        }
+        self.start_homing_sequence(vec![axis_id], false, 300.0);
+    }
+
+    pub fn home_axis_ordered(&mut self, axis_id: u32, rapid: bool, feed: f64) {
+        if self.estop { return; }
+        if find_axis(&self.axes, axis_id).is_none() { return; }
+        if let Some(ax) = find_axis_mut(&mut self.axes, axis_id) {
+            ax.target = 0.0;
+            ax.homed = false;
+        }
+        self.start_homing_sequence(vec![axis_id], rapid, feed);
+        log_at!(
+            self,
+            Info,
+            "homing",
+            "Homing axis {}: rapid={}, feed={}",
+            axis_id,
+            rapid,
+            feed
+        );
+    }
+
+    // ── Jogging ───────────────────────────────────────────────────────────
+
+    pub fn jog_axis(&mut self, axis_id: u32, delta: f64) {
+        if self.estop { return; }
+        if let Some(ax) = find_axis_mut(&mut self.axes, axis_id) {
+            let next = ax.target + delta;
+            ax.target = limit_axis_target(ax, next);
+        }
+    }
+
+    /// Jogs an axis by `delta` at `feed`. The move runs on the axis's own
+    /// jog state; channel feed and motion modals are left alone.
+    pub fn jog_axis_feed(&mut self, axis_id: u32, delta: f64, feed: f64) {
+        self.start_jog(axis_id, delta, feed.max(1.0), false);
+    }
+
+    pub fn jog_axis_rapid(&mut self, axis_id: u32, delta: f64) {
+        let rapid_feed = find_axis(&self.axes, axis_id)
+            .map(axis_rapid_feed)
+            .unwrap_or(RAPID_LINEAR_MAX_MM_MIN);
+        self.start_jog(axis_id, delta, rapid_feed, true);
+    }
+
+    fn start_jog(&mut self, axis_id: u32, delta: f64, feed: f64, rapid: bool) {
+        if self.estop { return; }
+        let Some(ax) = find_axis_mut(&mut self.axes, axis_id) else { return };
+        let next = ax.target + delta;
+        ax.target = limit_axis_target(ax, next);
+        // Apply commanded jog feed immediately from panel.
+        ax.velocity = feed;
+        if delta != 0.0 {
+            self.jog_direction.insert(axis_id, delta.signum());
+        }
+        self.track_axis_move(JogState { axis_id, feed, rapid });
+    }
+
+    fn track_axis_move(&mut self, jog: JogState) {
+        match self.jogs.iter_mut().find(|j| j.axis_id == jog.axis_id) {
+            Some(existing) => *existing = jog,
+            None => self.jogs.push(jog),
+        }
+    }
+
+    pub fn get_jog_states(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.jogs).unwrap_or(JsValue::NULL)
+    }
+
+    /// Replaces the pendant jog-speed table: rates in percent of rapid,
+    /// each in (0, 100]. Kept sorted; the selection moves to the nearest
+    /// remaining entry.
+    pub fn set_jog_feed_table(&mut self, percents: Vec<f64>) -> bool {
+        if percents.is_empty() || percents.iter().any(|p| !(p.is_finite() && *p > 0.0 && *p <= 100.0)) {
+            return false;
+        }
+        if !self.permitted(Permission::Settings, "set_jog_feed_table") {
+            return false;
+        }
+        let mut table = percents;
+        table.sort_by(f64::total_cmp);
+        table.dedup();
+        self.jog.selected = self.jog.selected.min(table.len() - 1);
+        self.jog.feed_table = table;
+        true
+    }
+
+    /// Selects a jog-speed table entry, as the pendant's rate switch does.
+    pub fn select_jog_feed(&mut self, index: usize) -> bool {
+        if index >= self.jog.feed_table.len() {
+            return false;
+        }
+        self.jog.selected = index;
+        true
+    }
+
+    /// Rapid key: while set, jogs run at full rapid whatever the selection.
+    pub fn set_jog_rapid(&mut self, rapid: bool) {
+        self.jog.rapid = rapid;
+    }
+
+    pub fn get_jog_selector(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.jog).unwrap_or(JsValue::NULL)
+    }
+
+    /// Jogs an axis by `delta` at the selected pendant rate.
+    pub fn jog_axis_selected(&mut self, axis_id: u32, delta: f64) {
+        let Some(feed) = find_axis(&self.axes, axis_id).map(|ax| self.jog.feed_for(ax)) else { return };
+        self.jog_axis_feed(axis_id, delta, feed);
+    }
+
+    // ── Work Zeros ────────────────────────────────────────────────────────
+
+    pub fn set_work_zero(&mut self, axis_id: u32, wcs_index: usize, machine_pos: f64) {
+        self.edit("set_work_zero", Permission::Offsets, |brain| {
+            let Some(wcs) = brain.work_offsets.get_mut(wcs_index) else { return };
+            if let Some(off) = wcs.offsets.iter_mut().find(|o| o.axis_id == axis_id) {
+                off.value = machine_pos;
+                log_at!(brain, Info, "offsets", "WCS {} axis {} offset = {}", wcs.label, axis_id, machine_pos);
+            }
+        })
+    }
+
+    /// Sets the WCS offset so the current position reads `desired_work_value`.
+    /// With `tool_compensated` the active tool counts: its length along the
+    /// tool axis, and for the other axes its radius (edge finder, probe ball)
+    /// on the side the axis was last jogged towards. False when the axis is
+    /// not on a channel, the WCS cannot be edited, or that side is unknown.
+    pub fn touch_off(&mut self, axis_id: u32, wcs_index: usize, desired_work_value: f64, tool_compensated: bool) -> bool {
+        self.edit("touch_off", Permission::Offsets, |brain| {
+            if !brain.work_offset_editable(wcs_index) || !desired_work_value.is_finite() {
+                return false;
+            }
+            let Some(contact) = brain.touch_off_contact(axis_id, tool_compensated) else { return false };
+            let wcs = &mut brain.work_offsets[wcs_index];
+            let Some(off) = wcs.offsets.iter_mut().find(|o| o.axis_id == axis_id) else { return false };
+            off.value = contact - desired_work_value;
+            let (label, value) = (wcs.label.clone(), off.value);
+            log_at!(brain, Info, "offsets", "WCS {} axis {} touched off at {}: offset = {}", label, axis_id, desired_work_value, value);
+            true
+        })
+    }
+
+    // Machine coordinate of the touched point on `axis_id`: the axis position,
+    // moved to the tool tip or the contacted side of the tool when compensated.
+    fn touch_off_contact(&self, axis_id: u32, tool_compensated: bool) -> Option<f64> {
+        let ax = find_axis(&self.axes, axis_id)?;
+        let chan = self.channels.iter().find(|c| c.axis_map.iter().any(|m| m.axis_id == axis_id))?;
+        if !tool_compensated || ax.axis_type == AxisType::Rotary {
+            return Some(ax.position);
+        }
+        let label = chan.axis_map.iter().find(|m| m.axis_id == axis_id).map(|m| m.display_label.trim().to_uppercase());
+        let Some(k) = ["X", "Y", "Z"].iter().position(|l| label.as_deref() == Some(*l)) else {
+            return Some(ax.position);
+        };
+        let tool = chan.tool_table.get(&chan.active_tool).copied().unwrap_or_default();
+        let direction = chan.active_tool_direction().unwrap_or(ToolDirection::MinusZ).vector();
+        if direction[k] != 0.0 {
+            return Some(ax.position + direction[k] * tool.total_length());
+        }
+        let side = self.jog_direction.get(&axis_id)?;
+        Some(ax.position + side * tool.total_radius())
+    }
+
+    pub fn set_active_wcs(&mut self, wcs_index: usize) {
+        if wcs_index < self.work_offsets.len() {
+            self.active_wcs = wcs_index;
+        }
+    }
+
+    /// Index of the new WCS; None when access is denied.
+    pub fn add_work_offset(&mut self, label: String) -> Option<usize> {
+        self.edit("add_work_offset", Permission::Offsets, |brain| {
+            let mut offsets = Vec::with_capacity(brain.axes.len());
+            for ax in &brain.axes {
+                offsets.push(AxisOffset { axis_id: ax.id, value: 0.0 });
+            }
+            brain.work_offsets.push(WorkOffset { label, offsets });
+            Some(brain.work_offsets.len() - 1)
+        })
+    }
+
+    /// Copies all axis values of one WCS into another (labels are kept).
+    pub fn copy_work_offset(&mut self, from: usize, to: usize) -> bool {
+        self.edit("copy_work_offset", Permission::Offsets, |brain| {
+            if !brain.work_offset_editable(to) {
+                return false;
+            }
+            let Some(src) = brain.work_offsets.get(from).map(|w| w.offsets.clone()) else { return false; };
+            brain.work_offsets[to].offsets = src;
+            true
+        })
+    }
+
+    /// Adds `delta` to one axis of every editable WCS (G153 stays at machine zero),
+    /// e.g. "shift everything +0.05 in Z". Returns the number of offsets changed.
+    pub fn shift_work_offsets(&mut self, axis_id: u32, delta: f64) -> usize {
+        self.edit("shift_work_offsets", Permission::Offsets, |brain| {
+            let indices: Vec<usize> = (0..brain.work_offsets.len()).filter(|i| brain.work_offset_editable(*i)).collect();
+            indices.into_iter().filter(|i| brain.shift_work_offset(*i, axis_id, delta)).count()
+        })
+    }
+
+    pub fn shift_work_offset(&mut self, wcs_index: usize, axis_id: u32, delta: f64) -> bool {
+        self.edit("shift_work_offset", Permission::Offsets, |brain| {
+            if !brain.work_offset_editable(wcs_index) || !delta.is_finite() {
+                return false;
+            }
+            match brain.work_offsets[wcs_index].offsets.iter_mut().find(|o| o.axis_id == axis_id) {
+                Some(off) => {
+                    off.value += delta;
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Exchanges the axis values of two WCS (labels stay with their index).
+    pub fn swap_work_offsets(&mut self, a: usize, b: usize) -> bool {
+        self.edit("swap_work_offsets", Permission::Offsets, |brain| {
+            if !brain.work_offset_editable(a) || !brain.work_offset_editable(b) {
+                return false;
+            }
+            let values_a = std::mem::take(&mut brain.work_offsets[a].offsets);
+            let values_b = std::mem::replace(&mut brain.work_offsets[b].offsets, values_a);
+            brain.work_offsets[a].offsets = values_b;
+            true
+        })
+    }
+
+    pub fn rename_work_offset(&mut self, wcs_index: usize, label: String) -> bool {
+        self.edit("rename_work_offset", Permission::Offsets, |brain| {
+            let label = label.trim().to_string();
+            if label.is_empty() || label == "G153" || !brain.work_offset_editable(wcs_index) {
+                return false;
+            }
+            brain.work_offsets[wcs_index].label = label;
+            true
+        })
+    }
+
+    // G153 is the machine coordinate system and never carries an offset.
+    fn work_offset_editable(&self, wcs_index: usize) -> bool {
+        self.work_offsets.get(wcs_index).is_some_and(|w| w.label != "G153")
+    }
+
+    // ── E-Stop ────────────────────────────────────────────────────────────
+
+    pub fn set_estop(&mut self, s: bool) {
+        self.estop = s;
+        if s {
+            self.raise_alarm("ESTOP", "Emergency stop triggered", AlarmSeverity::Fault, None);
+            self.feed_hold = false;
+            for chan in self.channels.iter_mut() {
+                chan.is_running = false;
+                chan.paused = false;
+                chan.pending.clear();
+                chan.rigid_tap = None;
+                chan.pause_pending = false;
+                chan.step_once = false;
+                chan.active_pc = -1;
+            }
+            for ax in self.axes.iter_mut() {
+                ax.target = ax.position;
+                ax.velocity = 0.0;
+            }
+        } else {
+            self.clear_alarm_condition("ESTOP", None);
+        }
+    }
+
+    // ── Alarms ────────────────────────────────────────────────────────────
+
+    pub fn get_alarms(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.alarms).unwrap_or(JsValue::NULL)
+    }
+
+    pub fn get_alarm_history(&self) -> JsValue {
+        let history: Vec<Alarm> = self.alarm_history.iter().cloned().collect();
+        serde_wasm_bindgen::to_value(&history).unwrap_or(JsValue::NULL)
+    }
+
+    pub fn acknowledge_alarm(&mut self, alarm_id: u32) -> bool {
+        let now = self.now_s();
+        let Some(idx) = self.alarms.iter().position(|a| a.id == alarm_id) else {
+            return false;
+        };
+        let alarm = &mut self.alarms[idx];
+        if !alarm.acknowledged {
+            alarm.acknowledged = true;
+            alarm.acknowledged_at_s = Some(now);
+        }
+        if !alarm.active {
+            let closed = self.alarms.remove(idx);
+            self.push_alarm_history(closed);
+        }
+        true
+    }
+
+    pub fn acknowledge_all_alarms(&mut self) {
+        let ids: Vec<u32> = self.alarms.iter().map(|a| a.id).collect();
+        for id in ids {
+            self.acknowledge_alarm(id);
+        }
+    }
+
+    /// Open alarms followed by closed history, as a JSON array for persistence.
+    pub fn export_alarm_history(&self) -> String {
+        let all: Vec<&Alarm> = self.alarms.iter().chain(self.alarm_history.iter()).collect();
+        serde_json::to_string(&all).unwrap_or_else(|_| "[]".to_string())
+    }
+
+    /// Restore a previously exported history. Restored alarms are closed records;
+    /// live conditions are re-raised by the machine itself.
+    pub fn import_alarm_history(&mut self, json: &str) -> bool {
+        let Ok(mut restored) = serde_json::from_str::<Vec<Alarm>>(json) else {
+            return false;
+        };
+        restored.sort_by(|a, b| a.raised_at_s.total_cmp(&b.raised_at_s));
+        self.alarm_history.clear();
+        for mut alarm in restored {
+            alarm.active = false;
+            self.next_alarm_id = self.next_alarm_id.max(alarm.id.wrapping_add(1));
+            self.push_alarm_history(alarm);
+        }
+        true
+    }
+
+    pub fn clear_alarm_history(&mut self) {
+        self.alarm_history.clear();
+    }
+
+    fn push_alarm_history(&mut self, alarm: Alarm) {
+        self.alarm_history.push_back(alarm);
+        while self.alarm_history.len() > ALARM_HISTORY_MAX {
+            self.alarm_history.pop_front();
+        }
+    }
+
+    fn now_s(&self) -> f64 {
+        self.sim_time_s
+    }
+
+    fn raise_alarm(&mut self, code: &str, message: &str, severity: AlarmSeverity, channel: Option<u32>) {
+        let now = self.now_s();
+        if let Some(alarm) = self.alarms.iter_mut().find(|a| a.code == code && a.channel == channel) {
+            // Same condition again: count the occurrence instead of adding a new row.
+            alarm.occurrences += 1;
+            alarm.last_raised_at_s = now;
+            alarm.message = message.to_string();
+            if !alarm.active {
+                alarm.active = true;
+                alarm.acknowledged = false;
+                alarm.acknowledged_at_s = None;
+                alarm.cleared_at_s = None;
+            }
+        } else {
+            let id = self.next_alarm_id;
+            self.next_alarm_id += 1;
+            self.alarms.push(Alarm {
+                id,
+                code: code.to_string(),
+                message: message.to_string(),
+                severity,
+                channel,
+                active: true,
+                acknowledged: false,
+                raised_at_s: now,
+                last_raised_at_s: now,
+                acknowledged_at_s: None,
+                cleared_at_s: None,
+                occurrences: 1,
+            });
+        }
+        log_at!(self, Warn, "alarms", "{}: {}", code, message);
+
+        if severity == AlarmSeverity::Fault {
+            let report = self.interruption_report(code, message, channel);
+            self.emit_event("INTERRUPTED", &format!("{}: {}", code, message), channel);
+            if let Some(event) = self.events.back_mut() {
+                event.interruption = Some(report.clone());
+            }
+            self.last_interruption = Some(report);
+            for chan in self.channels.iter_mut() {
+                if channel.is_some_and(|id| id != chan.id) {
+                    continue;
+                }
+                chan.is_running = false;
+                chan.pending.clear();
+                chan.rigid_tap = None;
+                chan.pause_pending = false;
+                chan.step_once = false;
+            }
+        }
+    }
+
+    /// The latest `InterruptionReport` (also carried by its INTERRUPTED event).
+    pub fn get_last_interruption(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.last_interruption).unwrap_or(JsValue::NULL)
+    }
+
+    // Built before the fault stops anything, so queues and targets still
+    // show what was left to do.
+    fn interruption_report(&self, code: &str, message: &str, channel: Option<u32>) -> InterruptionReport {
+        let mut channels = Vec::new();
+        let mut offsets_intact = true;
+        for chan in self.channels.iter().filter(|c| channel.is_none_or(|id| id == c.id)) {
+            let mut last: HashMap<u32, f64> = HashMap::new();
+            let mut remaining = 0.0;
+            let linear = |id: &u32| find_axis(&self.axes, *id).is_some_and(|ax| ax.axis_type == AxisType::Linear);
+            let step = |last: &mut HashMap<u32, f64>, targets: &mut dyn Iterator<Item = (u32, f64)>| {
+                let mut sq = 0.0;
+                for (id, to) in targets.filter(|(id, _)| linear(id)) {
+                    let from = last.insert(id, to).unwrap_or(to);
+                    sq += (to - from).powi(2);
+                }
+                sq.sqrt()
+            };
+            for m in &chan.axis_map {
+                if let Some(ax) = find_axis(&self.axes, m.axis_id) {
+                    last.insert(ax.id, ax.position);
+                }
+            }
+            let targets = chan.axis_map.iter().filter_map(|m| find_axis(&self.axes, m.axis_id)).map(|ax| (ax.id, ax.target));
+            remaining += step(&mut last, &mut targets.collect::<Vec<_>>().into_iter());
+            for entry in &chan.pending {
+                remaining += step(&mut last, &mut entry.targets().iter().copied());
+            }
+            let line = (chan.is_running && chan.active_pc >= 0).then_some(chan.active_pc as usize);
+            if chan.is_running && !chan.offsets_at_start.is_empty() && chan.offsets_at_start != self.work_offsets {
+                offsets_intact = false;
+            }
+            channels.push(InterruptedChannel {
+                channel: chan.id,
+                running: chan.is_running,
+                line: line.map(|l| l + 1),
+                block: line.and_then(|l| chan.program.get(l).cloned()),
+                distance_remaining: remaining,
+                tool: chan.active_tool,
+                spindle_on: matches!(chan.spindle_mode, 3 | 4) && chan.spindle_rpm > 0.0,
+                coolant_on: chan.coolant_on,
+            });
+        }
+        // An incremental encoder stopped hard while moving may have lost counts.
+        let unhomed: Vec<&str> = self
+            .axes
+            .iter()
+            .filter(|ax| !ax.homed || (code == "ESTOP" && ax.encoder == EncoderType::Incremental && ax.velocity > 0.0))
+            .map(|ax| ax.physical_name.as_str())
+            .collect();
+
+        let mut recovery = Vec::new();
+        if code == "ESTOP" {
+            recovery.push("Release the emergency stop".to_string());
+        }
+        recovery.push(format!("Acknowledge alarm {}", code));
+        if !unhomed.is_empty() {
+            recovery.push(format!("Home axes: {}", unhomed.join(", ")));
+        }
+        if !offsets_intact {
+            recovery.push("Check work offsets: changed since the program started".to_string());
+        }
+        for chan in channels.iter().filter(|c| c.running) {
+            if chan.spindle_on {
+                recovery.push(format!("Channel {}: restart the spindle before resuming", chan.channel));
+            }
+            if let Some(line) = chan.line {
+                recovery.push(format!(
+                    "Channel {}: retract, then restart from line {} ({:.1} mm of motion left)",
+                    chan.channel, line, chan.distance_remaining
+                ));
+            }
+        }
+        InterruptionReport {
+            cause: code.to_string(),
+            message: message.to_string(),
+            at_s: self.now_s(),
+            channels,
+            axes: self
+                .axes
+                .iter()
+                .map(|ax| AxisDigest { name: ax.physical_name.clone(), position: ax.position, homed: ax.homed })
+                .collect(),
+            active_wcs: self.work_offsets.get(self.active_wcs).map(|w| w.label.clone()).unwrap_or_default(),
+            rehome_required: !unhomed.is_empty(),
+            offsets_intact,
+            recovery,
+        }
+    }
+
+    fn clear_alarm_condition(&mut self, code: &str, channel: Option<u32>) {
+        let now = self.now_s();
+        let Some(idx) = self.alarms.iter().position(|a| a.code == code && a.channel == channel) else {
+            return;
+        };
+        let alarm = &mut self.alarms[idx];
+        alarm.active = false;
+        alarm.cleared_at_s = Some(now);
+        if alarm.acknowledged {
+            let closed = self.alarms.remove(idx);
+            self.push_alarm_history(closed);
+        }
+    }
+
+    // ── Logging ───────────────────────────────────────────────────────────
+
+    /// Sets the log threshold ("trace", "debug", "info", "warn" or "off") of
+    /// one subsystem (core, interpreter, homing, offsets, production,
+    /// alarms), or the default for all others with an empty `subsystem`.
+    pub fn set_log_level(&mut self, subsystem: String, level: String) -> bool {
+        let threshold = match level.trim().to_ascii_lowercase().as_str() {
+            "off" | "none" => None,
+            name => match LogLevel::from_name(name) {
+                Some(level) => Some(level),
+                None => return false,
+            },
+        };
+        let subsystem = subsystem.trim().to_ascii_lowercase();
+        if subsystem.is_empty() {
+            self.logger.level = threshold;
+        } else {
+            self.logger.subsystems.insert(subsystem, threshold);
+        }
+        true
+    }
+
+    /// Drops all per-subsystem thresholds; they follow the default again.
+    pub fn reset_log_levels(&mut self) {
+        self.logger.subsystems.clear();
+    }
+
+    pub fn set_log_console(&mut self, enabled: bool) {
+        self.logger.console = enabled;
+    }
+
+    /// Keeps the last `max_records` log records for `get_log_records`; 0
+    /// turns the ring buffer off.
+    pub fn set_log_buffer(&mut self, max_records: usize) {
+        self.logger.buffer_max = max_records;
+        while self.logger.buffer.len() > max_records {
+            self.logger.buffer.pop_front();
+        }
+    }
+
+    /// Buffered `LogRecord`s, oldest first.
+    pub fn get_log_records(&self) -> JsValue {
+        let records: Vec<&LogRecord> = self.logger.buffer.iter().collect();
+        serde_wasm_bindgen::to_value(&records).unwrap_or(JsValue::NULL)
+    }
+
+    pub fn clear_log_records(&mut self) {
+        self.logger.buffer.clear();
+    }
+
+    /// Calls `callback(record)` with every `LogRecord` that passes the
+    /// thresholds; None removes it.
+    pub fn set_log_callback(&mut self, callback: Option<js_sys::Function>) {
+        self.logger.callback = callback;
+    }
+
+    // ── Events ────────────────────────────────────────────────────────────
+
+    /// Returns and clears the pending informational events.
+    pub fn take_events(&mut self) -> JsValue {
+        let events: Vec<MachineEvent> = self.events.drain(..).collect();
+        serde_wasm_bindgen::to_value(&events).unwrap_or(JsValue::NULL)
+    }
+
+    fn emit_event(&mut self, code: &str, message: &str, channel: Option<u32>) {
+        let id = self.next_event_id;
+        self.next_event_id += 1;
+        let at_s = self.now_s();
+        self.events.push_back(MachineEvent {
+            id,
+            code: code.to_string(),
+            message: message.to_string(),
+            channel,
+            at_s,
+            interruption: None,
+        });
+        while self.events.len() > EVENT_LOG_MAX {
+            self.events.pop_front();
+        }
+    }
+
+    // ── Utilization ───────────────────────────────────────────────────────
+
+    pub fn get_utilization_timeline(&self) -> JsValue {
+        let spans: Vec<UtilizationSpan> = self.utilization_spans.iter().cloned().collect();
+        serde_wasm_bindgen::to_value(&spans).unwrap_or(JsValue::NULL)
+    }
+
+    pub fn get_utilization_summary(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.utilization_summary()).unwrap_or(JsValue::NULL)
+    }
+
+    pub fn reset_utilization(&mut self) {
+        self.utilization_spans.clear();
+        self.utilization_totals = UtilizationSummary::default();
+    }
+
+    /// Split timers of the channel's current (or last) program run.
+    pub fn get_run_timers(&self, channel_index: usize) -> JsValue {
+        let Some(chan) = self.channels.get(channel_index) else { return JsValue::NULL; };
+        serde_wasm_bindgen::to_value(&chan.run_timers).unwrap_or(JsValue::NULL)
+    }
+
+    /// Results of the `(ASSERT ...)` comments evaluated during the channel's
+    /// current (or last) program run.
+    pub fn get_assertions(&self, channel_index: usize) -> JsValue {
+        let Some(chan) = self.channels.get(channel_index) else { return JsValue::NULL; };
+        serde_wasm_bindgen::to_value(&chan.assertions).unwrap_or(JsValue::NULL)
+    }
+
+    /// Job report of the channel's current (or last) program run.
+    pub fn get_job_report(&self, channel_index: usize) -> JsValue {
+        let Some(report) = self.job_report(channel_index) else { return JsValue::NULL; };
+        serde_wasm_bindgen::to_value(&report).unwrap_or(JsValue::NULL)
+    }
+
+    /// `get_job_report` as JSON text for export; empty for an unknown channel.
+    pub fn export_job_report(&self, channel_index: usize) -> String {
+        self.job_report(channel_index)
+            .and_then(|r| serde_json::to_string_pretty(&r).ok())
+            .unwrap_or_default()
+    }
+
+    /// Job report of `code` verified offline, as JSON text. Spindle and
+    /// coolant time are only measured on live runs.
+    pub fn verify_job_report(&self, channel_index: usize, code: String) -> String {
+        self.verification_job_report(channel_index, &code)
+            .and_then(|r| serde_json::to_string_pretty(&r).ok())
+            .unwrap_or_default()
+    }
+
+    /// Setup sheet for `code` verified offline, as JSON text: each used work
+    /// offset's origin and the program extents in it, the tool list with
+    /// deepest cuts, and protected zones the tool passes closer than
+    /// `min_clearance` (mm). Empty for an unknown channel.
+    pub fn export_setup_sheet(&self, channel_index: usize, code: String, min_clearance: f64) -> String {
+        self.setup_sheet(channel_index, &code, min_clearance)
+            .and_then(|r| serde_json::to_string_pretty(&r).ok())
+            .unwrap_or_default()
+    }
+
+    /// Raw stock box for removed-volume reporting, in the channel's X/Y/Z
+    /// axis coordinates. Applies from the next program start. False in builds
+    /// without the `material-removal` feature.
+    pub fn set_stock_box(&mut self, min_x: f64, min_y: f64, min_z: f64, max_x: f64, max_y: f64, max_z: f64) -> bool {
+        let (min, max) = ([min_x, min_y, min_z], [max_x, max_y, max_z]);
+        if !MATERIAL_REMOVAL || (0..3).any(|k| !(min[k].is_finite() && max[k].is_finite() && min[k] < max[k])) {
+            return false;
+        }
+        self.stock = Some(StockBox { min, max });
+        true
+    }
+
+    pub fn clear_stock_box(&mut self) {
+        self.stock = None;
+    }
+
+    fn stock_box(&self) -> Option<StockBox> {
+        if MATERIAL_REMOVAL { self.stock } else { None }
+    }
+
+    /// Touches the active tool off the stock top under it (as cut so far by
+    /// the current run) and sets Z of work offset `wcs_index` so that Z0 is
+    /// that surface under the channel's current length compensation. The axis
+    /// is not moved. False without a stock box, with the tool clear of the
+    /// stock, or while the channel is running.
+    pub fn touch_off_stock_top(&mut self, channel_index: usize, wcs_index: usize) -> bool {
+        let Some(chan) = self.channels.get(channel_index) else { return false };
+        let Some(stock) = self.stock_box() else { return false };
+        if chan.is_running || wcs_index >= self.work_offsets.len() {
+            return false;
+        }
+        let Some(z_id) = chan.axis_map.iter().find(|m| m.display_label.trim().eq_ignore_ascii_case("Z")).map(|m| m.axis_id) else {
+            return false;
+        };
+        let Some(p) = channel_xyz(&chan.axis_map, |id| find_axis(&self.axes, id).map(|ax| ax.position)) else { return false };
+        let fresh;
+        let grid = match &chan.run_usage.stock {
+            Some(grid) => grid,
+            None => {
+                fresh = StockGrid::new(stock);
+                &fresh
+            }
+        };
+        let Some(top) = grid.top_under(p[0], p[1], chan.tool_radius.max(0.0)) else { return false };
+        let (chan_id, tool) = (chan.id, chan.active_tool);
+        let value = top - self.length_comp_vector(channel_index)[2];
+        let set = self.edit("touch_off_stock_top", Permission::Offsets, |brain| {
+            let wcs = &mut brain.work_offsets[wcs_index];
+            let Some(off) = wcs.offsets.iter_mut().find(|o| o.axis_id == z_id) else { return false };
+            off.value = value;
+            true
+        });
+        if set {
+            let label = self.work_offsets[wcs_index].label.clone();
+            let message = format!("T{} touched the stock top at Z{:.3}; {} Z = {:.3}", tool, top, label, value);
+            self.emit_event("TOOL_TOUCH_OFF", &message, Some(chan_id));
+        }
+        set
+    }
+
+    fn job_report(&self, channel_index: usize) -> Option<JobReport> {
+        let chan = self.channels.get(channel_index)?;
+        let program_lines = chan.program.len();
+        let usage = &chan.run_usage;
+        Some(JobReport {
+            source: "run".to_string(),
+            channel_id: chan.id,
+            program_lines,
+            lines_executed: chan.pc.min(program_lines),
+            running: chan.is_running,
+            completed: chan.finished && program_lines > 0,
+            part_count: chan.part_count,
+            timers: chan.run_timers,
+            tools: usage.tools.clone(),
+            cut_length: usage.tools.iter().map(|t| t.cut_length).sum(),
+            rapid_length: usage.tools.iter().map(|t| t.rapid_length).sum(),
+            max_cut_feed: usage.max_cut_feed,
+            max_rapid_feed: usage.max_rapid_feed,
+            alarms: self
+                .alarm_history
+                .iter()
+                .chain(self.alarms.iter())
+                .filter(|a| a.last_raised_at_s >= usage.started_at_s && a.channel.is_none_or(|id| id == chan.id))
+                .cloned()
+                .collect(),
+            travel_exceeded: Vec::new(),
+            stock_removed_mm3: usage.stock_box.map(|_| usage.stock.as_ref().map_or(0.0, StockGrid::removed_volume)),
+            generated_at_s: self.now_s(),
+        })
+    }
+
+    /// Per-operation tool engagement of `code` (see `OperationEngagement`),
+    /// computed offline against the stock box; empty without one.
+    pub fn compute_engagement_stats(&self, channel_index: usize, code: String) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.engagement_stats(channel_index, &code)).unwrap_or(JsValue::NULL)
+    }
+
+    /// Blocks of `code` whose programmed S or F is far from the recommended
+    /// cutting data for their tool (see `CuttingDataOutlier`). Plunges along
+    /// the tool are judged as drilling, other feed moves as roughing. Empty
+    /// without a workpiece material.
+    pub fn compute_cutting_data_outliers(&self, channel_index: usize, code: String) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.cutting_data_outliers(channel_index, &code)).unwrap_or(JsValue::NULL)
+    }
+
+    fn cutting_data_outliers(&self, channel_index: usize, code: &str) -> Vec<CuttingDataOutlier> {
+        if self.workpiece_material.is_none() {
+            return Vec::new();
+        }
+        let Some(run) = self.run_verification(channel_index, code) else { return Vec::new() };
+        let chan = &self.channels[channel_index];
+        let xyz = |p: &ToolpathPoint| channel_xyz(&chan.axis_map, |id| p.position.iter().find(|a| a.axis_id == id).map(|a| a.value));
+        let far = |programmed: f64, recommended: f64| {
+            let ratio = programmed / recommended;
+            !(1.0 / CUTTING_DATA_OUTLIER_RATIO..=CUTTING_DATA_OUTLIER_RATIO).contains(&ratio)
+        };
+        let mut out: Vec<CuttingDataOutlier> = Vec::new();
+        for pair in run.path.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            if !(1..=3).contains(&b.motion) {
+                continue;
+            }
+            let (Some(from), Some(to)) = (xyz(a), xyz(b)) else { continue };
+            let delta = [0, 1, 2].map(|k| to[k] - from[k]);
+            let len = delta.iter().map(|d| d * d).sum::<f64>().sqrt();
+            let dir = chan.tool_table.get(&b.tool).and_then(|e| e.direction).unwrap_or(ToolDirection::MinusZ).vector();
+            let descent: f64 = (0..3).map(|k| delta[k] * dir[k]).sum();
+            let operation = if len > 1e-9 && descent > len * 0.99 { "drilling" } else { "roughing" };
+            let Some(data) = self.cutting_recommendation(channel_index, b.tool, operation) else { continue };
+            let checks = [("rpm", b.spindle_rpm, data.rpm), ("feed", b.feed, data.feed)];
+            for (quantity, programmed, recommended) in checks {
+                // A stopped spindle is the cutting policy's concern.
+                if programmed <= 0.0 || !far(programmed, recommended) {
+                    continue;
+                }
+                let line = b.block + 1;
+                if out.iter().any(|o| o.line == line && o.quantity == quantity) {
+                    continue;
+                }
+                out.push(CuttingDataOutlier {
+                    line,
+                    tool: b.tool,
+                    operation: operation.to_string(),
+                    quantity: quantity.to_string(),
+                    programmed,
+                    recommended,
+                });
+            }
+        }
+        out
+    }
+
+    fn engagement_stats(&self, channel_index: usize, code: &str) -> Vec<OperationEngagement> {
+        let Some(mut grid) = self.stock_box().map(StockGrid::new) else { return Vec::new() };
+        let Some(run) = self.run_verification(channel_index, code) else { return Vec::new() };
+        let chan = &self.channels[channel_index];
+        let outline = build_program_outline(code);
+        let xyz = |p: &ToolpathPoint| channel_xyz(&chan.axis_map, |id| p.position.iter().find(|a| a.axis_id == id).map(|a| a.value));
+        // (operation, tool) -> cutting time and (ae, angle, ap) samples with their time.
+        type Samples = (f64, Vec<(f64, f64)>, Vec<(f64, f64)>, Vec<(f64, f64)>);
+        let mut groups: Vec<((Option<usize>, i32), Samples)> = Vec::new();
+        for pair in run.path.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            let (Some(from), Some(to)) = (xyz(a), xyz(b)) else { continue };
+            if b.motion == 0 {
+                continue;
+            }
+            let radius = chan.tool_table.get(&b.tool).map(|t| t.total_radius()).unwrap_or(chan.tool_radius);
+            let key = (outline.operation_at(b.block), b.tool);
+            let group = match groups.iter().position(|g| g.0 == key) {
+                Some(idx) => &mut groups[idx].1,
+                None => {
+                    groups.push((key, Samples::default()));
+                    &mut groups.last_mut().expect("just pushed").1
+                }
+            };
+            group.0 += b.duration_s;
+            let (dx, dy) = (to[0] - from[0], to[1] - from[1]);
+            let xy_len = dx.hypot(dy);
+            let dir = if xy_len > 1e-9 { [dx / xy_len, dy / xy_len] } else { [0.0, 0.0] };
+            let len = (dx * dx + dy * dy + (to[2] - from[2]).powi(2)).sqrt();
+            let steps = ((len / (grid.cell * 0.5)).ceil() as usize).max(1);
+            let dt = b.duration_s / steps as f64;
+            for i in 1..=steps {
+                let t = i as f64 / steps as f64;
+                let p = [0, 1, 2].map(|k| from[k] + (to[k] - from[k]) * t);
+                if let Some((width, depth)) = grid.engagement(p, dir, radius) {
+                    let angle = (1.0 - width / radius.max(1e-9)).clamp(-1.0, 1.0).acos().to_degrees();
+                    group.1.push((width, dt));
+                    group.2.push((angle, dt));
+                    group.3.push((depth, dt));
+                }
+            }
+            grid.cut_segment(from, to, radius);
+        }
+        groups
+            .into_iter()
+            .map(|((operation, tool), (cutting_s, mut widths, mut angles, mut depths))| OperationEngagement {
+                operation,
+                operation_name: operation.and_then(|i| outline.operations.get(i)).map(|op| op.name.clone()),
+                tool,
+                cutting_s,
+                engaged_s: widths.iter().map(|s| s.1).sum(),
+                radial_width: DistributionStats::from_weighted(&mut widths),
+                engagement_deg: DistributionStats::from_weighted(&mut angles),
+                axial_depth: DistributionStats::from_weighted(&mut depths),
+            })
+            .collect()
+    }
+
+    fn verification_job_report(&self, channel_index: usize, code: &str) -> Option<JobReport> {
+        let run = self.run_verification(channel_index, code)?;
+        let chan = &self.channels[channel_index];
+        let mut timers = RunTimers { total_s: run.cycle_time_s, ..RunTimers::default() };
+        let mut tools = Vec::new();
+        let (mut max_cut_feed, mut max_rapid_feed) = (0.0f64, 0.0f64);
+        let mut cuts = Vec::new();
+        let xyz = |p: &ToolpathPoint| channel_xyz(&chan.axis_map, |id| p.position.iter().find(|a| a.axis_id == id).map(|a| a.value));
+        for pair in run.path.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            let dist = point_coords(a).iter().zip(point_coords(b)).map(|(p, q)| (q - p).powi(2)).sum::<f64>().sqrt();
+            let feed = if b.duration_s > 0.0 { dist / b.duration_s * 60.0 } else { 0.0 };
+            let usage = tool_usage(&mut tools, b.tool);
+            if b.motion == 0 {
+                timers.rapid_s += b.duration_s;
+                usage.rapid_s += b.duration_s;
+                usage.rapid_length += dist;
+                max_rapid_feed = max_rapid_feed.max(feed);
+            } else {
+                timers.cutting_s += b.duration_s;
+                usage.cutting_s += b.duration_s;
+                usage.cut_length += dist;
+                max_cut_feed = max_cut_feed.max(feed);
+                let entry = chan.tool_table.get(&b.tool);
+                if let (Some(from), Some(to), false) = (xyz(a), xyz(b), entry.is_some_and(|t| t.setup.is_some())) {
+                    let radius = entry.map(|t| t.total_radius()).unwrap_or(chan.tool_radius);
+                    cuts.push((from, to, radius));
+                }
+            }
+        }
+        timers.idle_s = (timers.total_s - timers.cutting_s - timers.rapid_s).max(0.0);
+        let stock = self.stock_box().map(|bounds| {
+            let mut grid = StockGrid::new(bounds);
+            grid.cut_path(&cuts);
+            grid
+        });
+        Some(JobReport {
+            source: "verification".to_string(),
+            channel_id: chan.id,
+            program_lines: run.coverage.total_blocks,
+            lines_executed: run.coverage.executed_blocks,
+            running: false,
+            completed: run.completed,
+            part_count: run.part_count,
+            timers,
+            cut_length: tools.iter().map(|t| t.cut_length).sum(),
+            rapid_length: tools.iter().map(|t| t.rapid_length).sum(),
+            tools,
+            max_cut_feed,
+            max_rapid_feed,
+            alarms: run.alarms,
+            // The run clamps targets at the travel limits; the unclamped
+            // envelope check shows what was cut short.
+            travel_exceeded: self.program_envelope_check(channel_index, code).map(|c| c.exceeded).unwrap_or_default(),
+            stock_removed_mm3: stock.as_ref().map(StockGrid::removed_volume),
+            generated_at_s: self.now_s(),
+        })
+    }
+
+    fn setup_sheet(&self, channel_index: usize, code: &str, min_clearance: f64) -> Option<SetupSheet> {
+        let run = self.run_verification(channel_index, code)?;
+        let chan = &self.channels[channel_index];
+        let xyz_axes: Vec<(u32, usize)> = ["X", "Y", "Z"]
+            .iter()
+            .enumerate()
+            .filter_map(|(k, l)| chan.axis_map.iter().find(|m| m.display_label.trim().eq_ignore_ascii_case(l)).map(|m| (m.axis_id, k)))
+            .collect();
+        // Tool tip in the work coordinates of the point's own WCS.
+        let origin = |wcs: usize, id: u32| {
+            self.work_offsets.get(wcs).and_then(|w| w.offsets.iter().find(|o| o.axis_id == id)).map_or(0.0, |o| o.value)
+        };
+        let work = |p: &ToolpathPoint, a: &AxisOffset| {
+            let comp = xyz_axes.iter().find(|(id, _)| *id == a.axis_id).map_or(0.0, |&(_, k)| p.length_comp[k]);
+            a.value - origin(p.wcs, a.axis_id) - comp
+        };
+
+        let mut wcs: Vec<SetupWcs> = Vec::new();
+        let mut tools: Vec<SetupTool> = Vec::new();
+        let z_id = xyz_axes.iter().find(|(_, k)| *k == 2).map(|(id, _)| *id);
+        for p in run.path.iter().skip(1) {
+            let idx = match wcs.iter().position(|w| w.index == p.wcs) {
+                Some(idx) => idx,
+                None => {
+                    let axis_origin = |a: &AxisOffset| AxisOffset { axis_id: a.axis_id, value: origin(p.wcs, a.axis_id) };
+                    wcs.push(SetupWcs {
+                        index: p.wcs,
+                        label: self.work_offsets.get(p.wcs).map(|w| w.label.clone()).unwrap_or_default(),
+                        origin: p.position.iter().map(axis_origin).collect(),
+                        min: Vec::new(),
+                        max: Vec::new(),
+                    });
+                    wcs.len() - 1
+                }
+            };
+            // Extents of the move end points; the start position before the
+            // first move is not part of the program.
+            for a in &p.position {
+                let value = work(p, a);
+                let entry = &mut wcs[idx];
+                match entry.min.iter_mut().zip(entry.max.iter_mut()).find(|(lo, _)| lo.axis_id == a.axis_id) {
+                    Some((lo, hi)) => {
+                        lo.value = lo.value.min(value);
+                        hi.value = hi.value.max(value);
+                    }
+                    None => {
+                        entry.min.push(AxisOffset { axis_id: a.axis_id, value });
+                        entry.max.push(AxisOffset { axis_id: a.axis_id, value });
+                    }
+                }
+            }
+
+            if !tools.iter().any(|t| t.tool == p.tool) {
+                let entry = chan.tool_table.get(&p.tool);
+                tools.push(SetupTool {
+                    tool: p.tool,
+                    first_line: p.block + 1,
+                    radius: entry.map_or(0.0, |t| t.total_radius()),
+                    length: entry.map_or(0.0, |t| t.total_length()),
+                    max_depth: None,
+                });
+            }
+            if p.motion != 0 {
+                let depth = z_id.and_then(|id| p.position.iter().find(|a| a.axis_id == id)).map(|a| -work(p, a));
+                if let (Some(depth), Some(tool)) = (depth, tools.iter_mut().find(|t| t.tool == p.tool)) {
+                    tool.max_depth = Some(tool.max_depth.map_or(depth, |d| d.max(depth)));
+                }
+            }
+        }
+
+        let mut clamp_warnings = Vec::new();
+        let xyz = |p: &ToolpathPoint| channel_xyz(&chan.axis_map, |id| p.position.iter().find(|a| a.axis_id == id).map(|a| a.value));
+        for zone in self.protected_zones.iter().filter(|_| COLLISION) {
+            let mut closest: Option<ClampWarning> = None;
+            for pair in run.path.windows(2) {
+                let (Some(a), Some(b)) = (xyz(&pair[0]), xyz(&pair[1])) else { continue };
+                let p = &pair[1];
+                let comp = if zone.tool_length_aware { p.length_comp } else { [0.0; 3] };
+                let radius = chan.tool_table.get(&p.tool).map_or(0.0, |t| t.total_radius());
+                let len = (0..3).map(|k| (b[k] - a[k]).powi(2)).sum::<f64>().sqrt();
+                let steps = ((len / SETUP_SAMPLE_STEP).ceil() as usize).clamp(1, 1000);
+                for s in 0..=steps {
+                    let t = s as f64 / steps as f64;
+                    let at: [f64; 3] = std::array::from_fn(|k| a[k] + (b[k] - a[k]) * t - comp[k]);
+                    let clearance = zone.shape.distance(at) - radius;
+                    if clearance < min_clearance && closest.as_ref().is_none_or(|c| clearance < c.clearance) {
+                        closest = Some(ClampWarning {
+                            zone_id: zone.id,
+                            zone_name: zone.name.clone(),
+                            clearance,
+                            line: p.block + 1,
+                            at,
+                        });
+                    }
+                }
+            }
+            clamp_warnings.extend(closest);
+        }
+
+        Some(SetupSheet {
+            channel_id: chan.id,
+            completed: run.completed,
+            wcs,
+            tools,
+            min_clearance,
+            clamp_warnings,
+            alarms: run.alarms,
+            generated_at_s: self.now_s(),
+        })
+    }
+
+    // `before` holds axis positions at the start of the tick (same order as `axes`).
+    fn record_run_time(&mut self, c_idx: usize, before: &[f64], dt_sec: f64, tool_change: bool, dwelling: bool) {
+        let chan = &self.channels[c_idx];
+        let uses = |id: u32| chan.axis_map.iter().any(|m| m.axis_id == id);
+        let dist = self
+            .axes
+            .iter()
+            .zip(before)
+            .filter(|(ax, _)| uses(ax.id))
+            .map(|(ax, p)| (ax.position - p).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        let moved = dist > 0.0;
+        let rapid = chan.current_motion == 0;
+        let tool = chan.active_tool;
+        let radius = chan.tool_radius;
+        let cuts_stock = chan.tool_table.get(&tool).is_none_or(|t| t.setup.is_none());
+        let spindle_on = matches!(chan.spindle_mode, 3 | 4) && chan.spindle_rpm > 0.0;
+        let coolant_on = chan.coolant_on;
+        let position_before = |id: u32| self.axes.iter().position(|ax| ax.id == id).map(|i| before[i]);
+        let segment = channel_xyz(&chan.axis_map, position_before)
+            .zip(channel_xyz(&chan.axis_map, |id| find_axis(&self.axes, id).map(|ax| ax.position)));
+
+        let chan = &mut self.channels[c_idx];
+        let timers = &mut chan.run_timers;
+        timers.total_s += dt_sec;
+        match (moved, rapid) {
+            (false, _) if tool_change => timers.tool_change_s += dt_sec,
+            (false, _) if dwelling => timers.dwell_s += dt_sec,
+            (true, true) => timers.rapid_s += dt_sec,
+            (true, false) => timers.cutting_s += dt_sec,
+            (false, _) => timers.idle_s += dt_sec,
+        }
+        if moved {
+            let usage = &mut chan.run_usage;
+            let feed = dist / dt_sec * 60.0;
+            let tool_usage = tool_usage(&mut usage.tools, tool);
+            if rapid {
+                tool_usage.rapid_s += dt_sec;
+                tool_usage.rapid_length += dist;
+                usage.max_rapid_feed = usage.max_rapid_feed.max(feed);
+            } else {
+                tool_usage.cutting_s += dt_sec;
+                tool_usage.cut_length += dist;
+                usage.max_cut_feed = usage.max_cut_feed.max(feed);
+                if let (Some(bounds), Some((from, to)), true) = (usage.stock_box, segment, cuts_stock) {
+                    usage.stock.get_or_insert_with(|| StockGrid::new(bounds)).cut_segment(from, to, radius);
+                }
+            }
+        }
+        if spindle_on {
+            timers.spindle_on_s += dt_sec;
+        }
+        if coolant_on {
+            timers.coolant_on_s += dt_sec;
+        }
+    }
+
+    fn current_activity(&self) -> MachineActivity {
+        if self.estop {
+            return MachineActivity::Alarm;
+        }
+        if self.channels.iter().any(|c| c.is_running) {
+            let held = self.feed_hold || self.channels.iter().all(|c| !c.is_running || c.paused || c.auto_hold);
+            return if held { MachineActivity::Paused } else { MachineActivity::Running };
+        }
+        // Homing and manual motion outside a program count as setup time.
+        if self.is_homing || self.axes.iter().any(|ax| (ax.target - ax.position).abs() > self.tolerances.position_mm) {
+            return MachineActivity::Setup;
+        }
+        MachineActivity::Idle
+    }
+
+    fn record_utilization(&mut self, dt_sec: f64) {
+        let state = self.current_activity();
+        let end = self.now_s();
+        let start = end - dt_sec;
+
+        match self.utilization_spans.back_mut() {
+            Some(last) if last.state == state && (last.end_s - start).abs() <= 1e-9 => last.end_s = end,
+            _ => {
+                self.utilization_spans.push_back(UtilizationSpan { state, start_s: start, end_s: end });
+                if self.utilization_spans.len() > UTILIZATION_MAX_SPANS {
+                    self.utilization_spans.pop_front();
+                }
+            }
+        }
+
+        let totals = &mut self.utilization_totals;
+        totals.total_s += dt_sec;
+        match state {
+            MachineActivity::Running => totals.running_s += dt_sec,
+            MachineActivity::Paused => totals.paused_s += dt_sec,
+            MachineActivity::Alarm => totals.alarm_s += dt_sec,
+            MachineActivity::Idle => totals.idle_s += dt_sec,
+            MachineActivity::Setup => totals.setup_s += dt_sec,
+        }
+    }
+
+    fn utilization_summary(&self) -> UtilizationSummary {
+        let mut summary = self.utilization_totals.clone();
+        summary.utilization = if summary.total_s > 0.0 { summary.running_s / summary.total_s } else { 0.0 };
+        summary
+    }
+
+    // ── Position capture ──────────────────────────────────────────────────
+
+    /// Latches every axis position on the rising edge of `input` (a probe
+    /// trip, say). `enabled: false` disarms it.
+    pub fn set_capture_input(&mut self, input: String, enabled: bool) -> bool {
+        let input = input.trim().to_string();
+        if input.is_empty() || !self.permitted(Permission::Settings, "set_capture_input") {
+            return false;
+        }
+        self.capture_inputs.retain(|i| *i != input);
+        if enabled {
+            self.capture_inputs.push(input);
+        }
+        true
+    }
+
+    /// Latches every axis position once per spindle revolution of the channel
+    /// (the encoder index pulse) while its spindle turns.
+    pub fn set_index_capture(&mut self, channel_index: usize, enabled: bool) -> bool {
+        if !self.permitted(Permission::Settings, "set_index_capture") {
+            return false;
+        }
+        let Some(chan) = self.channels.get_mut(channel_index) else { return false };
+        chan.index_capture = enabled;
+        chan.spindle_revs = 0.0;
+        true
+    }
+
+    /// Latches every axis position now, tagged with `label`.
+    pub fn capture_positions(&mut self, label: String) {
+        let positions: Vec<f64> = self.axes.iter().map(|ax| ax.position).collect();
+        self.log_capture(label, None, self.now_s(), &positions);
+    }
+
+    /// Captured positions (`PositionCapture` list), oldest first.
+    pub fn get_capture_log(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.captures).unwrap_or(JsValue::NULL)
+    }
+
+    pub fn clear_capture_log(&mut self) {
+        self.captures.clear();
+    }
+
+    fn log_capture(&mut self, source: String, channel: Option<u32>, timestamp_s: f64, positions: &[f64]) {
+        let positions = self.axes.iter().zip(positions).map(|(ax, &value)| AxisOffset { axis_id: ax.id, value }).collect();
+        self.captures.push_back(PositionCapture { source, channel, timestamp_s, positions });
+        if self.captures.len() > CAPTURE_LOG_MAX {
+            self.captures.pop_front();
+        }
+    }
+
+    // Turns every running spindle through the tick that moved the axes from
+    // `before`. A spindle rigid tapping at the start of the tick (`taps`)
+    // turns with its tap axis instead of at S. With index capture on, each
+    // whole revolution latches positions interpolated to the moment it completed.
+    fn advance_spindles(&mut self, before: &[f64], taps: &[Option<RigidTap>], dt_sec: f64) {
+        let end = self.now_s();
+        for c_idx in 0..self.channels.len() {
+            let tap_travel = taps.get(c_idx).copied().flatten().and_then(|tap| {
+                let k = self.axes.iter().position(|ax| ax.id == tap.axis_id)?;
+                Some((self.axes[k].position - before[k]).abs() / tap.pitch)
+            });
+            let chan = &mut self.channels[c_idx];
+            let start = chan.spindle_revs;
+            let revs = match tap_travel {
+                Some(revs) => {
+                    chan.spindle_revs = (start + revs).fract();
+                    revs
+                }
+                None => chan.advance_spindle(dt_sec),
+            };
+            chan.spindle_actual_rpm = revs / dt_sec * 60.0;
+            if !chan.index_capture || revs <= 0.0 {
+                continue;
+            }
+            let chan_id = chan.id;
+            let mut pulse = 1.0;
+            while pulse <= start + revs {
+                let frac = (pulse - start) / revs;
+                let positions: Vec<f64> = self.axes.iter().zip(before).map(|(ax, b)| b + (ax.position - b) * frac).collect();
+                self.log_capture("index".to_string(), Some(chan_id), end - dt_sec * (1.0 - frac), &positions);
+                pulse += 1.0;
+            }
+        }
+    }
+
+    // ── Interlocks ────────────────────────────────────────────────────────
+
+    /// Sets a named digital input (probe_armed, clamp_closed, ...). Unset
+    /// inputs read low.
+    pub fn set_input(&mut self, name: String, value: bool) {
+        let name = name.trim().to_string();
+        let rising = value && !self.inputs.get(&name).copied().unwrap_or(false);
+        if rising && self.capture_inputs.contains(&name) {
+            let positions: Vec<f64> = self.axes.iter().map(|ax| ax.position).collect();
+            self.log_capture(format!("input:{}", name), None, self.now_s(), &positions);
+        }
+        self.inputs.insert(name, value);
+    }
+
+    /// Adds a rule holding feed while `input` is at `hold_level`; the run
+    /// decelerates along its path and resumes by itself once no rule holds.
+    pub fn add_hold_rule(&mut self, input: String, hold_level: bool, channel_id: Option<u32>) -> bool {
+        let input = input.trim().to_string();
+        if input.is_empty() {
+            return false;
+        }
+        if !self.permitted(Permission::Settings, "add_hold_rule") {
+            return false;
+        }
+        self.hold_rules.retain(|r| !(r.input == input && r.channel == channel_id));
+        self.hold_rules.push(HoldRule { input, hold_level, channel: channel_id });
+        true
+    }
+
+    pub fn remove_hold_rule(&mut self, input: String, channel_id: Option<u32>) -> bool {
+        if !self.permitted(Permission::Settings, "remove_hold_rule") {
+            return false;
+        }
+        let before = self.hold_rules.len();
+        self.hold_rules.retain(|r| !(r.input == input.trim() && r.channel == channel_id));
+        self.hold_rules.len() != before
+    }
+
+    pub fn get_hold_rules(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.hold_rules).unwrap_or(JsValue::NULL)
+    }
+
+    fn holding_rule(&self, chan_id: u32) -> Option<&HoldRule> {
+        self.hold_rules.iter().find(|r| {
+            r.channel.is_none_or(|id| id == chan_id) && self.inputs.get(&r.input).copied().unwrap_or(false) == r.hold_level
+        })
+    }
+
+    fn update_auto_holds(&mut self) {
+        for c_idx in 0..self.channels.len() {
+            let chan_id = self.channels[c_idx].id;
+            let rule = self.holding_rule(chan_id).map(|r| r.input.clone());
+            let held = rule.is_some();
+            if held == self.channels[c_idx].auto_hold {
+                continue;
+            }
+            self.channels[c_idx].auto_hold = held;
+            match rule {
+                Some(input) => self.emit_event("AUTO_HOLD", &format!("Feed hold: input {} interlock", input), Some(chan_id)),
+                None => self.emit_event("AUTO_RESUME", "Interlock cleared; resuming", Some(chan_id)),
+            }
+        }
+    }
+
+    // Ramps the channel's axes down together so the tool stays on the path.
+    fn decelerate_channel(&mut self, c_idx: usize, dt_sec: f64, tol: &ToleranceProfile) {
+        let ids: Vec<u32> = self.channels[c_idx].axis_map.iter().map(|m| m.axis_id).collect();
+        let moving: Vec<&Axis> = self.axes.iter().filter(|ax| ids.contains(&ax.id) && ax.velocity > 0.0).collect();
+        let ratio = moving
+            .iter()
+            .map(|ax| 1.0 - dt_sec * ax.accel.max(1.0) / ax.velocity)
+            .fold(1.0f64, f64::min)
+            .max(0.0);
+        for ax in self.axes.iter_mut().filter(|ax| ids.contains(&ax.id)) {
+            let vel = ax.velocity * ratio;
+            if vel <= 0.0 {
+                ax.velocity = 0.0;
+                continue;
+            }
+            let step = vel / 60.0 * dt_sec;
+            let diff = ax.target - ax.position;
+            if diff.abs() <= step.max(tol.position_mm) {
+                ax.position = ax.target;
+            } else {
+                ax.position += step * diff.signum();
+            }
+            ax.velocity = vel;
+        }
+    }
+
+    // ── Cycle start ───────────────────────────────────────────────────────
+
+    pub fn set_door_open(&mut self, open: bool) {
+        self.door_open = open;
+        self.inputs.insert("door_open".to_string(), open);
+    }
+
+    /// Conditions currently preventing cycle start on a channel; empty when it
+    /// can start.
+    pub fn why_cannot_start(&self, channel_index: usize) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.start_inhibits(channel_index)).unwrap_or(JsValue::NULL)
+    }
+
+    fn start_inhibits(&self, channel_index: usize) -> Vec<StartInhibit> {
+        let Some(chan) = self.channels.get(channel_index) else {
+            return vec![start_inhibit("NO_CHANNEL", format!("Channel {} does not exist", channel_index))];
+        };
+        let mut out = Vec::new();
+        if self.estop {
+            out.push(start_inhibit("ESTOP", "Emergency stop is engaged; release it and reset".to_string()));
+        }
+        for alarm in self.alarms.iter().filter(|a| {
+            a.active && a.severity == AlarmSeverity::Fault && a.code != "ESTOP" && (a.channel.is_none() || a.channel == Some(chan.id))
+        }) {
+            out.push(start_inhibit("ALARM", format!("Alarm {} active: {}", alarm.code, alarm.message)));
+        }
+        if self.is_homing {
+            out.push(start_inhibit("HOMING", "Homing is in progress".to_string()));
+        }
+        let unhomed: Vec<&str> = chan
+            .axis_map
+            .iter()
+            .filter(|m| find_axis(&self.axes, m.axis_id).is_some_and(|ax| !ax.homed))
+            .map(|m| m.display_label.as_str())
+            .collect();
+        if !unhomed.is_empty() {
+            out.push(start_inhibit("NOT_HOMED", format!("Axes not homed: {}", unhomed.join(", "))));
+        }
+        if self.door_open {
+            out.push(start_inhibit("DOOR_OPEN", "Machine door is open".to_string()));
+        }
+        if chan.program.is_empty() {
+            out.push(start_inhibit("NO_PROGRAM", "No program loaded".to_string()));
+        }
+        if chan.is_running && !chan.paused {
+            out.push(start_inhibit("RUNNING", "Program is already running".to_string()));
+        }
+        if chan.retracing {
+            out.push(start_inhibit("RETRACING", "Retrace in progress".to_string()));
+        }
+        if chan.parts_target_reached {
+            out.push(start_inhibit(
+                "PART_TARGET",
+                format!("Required parts reached ({}); reset the part counter", chan.part_target),
+            ));
+        }
+        out
+    }
+
+    // ── Observability ─────────────────────────────────────────────────────
+
+    pub fn get_observability_snapshot(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.observability_snapshot()).unwrap_or(JsValue::NULL)
+    }
+
+    fn observability_snapshot(&self) -> ObservabilitySnapshot {
+        let paths = self
+            .channels
+            .iter()
+            .map(|c| {
+                let execution = if (c.parts_target_reached || c.finished) && !c.is_running {
+                    "PROGRAM_COMPLETED"
+                } else if !c.is_running {
+                    if self.estop { "STOPPED" } else { "READY" }
+                } else if self.feed_hold || c.auto_hold {
+                    "FEED_HOLD"
+                } else if c.paused {
+                    match c.program_stop {
+                        Some(0) => "PROGRAM_STOPPED",
+                        Some(_) => "OPTIONAL_STOP",
+                        None if c.single_block => "INTERRUPTED",
+                        None => "STOPPED",
+                    }
+                } else {
+                    "ACTIVE"
+                };
+                let controller_mode = if !c.is_running {
+                    "MANUAL"
+                } else if c.single_block {
+                    "SEMI_AUTOMATIC"
+                } else {
+                    "AUTOMATIC"
+                };
+                let speed_sq: f64 = c
+                    .axis_map
+                    .iter()
+                    .filter_map(|m| find_axis(&self.axes, m.axis_id))
+                    .filter(|ax| ax.axis_type == AxisType::Linear)
+                    .map(|ax| ax.velocity * ax.velocity)
+                    .sum();
+                let block = if c.active_pc >= 0 {
+                    c.program.get(c.active_pc as usize).cloned().unwrap_or_default()
+                } else {
+                    String::new()
+                };
+                PathDataItems {
+                    path_id: c.id,
+                    execution: execution.to_string(),
+                    controller_mode: controller_mode.to_string(),
+                    line: c.active_pc,
+                    block,
+                    path_feedrate: speed_sq.sqrt() / 60.0,
+                    path_feedrate_commanded: c.feed_rate / 60.0,
+                    path_feedrate_override: c.feed_override_ratio() * 100.0,
+                    rotary_velocity: if c.spindle_mode == 5 { 0.0 } else { c.spindle_speed() },
+                    spindle_direction: match c.spindle_mode {
+                        3 => "CLOCKWISE",
+                        4 => "COUNTER_CLOCKWISE",
+                        _ => "STOPPED",
+                    }
+                    .to_string(),
+                    tool_number: c.active_tool,
+                    part_count: c.part_count,
+                }
+            })
+            .collect();
+
+        let axes = self
+            .axes
+            .iter()
+            .map(|ax| AxisDataItems {
+                name: ax.physical_name.clone(),
+                axis_type: match ax.axis_type {
+                    AxisType::Linear => "LINEAR",
+                    AxisType::Rotary => "ROTARY",
+                }
+                .to_string(),
+                units: ax.axis_type.units().to_string(),
+                position: ax.position,
+                position_commanded: ax.target,
+                axis_feedrate: ax.feed,
+                load: ax.load,
+                homed: ax.homed,
+            })
+            .collect();
+
+        let mut conditions: Vec<ConditionDataItem> = self
+            .alarms
+            .iter()
+            .filter(|a| a.active)
+            .map(|a| ConditionDataItem {
+                level: match a.severity {
+                    AlarmSeverity::Fault => "FAULT",
+                    _ => "WARNING",
+                }
+                .to_string(),
+                condition_type: if a.channel.is_some() { "LOGIC_PROGRAM" } else { "SYSTEM" }.to_string(),
+                native_code: a.code.clone(),
+                message: a.message.clone(),
+                path_id: a.channel,
+            })
+            .collect();
+        for c in &self.channels {
+            if c.parts_target_reached {
+                conditions.push(ConditionDataItem {
+                    level: "WARNING".to_string(),
+                    condition_type: "LOGIC_PROGRAM".to_string(),
+                    native_code: "PARTS_REACHED".to_string(),
+                    message: format!("Required parts reached ({})", c.part_count),
+                    path_id: Some(c.id),
+                });
+            }
+        }
+        if conditions.is_empty() {
+            conditions.push(ConditionDataItem {
+                level: "NORMAL".to_string(),
+                condition_type: "SYSTEM".to_string(),
+                native_code: String::new(),
+                message: String::new(),
+                path_id: None,
+            });
+        }
+
+        ObservabilitySnapshot {
+            availability: "AVAILABLE".to_string(),
+            emergency_stop: if self.estop { "TRIGGERED" } else { "ARMED" }.to_string(),
+            timestamp_s: self.now_s(),
+            paths,
+            axes,
+            conditions,
+        }
+    }
+
+    // ── Verification ──────────────────────────────────────────────────────
+
+    /// Coverage of the program currently loaded in the channel (live run).
+    pub fn get_program_coverage(&self, channel_index: usize) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.program_coverage(channel_index)).unwrap_or(JsValue::NULL)
+    }
+
+    /// Runs `code` offline and reports completion, coverage and alarms raised.
+    pub fn verify_program(&self, channel_index: usize, code: String) -> JsValue {
+        let Some(run) = self.run_verification(channel_index, &code) else {
+            return JsValue::NULL;
+        };
+        let report = VerificationReport {
+            completed: run.completed,
+            path_points: run.path.len(),
+            cycle_time_s: run.cycle_time_s,
+            coverage: run.coverage,
+            alarms: run.alarms,
+        };
+        serde_wasm_bindgen::to_value(&report).unwrap_or(JsValue::NULL)
+    }
+
+    /// Estimated motion time (seconds) of `code` on this machine, computed offline.
+    /// Adaptive/trochoidal programs take the block-geometry fast path unless
+    /// they need the executor (dwells, tool changes, block time, feed caps,
+    /// threading, macros).
+    pub fn estimate_cycle_time(&self, channel_index: usize, code: String) -> f64 {
+        let fast = self.block_time_s == 0.0 && self.descent_limits == DescentLimits::default() && scan_times_program(&code);
+        if let Some(stats) = self.path_statistics(channel_index, &code).filter(|s| fast && s.adaptive) {
+            return stats.estimated_time_s;
+        }
+        self.run_verification(channel_index, &code).map(|r| r.cycle_time_s).unwrap_or(0.0)
+    }
+
+    /// Move/arc counts, lengths, time and adaptive-clearing statistics of `code`
+    /// from a single pass over its blocks (no interpreter run).
+    pub fn get_path_statistics(&self, channel_index: usize, code: String) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.path_statistics(channel_index, &code)).unwrap_or(JsValue::NULL)
+    }
+
+    fn path_statistics(&self, channel_index: usize, code: &str) -> Option<PathStatistics> {
+        let chan = self.channels.get(channel_index)?;
+        let mut start = [0.0; 3];
+        let mut xy_accel = f64::INFINITY;
+        for m in &chan.axis_map {
+            let k = match m.display_label.to_uppercase().as_str() {
+                "X" => 0,
+                "Y" => 1,
+                "Z" => 2,
+                _ => continue,
+            };
+            let Some(ax) = find_axis(&self.axes, m.axis_id) else { continue };
+            start[k] = chan
+                .programmed_work
+                .get(&m.axis_id)
+                .copied()
+                .unwrap_or_else(|| self.machine_to_work(m.axis_id, ax.position));
+            if k < 2 {
+                xy_accel = xy_accel.min(ax.accel.max(1.0));
+            }
+        }
+        let params = PathScanParams {
+            start,
+            units_mm: chan.units_mm,
+            abs_mode: chan.abs_mode,
+            arc_center_absolute: chan.arc_center_absolute,
+            feed_rate: chan.feed_rate,
+            feed_override: chan.feed_override_ratio(),
+            rapid_feed: self.channel_rapid_feed(channel_index),
+            xy_accel: if xy_accel.is_finite() { xy_accel } else { 1.0 },
+            tool_radius: chan.tool_radius,
+        };
+        Some(scan_path_statistics(code, &params))
+    }
+
+    // ── Live trace ────────────────────────────────────────────────────────
+
+    /// Chord tolerance (mm) used to decimate the recorded trace.
+    pub fn set_trace_tolerance(&mut self, tolerance: f64) {
+        self.trace_tolerance = tolerance.max(0.0);
+    }
+
+    /// Number of trace points recorded so far (absolute, including dropped ones).
+    pub fn get_trace_len(&self, channel_index: usize) -> usize {
+        self.channels.get(channel_index).map(|c| c.trace.len()).unwrap_or(0)
+    }
+
+    /// Values per point in `get_trace_chunk`: the channel's axes, motion, tool.
+    pub fn get_trace_stride(&self, channel_index: usize) -> usize {
+        self.channels.get(channel_index).map(|c| c.axis_map.len() + 2).unwrap_or(0)
+    }
+
+    /// Trace points from absolute index `start`, flattened with `get_trace_stride`
+    /// values each. The last point follows the tool and may still change, so
+    /// callers should refetch from `len - 1` on the next frame.
+    pub fn get_trace_chunk(&self, channel_index: usize, start: usize, max_points: usize) -> Vec<f64> {
+        self.channels
+            .get(channel_index)
+            .map(|c| c.trace.chunk(start, max_points))
+            .unwrap_or_default()
+    }
+
+    /// Trace as polylines per tool, operation and motion, with display colors.
+    pub fn get_trace_segments(&self, channel_index: usize) -> JsValue {
+        let Some(chan) = self.channels.get(channel_index) else { return JsValue::NULL; };
+        let segments = chan.trace.segments(&chan.outline, &self.trace_tool_colors);
+        serde_wasm_bindgen::to_value(&segments).unwrap_or(JsValue::NULL)
+    }
+
+    /// Trace segments as JSON, for saving alongside setup documentation.
+    pub fn export_trace(&self, channel_index: usize) -> String {
+        let Some(chan) = self.channels.get(channel_index) else { return "[]".to_string(); };
+        let segments = chan.trace.segments(&chan.outline, &self.trace_tool_colors);
+        serde_json::to_string(&segments).unwrap_or_else(|_| "[]".to_string())
+    }
+
+    /// Overrides the trace color (CSS string) of a tool.
+    pub fn set_trace_tool_color(&mut self, tool: i32, color: String) {
+        self.trace_tool_colors.insert(tool, color);
+    }
+
+    pub fn clear_trace(&mut self, channel_index: usize) {
+        if let Some(chan) = self.channels.get_mut(channel_index) {
+            chan.trace.clear();
+        }
+    }
+
+    // ── Interpreter trace ─────────────────────────────────────────────────
+
+    /// Records, per executed block of the channel, its words, modal changes,
+    /// cutter comp decisions and generated segments (`BlockTrace`). Turning
+    /// it off drops the records.
+    pub fn set_block_trace(&mut self, channel_index: usize, enabled: bool) -> bool {
+        let Some(chan) = self.channels.get_mut(channel_index) else { return false };
+        if enabled != chan.block_trace.is_some() {
+            chan.block_trace = enabled.then(BlockTraceLog::default);
+        }
+        true
+    }
+
+    /// Traced blocks of program lines `first_line..=last_line` (1-based), oldest first.
+    pub fn get_block_trace(&self, channel_index: usize, first_line: usize, last_line: usize) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.block_trace_range(channel_index, first_line, last_line)).unwrap_or(JsValue::NULL)
+    }
+
+    fn block_trace_range(&self, channel_index: usize, first_line: usize, last_line: usize) -> Vec<BlockTrace> {
+        let Some(log) = self.channels.get(channel_index).and_then(|c| c.block_trace.as_ref()) else { return Vec::new() };
+        log.records.iter().filter(|r| (first_line..=last_line).contains(&r.line)).cloned().collect()
+    }
+
+    pub fn clear_block_trace(&mut self, channel_index: usize) {
+        if let Some(log) = self.channels.get_mut(channel_index).and_then(|c| c.block_trace.as_mut()) {
+            log.records.clear();
+        }
+    }
+
+    // ── Telemetry ─────────────────────────────────────────────────────────
+
+    /// Starts recording position, velocity and acceleration of every current
+    /// axis: `capacity` samples, one per `decimation` ticks. Capacity 0 stops.
+    pub fn set_telemetry(&mut self, capacity: usize, decimation: u32) {
+        self.telemetry.configure(capacity, decimation, &self.axes);
+    }
+
+    pub fn clear_telemetry(&mut self) {
+        let (capacity, decimation) = (self.telemetry.capacity, self.telemetry.decimation);
+        self.set_telemetry(capacity, decimation);
+    }
+
+    /// Axis ids recorded by the telemetry, in recording order.
+    pub fn get_telemetry_axes(&self) -> Vec<u32> {
+        self.telemetry.axes.iter().map(|t| t.axis_id).collect()
+    }
+
+    /// Sample times (s), oldest first.
+    pub fn get_telemetry_time(&self) -> Vec<f64> {
+        self.telemetry.time.iter().copied().collect()
+    }
+
+    /// One signal of an axis ("position", "velocity" or "acceleration"),
+    /// aligned with `get_telemetry_time`. NaN marks samples after the axis
+    /// was removed.
+    pub fn get_telemetry(&self, axis_id: u32, signal: String) -> Vec<f64> {
+        self.telemetry
+            .signal(axis_id, &signal.to_lowercase())
+            .map(|buf| buf.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    // ── Invariants ────────────────────────────────────────────────────────
+
+    /// Returns the list of violated internal invariants (empty when consistent).
+    /// With the `invariants` feature these are also asserted after every tick.
+    pub fn check_invariants(&mut self) -> Vec<String> {
+        self.invariant_violations()
+    }
+
+    // ── Toolpath ──────────────────────────────────────────────────────────
+
+    /// Executed path of `code` (machine coordinates per point), computed offline.
+    pub fn compute_toolpath(&self, channel_index: usize, code: String) -> JsValue {
+        let path = self.run_verification(channel_index, &code).map(|r| r.path).unwrap_or_default();
+        serde_wasm_bindgen::to_value(&path).unwrap_or(JsValue::NULL)
+    }
+
+    /// Cutter-compensated contours of `code` (see `CompContour`), computed
+    /// offline, for overlaying on the CAD profile.
+    pub fn compute_comp_contours(&self, channel_index: usize, code: String) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.comp_contours(channel_index, &code)).unwrap_or(JsValue::NULL)
+    }
+
+    /// Coordinates, feed and modal state at parameter `t` (0..1) along
+    /// program line `block` of the loaded program, interpreted as for the
+    /// backplot; null when the program never reaches the block.
+    pub fn query_path_point(&self, channel_index: usize, block: usize, t: f64) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.path_point(channel_index, block, t)).unwrap_or(JsValue::NULL)
+    }
+
+    fn path_point(&self, channel_index: usize, block: usize, t: f64) -> Option<PathPointInfo> {
+        let mut scan = self.scan_to_line(channel_index, block)?;
+        let mut points = vec![scan.toolpath_point(channel_index)];
+        scan.advance_channel(channel_index);
+        loop {
+            scan.complete_channel_motion(channel_index);
+            let point = scan.toolpath_point(channel_index);
+            if points.last().is_some_and(|p| toolpath_point_distance(p, &point) > 1e-9) {
+                points.push(point);
+            }
+            if scan.channels[channel_index].pending.is_empty() || !scan.channels[channel_index].is_running {
+                break;
+            }
+            scan.advance_channel(channel_index);
+        }
+
+        // Walk the block's segments (arcs come as chords) to the point at `t`.
+        let t = if t.is_finite() { t.clamp(0.0, 1.0) } else { 0.0 };
+        let lengths: Vec<f64> = points.windows(2).map(|w| toolpath_point_distance(&w[0], &w[1])).collect();
+        let mut along = t * lengths.iter().sum::<f64>();
+        let mut at = (&points[0], &points[0], 0.0);
+        for (w, len) in points.windows(2).zip(&lengths) {
+            at = (&w[0], &w[1], if *len > 0.0 { (along / len).min(1.0) } else { 1.0 });
+            if along <= *len {
+                break;
+            }
+            along -= len;
+        }
+        let (a, b, f) = at;
+        let lerp = |u: f64, v: f64| u + (v - u) * f;
+        let machine: Vec<AxisOffset> = a
+            .position
+            .iter()
+            .zip(&b.position)
+            .map(|(p, q)| AxisOffset { axis_id: p.axis_id, value: lerp(p.value, q.value) })
+            .collect();
+        let length_comp = [0, 1, 2].map(|k| lerp(a.length_comp[k], b.length_comp[k]));
+        let chan = &scan.channels[channel_index];
+        let xyz = |id: u32| {
+            let label = chan.axis_map.iter().find(|m| m.axis_id == id)?.display_label.trim().to_uppercase();
+            ["X", "Y", "Z"].iter().position(|l| *l == label)
+        };
+        let work = machine
+            .iter()
+            .map(|p| {
+                let comp = xyz(p.axis_id).map_or(0.0, |k| length_comp[k]);
+                AxisOffset { axis_id: p.axis_id, value: scan.machine_to_work(p.axis_id, p.value) - comp }
+            })
+            .collect();
+        let feed = match b.motion {
+            0 => scan.channel_rapid_feed_for(channel_index, AxisType::Linear).unwrap_or(b.feed),
+            _ => b.feed,
+        };
+        let modal = scan.block_context(channel_index, "").modal;
+        Some(PathPointInfo { block, t, machine, work, feed, modal })
+    }
+
+    fn comp_contours(&self, channel_index: usize, code: &str) -> Vec<CompContour> {
+        let Some(run) = self.run_verification(channel_index, code) else { return Vec::new() };
+        let chan = &self.channels[channel_index];
+        let outline = build_program_outline(code);
+        let work_xy = |p: &ToolpathPoint| {
+            let offset = |id: u32| {
+                self.work_offsets
+                    .get(p.wcs)
+                    .and_then(|w| w.offsets.iter().find(|o| o.axis_id == id))
+                    .map_or(0.0, |o| o.value)
+            };
+            let xyz = channel_xyz(&chan.axis_map, |id| {
+                p.position.iter().find(|a| a.axis_id == id).map(|a| a.value - offset(id))
+            })?;
+            let plate = chan.tool_table.get(&p.tool).map(|t| t.plate_offset).unwrap_or_default();
+            Some([xyz[0] - plate[0], xyz[1] - plate[1]])
+        };
+        let mut out: Vec<CompContour> = Vec::new();
+        let mut open = false;
+        for pair in run.path.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            if !matches!(b.cutter_comp, 41 | 42) {
+                open = false;
+                continue;
+            }
+            let Some(to) = work_xy(b) else { continue };
+            let operation = outline.operation_at(b.block);
+            let continues = open
+                && out
+                    .last()
+                    .is_some_and(|c| c.side == b.cutter_comp && c.tool == b.tool && c.operation == operation);
+            if !continues {
+                let Some(from) = work_xy(a) else { continue };
+                out.push(CompContour {
+                    operation,
+                    operation_name: operation.and_then(|i| outline.operations.get(i)).map(|op| op.name.clone()),
+                    tool: b.tool,
+                    side: b.cutter_comp,
+                    points: vec![from],
+                    blocks: vec![a.block],
+                });
+                open = true;
+            }
+            // Moves along the tool axis leave the contour where it is.
+            let Some(contour) = out.last_mut() else { continue };
+            if contour.points.last().is_some_and(|p| (p[0] - to[0]).hypot(p[1] - to[1]) > 1e-9) {
+                contour.points.push(to);
+                contour.blocks.push(b.block);
+            }
+        }
+        out.retain(|c| c.points.len() >= 2);
+        out
+    }
+
+    /// Stable digest of the computed toolpath, bucketed by `tolerance` (mm),
+    /// for storing golden paths and detecting interpretation changes. The
+    /// program runs from a reference start (see `digest_reference`), so jogs
+    /// and work offset edits on the live machine leave the digest alone.
+    pub fn compute_toolpath_digest(&self, channel_index: usize, code: String, tolerance: f64) -> String {
+        if channel_index >= self.channels.len() {
+            return toolpath_digest(&[], tolerance);
+        }
+        let reference = self.digest_reference(channel_index);
+        let path = reference.run_verification(channel_index, &code).map(|r| r.path).unwrap_or_default();
+        toolpath_digest(&path, tolerance)
+    }
+
+    // Copy of the machine in the digest's reference state: every work offset
+    // and G52 shift zero (machine = work coordinates), G54 active, the
+    // channel's axes at zero without travel limits, and power-on modals.
+    fn digest_reference(&self, channel_index: usize) -> MachineBrain {
+        let mut reference = self.clone();
+        for wcs in reference.work_offsets.iter_mut() {
+            for offset in wcs.offsets.iter_mut() {
+                offset.value = 0.0;
+            }
+        }
+        reference.local_shift.clear();
+        reference.active_wcs = 0;
+        let chan = &mut reference.channels[channel_index];
+        chan.reset_modals_at_end();
+        chan.programmed_work.clear();
+        for m in &chan.axis_map {
+            if let Some(ax) = find_axis_mut(&mut reference.axes, m.axis_id) {
+                ax.position = 0.0;
+                ax.target = 0.0;
+                ax.velocity = 0.0;
+                ax.min_range = f64::NEG_INFINITY;
+                ax.max_range = f64::INFINITY;
+            }
+        }
+        reference
+    }
+
+    /// Compares two programs by their resulting toolpaths on this machine and
+    /// returns the path regions that differ by more than `tolerance` (mm).
+    pub fn compare_programs(&self, channel_index: usize, code_a: String, code_b: String, tolerance: f64) -> JsValue {
+        let diff = self.compare_program_paths(channel_index, &code_a, &code_b, tolerance);
+        serde_wasm_bindgen::to_value(&diff).unwrap_or(JsValue::NULL)
+    }
+
+    fn compare_program_paths(&self, channel_index: usize, code_a: &str, code_b: &str, tolerance: f64) -> Option<ProgramDiff> {
+        let a = self.run_verification(channel_index, code_a)?;
+        let b = self.run_verification(channel_index, code_b)?;
+        Some(compare_toolpaths(&a.path, &b.path, tolerance))
+    }
+
+    // ── Work envelope ─────────────────────────────────────────────────────
+
+    /// Travel limits as renderable geometry, in machine and active-WCS coordinates.
+    pub fn get_work_envelope(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.work_envelope()).unwrap_or(JsValue::NULL)
+    }
+
+    /// Program bounding box (from an offline run, before soft-limit clamping)
+    /// against the work envelope, listing the axes it would exceed.
+    pub fn check_program_envelope(&self, channel_index: usize, code: String) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.program_envelope_check(channel_index, &code)).unwrap_or(JsValue::NULL)
+    }
+
+    fn work_envelope(&self) -> WorkEnvelope {
+        let mut env = WorkEnvelope {
+            active_wcs: self.active_wcs,
+            box_min: [f64::INFINITY; 3],
+            box_max: [f64::NEG_INFINITY; 3],
+            work_box_min: [f64::INFINITY; 3],
+            work_box_max: [f64::NEG_INFINITY; 3],
+            ..WorkEnvelope::default()
+        };
+        for ax in &self.axes {
+            let direction = axis_direction_index(ax);
+            let rotary = ax.axis_type == AxisType::Rotary;
+            let (work_min, work_max) = if rotary {
+                (ax.min_range, ax.max_range)
+            } else {
+                (self.machine_to_work(ax.id, ax.min_range), self.machine_to_work(ax.id, ax.max_range))
+            };
+            if let (Some(d), false) = (direction, rotary) {
+                env.box_min[d] = env.box_min[d].min(ax.min_range);
+                env.box_max[d] = env.box_max[d].max(ax.max_range);
+                env.work_box_min[d] = env.work_box_min[d].min(work_min);
+                env.work_box_max[d] = env.work_box_max[d].max(work_max);
+            }
+            env.axes.push(AxisExtent {
+                axis_id: ax.id,
+                name: ax.physical_name.clone(),
+                rotary,
+                side: ax.side,
+                direction,
+                min: ax.min_range,
+                max: ax.max_range,
+                work_min,
+                work_max,
+            });
+        }
+        for v in env.box_min.iter_mut().chain(env.work_box_min.iter_mut()) {
+            if !v.is_finite() {
+                *v = 0.0;
+            }
+        }
+        for v in env.box_max.iter_mut().chain(env.work_box_max.iter_mut()) {
+            if !v.is_finite() {
+                *v = 0.0;
+            }
+        }
+        env
+    }
+
+    fn program_envelope_check(&self, channel_index: usize, code: &str) -> Option<EnvelopeCheck> {
+        let chan = self.channels.get(channel_index)?;
+        let mut check = EnvelopeCheck { envelope: self.work_envelope(), within: true, ..EnvelopeCheck::default() };
+        let mut xyz: Vec<(usize, &Axis)> = Vec::new();
+        for m in &chan.axis_map {
+            let k = match m.display_label.to_uppercase().as_str() {
+                "X" => 0,
+                "Y" => 1,
+                "Z" => 2,
+                _ => continue,
+            };
+            if let Some(ax) = find_axis(&self.axes, m.axis_id) {
+                xyz.push((k, ax));
+            }
+        }
+
+        // Run on a copy with the travel unclamped so the executed positions
+        // (work offsets, G52 shifts, loops, tool offsets) show the excess.
+        let mut sim = self.clone();
+        for ax in sim.axes.iter_mut().filter(|ax| xyz.iter().any(|(_, a)| a.id == ax.id)) {
+            ax.min_range = f64::NEG_INFINITY;
+            ax.max_range = f64::INFINITY;
+        }
+        let run = sim.run_verification(channel_index, code)?;
+        let mut bounds: Option<([f64; 3], [f64; 3])> = None;
+        for point in run.path.iter().skip(1) {
+            let mut p = [0.0; 3];
+            for (k, ax) in &xyz {
+                p[*k] = point.position.iter().find(|o| o.axis_id == ax.id).map_or(ax.position, |o| o.value);
+            }
+            let (min, max) = bounds.get_or_insert((p, p));
+            for k in 0..3 {
+                min[k] = min[k].min(p[k]);
+                max[k] = max[k].max(p[k]);
+            }
+        }
+        let Some((pmin, pmax)) = bounds else {
+            return Some(check);
+        };
+        for (k, ax) in &xyz {
+            let below_by = (ax.min_range - pmin[*k]).max(0.0);
+            let above_by = (pmax[*k] - ax.max_range).max(0.0);
+            if below_by > 1e-9 || above_by > 1e-9 {
+                check.within = false;
+                check.exceeded.push(EnvelopeExcess { axis_id: ax.id, name: ax.physical_name.clone(), below_by, above_by });
+            }
+        }
+        check.program_min = Some(pmin);
+        check.program_max = Some(pmax);
+        Some(check)
+    }
+
+    // ── Protected zones ───────────────────────────────────────────────────
+
+    /// Adds a prohibited region (`ProtectedZone` without id); returns its id or
+    /// -1 (always in builds without the `collision` feature, or without
+    /// setup access).
+    pub fn add_protected_zone(&mut self, zone: JsValue) -> i32 {
+        if !COLLISION || !self.permitted(Permission::Setup, "add_protected_zone") {
+            return -1;
+        }
+        match serde_wasm_bindgen::from_value::<ProtectedZone>(zone) {
+            Ok(zone) => self.insert_protected_zone(zone) as i32,
+            Err(_) => -1,
+        }
+    }
+
+    fn insert_protected_zone(&mut self, mut zone: ProtectedZone) -> u32 {
+        zone.id = self.protected_zones.iter().map(|z| z.id + 1).max().unwrap_or(1);
+        let id = zone.id;
+        self.protected_zones.push(zone);
+        id
+    }
+
+    pub fn remove_protected_zone(&mut self, id: u32) -> bool {
+        if !self.permitted(Permission::Setup, "remove_protected_zone") {
+            return false;
+        }
+        let before = self.protected_zones.len();
+        self.protected_zones.retain(|z| z.id != id);
+        self.protected_zones.len() != before
+    }
+
+    pub fn get_protected_zones(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.protected_zones).unwrap_or(JsValue::NULL)
+    }
+
+    // Machine X/Y/Z of the channel's checked point for the given axis positions:
+    // the spindle gauge point, or the tool tip along the spindle direction.
+    fn zone_point(&self, c_idx: usize, positions: &HashMap<u32, f64>, tool_tip: bool) -> [f64; 3] {
+        let chan = &self.channels[c_idx];
+        let mut p = [0.0; 3];
+        for m in &chan.axis_map {
+            let k = match m.display_label.to_uppercase().as_str() {
+                "X" => 0,
+                "Y" => 1,
+                "Z" => 2,
+                _ => continue,
+            };
+            p[k] = positions.get(&m.axis_id).copied().unwrap_or(0.0);
+        }
+        if tool_tip {
+            // Aggregate tools point their own way; others along the spindle.
+            let tip = match chan.active_tool_direction() {
+                Some(d) => d.vector(),
+                None => {
+                    let dir = spindle_orientation(&self.spindle_direction).unwrap_or(MAT4_IDENTITY);
+                    [-dir[8], -dir[9], -dir[10]]
+                }
+            };
+            for (k, v) in p.iter_mut().enumerate() {
+                *v += tip[k] * chan.tool_length;
+            }
+        }
+        p
+    }
+
+    // Checks the channel's queued motion (current position → axis targets →
+    // pending segments) against the zones. On entry raises PROTECTED_ZONE and
+    // holds the channel's axes where they are.
+    fn check_protected_zones(&mut self, c_idx: usize) -> bool {
+        if !COLLISION || self.protected_zones.is_empty() {
+            return true;
+        }
+        let chan = &self.channels[c_idx];
+        let waypoints = self.queued_waypoints(c_idx);
+        for zone in &self.protected_zones {
+            for w in waypoints.windows(2) {
+                let a = self.zone_point(c_idx, &w[0], zone.tool_length_aware);
+                let b = self.zone_point(c_idx, &w[1], zone.tool_length_aware);
+                let Some(t) = zone.shape.segment_entry(a, b) else { continue };
+                let at: Vec<String> = (0..3).map(|k| format!("{:.3}", a[k] + (b[k] - a[k]) * t)).collect();
+                let message = format!(
+                    "Motion enters protected zone '{}' at X{} Y{} Z{} (line {})",
+                    zone.name,
+                    at[0],
+                    at[1],
+                    at[2],
+                    chan.active_pc + 1
+                );
+                let chan_id = chan.id;
+                self.raise_alarm("PROTECTED_ZONE", &message, AlarmSeverity::Fault, Some(chan_id));
+                self.hold_channel_axes(c_idx);
+                return false;
+            }
+        }
+        true
+    }
+
+    // Machine positions of the channel's axes along its queued motion: current
+    // position, axis targets, then each pending segment.
+    fn queued_waypoints(&self, c_idx: usize) -> Vec<HashMap<u32, f64>> {
+        let chan = &self.channels[c_idx];
+        let mut current: HashMap<u32, f64> = HashMap::new();
+        let mut target: HashMap<u32, f64> = HashMap::new();
+        for m in &chan.axis_map {
+            if let Some(ax) = find_axis(&self.axes, m.axis_id) {
+                current.insert(m.axis_id, ax.position);
+                target.insert(m.axis_id, ax.target);
+            }
+        }
+        let mut waypoints = vec![current, target];
+        for seg in &chan.pending {
+            let mut next = waypoints.last().cloned().unwrap_or_default();
+            for (id, v) in seg.targets() {
+                next.insert(*id, *v);
+            }
+            waypoints.push(next);
+        }
+        waypoints
+    }
+
+    fn hold_channel_axes(&mut self, c_idx: usize) {
+        for m in &self.channels[c_idx].axis_map {
+            if let Some(ax) = find_axis_mut(&mut self.axes, m.axis_id) {
+                ax.target = ax.position;
+                ax.velocity = 0.0;
+            }
+        }
+    }
+
+    // ── WCS travel limits ─────────────────────────────────────────────────
+
+    /// Travel limit in work coordinates of WCS `wcs` (0 = G54 … 5 = G59) for one
+    /// axis, e.g. never below Z-5 in G55. `None` leaves that side open; both
+    /// `None` removes the limit.
+    pub fn set_wcs_travel_limit(&mut self, wcs: usize, axis_id: u32, min: Option<f64>, max: Option<f64>) {
+        if !self.permitted(Permission::Setup, "set_wcs_travel_limit") {
+            return;
+        }
+        self.wcs_limits.retain(|l| !(l.wcs == wcs && l.axis_id == axis_id));
+        if min.is_some() || max.is_some() {
+            self.wcs_limits.push(WcsTravelLimit { wcs, axis_id, min, max });
+        }
+    }
+
+    pub fn get_wcs_travel_limits(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.wcs_limits).unwrap_or(JsValue::NULL)
+    }
+
+    pub fn clear_wcs_travel_limits(&mut self) {
+        if !self.permitted(Permission::Setup, "clear_wcs_travel_limits") {
+            return;
+        }
+        self.wcs_limits.clear();
+    }
+
+    // Work coordinate (tool tip, as programmed) of an axis at machine position `m`.
+    fn programmed_work_at(&self, c_idx: usize, axis_id: u32, machine_pos: f64) -> f64 {
+        let chan = &self.channels[c_idx];
+        let mut w = self.machine_to_work(axis_id, machine_pos);
+        let label = chan.axis_map.iter().find(|m| m.axis_id == axis_id).map(|m| m.display_label.to_uppercase());
+        if let Some(k) = label.and_then(|l| ["X", "Y", "Z"].iter().position(|c| *c == l)) {
+            w -= self.length_comp_vector(c_idx)[k];
+        }
+        w
+    }
+
+    // First waypoint breaking a limit of the active WCS: (axis name, work value,
+    // limit, below). Work coordinates are linear in machine ones, so checking
+    // waypoints covers the straight moves between them.
+    fn wcs_limit_violation(&self, c_idx: usize, waypoints: &[HashMap<u32, f64>]) -> Option<(String, f64, f64, bool)> {
+        for limit in self.wcs_limits.iter().filter(|l| l.wcs == self.active_wcs) {
+            for w in waypoints {
+                let Some(m) = w.get(&limit.axis_id) else { continue };
+                let work = self.programmed_work_at(c_idx, limit.axis_id, *m);
+                let name = find_axis(&self.axes, limit.axis_id)
+                    .map(|a| a.physical_name.clone())
+                    .unwrap_or_default();
+                if let Some(min) = limit.min.filter(|min| work < min - INVARIANT_EPS) {
+                    return Some((name, work, min, true));
+                }
+                if let Some(max) = limit.max.filter(|max| work > max + INVARIANT_EPS) {
+                    return Some((name, work, max, false));
+                }
+            }
+        }
+        None
+    }
+
+    // Parse time: queued motion of the new block. Execution: current positions.
+    fn check_wcs_limits(&mut self, c_idx: usize, queued: bool) -> bool {
+        if self.wcs_limits.is_empty() {
+            return true;
+        }
+        let waypoints = if queued {
+            self.queued_waypoints(c_idx)
+        } else {
+            self.queued_waypoints(c_idx).into_iter().take(1).collect()
+        };
+        let Some((name, work, limit, below)) = self.wcs_limit_violation(c_idx, &waypoints) else {
+            return true;
+        };
+        let message = format!(
+            "{}{:.3} {} {} limit {}{:.3} (line {})",
+            name,
+            work,
+            if below { "below" } else { "above" },
+            wcs_name(self.active_wcs),
+            name,
+            limit,
+            self.channels[c_idx].active_pc + 1
+        );
+        let chan_id = self.channels[c_idx].id;
+        self.raise_alarm("WCS_LIMIT", &message, AlarmSeverity::Fault, Some(chan_id));
+        self.hold_channel_axes(c_idx);
+        false
+    }
+
+    // ── Retrace ───────────────────────────────────────────────────────────
+
+    /// Runs a paused channel backward along its executed trace by up to `steps`
+    /// trace points. Moves that cannot physically run backward stop the retrace
+    /// with a RETRACE_BLOCKED event. Resuming re-runs the retraced path forward.
+    /// Returns the number of points queued.
+    pub fn retrace(&mut self, channel_index: usize, steps: usize) -> usize {
+        let Some(chan) = self.channels.get(channel_index) else { return 0 };
+        if self.estop || !chan.paused {
+            return 0;
+        }
+        let points = &chan.trace.points;
+        let mut back: Vec<Vec<f64>> = Vec::new();
+        let mut blocked = None;
+        let mut i = points.len().saturating_sub(1);
+        while back.len() < steps && i > 0 {
+            if let Some(reason) = non_reversible_motion(points[i].motion) {
+                blocked = Some(reason);
+                break;
+            }
+            back.push(points[i - 1].position.clone());
+            i -= 1;
+        }
+        let chan_id = chan.id;
+        if let Some(reason) = blocked {
+            let message = format!("Retrace stopped: {} cannot run backward", reason);
+            self.emit_event("RETRACE_BLOCKED", &message, Some(chan_id));
+        }
+        if back.is_empty() {
+            return 0;
+        }
+
+        let ids: Vec<u32> = self.channels[channel_index].axis_map.iter().map(|m| m.axis_id).collect();
+        let as_targets = |pos: &[f64]| -> Vec<(u32, f64)> { ids.iter().copied().zip(pos.iter().copied()).collect() };
+        let here: Vec<(u32, f64)> = ids
+            .iter()
+            .filter_map(|id| find_axis(&self.axes, *id).map(|ax| (*id, ax.position)))
+            .collect();
+        let resume: Vec<(u32, f64)> = ids
+            .iter()
+            .filter_map(|id| find_axis(&self.axes, *id).map(|ax| (*id, ax.target)))
+            .collect();
+
+        let chan = &mut self.channels[channel_index];
+        // Forward replay on resume: back up the path, then on to the interrupted target.
+        chan.pending.push_front(PendingEntry::Move(resume));
+        chan.pending.push_front(PendingEntry::Move(here));
+        for pos in back.iter().take(back.len() - 1) {
+            chan.pending.push_front(PendingEntry::Move(as_targets(pos)));
+        }
+        chan.pending_queued = chan.pending_queued.wrapping_add(back.len() + 1);
+        chan.retrace = back.iter().map(|pos| as_targets(pos)).collect();
+        chan.retracing = true;
+        chan.trace.points.truncate(i + 1);
+        chan.trace.span.clear();
+        self.next_retrace_target(channel_index);
+        back.len()
+    }
+
+    fn next_retrace_target(&mut self, c_idx: usize) {
+        let Some(next) = self.channels[c_idx].retrace.pop_front() else {
+            self.channels[c_idx].retracing = false;
+            return;
+        };
+        for (axis_id, tgt) in next {
+            if let Some(ax) = find_axis_mut(&mut self.axes, axis_id) {
+                ax.target = limit_axis_target(ax, tgt);
+            }
+        }
+    }
+
+    // ── Access levels ─────────────────────────────────────────────────────
+
+    /// Turns the key switch. Lowering is always allowed; raising needs the key
+    /// configured for the target level (if any). Not a security boundary: it keeps
+    /// classroom users out of machine configuration.
+    pub fn set_access_level(&mut self, level: String, key: String) -> bool {
+        let Some(level) = AccessLevel::from_name(&level) else { return false; };
+        if level > self.access_level && self.access_keys.get(&level).is_some_and(|k| *k != key) {
+            self.emit_event("ACCESS_DENIED", &format!("Wrong key for {:?} level", level), None);
+            return false;
+        }
+        self.access_level = level;
+        true
+    }
+
+    pub fn get_access_level(&self) -> String {
+        format!("{:?}", self.access_level).to_lowercase()
+    }
+
+    /// Key required to raise the switch to `level` ("" removes it). Admin only.
+    pub fn set_access_key(&mut self, level: String, key: String) -> bool {
+        let Some(level) = AccessLevel::from_name(&level) else { return false; };
+        if !self.permitted_level(AccessLevel::Admin, "set_access_key") {
+            return false;
+        }
+        if key.is_empty() {
+            self.access_keys.remove(&level);
+        } else {
+            self.access_keys.insert(level, key);
+        }
+        true
+    }
+
+    /// Minimum level for a permission group ("offsets", "tool_geometry",
+    /// "tool_wear", "settings", "programs", "setup"). Admin only.
+    pub fn set_permission_level(&mut self, permission: String, level: String) -> bool {
+        let (Some(permission), Some(level)) = (Permission::from_name(&permission), AccessLevel::from_name(&level)) else {
+            return false;
+        };
+        if !self.permitted_level(AccessLevel::Admin, "set_permission_level") {
+            return false;
+        }
+        self.permission_levels.insert(permission, level);
+        true
+    }
+
+    fn permitted(&mut self, permission: Permission, action: &str) -> bool {
+        self.permitted_level(self.permission_level(permission), action)
+    }
+
+    fn permission_level(&self, permission: Permission) -> AccessLevel {
+        self.permission_levels.get(&permission).copied().unwrap_or(permission.default_level())
+    }
+
+    fn permitted_level(&mut self, required: AccessLevel, action: &str) -> bool {
+        if self.access_level >= required {
+            return true;
+        }
+        let message = format!("{} needs {:?} access (current {:?})", action, required, self.access_level);
+        self.emit_event("ACCESS_DENIED", &message, None);
+        false
+    }
+
+    // ── Edit history ──────────────────────────────────────────────────────
+
+    /// Tag recorded with subsequent edits in the audit log (e.g. "alice 10:42").
+    pub fn set_edit_tag(&mut self, tag: String) {
+        self.edit_tag = tag;
+    }
+
+    /// Reverts the last offset, tool-table or settings edit; refused below
+    /// the access level the edit itself needed.
+    pub fn undo(&mut self) -> bool {
+        let Some(record) = self.undo_stack.last() else { return false; };
+        if !self.history_permitted(record.permissions.clone(), "undo") {
+            return false;
+        }
+        let Some(record) = self.undo_stack.pop() else { return false; };
+        self.restore_config(&record.before);
+        self.push_audit(format!("undo {}", record.action));
+        self.redo_stack.push(record);
+        true
+    }
+
+    pub fn redo(&mut self) -> bool {
+        let Some(record) = self.redo_stack.last() else { return false; };
+        if !self.history_permitted(record.permissions.clone(), "redo") {
+            return false;
+        }
+        let Some(record) = self.redo_stack.pop() else { return false; };
+        self.restore_config(&record.after);
+        self.push_audit(format!("redo {}", record.action));
+        self.undo_stack.push(record);
+        true
+    }
+
+    fn history_permitted(&mut self, permissions: Vec<Permission>, action: &str) -> bool {
+        permissions.into_iter().all(|permission| self.permitted(permission, action))
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    pub fn get_audit_log(&self) -> JsValue {
+        let log: Vec<&AuditEntry> = self.audit_log.iter().collect();
+        serde_wasm_bindgen::to_value(&log).unwrap_or(JsValue::NULL)
+    }
+
+    // Runs an edit; if it changed the configuration, records it for undo and audit.
+    // Nested edits (one API calling another) record once, as the outermost action.
+    // Denied edits (access level too low) return R::default().
+    fn edit<R: Default>(&mut self, action: &str, permission: Permission, apply: impl FnOnce(&mut Self) -> R) -> R {
+        if self.edit_depth > 0 {
+            return apply(self);
+        }
+        if !self.permitted(permission, action) {
+            return R::default();
+        }
+        self.record_edit(action, vec![permission], apply)
+    }
+
+    fn record_edit<R>(&mut self, action: &str, permissions: Vec<Permission>, apply: impl FnOnce(&mut Self) -> R) -> R {
+        let before = self.config_snapshot();
+        self.edit_depth += 1;
+        let result = apply(self);
+        self.edit_depth -= 1;
+        let after = self.config_snapshot();
+        if after != before {
+            self.undo_stack.push(EditRecord { action: action.to_string(), permissions, before, after });
+            if self.undo_stack.len() > EDIT_HISTORY_MAX {
+                self.undo_stack.remove(0);
+            }
+            self.redo_stack.clear();
+            self.push_audit(action.to_string());
+        }
+        result
+    }
+
+    fn config_snapshot(&self) -> ConfigSnapshot {
+        ConfigSnapshot {
+            work_offsets: self.work_offsets.clone(),
+            channels: self
+                .channels
+                .iter()
+                .map(|c| ChannelEditData {
+                    id: c.id,
+                    tool_table: c.tool_table.clone(),
+                    tool_length: c.tool_length,
+                    tool_radius: c.tool_radius,
+                    active_d: c.active_d,
+                    active_h: c.active_h,
+                    turret_offsets: c.turret.as_ref().map(|t| t.offsets.clone()),
+                    tool_changer: c.tool_changer.clone(),
+                })
+                .collect(),
+            dialect: self.dialect,
+            word_limits: self.word_limits,
+            tolerances: self.tolerances,
+            persistent_params: self.persistent_params.clone(),
+        }
+    }
+
+    fn restore_config(&mut self, snapshot: &ConfigSnapshot) {
+        self.work_offsets = snapshot.work_offsets.clone();
+        for chan in self.channels.iter_mut() {
+            // Matched by id: channels may have been added or removed since.
+            let Some(tools) = snapshot.channels.iter().find(|c| c.id == chan.id) else { continue };
+            chan.tool_table = tools.tool_table.clone();
+            chan.tool_length = tools.tool_length;
+            chan.tool_radius = tools.tool_radius;
+            chan.active_d = tools.active_d;
+            chan.active_h = tools.active_h;
+            if let (Some(turret), Some(offsets)) = (chan.turret.as_mut(), &tools.turret_offsets) {
+                turret.offsets = offsets.clone();
+            }
+            chan.tool_changer = tools.tool_changer.clone();
+        }
+        self.dialect = snapshot.dialect;
+        self.word_limits = snapshot.word_limits;
+        self.tolerances = snapshot.tolerances;
+        self.replace_persistent_params(snapshot.persistent_params.clone());
+    }
+
+    fn push_audit(&mut self, action: String) {
+        let seq = self.next_audit_seq;
+        self.next_audit_seq += 1;
+        let at_s = self.now_s();
+        self.audit_log.push_back(AuditEntry { seq, action, tag: self.edit_tag.clone(), at_s });
+        while self.audit_log.len() > AUDIT_LOG_MAX {
+            self.audit_log.pop_front();
+        }
+    }
+
+    // ── Batch commands ────────────────────────────────────────────────────
+
+    /// Applies a JSON array of configuration commands all-or-nothing: every
+    /// command runs, and if any fails the configuration is rolled back. The
+    /// outcome lists each command's result. A kept batch is one undo step.
+    pub fn apply_batch(&mut self, commands_json: String) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.apply_batch_json(&commands_json)).unwrap_or(JsValue::NULL)
+    }
+
+    fn apply_batch_json(&mut self, commands_json: &str) -> BatchOutcome {
+        let commands: Vec<BatchCommand> = match serde_json::from_str(commands_json) {
+            Ok(c) => c,
+            Err(e) => return BatchOutcome { error: Some(e.to_string()), ..BatchOutcome::default() },
+        };
+        let mut permissions: Vec<Permission> = Vec::new();
+        for permission in commands.iter().map(BatchCommand::permission) {
+            if !permissions.contains(&permission) {
+                permissions.push(permission);
+            }
+        }
+        self.record_edit("apply_batch", permissions, |brain| {
+            let before = brain.config_snapshot();
+            let results: Vec<BatchCommandResult> = commands
+                .iter()
+                .enumerate()
+                .map(|(index, cmd)| {
+                    let error = brain.apply_batch_command(cmd).err();
+                    BatchCommandResult { index, ok: error.is_none(), error }
+                })
+                .collect();
+            let applied = results.iter().all(|r| r.ok);
+            if !applied {
+                brain.restore_config(&before);
+            }
+            BatchOutcome { applied, error: None, results }
+        })
+    }
+
+    fn apply_batch_command(&mut self, cmd: &BatchCommand) -> Result<(), String> {
+        if !self.permitted(cmd.permission(), "apply_batch") {
+            return Err(format!("needs {:?} access", self.permission_level(cmd.permission())));
+        }
+        let axis_known = |brain: &Self, axis_id: u32| find_axis(&brain.axes, axis_id).is_some();
+        match cmd {
+            BatchCommand::SetWorkZero { wcs, axis_id, value } => {
+                if !self.work_offset_editable(*wcs) || !axis_known(self, *axis_id) || !value.is_finite() {
+                    return Err(format!("cannot set WCS {} axis {} to {}", wcs, axis_id, value));
+                }
+                self.set_work_zero(*axis_id, *wcs, *value);
+            }
+            BatchCommand::ShiftWorkOffset { wcs: Some(wcs), axis_id, delta } => {
+                if !self.shift_work_offset(*wcs, *axis_id, *delta) {
+                    return Err(format!("cannot shift WCS {} axis {}", wcs, axis_id));
+                }
+            }
+            BatchCommand::ShiftWorkOffset { wcs: None, axis_id, delta } => {
+                if !axis_known(self, *axis_id) || !delta.is_finite() || self.shift_work_offsets(*axis_id, *delta) == 0 {
+                    return Err(format!("cannot shift axis {}", axis_id));
+                }
+            }
+            BatchCommand::CopyWorkOffset { from, to } => {
+                if !self.copy_work_offset(*from, *to) {
+                    return Err(format!("cannot copy WCS {} to {}", from, to));
+                }
+            }
+            BatchCommand::SetToolEntry { channel, slot, length, radius } => {
+                if *channel >= self.channels.len() || !length.is_finite() || !radius.is_finite() {
+                    return Err(format!("invalid tool entry for channel {} slot {}", channel, slot));
+                }
+                self.set_tool_table_entry(*channel, *slot, *length, *radius);
+            }
+            BatchCommand::SetToolWear { channel, slot, length_wear, radius_wear } => {
+                if !length_wear.is_finite() || !radius_wear.is_finite() || !self.set_tool_wear(*channel, *slot, *length_wear, *radius_wear) {
+                    return Err(format!("no tool slot {} on channel {}", slot, channel));
+                }
+            }
+            BatchCommand::SetDialect { name } => {
+                if !self.set_dialect(name.clone()) {
+                    return Err(format!("unknown dialect {}", name));
+                }
+            }
+            BatchCommand::SetWordLimits { spindle_max_rpm, magazine_pockets, offset_slots } => {
+                self.set_word_limits(*spindle_max_rpm, *magazine_pockets, *offset_slots);
+            }
+            BatchCommand::SetTolerances { profile } => {
+                if !self.apply_tolerance_profile(*profile) {
+                    return Err("tolerances must be positive".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // ── Machine configuration ─────────────────────────────────────────────
+
+    /// Applies a machine configuration by difference: unchanged axes and
+    /// channels keep positions, offsets and programs; changed axes keep their
+    /// position (clamped into new limits); only added, removed or retyped
+    /// items start fresh. Refused while a channel runs or the machine homes.
+    pub fn reconfigure(&mut self, config_json: String) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.reconfigure_json(&config_json)).unwrap_or(JsValue::NULL)
+    }
+
+    fn reconfigure_json(&mut self, config_json: &str) -> ReconfigureReport {
+        let fail = |msg: String| ReconfigureReport { error: Some(msg), ..ReconfigureReport::default() };
+        if !self.permitted(Permission::Settings, "reconfigure") {
+            return fail(format!("needs {:?} access", self.permission_level(Permission::Settings)));
+        }
+        let config: MachineConfig = match serde_json::from_str(config_json) {
+            Ok(c) => c,
+            Err(e) => return fail(e.to_string()),
+        };
+        self.apply_machine_config(&config)
+    }
+
+    fn apply_machine_config(&mut self, config: &MachineConfig) -> ReconfigureReport {
+        let fail = |msg: String| ReconfigureReport { error: Some(msg), ..ReconfigureReport::default() };
+        if let Err(msg) = validate_machine_config(config) {
+            return fail(msg);
+        }
+        if self.is_homing || self.channels.iter().any(|c| c.is_running) {
+            return fail("machine is busy (program running or homing)".to_string());
+        }
+        let mut report = ReconfigureReport { applied: true, ..ReconfigureReport::default() };
+
+        // Axes: drop missing or retyped ones, update the rest, add new ones.
+        let stale: Vec<(u32, String)> = self
+            .axes
+            .iter()
+            .filter(|ax| !config.axes.iter().any(|a| a.name == ax.physical_name && a.kind == ax.axis_type))
+            .map(|ax| (ax.id, ax.physical_name.clone()))
+            .collect();
+        for (id, name) in stale {
+            self.remove_axis(id);
+            report.removed_axes.push(name);
+        }
+        let mut order = Vec::with_capacity(config.axes.len());
+        for cfg in &config.axes {
+            let id = match self.axes.iter().find(|ax| ax.physical_name == cfg.name) {
+                Some(ax) => ax.id,
+                None => {
+                    report.added_axes.push(cfg.name.clone());
+                    let Some(id) = self.add_axis(cfg.name.clone(), cfg.kind, cfg.min, cfg.max) else { continue };
+                    id
+                }
+            };
+            order.push(id);
+            let Some(ax) = find_axis_mut(&mut self.axes, id) else { continue };
+            let rotary_mode = cfg.rotary_mode.unwrap_or(ax.rotary_mode);
+            let side = cfg.side.unwrap_or(ax.side);
+            let link = cfg.link.map(|c| c.to_ascii_uppercase()).or(ax.link_axis);
+            let encoder = cfg.encoder.unwrap_or(ax.encoder);
+            let changed = ax.min_range != cfg.min
+                || ax.max_range != cfg.max
+                || ax.accel != cfg.accel
+                || ax.invert != cfg.invert
+                || ax.machine_zero != cfg.machine_zero
+                || ax.rotary_mode != rotary_mode
+                || ax.side != side
+                || ax.link_axis != link
+                || ax.encoder != encoder;
+            ax.min_range = cfg.min;
+            ax.max_range = cfg.max;
+            ax.accel = cfg.accel;
+            ax.invert = cfg.invert;
+            ax.machine_zero = cfg.machine_zero;
+            ax.rotary_mode = rotary_mode;
+            ax.side = side;
+            ax.link_axis = link;
+            ax.encoder = encoder;
+            if encoder == EncoderType::Absolute && report.added_axes.contains(&cfg.name) {
+                ax.homed = true; // powered up with the new axis
+            }
+            if ax.axis_type == AxisType::Linear || ax.rotary_mode == RotaryMode::Limited {
+                ax.position = ax.position.clamp(ax.min_range, ax.max_range);
+                ax.target = ax.target.clamp(ax.min_range, ax.max_range);
+            }
+            if changed && !report.added_axes.contains(&cfg.name) {
+                report.changed_axes.push(cfg.name.clone());
+            }
+        }
+        self.reorder_axes(order);
+
+        // Channels: by id.
+        let stale: Vec<usize> = (0..self.channels.len())
+            .rev()
+            .filter(|i| !config.channels.iter().any(|c| c.id == self.channels[*i].id))
+            .collect();
+        for idx in stale {
+            report.removed_channels.push(self.channels[idx].id);
+            self.remove_channel(idx);
+        }
+        report.removed_channels.sort_unstable();
+        for cfg in &config.channels {
+            let axis_map: Vec<ChannelAxisMap> = cfg
+                .axes
+                .iter()
+                .filter_map(|a| {
+                    let ax = self.axes.iter().find(|ax| ax.physical_name == a.axis)?;
+                    Some(ChannelAxisMap { axis_id: ax.id, display_label: a.label.clone() })
+                })
+                .collect();
+            let idx = match self.channels.iter().position(|c| c.id == cfg.id) {
+                Some(idx) => {
+                    let same = {
+                        let current = &self.channels[idx].axis_map;
+                        current.len() == axis_map.len()
+                            && current.iter().zip(&axis_map).all(|(a, b)| a.axis_id == b.axis_id && a.display_label == b.display_label)
+                    };
+                    if !same {
+                        self.reassign_channel_axes(idx, axis_map);
+                        report.remapped_channels.push(cfg.id);
+                    }
+                    idx
+                }
+                None => {
+                    let mut chan = Channel::new(cfg.id, axis_map);
+                    chan.arc_center_absolute = self.dialect.arc_center_absolute_default();
+                    self.channels.push(chan);
+                    report.added_channels.push(cfg.id);
+                    self.channels.len() - 1
+                }
+            };
+            let chan = &mut self.channels[idx];
+            chan.rear_turret = cfg.rear_turret;
+            if cfg.defaults.is_valid() && chan.defaults != cfg.defaults {
+                chan.defaults = cfg.defaults;
+                chan.apply_modal_defaults();
+            }
+            for tool in &cfg.tools {
+                let entry = chan.tool_table.entry(tool.slot.max(0)).or_default();
+                entry.length = tool.length;
+                entry.radius = tool.radius.abs();
+                entry.plate_offset = tool.plate_offset;
+                entry.flutes = tool.flutes;
+            }
+        }
+        report.rehome_axes = self.restore_references(&config.references);
+        if !config.persistent_params.is_empty() {
+            self.replace_persistent_params(config.persistent_params.clone());
+        }
+        report
+    }
+
+    /// Home references of every axis (`AxisReference` list as JSON) for
+    /// saving with a session.
+    pub fn export_axis_references(&self) -> String {
+        let references: Vec<AxisReference> = self
+            .axes
+            .iter()
+            .map(|ax| AxisReference {
+                name: ax.physical_name.clone(),
+                homed: ax.homed,
+                machine_zero: ax.machine_zero,
+                position: ax.position,
+                encoder: Some(ax.encoder),
+            })
+            .collect();
+        serde_json::to_string(&references).unwrap_or_default()
+    }
+
+    /// Restores saved home references (see `restore_references`). Returns
+    /// the names of axes that must be homed again, or null if the list does
+    /// not parse, the machine is busy or without settings access.
+    pub fn restore_axis_references(&mut self, json: String) -> JsValue {
+        let Ok(references) = serde_json::from_str::<Vec<AxisReference>>(&json) else { return JsValue::NULL };
+        if self.is_homing || self.channels.iter().any(|c| c.is_running) {
+            return JsValue::NULL;
+        }
+        if !self.permitted(Permission::Settings, "restore_axis_references") {
+            return JsValue::NULL;
+        }
+        serde_wasm_bindgen::to_value(&self.restore_references(&references)).unwrap_or(JsValue::NULL)
+    }
+
+    // Machine zero and position come back for every named axis. The homed
+    // flag only survives on absolute encoders: an incremental axis that was
+    // homed is left unhomed and listed in the result. Unknown names are skipped.
+    fn restore_references(&mut self, references: &[AxisReference]) -> Vec<String> {
+        let mut rehome = Vec::new();
+        for reference in references {
+            let Some(ax) = self.axes.iter_mut().find(|ax| ax.physical_name == reference.name) else { continue };
+            ax.machine_zero = reference.machine_zero;
+            let mut position = reference.position;
+            if ax.axis_type == AxisType::Linear || ax.rotary_mode == RotaryMode::Limited {
+                position = position.clamp(ax.min_range, ax.max_range);
+            }
+            ax.position = position;
+            ax.target = position;
+            ax.velocity = 0.0;
+            ax.homed = reference.homed && ax.encoder == EncoderType::Absolute;
+            if reference.homed && !ax.homed {
+                rehome.push(ax.physical_name.clone());
+            }
+        }
+        rehome
+    }
+
+    /// Position feedback of an axis: "absolute" or "incremental".
+    pub fn set_axis_encoder(&mut self, axis_id: u32, encoder: String) -> bool {
+        let encoder = match encoder.trim().to_ascii_lowercase().as_str() {
+            "absolute" => EncoderType::Absolute,
+            "incremental" => EncoderType::Incremental,
+            _ => return false,
+        };
+        let Some(ax) = find_axis_mut(&mut self.axes, axis_id) else { return false };
+        ax.encoder = encoder;
+        true
+    }
+
+    /// Names accepted by `load_machine_template`.
+    pub fn list_machine_templates(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(MACHINE_TEMPLATES).unwrap_or(JsValue::NULL)
+    }
+
+    /// Replaces the whole machine with a built-in template (axes, limits,
+    /// kinematic chain, channels and a starter tool table). False for an
+    /// unknown name, while the machine is busy or without settings access.
+    pub fn load_machine_template(&mut self, name: String) -> bool {
+        let Some(config) = machine_template(&name) else { return false; };
+        if self.is_homing || self.channels.iter().any(|c| c.is_running) {
+            return false;
+        }
+        if !self.permitted(Permission::Settings, "load_machine_template") {
+            return false;
+        }
+        self.clear_config();
+        self.apply_machine_config(&config).applied
+    }
+
+    // ── Kinematics ────────────────────────────────────────────────────────
+
+    /// Spindle mounting on the head: tool direction ("+X".."-Z") and offset (mm).
+    pub fn set_spindle_mount(&mut self, direction: String, offset_x: f64, offset_y: f64, offset_z: f64) -> bool {
+        let direction = direction.trim().to_uppercase();
+        if spindle_orientation(&direction).is_none() || !self.permitted(Permission::Settings, "set_spindle_mount") {
+            return false;
+        }
+        self.spindle_direction = direction;
+        self.spindle_offset = [offset_x, offset_y, offset_z];
+        true
+    }
+
+    /// World transform of every machine component computed from the axis
+    /// positions through the tool-side and table-side kinematic chains.
+    pub fn get_component_poses(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.component_poses()).unwrap_or(JsValue::NULL)
+    }
+
+    /// Centerline and current angle of every rotary axis (`RotaryAxisLine`),
+    /// for drawing rotation axes and checking pivot distances.
+    pub fn get_rotary_axis_lines(&self) -> JsValue {
+        serde_wasm_bindgen::to_value(&self.rotary_axis_lines()).unwrap_or(JsValue::NULL)
+    }
+
+    /// Calibration errors of a rotary axis (centerline offset in mm, tilt in
+    /// degrees about the two perpendicular directions). False for linear axes.
+    pub fn set_axis_calibration(&mut self, axis_id: u32, dx: f64, dy: f64, dz: f64, tilt_1_deg: f64, tilt_2_deg: f64) -> bool {
+        let values = [dx, dy, dz, tilt_1_deg, tilt_2_deg];
+        if values.iter().any(|v| !v.is_finite()) {
+            return false;
+        }
+        if !find_axis(&self.axes, axis_id).is_some_and(|ax| ax.axis_type == AxisType::Rotary) {
+            return false;
+        }
+        if !self.permitted(Permission::Settings, "set_axis_calibration") {
+            return false;
+        }
+        let calibration = AxisCalibration { center_offset: [dx, dy, dz], tilt_deg: [tilt_1_deg, tilt_2_deg] };
+        if calibration == AxisCalibration::default() {
+            self.axis_calibration.remove(&axis_id);
+        } else {
+            self.axis_calibration.insert(axis_id, calibration);
+        }
+        true
+    }
+
+    pub fn get_axis_calibration(&self, axis_id: u32) -> JsValue {
+        let calibration = self.axis_calibration.get(&axis_id).copied().unwrap_or_default();
+        serde_wasm_bindgen::to_value(&calibration).unwrap_or(JsValue::NULL)
+    }
+
+    /// "Calibrates" the machine: every rotary axis error back to zero.
+    /// Returns how many axes were corrected.
+    pub fn calibrate_axes(&mut self) -> usize {
+        if !self.permitted(Permission::Settings, "calibrate_axes") {
+            return 0;
+        }
+        let corrected = self.axis_calibration.len();
+        self.axis_calibration.clear();
+        corrected
+    }
+
+    /// Tool tip error in the part (table) frame at the current axis positions:
+    /// where the calibration errors put the tip minus where an ideal machine
+    /// would, [dx, dy, dz] in mm.
+    pub fn get_tcp_error(&self) -> Vec<f64> {
+        self.tcp_error().to_vec()
+    }
+
+    /// Largest tool tip error along `code`, run offline on this machine.
+    pub fn max_tcp_error(&self, channel_index: usize, code: String) -> f64 {
+        let Some(run) = self.run_verification(channel_index, &code) else { return 0.0; };
+        let mut sim = self.clone();
+        run.path
+            .iter()
+            .map(|p| {
+                for a in &p.position {
+                    if let Some(ax) = find_axis_mut(&mut sim.axes, a.axis_id) {
+                        ax.position = a.value;
+                    }
+                }
+                let e = sim.tcp_error();
+                (e[0] * e[0] + e[1] * e[1] + e[2] * e[2]).sqrt()
+            })
+            .fold(0.0, f64::max)
+    }
+
+    fn tcp_error(&self) -> [f64; 3] {
+        let tip = |calibrated: bool| {
+            let poses = self.chain_poses(calibrated);
+            let pose = |name: &str| poses.iter().find(|p| p.name == name).map(|p| p.matrix).unwrap_or(MAT4_IDENTITY);
+            let tool = pose("tool");
+            mat4_apply(&mat4_rigid_inverse(&pose("table")), [tool[12], tool[13], tool[14]])
+        };
+        let (actual, ideal) = (tip(true), tip(false));
+        [0, 1, 2].map(|k| actual[k] - ideal[k])
+    }
+
+    fn component_poses(&self) -> Vec<ComponentPose> {
+        self.chain_poses(true)
+    }
+
+    // An axis pose includes its own rotation, which leaves its centerline in place.
+    fn rotary_axis_lines(&self) -> Vec<RotaryAxisLine> {
+        let (actual, nominal) = (self.chain_poses(true), self.chain_poses(false));
+        let pose = |poses: &[ComponentPose], name: &str, id: Option<u32>| {
+            poses.iter().find(|p| p.kind == name && (id.is_none() || p.axis_id == id)).map(|p| p.matrix)
+        };
+        let tool = pose(&actual, "tool", None).unwrap_or(MAT4_IDENTITY);
+        let tip = [tool[12], tool[13], tool[14]];
+        self.axes
+            .iter()
+            .filter(|ax| ax.axis_type == AxisType::Rotary)
+            .filter_map(|ax| {
+                let dir = axis_direction_index(ax)?;
+                let (real, ideal) = (pose(&actual, "axis", Some(ax.id))?, pose(&nominal, "axis", Some(ax.id))?);
+                let (center, axis) = self.axis_calibration.get(&ax.id).copied().unwrap_or_default().centerline(dir);
+                let origin = mat4_apply(&real, center);
+                let direction = mat4_apply_vector(&real, axis);
+                let mut unit = [0.0; 3];
+                unit[dir] = 1.0;
+                let along = |k: f64| [0, 1, 2].map(|i| origin[i] + direction[i] * k);
+                let to_tip = [0, 1, 2].map(|i| tip[i] - origin[i]);
+                let cross = [
+                    to_tip[1] * direction[2] - to_tip[2] * direction[1],
+                    to_tip[2] * direction[0] - to_tip[0] * direction[2],
+                    to_tip[0] * direction[1] - to_tip[1] * direction[0],
+                ];
+                Some(RotaryAxisLine {
+                    axis_id: ax.id,
+                    name: ax.physical_name.clone(),
+                    side: ax.side,
+                    angle_deg: ax.position,
+                    origin,
+                    direction,
+                    nominal_origin: mat4_apply(&ideal, [0.0; 3]),
+                    nominal_direction: mat4_apply_vector(&ideal, unit),
+                    start: along(-ROTARY_LINE_HALF_LENGTH),
+                    end: along(ROTARY_LINE_HALF_LENGTH),
+                    tool_distance: cross.iter().map(|v| v * v).sum::<f64>().sqrt(),
+                })
+            })
+            .collect()
+    }
+
+    // Each chain applies its axes in id order; table-side linear axes move the
+    // table opposite to the commanded direction (tool moves relative to part).
+    // `calibrated` applies the rotary axis errors.
+    fn chain_poses(&self, calibrated: bool) -> Vec<ComponentPose> {
+        let mut poses = vec![ComponentPose {
+            name: "base".to_string(),
+            kind: "base".to_string(),
+            axis_id: None,
+            matrix: MAT4_IDENTITY,
+        }];
+        let mut head = MAT4_IDENTITY;
+        let mut table = MAT4_IDENTITY;
+        for ax in &self.axes {
+            let Some(dir) = axis_direction_index(ax) else { continue };
+            let value = if ax.invert { -ax.position } else { ax.position };
+            let chain = match ax.side {
+                AxisSide::Tool => &mut head,
+                AxisSide::Table => &mut table,
+            };
+            let step = match ax.axis_type {
+                AxisType::Linear => {
+                    let mut v = [0.0; 3];
+                    v[dir] = if ax.side == AxisSide::Table { -value } else { value };
+                    mat4_translation(v)
+                }
+                AxisType::Rotary => match self.axis_calibration.get(&ax.id).filter(|_| calibrated) {
+                    Some(calibration) => calibration.rotation(dir, value),
+                    None => mat4_rotation(dir, value),
+                },
+            };
+            *chain = mat4_mul(chain, &step);
+            poses.push(ComponentPose {
+                name: ax.physical_name.clone(),
+                kind: "axis".to_string(),
+                axis_id: Some(ax.id),
+                matrix: *chain,
+            });
+        }
+        let orientation = spindle_orientation(&self.spindle_direction).unwrap_or(MAT4_IDENTITY);
+        let spindle = mat4_mul(&mat4_mul(&head, &mat4_translation(self.spindle_offset)), &orientation);
+        let tool_length = self.channels.first().map(|c| c.tool_length).unwrap_or(0.0);
+        let tool = mat4_mul(&spindle, &mat4_translation([0.0, 0.0, -tool_length]));
+        for (name, matrix) in [("table", table), ("head", head), ("spindle", spindle), ("tool", tool)] {
+            poses.push(ComponentPose {
+                name: name.to_string(),
+                kind: name.to_string(),
+                axis_id: None,
+                matrix,
+            });
+        }
+        // Turrets ride on the head and index about Z.
+        for chan in &self.channels {
+            let Some(turret) = &chan.turret else { continue };
+            poses.push(ComponentPose {
+                name: format!("turret{}", chan.id),
+                kind: "turret".to_string(),
+                axis_id: None,
+                matrix: mat4_mul(&head, &mat4_rotation(2, turret.angle_deg)),
+            });
+        }
+        poses
+    }
+
+    // ── Simulation clock ──────────────────────────────────────────────────
+
+    /// Simulation time (s): the scaled tick time since the machine was
+    /// created. Events, alarms, telemetry, utilization and reports use it.
+    pub fn get_sim_time(&self) -> f64 {
+        self.now_s()
+    }
+
+    /// Multiplier applied to every tick's dt (2 = twice real time, 0 freezes).
+    pub fn set_sim_speed(&mut self, speed: f64) -> bool {
+        if !speed.is_finite() || speed < 0.0 {
+            return false;
+        }
+        self.sim_speed = speed;
+        true
+    }
+
+    pub fn get_sim_speed(&self) -> f64 {
+        self.sim_speed
+    }
+
+    /// Time (ms) the control needs to prepare each program block, 0 = none.
+    /// Blocks shorter than this starve: motion stops until the next block
+    /// is ready.
+    pub fn set_block_processing_time(&mut self, ms: f64) -> bool {
+        if !ms.is_finite() || !(0.0..=BLOCK_TIME_MAX_MS).contains(&ms) {
+            return false;
+        }
+        if !self.permitted(Permission::Settings, "set_block_processing_time") {
+            return false;
+        }
+        self.block_time_s = ms / 1000.0;
+        true
+    }
+
+    pub fn get_block_processing_time(&self) -> f64 {
+        self.block_time_s * 1000.0
+    }
+
+    // ── Tick ──────────────────────────────────────────────────────────────
+
+    pub fn tick(&mut self, dt_ms: f64) {
+        let dt_ms = dt_ms * self.sim_speed;
+        if dt_ms > 0.0 {
+            self.sim_time_s += dt_ms / 1000.0;
+        }
+        let before: Vec<f64> = self.axes.iter().map(|ax| ax.position).collect();
+        let running: Vec<(bool, bool, bool)> = self
+            .channels
+            .iter()
+            .map(|c| {
+                let indexing = c.turret.as_ref().is_some_and(|t| t.index_remaining_s > 0.0);
+                let changing = c.tool_change.as_ref().is_some_and(|e| e.phase == "CHANGING");
+                let held = self.feed_hold || c.paused || c.auto_hold;
+                let dwelling = !held && matches!(c.pending.front(), Some(PendingEntry::Dwell(_)));
+                (c.is_running, indexing || changing, dwelling)
+            })
+            .collect();
+        let taps: Vec<Option<RigidTap>> = self.channels.iter().map(|c| c.rigid_tap).collect();
+        self.tick_motion(dt_ms);
+        if dt_ms > 0.0 {
+            let dt_sec = dt_ms / 1000.0;
+            for (c_idx, (was_running, indexing, dwelling)) in running.into_iter().enumerate() {
+                if was_running && !self.estop {
+                    self.record_run_time(c_idx, &before, dt_sec, indexing, dwelling);
+                }
+            }
+            self.advance_spindles(&before, &taps, dt_sec);
+            for c_idx in 0..self.channels.len() {
+                self.check_setup_tool(c_idx);
+            }
+            let now = self.now_s();
+            self.telemetry.record(&self.axes, &before, dt_sec, now);
+            for (ax, position) in self.axes.iter_mut().zip(before) {
+                update_axis_meters(ax, position, dt_sec);
+            }
+        }
+    }
+
+    fn tick_motion(&mut self, dt_ms: f64) {
+    if dt_ms > 0.0 {
+        self.record_utilization(dt_ms / 1000.0);
+    }
+    if self.estop || dt_ms <= 0.0 { return; }
+    if self.feed_hold {
+        for ax in self.axes.iter_mut() {
+            ax.velocity = 0.0;
+        }
+        return;
+    }
+    let dt_sec = dt_ms / 1000.0;
+    let tol = self.tolerances;
+
+    // ── Helper closure: trapezoidal move for one axis ──────────────────
+    // Returns true if still moving
+    fn move_axis(ax: &mut Axis, feed: f64, dt_sec: f64, stop_at_target: bool, tol: &ToleranceProfile) -> bool {
+        let diff = ax.target - ax.position;
+        let dist = diff.abs();
+        if dist <= tol.position_mm {
+            ax.position = ax.target;
+            if stop_at_target {
+                ax.velocity = 0.0;
+            }
+            return false;
+        }
+
+        let dir = diff.signum();
+        let feed = feed.max(1.0);
+        let accel = ax.accel.max(1.0);
+        let mut vel = ax.velocity.max(0.0);
+
+        let stop_dist = (vel * vel) / (2.0 * accel);
+
+        if stop_at_target && dist <= stop_dist + 0.01 {
+            vel = (vel - accel * dt_sec).max(0.0);
+        } else if vel < feed {
+            vel = (vel + accel * dt_sec).min(feed);
+        }
+
+        let mut step = (vel / 60.0) * dt_sec;
+        if step <= 0.000001 {
+            if dist <= tol.crawl_snap_mm {
+                ax.position = ax.target;
+                ax.velocity = 0.0;
+                return false;
+            }
+            vel = (feed * 0.02).max(1.0).min(feed);
+            step = (vel / 60.0) * dt_sec;
+        }
+
+        if step >= dist {
+            ax.position = ax.target;
+            if stop_at_target {
+                ax.velocity = 0.0;
+            } else {
+                ax.velocity = vel;
+            }
+            false
+        } else {
+            ax.position += step * dir;
+            ax.velocity = vel;
+            true
+        }
+    }
+
+    // ── Homing: takes priority over programs ───────────────────────────
+    if self.is_homing {
+        if self.homing_index >= self.homing_sequence.len() {
+            self.is_homing = false;
+            self.homing_sequence.clear();
+            self.homing_index = 0;
+            log_at!(self, Info, "homing", "Homing complete");
+            return;
+        }
+        let axis_id = self.homing_sequence[self.homing_index];
+        if let Some(ax) = find_axis_mut(&mut self.axes, axis_id) {
+            let home_feed = if self.homing_rapid {
+                axis_rapid_feed(ax)
+            } else {
+                self.homing_feed.max(1.0)
+            };
+            if ax.velocity < 1.0 {
+                // Avoid ultra-slow startup ramp; honor selected homing feed immediately.
+                ax.velocity = home_feed;
+            }
+            let still_moving = move_axis(ax, home_feed, dt_sec, true, &tol);
+            if !still_moving {
+                ax.homed = true;
+                ax.position = 0.0;
+                ax.target = 0.0;
+                ax.velocity = 0.0;
+                self.homing_index += 1;
+                if self.homing_index >= self.homing_sequence.len() {
+                    self.is_homing = false;
+                    self.homing_sequence.clear();
+                    self.homing_index = 0;
+                    log_at!(self, Info, "homing", "Homing complete");
+                }
+            }
+        } else {
+            self.homing_index += 1;
         }
+        return;
     }
 
-    pub fn step_once(&mut self, channel_index: usize) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            if !chan.is_running { return; }
-            chan.step_once = true;
-            chan.paused = false;
+    // ── Jogs: axes outside running programs move at their jog feed ─────
+    let mut jogs = std::mem::take(&mut self.jogs);
+    jogs.retain(|jog| {
+        let owned = self.channels.iter().any(|c| c.is_running && c.axis_map.iter().any(|m| m.axis_id == jog.axis_id));
+        match find_axis_mut(&mut self.axes, jog.axis_id) {
+            Some(ax) if !owned => move_axis(ax, jog.feed, dt_sec, true, &tol),
+            _ => false,
+        }
+    });
+    self.jogs = jogs;
+
+    // ── Channel program execution ──────────────────────────────────────
+    self.update_auto_holds();
+    for c_idx in 0..self.channels.len() {
+        if self.channels[c_idx].paused && !self.channels[c_idx].retracing { continue; }
+        // The next block is prepared while the current one moves.
+        let prep = &mut self.channels[c_idx].block_prep_s;
+        *prep = (*prep - dt_sec).max(0.0);
+        if self.channels[c_idx].auto_hold {
+            self.decelerate_channel(c_idx, dt_sec, &tol);
+            continue;
+        }
+        if self.channels[c_idx].dry_geometric && !self.channels[c_idx].retracing {
+            self.run_channel_dry(c_idx);
+            continue;
+        }
+        // Motion waits for the turret to finish indexing.
+        if let Some(turret) = self.channels[c_idx].turret.as_mut().filter(|t| t.index_remaining_s > 0.0) {
+            turret.advance(dt_sec);
+            continue;
+        }
+        // A dwell holds the channel at rest until its time has run out.
+        if let Some(PendingEntry::Dwell(left)) = self.channels[c_idx].pending.front_mut() {
+            *left -= dt_sec;
+            if *left <= 0.0 {
+                self.channels[c_idx].pending.pop_front();
+            }
+            for m in &self.channels[c_idx].axis_map {
+                if let Some(ax) = find_axis_mut(&mut self.axes, m.axis_id) {
+                    ax.velocity = 0.0;
+                }
+            }
+            continue;
+        }
+
+        let motion = self.channels[c_idx].current_motion;
+        let feed = self.effective_feed(c_idx);
+        // 0% feed override behaves like feed-hold for feed moves (G1/G2/G3).
+        // Rapid (G0) is still allowed.
+        if feed <= 0.0 && motion != 0 {
+            for m in &self.channels[c_idx].axis_map {
+                if self.jogs.iter().any(|j| j.axis_id == m.axis_id) {
+                    continue;
+                }
+                if let Some(ax) = find_axis_mut(&mut self.axes, m.axis_id) {
+                    ax.velocity = 0.0;
+                }
+            }
+            continue;
+        }
+        let pending_active = !self.channels[c_idx].pending.is_empty();
+        let has_future = pending_active
+            || (self.channels[c_idx].is_running && self.channels[c_idx].pc < self.channels[c_idx].program.len());
+        // Continuous mode: only force exact stop when requested (G61), at final stop,
+        // or when a pause/single-block stop is pending.
+        let starved = !pending_active && self.channels[c_idx].block_prep_s > 0.0;
+        let stop_at_target = self.channels[c_idx].exact_stop
+            || !has_future
+            || starved
+            || self.channels[c_idx].pause_pending
+            || self.channels[c_idx].rigid_tap.is_some()
+            || matches!(self.channels[c_idx].pending.front(), Some(PendingEntry::ToolChange(_)));
+        let mut still_moving = false;
+        // Rapids run each axis type at its own rate; feed moves take F as deg/min on rotaries.
+        let rotary_feed = if motion == 0 {
+            self.channel_rapid_feed_for(c_idx, AxisType::Rotary).unwrap_or(feed)
+        } else {
+            feed
+        };
+
+        for m in &self.channels[c_idx].axis_map {
+            if self.jogs.iter().any(|j| j.axis_id == m.axis_id) {
+                continue;
+            }
+            if let Some(ax) = find_axis_mut(&mut self.axes, m.axis_id) {
+                let axis_feed = if ax.axis_type == AxisType::Rotary { rotary_feed } else { feed };
+                if move_axis(ax, axis_feed, dt_sec, stop_at_target, &tol) {
+                    still_moving = true;
+                }
+            }
+        }
+        if self.channels[c_idx].retracing {
+            if !still_moving {
+                self.next_retrace_target(c_idx);
+            }
+            continue;
+        }
+        self.record_trace(c_idx);
+        if still_moving && !self.check_wcs_limits(c_idx, false) {
+            continue;
+        }
+
+        if self.channels[c_idx].is_running && !still_moving && !starved {
+            let parses_block = !pending_active;
+            self.advance_channel(c_idx);
+            if parses_block {
+                self.channels[c_idx].block_prep_s = self.block_time_s;
+            }
         }
     }
 
-    pub fn jump_blocks(&mut self, channel_index: usize, delta: i32) {
-        let Some(chan) = self.channels.get_mut(channel_index) else { return; };
-        if chan.program.is_empty() { return; }
-
-        let len = chan.program.len() as i32;
-        let mut next_pc = chan.pc as i32 + delta;
-        if next_pc < 0 { next_pc = 0; }
-        if next_pc > len { next_pc = len; }
+    #[cfg(feature = "invariants")]
+    {
+        let violations = self.invariant_violations();
+        assert!(violations.is_empty(), "machine invariants violated: {:?}", violations);
+    }
+}
 
-        chan.pc = next_pc as usize;
-        chan.active_pc = if chan.pc == 0 { -1 } else { (chan.pc - 1) as i32 };
-        chan.pending.clear();
-        chan.pause_pending = false;
-        chan.step_once = false;
-        chan.paused = true;
-        chan.is_running = true;
+// Checks internal consistency; used by the `invariants` feature after each tick
+// and by embedders/fuzzers through `check_invariants`.
+fn invariant_violations(&mut self) -> Vec<String> {
+    let mut out = Vec::new();
 
-        for m in &chan.axis_map {
-            if let Some(ax) = self.axes.get_mut(m.axis_id as usize) {
-                ax.velocity = 0.0;
-            }
+    for ax in &self.axes {
+        if !ax.position.is_finite() || !ax.target.is_finite() || !ax.velocity.is_finite() {
+            out.push(format!("axis {} has non-finite state", ax.physical_name));
+            continue;
+        }
+        if ax.velocity < 0.0 {
+            out.push(format!("axis {} velocity {} is negative", ax.physical_name, ax.velocity));
+        }
+        if (ax.axis_type == AxisType::Linear || ax.rotary_mode == RotaryMode::Limited)
+            && (ax.position < ax.min_range - INVARIANT_EPS || ax.position > ax.max_range + INVARIANT_EPS)
+        {
+            out.push(format!(
+                "axis {} position {} outside soft limits [{}, {}]",
+                ax.physical_name, ax.position, ax.min_range, ax.max_range
+            ));
         }
     }
 
-    pub fn set_tool_length(&mut self, channel_index: usize, length: f64) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            chan.tool_length = length;
-            chan.active_h = 0;
-            let entry = chan.tool_table.entry(0).or_insert(ToolTableEntry { radius: 0.0, length: 0.0 });
-            entry.length = length;
+    self.invariant_track.resize(
+        self.channels.len(),
+        InvariantTrack { cutter_comp: 40, ..InvariantTrack::default() },
+    );
+    for c_idx in 0..self.channels.len() {
+        let chan = &self.channels[c_idx];
+        let prev = std::mem::take(&mut self.invariant_track[c_idx]);
+        let mut track = InvariantTrack {
+            pc: chan.pc,
+            pending_len: chan.pending.len(),
+            pending_queued: chan.pending_queued,
+            cutter_comp: chan.cutter_comp,
+            programmed_work: chan.programmed_work.clone(),
+            unverified_axes: prev.unverified_axes,
+        };
+        if chan.pc > chan.program.len() {
+            out.push(format!("channel {} pc {} beyond program end {}", chan.id, chan.pc, chan.program.len()));
+        }
+        if chan.pc == prev.pc {
+            // Without a new block, pending segments may only be consumed
+            // (or queued by the executor itself).
+            let queued = chan.pending_queued.wrapping_sub(prev.pending_queued);
+            if chan.pending.len() > prev.pending_len + queued {
+                out.push(format!(
+                    "channel {} pending segments grew from {} to {} without a new block",
+                    chan.id,
+                    prev.pending_len,
+                    chan.pending.len()
+                ));
+            }
+        } else if prev.cutter_comp == 40 && chan.cutter_comp == 40 {
+            // Comp off before and after the new block: its programmed axes must be hit exactly.
+            for (axis_id, work) in &chan.programmed_work {
+                let changed = prev.programmed_work.get(axis_id).is_none_or(|p| (p - work).abs() > INVARIANT_EPS);
+                if changed && !track.unverified_axes.contains(axis_id) {
+                    track.unverified_axes.push(*axis_id);
+                }
+            }
+        } else {
+            track.unverified_axes.clear();
         }
-    }
 
-    pub fn set_tool_length_comp(&mut self, channel_index: usize, active: bool) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            chan.length_comp_active = active;
+        if chan.pending.is_empty() {
+            for axis_id in std::mem::take(&mut track.unverified_axes) {
+                let Some(m) = chan.axis_map.iter().find(|m| m.axis_id == axis_id) else {
+                    continue;
+                };
+                let label = m.display_label.to_uppercase();
+                if label != "X" && label != "Y" {
+                    continue;
+                }
+                let (Some(work), Some(ax)) = (chan.programmed_work.get(&axis_id), find_axis(&self.axes, axis_id)) else {
+                    continue;
+                };
+                let expected = self.machine_target_with_limits(axis_id, self.work_to_machine(axis_id, *work));
+                if (ax.target - expected).abs() > INVARIANT_EPS {
+                    out.push(format!(
+                        "channel {} axis {} target {} differs from programmed {} with comp off",
+                        chan.id, label, ax.target, expected
+                    ));
+                }
+            }
         }
+        self.invariant_track[c_idx] = track;
     }
+    out
+}
 
-    pub fn set_tool_radius(&mut self, channel_index: usize, radius: f64) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            chan.tool_radius = radius.abs();
-            chan.active_d = 0;
-            let entry = chan.tool_table.entry(0).or_insert(ToolTableEntry { radius: 0.0, length: 0.0 });
-            entry.radius = chan.tool_radius;
-        }
+// Called once the channel's axes reached their targets: pause if requested,
+// feed the next pending segment, or interpret the next program block.
+fn advance_channel(&mut self, c_idx: usize) {
+    if self.channels[c_idx].pause_pending && self.channels[c_idx].pending.is_empty() {
+        self.channels[c_idx].paused = true;
+        self.channels[c_idx].pause_pending = false;
+        self.channels[c_idx].step_once = false;
+        return;
     }
 
-    pub fn set_tool_table_entry(&mut self, channel_index: usize, slot: i32, length: f64, radius: f64) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            let idx = slot.max(0);
-            let radius_abs = radius.abs();
-            chan.tool_table.insert(idx, ToolTableEntry { radius: radius_abs, length });
-            if chan.active_tool == idx {
-                chan.tool_length = length;
-                chan.tool_radius = radius_abs;
+    // If we have pending arc segments, execute them before advancing the program counter.
+    // Dwells are timed by tick; instant and dry runs just step over them.
+    if let Some(next) = self.channels[c_idx].pending.pop_front() {
+        let now_s = self.now_s();
+        let chan = &mut self.channels[c_idx];
+        match next {
+            PendingEntry::TapReverse => chan.spindle_mode = if chan.spindle_mode == 3 { 4 } else { 3 },
+            PendingEntry::TapEnd => {
+                if let Some(tap) = chan.rigid_tap.take() {
+                    chan.spindle_mode = tap.restore_mode;
+                }
             }
+            // The changer holds the channel like a dwell, then the tool is in.
+            PendingEntry::ToolChange(tool) => {
+                let change_time_s = chan.tool_changer.as_ref().map_or(0.0, |c| c.change_time_s);
+                chan.pending.push_front(PendingEntry::ToolLoad(tool));
+                chan.pending_queued = chan.pending_queued.wrapping_add(1);
+                if change_time_s > 0.0 {
+                    chan.pending.push_front(PendingEntry::Dwell(change_time_s));
+                    chan.pending_queued = chan.pending_queued.wrapping_add(1);
+                }
+                if let Some(event) = chan.tool_change.as_mut() {
+                    event.enter("CHANGING", now_s);
+                }
+            }
+            PendingEntry::ToolLoad(tool) => {
+                if let Some(event) = chan.tool_change.as_mut() {
+                    event.enter("DONE", now_s);
+                }
+                self.set_active_tool(c_idx, tool);
+                return;
+            }
+            _ => {}
         }
-    }
-
-    pub fn set_active_tool(&mut self, channel_index: usize, slot: i32) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            let idx = slot.max(0);
-            chan.active_tool = idx;
-            chan.active_d = 0;
-            chan.active_h = 0;
-            if idx == 0 {
-                chan.tool_length = 0.0;
-                chan.tool_radius = 0.0;
-                chan.length_comp_active = false;
-                chan.cutter_comp = 40;
-                chan.tool_table.insert(0, ToolTableEntry { radius: 0.0, length: 0.0 });
-            } else if let Some(entry) = chan.tool_table.get(&idx).copied() {
-                chan.tool_length = entry.length;
-                chan.tool_radius = entry.radius.abs();
-                // Keep D0/H0 in sync with currently loaded tool.
-                chan.tool_table.insert(0, entry);
+        for (axis_id, tgt) in next.targets() {
+            if let Some(ax) = find_axis_mut(&mut self.axes, *axis_id) {
+                ax.target = limit_axis_target(ax, *tgt);
             }
         }
+        return;
     }
-
-    pub fn set_cutter_comp(&mut self, channel_index: usize, mode: i32) {
-        if let Some(chan) = self.channels.get_mut(channel_index) {
-            chan.cutter_comp = match mode {
-                41 => 41,
-                42 => 42,
-                _ => 40,
-            };
-            chan.comp_linear_prev = None;
-            chan.comp_entry_pending = false;
+    if let Some(line) = self.channels[c_idx].injected.pop_front() {
+        log_at!(self, Trace, "interpreter", "channel {} synthesized: {}", self.channels[c_idx].id, line);
+        let traced = self.begin_block_trace(c_idx, &line, true);
+        self.channels[c_idx].synthesizing = true;
+        self.parse_line(c_idx, &line);
+        self.channels[c_idx].synthesizing = false;
+        self.end_block_trace(c_idx, traced);
+        if self.check_protected_zones(c_idx) {
+            self.check_wcs_limits(c_idx, true);
         }
+        return;
     }
 
-    fn resolve_d_radius(&self, channel_index: usize, d_raw: f64, d_scaled: f64) -> f64 {
-        let Some(chan) = self.channels.get(channel_index) else {
-            return d_scaled.abs();
+    let current_pc = self.channels[c_idx].pc;
+    if current_pc < self.channels[c_idx].program.len() {
+        // Streamed programs: the block and its look-ahead successor must be resident.
+        let budget = self.program_memory_budget;
+        let program = &mut self.channels[c_idx].program;
+        if !program.ensure_resident(current_pc, budget) {
+            let chan_id = self.channels[c_idx].id;
+            let message = format!("Program source could not supply line {}", current_pc + 1);
+            self.raise_alarm("PROGRAM_SOURCE", &message, AlarmSeverity::Fault, Some(chan_id));
+            return;
+        }
+        program.ensure_resident(current_pc + 1, budget);
+        let mut line = program.get(current_pc).cloned().unwrap_or_default();
+        if self.block_deleted(&line) {
+            self.channels[c_idx].pc += 1;
+            return;
+        }
+        self.channels[c_idx].program_stop = None;
+        self.channels[c_idx].active_pc = current_pc as i32;
+        if let Some(n) = block_number(&line) {
+            self.channels[c_idx].block_number = Some(n);
+        }
+        if let Some(hits) = self.channels[c_idx].block_hits.get_mut(current_pc) {
+            *hits += 1;
+        }
+        if line.contains('#') {
+            self.refresh_cutting_params(c_idx);
+        }
+        // M99 outside a call is a plain M code (a part-count code, say).
+        let in_call = matches!(self.channels[c_idx].flow.last(), Some(FlowFrame::Call { .. }));
+        let fanuc_flow = parse_fanuc_flow(&line).filter(|f| *f != FanucFlow::Return || in_call);
+        let flow = if !MACROS && (parse_o_word(&line).is_some() || macro_call_code(&line).is_some() || fanuc_flow.is_some()) {
+            Some(Err("macros are not compiled into this build".to_string()))
+        } else if let Some(flow) = fanuc_flow {
+            Some(self.execute_fanuc_flow(c_idx, current_pc, flow, &line))
+        } else if let Some(word) = parse_o_word(&line) {
+            Some(self.execute_o_word(c_idx, current_pc, &word))
+        } else if let Some(code) = macro_call_code(&line) {
+            let Some(text) = self.substitute_parameters(c_idx, &line) else { return; };
+            Some(self.execute_macro_call(c_idx, current_pc, code, &text))
+        } else {
+            None
         };
-
-        let idx = d_raw.round() as i32;
-        if (d_raw - idx as f64).abs() <= 1e-9 {
-            if let Some(entry) = chan.tool_table.get(&idx) {
-                return entry.radius.abs();
+        if let Some(result) = flow {
+            match result {
+                Ok(next_pc) => self.channels[c_idx].pc = next_pc,
+                Err(message) => {
+                    let chan_id = self.channels[c_idx].id;
+                    let message = format!("line {}: {} ({})", current_pc + 1, message, line);
+                    self.raise_alarm("O_WORD", &message, AlarmSeverity::Fault, Some(chan_id));
+                }
             }
+            return;
         }
-        d_scaled.abs()
+        let hook = self.block_hook.clone();
+        if let Some(hook) = &hook {
+            match hook.before(&self.block_context(c_idx, &line)) {
+                BlockHookAction::Run => {}
+                BlockHookAction::Replace(text) => line = text.trim().to_uppercase(),
+                BlockHookAction::Veto(reason) => {
+                    // The vetoed block stays current so the operator sees it.
+                    let chan_id = self.channels[c_idx].id;
+                    let message = format!("line {} vetoed: {} ({})", current_pc + 1, reason, line);
+                    self.raise_alarm("BLOCK_HOOK", &message, AlarmSeverity::Fault, Some(chan_id));
+                    return;
+                }
+            }
+        }
+        log_at!(self, Trace, "interpreter", "channel {} line {}: {}", self.channels[c_idx].id, current_pc + 1, line);
+        let traced = self.begin_block_trace(c_idx, &line, false);
+        self.parse_line(c_idx, &line);
+        self.end_block_trace(c_idx, traced);
+        if self.channels[c_idx].is_running {
+            self.check_block_assertions(c_idx, &line);
+        }
+        if let Some(hook) = &hook {
+            hook.after(&self.block_context(c_idx, &line));
+        }
+        if self.check_protected_zones(c_idx) {
+            self.check_wcs_limits(c_idx, true);
+        }
+        if self.channels[c_idx].single_block || self.channels[c_idx].step_once {
+            self.channels[c_idx].pause_pending = true;
+        }
+        self.channels[c_idx].pc += 1;
+        // M02/M30: nothing after the end block runs (subprograms often follow it).
+        if self.channels[c_idx].program_end.is_some() {
+            let chan = &mut self.channels[c_idx];
+            chan.pc = chan.program.len();
+            chan.flow.clear();
+            return;
+        }
+        // G66: the modal macro follows the block's move.
+        let chan = &self.channels[c_idx];
+        let modal = chan.modal_call.clone().filter(|m| chan.is_running && m.depth == chan.flow.len());
+        if let Some(call) = modal.filter(|_| self.block_moves_axes(c_idx, &line)) {
+            match self.call_subroutine(c_idx, current_pc, &call.label, call.args, MACRO_LOCAL_PARAMS) {
+                Ok(next_pc) => self.channels[c_idx].pc = next_pc,
+                Err(message) => {
+                    let chan_id = self.channels[c_idx].id;
+                    let message = format!("line {}: G66 {} ({})", current_pc + 1, message, line);
+                    self.raise_alarm("O_WORD", &message, AlarmSeverity::Fault, Some(chan_id));
+                }
+            }
+        }
+    } else {
+        let chan = &mut self.channels[c_idx];
+        chan.is_running = false;
+        chan.active_pc = -1;
+        chan.finished = true;
+        if chan.program_end.take() == Some(30) {
+            chan.pc = 0;
+            chan.reset_modals_at_end();
+        }
+        log_at!(self, Debug, "interpreter", "Channel {}: program end", self.channels[c_idx].id);
     }
+}
 
-    fn resolve_table_slot_index(raw: f64) -> Option<i32> {
-        let idx = raw.round() as i32;
-        if (raw - idx as f64).abs() <= 1e-9 {
-            Some(idx.max(0))
-        } else {
+// A "/" block skipped while block delete is on.
+fn block_deleted(&self, line: &str) -> bool {
+    self.block_delete && line.trim_start().starts_with('/')
+}
+
+// Applies the block's parameter assignments and returns its text with
+// `#n` / `#<name>` / `[expr]` values replaced. None (after a PARAMETER
+// fault) when an expression does not evaluate.
+fn substitute_parameters(&mut self, c_idx: usize, line: &str) -> Option<String> {
+    if !line.contains(['#', '[']) {
+        return Some(line.to_string());
+    }
+    let (params, dialect) = (&self.channels[c_idx].params, self.dialect);
+    let result = split_assignments(line, params, dialect)
+        .and_then(|(rest, assignments)| Ok((expand_parameters(&rest, params, dialect)?, assignments)));
+    match result {
+        Ok((text, assignments)) => {
+            self.apply_assignments(c_idx, assignments);
+            Some(text)
+        }
+        Err(message) => {
+            let chan = &self.channels[c_idx];
+            let message = format!("line {}: {} ({})", chan.active_pc + 1, message, line.trim());
+            let chan_id = chan.id;
+            self.raise_alarm("PARAMETER", &message, AlarmSeverity::Fault, Some(chan_id));
             None
         }
     }
+}
 
-    fn resolve_h_length(&self, channel_index: usize, h_raw: f64, h_scaled: f64) -> f64 {
-        let Some(chan) = self.channels.get(channel_index) else {
-            return h_scaled;
-        };
-
-        let idx = h_raw.round() as i32;
-        if (h_raw - idx as f64).abs() <= 1e-9 {
-            if let Some(entry) = chan.tool_table.get(&idx) {
-                return entry.length;
-            }
+fn apply_assignments(&mut self, c_idx: usize, assignments: Vec<(String, f64)>) {
+    self.share_persistent_params(&assignments);
+    let params = &mut self.channels[c_idx].params;
+    for (key, value) in assignments {
+        // NaN: assigned vacant #0, so the parameter is emptied.
+        if value.is_nan() {
+            params.remove(&key);
+        } else {
+            params.insert(key, value);
         }
-        h_scaled
     }
+}
 
-    pub fn move_to(&mut self, axis_id: u32, target: f64) {
-        if let Some(ax) = self.axes.get_mut(axis_id as usize) {
-            ax.target = match ax.axis_type {
-                AxisType::Rotary => normalize_rotary_target(target),
-                AxisType::Linear => target.clamp(ax.min_range, ax.max_range),
+// Persistent parameters written by a block go to machine memory and every
+// channel's view of it.
+fn share_persistent_params(&mut self, assignments: &[(String, f64)]) {
+    for (key, value) in assignments.iter().filter(|(key, _)| param_scope(key) == ParamScope::Persistent) {
+        if let Ok(n) = key.parse() {
+            match value.is_nan() {
+                true => self.persistent_params.remove(&n),
+                false => self.persistent_params.insert(n, *value),
+            };
+        }
+        for chan in self.channels.iter_mut() {
+            match value.is_nan() {
+                true => chan.params.remove(key),
+                false => chan.params.insert(key.clone(), *value),
             };
         }
     }
+}
 
-    // ── Homing ────────────────────────────────────────────────────────────
+fn replace_persistent_params(&mut self, params: BTreeMap<u32, f64>) {
+    for chan in self.channels.iter_mut() {
+        chan.params.retain(|key, _| param_scope(key) != ParamScope::Persistent);
+        chan.params.extend(params.iter().map(|(n, v)| (n.to_string(), *v)));
+    }
+    self.persistent_params = params;
+}
 
-    pub fn home_all(&mut self) {
-        if self.estop { return; }
-        let mut order: Vec<u32> = Vec::with_capacity(self.axes.len());
-        if let Some(z) = self.axes.iter().find(|ax| ax.physical_name.eq_ignore_ascii_case("Z")) {
-            order.push(z.id);
+// Recommended data for the active tool as read-only program parameters:
+// #<_ROUGH_RPM>, #<_ROUGH_FEED>, #<_FINISH_RPM>, #<_FINISH_FEED>. Unset
+// (and so an error to read) without a workpiece material or tool diameter.
+fn refresh_cutting_params(&mut self, c_idx: usize) {
+    let (material, max_rpm) = (self.workpiece_material, self.word_limits.spindle_max_rpm);
+    let chan = &mut self.channels[c_idx];
+    let tool = chan.tool_table.get(&chan.active_tool).copied().unwrap_or_default();
+    for (operation, prefix) in [(CutOperation::Roughing, "ROUGH"), (CutOperation::Finishing, "FINISH")] {
+        let data = material.and_then(|m| recommend_cutting(m, operation, tool.total_radius() * 2.0, tool.flutes, max_rpm));
+        for (quantity, value) in [("RPM", data.as_ref().map(|d| d.rpm)), ("FEED", data.as_ref().map(|d| d.feed))] {
+            let key = format!("<_{}_{}>", prefix, quantity);
+            match value {
+                Some(v) => chan.params.insert(key, v),
+                None => chan.params.remove(&key),
+            };
         }
-        for ax in self.axes.iter() {
-            if !order.iter().any(|id| *id == ax.id) {
-                order.push(ax.id);
+    }
+}
+
+// Runs an O-word statement at `pc`; returns the next program line.
+fn execute_o_word(&mut self, c_idx: usize, pc: usize, word: &OWord) -> Result<usize, String> {
+    let (params, dialect) = (&self.channels[c_idx].params, self.dialect);
+    let arg = |k: usize| -> Result<f64, String> {
+        let text = word.args.get(k).ok_or_else(|| format!("O{} {:?} needs a [condition]", word.label, word.keyword))?;
+        eval_expression(text, params, dialect)
+    };
+    let len = self.channels[c_idx].program.len();
+    let find_after = |from: usize, keywords: &[OKeyword]| self.find_o_word(c_idx, (from + 1)..len, &word.label, keywords);
+    let find_before = |keywords: &[OKeyword]| self.find_o_word(c_idx, (0..pc).rev(), &word.label, keywords);
+    let missing = |what: &str| format!("O{} {} not found", word.label, what);
+    let mut branches: Vec<(usize, bool)> = Vec::new();
+    let next = match word.keyword {
+        OKeyword::Sub => find_after(pc, &[OKeyword::EndSub]).ok_or_else(|| missing("ENDSUB"))?.0 + 1,
+        OKeyword::Call => {
+            let args = (0..word.args.len()).map(arg).collect::<Result<Vec<f64>, String>>()?;
+            if args.len() > CALL_ARG_PARAMS {
+                return Err(format!("O{} CALL takes at most {} arguments", word.label, CALL_ARG_PARAMS));
             }
+            let args = args.into_iter().enumerate().map(|(n, v)| (n + 1, v)).collect();
+            self.call_subroutine(c_idx, pc, &word.label, args, CALL_ARG_PARAMS)?
         }
-        for ax in self.axes.iter_mut() {
-            ax.target = 0.0;
-            ax.homed = false;
+        OKeyword::EndSub | OKeyword::Return => {
+            let chan = &mut self.channels[c_idx];
+            match chan.flow.last() {
+                Some(FlowFrame::Call { label, .. }) if *label == word.label => {}
+                _ if word.keyword == OKeyword::EndSub => return Ok(pc + 1),
+                _ => return Err(format!("O{} RETURN outside its subroutine", word.label)),
+            }
+            let Some(FlowFrame::Call { return_pc, saved, .. }) = chan.flow.pop() else { unreachable!() };
+            for (key, value) in saved {
+                match value {
+                    Some(v) => chan.params.insert(key, v),
+                    None => chan.params.remove(&key),
+                };
+            }
+            return_pc
         }
-        self.start_homing_sequence(order, false, 300.0);
-        console_log!("Homing all axes");
+        OKeyword::If => {
+            // Try the IF, then the ELSEIF branches in order, then ELSE.
+            let mut at = pc;
+            let mut holds = arg(0)? != 0.0;
+            loop {
+                branches.push((at, holds));
+                if holds {
+                    break at + 1;
+                }
+                let keywords = [OKeyword::ElseIf, OKeyword::Else, OKeyword::EndIf];
+                let (branch_pc, branch) = find_after(at, &keywords).ok_or_else(|| missing("ENDIF"))?;
+                if branch.keyword != OKeyword::ElseIf {
+                    break branch_pc + 1;
+                }
+                let condition = branch.args.first().ok_or_else(|| missing("ELSEIF [condition]"))?;
+                holds = eval_expression(condition, params, dialect)? != 0.0;
+                at = branch_pc;
+            }
+        }
+        // Reached at the end of a taken branch.
+        OKeyword::ElseIf | OKeyword::Else => find_after(pc, &[OKeyword::EndIf]).ok_or_else(|| missing("ENDIF"))?.0 + 1,
+        OKeyword::EndIf => pc + 1,
+        OKeyword::While => {
+            let holds = arg(0)? != 0.0;
+            branches.push((pc, holds));
+            if holds {
+                pc + 1
+            } else {
+                find_after(pc, &[OKeyword::EndWhile]).ok_or_else(|| missing("ENDWHILE"))?.0 + 1
+            }
+        }
+        OKeyword::EndWhile => find_before(&[OKeyword::While]).ok_or_else(|| missing("WHILE"))?.0,
+        OKeyword::Break | OKeyword::Continue => {
+            let in_repeat = matches!(self.channels[c_idx].flow.last(), Some(FlowFrame::Repeat { label, .. }) if *label == word.label);
+            let (end_pc, _) = find_after(pc, &[OKeyword::EndWhile, OKeyword::EndRepeat]).ok_or_else(|| missing("loop end"))?;
+            match (word.keyword, in_repeat) {
+                (OKeyword::Break, true) => {
+                    self.channels[c_idx].flow.pop();
+                    end_pc + 1
+                }
+                (OKeyword::Break, false) => end_pc + 1,
+                (_, true) => end_pc, // ENDREPEAT counts the pass
+                (_, false) => find_before(&[OKeyword::While]).ok_or_else(|| missing("WHILE"))?.0,
+            }
+        }
+        OKeyword::Repeat => {
+            let count = arg(0)?.max(0.0).round() as u64;
+            if count == 0 {
+                return Ok(find_after(pc, &[OKeyword::EndRepeat]).ok_or_else(|| missing("ENDREPEAT"))?.0 + 1);
+            }
+            let chan = &mut self.channels[c_idx];
+            if chan.flow.len() >= FLOW_MAX_DEPTH {
+                return Err(format!("O{} REPEAT nests deeper than {}", word.label, FLOW_MAX_DEPTH));
+            }
+            chan.flow.push(FlowFrame::Repeat { label: word.label.clone(), body_pc: pc + 1, remaining: count });
+            pc + 1
+        }
+        OKeyword::EndRepeat => {
+            let chan = &mut self.channels[c_idx];
+            match chan.flow.last_mut() {
+                Some(FlowFrame::Repeat { label, body_pc, remaining }) if *label == word.label => {
+                    *remaining -= 1;
+                    if *remaining > 0 {
+                        return Ok(*body_pc);
+                    }
+                }
+                _ => return Err(format!("O{} ENDREPEAT without REPEAT", word.label)),
+            }
+            chan.flow.pop();
+            pc + 1
+        }
+    };
+    for (line, holds) in branches {
+        self.record_branch(c_idx, line, holds);
     }
+    Ok(next)
+}
 
-    pub fn home_all_ordered(&mut self, primary_axis_id: i32, rapid: bool, feed: f64) {
-        if self.estop { return; }
-        let mut order: Vec<u32> = Vec::with_capacity(self.axes.len());
-        if primary_axis_id >= 0 {
-            let pid = primary_axis_id as u32;
-            if (pid as usize) < self.axes.len() {
-                order.push(pid);
+fn record_branch(&mut self, c_idx: usize, line: usize, holds: bool) {
+    let hits = self.channels[c_idx].branch_hits.entry(line).or_insert([0, 0]);
+    hits[usize::from(!holds)] += 1;
+}
+
+// G65 calls macro P once, G66 arms it as a modal call and G67 cancels that.
+// Returns the next program line.
+fn execute_macro_call(&mut self, c_idx: usize, pc: usize, code: i32, line: &str) -> Result<usize, String> {
+    if code == 67 {
+        self.channels[c_idx].modal_call = None;
+        return Ok(pc + 1);
+    }
+    let (label, args) = parse_macro_call(line)?;
+    if code == 66 {
+        let depth = self.channels[c_idx].flow.len();
+        self.channels[c_idx].modal_call = Some(ModalMacroCall { label, args, depth });
+        return Ok(pc + 1);
+    }
+    self.call_subroutine(c_idx, pc, &label, args, MACRO_LOCAL_PARAMS)
+}
+
+// Runs a Fanuc control-flow block at `pc` (`line` as written); returns the
+// next program line.
+fn execute_fanuc_flow(&mut self, c_idx: usize, pc: usize, flow: FanucFlow, line: &str) -> Result<usize, String> {
+    let (params, dialect) = (&self.channels[c_idx].params, self.dialect);
+    let eval = |text: &str| eval_expression(text, params, dialect);
+    let next = match flow {
+        FanucFlow::Goto(target) => self.goto_block(c_idx, pc, eval(&target)?)?,
+        FanucFlow::IfGoto(condition, target) => {
+            let holds = eval(&condition)? != 0.0;
+            let target = eval(&target)?;
+            self.record_branch(c_idx, pc, holds);
+            if holds {
+                self.goto_block(c_idx, pc, target)?
+            } else {
+                pc + 1
             }
         }
-        for ax in self.axes.iter() {
-            if !order.iter().any(|id| *id == ax.id) {
-                order.push(ax.id);
+        FanucFlow::IfThen(condition, statement) => {
+            let holds = eval(&condition)? != 0.0;
+            if holds {
+                let (rest, assignments) = split_assignments(&statement, params, dialect)?;
+                if is_executable_line(&rest) || assignments.is_empty() {
+                    return Err("IF .. THEN takes only parameter assignments".to_string());
+                }
+                self.apply_assignments(c_idx, assignments);
             }
+            self.record_branch(c_idx, pc, holds);
+            pc + 1
         }
-        for ax in self.axes.iter_mut() {
-            ax.target = 0.0;
-            ax.homed = false;
+        FanucFlow::While(condition, m) => {
+            let run = match condition {
+                Some(condition) => eval(&condition)? != 0.0,
+                None => true,
+            };
+            self.record_branch(c_idx, pc, run);
+            if run {
+                pc + 1
+            } else {
+                let len = self.channels[c_idx].program.len();
+                self.find_fanuc_flow(c_idx, (pc + 1)..len, |f| *f == FanucFlow::End(m)).ok_or_else(|| format!("END{} not found", m))? + 1
+            }
         }
-        self.start_homing_sequence(order, rapid, feed);
-        console_log!(
-            "Homing ordered: primary={}, rapid={}, feed={}",
-            primary_axis_id,
-            rapid,
-            feed
-        );
+        FanucFlow::End(m) => self
+            .find_fanuc_flow(c_idx, (0..pc).rev(), |f| matches!(f, FanucFlow::While(_, n) if *n == m))
+            .ok_or_else(|| format!("END{} without DO{}", m, m))?,
+        FanucFlow::Call => {
+            // A PARAMETER fault is raised already; the block stays current.
+            let Some(text) = self.substitute_parameters(c_idx, line) else { return Ok(pc) };
+            let words: HashMap<char, f64> = tokenize_block(&text)
+                .into_iter()
+                .filter_map(|t| match t {
+                    BlockToken::Word(l, v) => v.parse().ok().map(|v| (l, v)),
+                    _ => None,
+                })
+                .collect();
+            let p = words.get(&'P').ok_or("M98 needs a P program number")?.max(0.0).round() as u64;
+            // P20010 without L: two runs of O0010.
+            let (program, count) = match words.get(&'L') {
+                Some(l) => (p, l.max(0.0).round() as u64),
+                None if p > 9999 => (p % 10000, p / 10000),
+                None => (p, 1),
+            };
+            if count == 0 {
+                return Ok(pc + 1);
+            }
+            // M98 shares the caller's local parameters.
+            let body = self.call_subroutine(c_idx, pc, &program.to_string(), Vec::new(), 0)?;
+            if let Some(FlowFrame::Call { passes, .. }) = self.channels[c_idx].flow.last_mut() {
+                *passes = count - 1;
+            }
+            body
+        }
+        FanucFlow::Return => {
+            let chan = &mut self.channels[c_idx];
+            let Some(FlowFrame::Call { label, return_pc, saved, passes }) = chan.flow.pop() else {
+                return Err("M99 outside a subprogram".to_string());
+            };
+            for (key, value) in saved {
+                match value {
+                    Some(v) => chan.params.insert(key, v),
+                    None => chan.params.remove(&key),
+                };
+            }
+            if passes > 0 {
+                let body = self.subroutine_pc(c_idx, &label)? + 1;
+                let saved = Vec::new();
+                let frame = FlowFrame::Call { label, return_pc, saved, passes: passes - 1 };
+                self.channels[c_idx].flow.push(frame);
+                return Ok(body);
+            }
+            // M99 P<n> returns to block N<n> of the caller.
+            let target = tokenize_block(line).into_iter().find_map(|t| match t {
+                BlockToken::Word('P', v) => v.parse::<f64>().ok(),
+                _ => None,
+            });
+            match target {
+                Some(n) => self.goto_block(c_idx, return_pc.saturating_sub(1), n)?,
+                None => return_pc,
+            }
+        }
+    };
+    Ok(next)
+}
+
+// Line of block N<n>, searched forward from `pc` and then from the start.
+fn goto_block(&self, c_idx: usize, pc: usize, n: f64) -> Result<usize, String> {
+    let program = &self.channels[c_idx].program;
+    let target = n.round() as i64;
+    let len = program.len();
+    ((pc + 1)..len)
+        .chain(0..=pc.min(len.saturating_sub(1)))
+        .find(|i| program.get(*i).and_then(|l| block_number(l)).is_some_and(|b| b as i64 == target))
+        .ok_or_else(|| format!("GOTO target N{} not found", target))
+}
+
+// First Fanuc control-flow block over `lines` that `matches`.
+fn find_fanuc_flow(&self, c_idx: usize, lines: impl Iterator<Item = usize>, matches: impl Fn(&FanucFlow) -> bool) -> Option<usize> {
+    let program = &self.channels[c_idx].program;
+    lines.into_iter().find(|i| program.get(*i).and_then(|l| parse_fanuc_flow(l)).is_some_and(|f| matches(&f)))
+}
+
+// Whether a block commands axis motion (dwells and G52 shifts do not).
+fn block_moves_axes(&self, c_idx: usize, line: &str) -> bool {
+    let tokens = tokenize_block(line);
+    let g_codes: Vec<f64> = tokens
+        .iter()
+        .filter_map(|t| match t {
+            BlockToken::Word('G', v) => v.parse().ok(),
+            _ => None,
+        })
+        .collect();
+    if g_codes.iter().any(|g| *g == 4.0 || *g == 52.0 || *g == 50.1 || *g == 51.1) {
+        return false;
+    }
+    let labels = &self.channels[c_idx].axis_map;
+    tokens.iter().any(|t| {
+        matches!(t, BlockToken::Word(letter, _) if labels.iter().any(|m| m.display_label.to_uppercase().starts_with(*letter)))
+    })
+}
+
+// Whether programmed arcs and comp sides run reversed in `plane`: an odd
+// number of its two axes mirrored, flipped again for a rear turret in G18.
+fn mirror_reverses(&self, c_idx: usize, plane: u8) -> bool {
+    let chan = &self.channels[c_idx];
+    let letters = match plane {
+        18 => ["Z", "X"],
+        19 => ["Y", "Z"],
+        _ => ["X", "Y"],
+    };
+    let mirrored = letters
+        .iter()
+        .filter(|l| chan.axis_map.iter().any(|m| m.display_label.eq_ignore_ascii_case(l) && chan.mirror.contains_key(&m.axis_id)))
+        .count();
+    (mirrored % 2 == 1) != (plane == 18 && chan.rear_turret)
+}
+
+// Enters subroutine `label` from the block at `pc` with (parameter, value)
+// arguments in a fresh frame of `locals` parameters (#1..), and returns its
+// first body line. The program's own SUB wins over the macro library.
+fn call_subroutine(&mut self, c_idx: usize, pc: usize, label: &str, args: Vec<(usize, f64)>, locals: usize) -> Result<usize, String> {
+    let sub_pc = self.subroutine_pc(c_idx, label)?;
+    let chan = &mut self.channels[c_idx];
+    if chan.flow.len() >= FLOW_MAX_DEPTH {
+        return Err(format!("O{} CALL nests deeper than {}", label, FLOW_MAX_DEPTH));
+    }
+    let saved = (1..=locals).map(|n| (n.to_string(), chan.params.remove(&n.to_string()))).collect();
+    for (n, value) in args {
+        chan.params.insert(n.to_string(), value);
+    }
+    chan.flow.push(FlowFrame::Call { label: label.to_string(), return_pc: pc + 1, saved, passes: 0 });
+    *chan.call_hits.entry(label.to_string()).or_insert(0) += 1;
+    Ok(sub_pc + 1)
+}
+
+// Head line of subroutine `label`: an `O.. SUB` or a bare Fanuc `O....`
+// program number in the channel's program, else a library macro.
+fn subroutine_pc(&mut self, c_idx: usize, label: &str) -> Result<usize, String> {
+    let len = self.channels[c_idx].program.len();
+    if let Some((sub_pc, _)) = self.find_o_word(c_idx, 0..len, label, &[OKeyword::Sub]) {
+        return Ok(sub_pc);
     }
+    if let Some(sub_pc) = self.find_program_number(c_idx, label) {
+        return Ok(sub_pc);
+    }
+    self.link_library_macro(c_idx, label).ok_or_else(|| format!("unknown program O{}", label))
+}
+
+fn find_program_number(&self, c_idx: usize, label: &str) -> Option<usize> {
+    let number: u64 = label.parse().ok()?;
+    let program = &self.channels[c_idx].program;
+    (0..program.len()).find(|i| program.get(*i).and_then(|l| program_number(l)) == Some(number))
+}
+
+// Appends library macro `label` to the channel's program on its first call;
+// returns the line of its SUB. The event records which package version ran.
+fn link_library_macro(&mut self, c_idx: usize, label: &str) -> Option<usize> {
+    let (package, lines) = self
+        .macro_packages
+        .iter()
+        .find_map(|p| p.macros.iter().find(|(l, _)| l == label).map(|(_, lines)| (format!("{} {}", p.name, p.version), lines)))?;
+    let chan = &mut self.channels[c_idx];
+    let sub_pc = chan.program.len();
+    chan.program.link(lines);
+    let chan_id = chan.id;
+    self.emit_event("MACRO_LINKED", &format!("O{} from {}", label, package), Some(chan_id));
+    Some(sub_pc)
+}
+
+// First O-word statement with `label` and one of `keywords` over `lines`.
+fn find_o_word(&self, c_idx: usize, lines: impl Iterator<Item = usize>, label: &str, keywords: &[OKeyword]) -> Option<(usize, OWord)> {
+    let program = &self.channels[c_idx].program;
+    lines
+        .filter_map(|i| program.get(i).and_then(|l| parse_o_word(l)).map(|w| (i, w)))
+        .find(|(_, w)| w.label == label && keywords.contains(&w.keyword))
+}
 
-    pub fn home_axis(&mut self, axis_id: u32) {
-        if self.estop { return; }
-        if (axis_id as usize) >= self.axes.len() { return; }
-        if let Some(ax) = self.axes.get_mut(axis_id as usize) {
-            ax.target = 0.0;
-            ax.homed = false;
+// Evaluates `(ASSERT X=10 F=500 TOL=0.01)` comments once the block has been
+// interpreted. Axis labels compare the programmed work position, F/S/T/D/H the
+// modal values, in the channel's units (linear axes and F in inches under
+// G20). Any failure faults the run.
+fn check_block_assertions(&mut self, c_idx: usize, line: &str) {
+    let checks: Vec<String> = tokenize_block(line.trim())
+        .iter()
+        .filter_map(|t| match t {
+            BlockToken::Comment(c) => Some(comment_text(c)),
+            _ => None,
+        })
+        .filter_map(|c| {
+            let (head, rest) = c.split_once(char::is_whitespace).unwrap_or((c.as_str(), ""));
+            head.eq_ignore_ascii_case("ASSERT").then(|| rest.to_uppercase())
+        })
+        .collect();
+    let line_no = self.channels[c_idx].active_pc.max(0) as usize + 1;
+    let mut failures = Vec::new();
+    for check in checks {
+        let terms: Vec<(&str, Option<f64>)> = check
+            .split_whitespace()
+            .map(|term| {
+                let (key, value) = term.split_once('=').unwrap_or((term, ""));
+                (key, value.parse::<f64>().ok().filter(|v| v.is_finite()))
+            })
+            .collect();
+        let tolerance = terms
+            .iter()
+            .find(|(key, _)| *key == "TOL")
+            .and_then(|(_, v)| *v)
+            .unwrap_or(ASSERT_DEFAULT_TOL)
+            .abs();
+        for (key, expected) in terms.into_iter().filter(|(key, _)| *key != "TOL") {
+            let unit = self.assert_unit(c_idx, key);
+            let actual = self.block_state_value(c_idx, key).map(|a| a / unit);
+            self.channels[c_idx].assertions.checked += 1;
+            let message = match (expected, actual) {
+                (None, _) => format!("{} has no numeric expected value", key),
+                (_, None) => format!("{} is not an axis or modal value", key),
+                (Some(e), Some(a)) if (a - e).abs() > tolerance => {
+                    format!("{} is {} (expected {} ±{})", key, a, e, tolerance)
+                }
+                _ => continue,
+            };
+            failures.push(AssertionFailure { line: line_no, key: key.to_string(), expected, actual, tolerance, message });
         }
-        self.start_homing_sequence(vec![axis_id], false, 300.0);
     }
-
-    pub fn home_axis_ordered(&mut self, axis_id: u32, rapid: bool, feed: f64) {
-        if self.estop { return; }
-        if (axis_id as usize) >= self.axes.len() { return; }
-        if let Some(ax) = self.axes.get_mut(axis_id as usize) {
-            ax.target = 0.0;
-            ax.homed = false;
-        }
-        self.start_homing_sequence(vec![axis_id], rapid, feed);
-        console_log!(
-            "Homing axis {}: rapid={}, feed={}",
-            axis_id,
-            rapid,
-            feed
-        );
+    if failures.is_empty() {
+        return;
     }
+    let chan_id = self.channels[c_idx].id;
+    let summary: Vec<&str> = failures.iter().map(|f| f.message.as_str()).collect();
+    let message = format!("assertion failed on line {}: {}", line_no, summary.join("; "));
+    self.channels[c_idx].assertions.failures.extend(failures);
+    self.raise_alarm("ASSERTION", &message, AlarmSeverity::Fault, Some(chan_id));
+}
 
-    // ── Jogging ───────────────────────────────────────────────────────────
+// mm per program unit of an ASSERT key: 25.4 for lengths and feeds in G20.
+fn assert_unit(&self, c_idx: usize, key: &str) -> f64 {
+    let chan = &self.channels[c_idx];
+    if chan.units_mm {
+        return 1.0;
+    }
+    let length = key == "F"
+        || chan
+            .axis_map
+            .iter()
+            .find(|m| m.display_label.eq_ignore_ascii_case(key))
+            .and_then(|m| find_axis(&self.axes, m.axis_id))
+            .is_some_and(|ax| ax.axis_type == AxisType::Linear);
+    if length { 25.4 } else { 1.0 }
+}
 
-    pub fn jog_axis(&mut self, axis_id: u32, delta: f64) {
-        if self.estop { return; }
-        if let Some(ax) = self.axes.get_mut(axis_id as usize) {
-            let next = ax.target + delta;
-            ax.target = match ax.axis_type {
-                AxisType::Rotary => normalize_rotary_target(next),
-                AxisType::Linear => next.clamp(ax.min_range, ax.max_range),
-            };
+fn block_state_value(&self, c_idx: usize, key: &str) -> Option<f64> {
+    let chan = &self.channels[c_idx];
+    if let Some(m) = chan.axis_map.iter().find(|m| m.display_label.eq_ignore_ascii_case(key)) {
+        // Read back the way the program writes it: X as a diameter in G7.
+        let scale = if chan.diameter_mode && key.eq_ignore_ascii_case("X") { 2.0 } else { 1.0 };
+        if let Some(w) = chan.programmed_work.get(&m.axis_id) {
+            return Some(*w * scale);
         }
+        return find_axis(&self.axes, m.axis_id).map(|ax| self.programmed_work_at(c_idx, m.axis_id, ax.target) * scale);
+    }
+    match key {
+        "F" => Some(chan.feed_rate),
+        "S" => Some(chan.spindle_rpm),
+        "T" => Some(chan.active_tool as f64),
+        "D" => Some(chan.active_d as f64),
+        "H" => Some(chan.active_h as f64),
+        _ => None,
     }
+}
 
-    pub fn jog_axis_feed(&mut self, axis_id: u32, delta: f64, feed: f64) {
-        if self.estop { return; }
-        let f = feed.max(1.0);
-        if let Some(ax) = self.axes.get_mut(axis_id as usize) {
-            let next = ax.target + delta;
-            ax.target = match ax.axis_type {
-                AxisType::Rotary => normalize_rotary_target(next),
-                AxisType::Linear => next.clamp(ax.min_range, ax.max_range),
-            };
-            // Apply commanded jog feed immediately from panel.
-            ax.velocity = f;
-        }
-        for chan in self.channels.iter_mut() {
-            if chan.axis_map.iter().any(|m| m.axis_id == axis_id) && !chan.is_running {
-                chan.current_motion = 1;
-                chan.feed_rate = f;
-            }
-        }
+fn block_context(&self, c_idx: usize, line: &str) -> BlockContext {
+    let chan = &self.channels[c_idx];
+    let words = tokenize_block(line.trim())
+        .into_iter()
+        .filter_map(|t| match t {
+            BlockToken::Word(l, v) => v.parse::<f64>().ok().map(|value| BlockWord { address: l.to_string(), value }),
+            _ => None,
+        })
+        .collect();
+    let position = chan
+        .axis_map
+        .iter()
+        .filter_map(|m| {
+            let value = self.block_state_value(c_idx, &m.display_label.to_uppercase())?;
+            Some(BlockWord { address: m.display_label.clone(), value })
+        })
+        .collect();
+    BlockContext {
+        channel_id: chan.id,
+        line: chan.active_pc.max(0) as usize + 1,
+        block: line.to_string(),
+        words,
+        modal: ModalSnapshot {
+            motion: chan.current_motion,
+            absolute: chan.abs_mode,
+            units_mm: chan.units_mm,
+            diameter_mode: chan.diameter_mode,
+            wcs: self.work_offsets.get(self.active_wcs).map(|w| w.label.clone()).unwrap_or_default(),
+            cutter_comp: chan.cutter_comp,
+            length_comp_active: chan.length_comp_active,
+            tool: chan.active_tool,
+            feed_rate: chan.feed_rate,
+            spindle_rpm: chan.spindle_rpm,
+            spindle_mode: chan.spindle_mode,
+            coolant_on: chan.coolant_on,
+            position,
+        },
     }
+}
 
-    pub fn jog_axis_rapid(&mut self, axis_id: u32, delta: f64) {
-        let rapid_feed = self
-            .axes
-            .get(axis_id as usize)
-            .map(axis_rapid_feed)
-            .unwrap_or(RAPID_LINEAR_MAX_MM_MIN);
-        self.jog_axis_feed(axis_id, delta, rapid_feed);
-        if let Some(ax) = self.axes.get_mut(axis_id as usize) {
-            ax.velocity = rapid_feed;
-        }
-        for chan in self.channels.iter_mut() {
-            if chan.axis_map.iter().any(|m| m.axis_id == axis_id) && !chan.is_running {
-                chan.current_motion = 0;
-                chan.feed_rate = rapid_feed;
+// Opens the channel's trace record for `line` when tracing is on.
+fn begin_block_trace(&mut self, c_idx: usize, line: &str, synthesized: bool) -> Option<BlockTraceStart> {
+    self.channels[c_idx].block_trace.as_ref()?;
+    let ctx = self.block_context(c_idx, line);
+    let chan = &self.channels[c_idx];
+    let targets = chan.axis_map.iter().filter_map(|m| find_axis(&self.axes, m.axis_id)).map(|ax| (ax.id, ax.target)).collect();
+    let start = BlockTraceStart { modal: ctx.modal, targets, pending: chan.pending.len() };
+    let record = BlockTrace { line: ctx.line, block: ctx.block, synthesized, words: ctx.words, ..BlockTrace::default() };
+    self.channels[c_idx].block_trace.as_mut()?.open = Some(record);
+    Some(start)
+}
+
+// Closes the open trace record: modal changes and the segments the block queued.
+fn end_block_trace(&mut self, c_idx: usize, start: Option<BlockTraceStart>) {
+    let Some(start) = start else { return };
+    let after = self.block_context(c_idx, "").modal;
+    let chan = &self.channels[c_idx];
+    let mut position = start.targets.clone();
+    let mut steps: Vec<Vec<(u32, f64)>> = Vec::new();
+    let first: Vec<(u32, f64)> =
+        chan.axis_map.iter().filter_map(|m| find_axis(&self.axes, m.axis_id)).map(|ax| (ax.id, ax.target)).collect();
+    if first != start.targets {
+        steps.push(first);
+    }
+    steps.extend(chan.pending.iter().skip(start.pending).map(|e| e.targets().to_vec()).filter(|t| !t.is_empty()));
+    let segments = steps
+        .into_iter()
+        .map(|step| {
+            for (id, v) in step {
+                if let Some(p) = position.iter_mut().find(|p| p.0 == id) {
+                    p.1 = v;
+                }
             }
-        }
+            position.iter().map(|&(axis_id, value)| AxisOffset { axis_id, value }).collect()
+        })
+        .collect();
+    let Some(log) = self.channels[c_idx].block_trace.as_mut() else { return };
+    let Some(mut record) = log.open.take() else { return };
+    record.modal_changes = modal_changes(&start.modal, &after);
+    record.segments = segments;
+    log.records.push_back(record);
+    while log.records.len() > BLOCK_TRACE_MAX {
+        log.records.pop_front();
     }
+}
 
-    // ── Work Zeros ────────────────────────────────────────────────────────
+// Notes an interpreter decision on the open trace record; `note` only runs
+// while tracing.
+fn trace_decision(&mut self, c_idx: usize, note: impl FnOnce() -> String) {
+    if let Some(record) = self.channels[c_idx].block_trace.as_mut().and_then(|log| log.open.as_mut()) {
+        record.decisions.push(note());
+    }
+}
 
-    pub fn set_work_zero(&mut self, axis_id: u32, wcs_index: usize, machine_pos: f64) {
-        if let Some(wcs) = self.work_offsets.get_mut(wcs_index) {
-            if let Some(off) = wcs.offsets.iter_mut().find(|o| o.axis_id == axis_id) {
-                off.value = machine_pos;
-                console_log!("WCS {} axis {} offset = {}", wcs.label, axis_id, machine_pos);
-            }
+// Cutting policy events for a block about to move with `motion`.
+fn check_cutting_policy(&mut self, c_idx: usize, motion: i32, line: &str) {
+    if !matches!(motion, 1..=3) {
+        return;
+    }
+    let policy = &self.cutting_policy;
+    let chan = &self.channels[c_idx];
+    let spindle_off = !(matches!(chan.spindle_mode, 3 | 4) && chan.spindle_rpm > 0.0);
+    let coolant_off = !chan.coolant_on && !policy.dry_tools.contains(&chan.active_tool);
+    let checks = [
+        (policy.spindle_before_feed && spindle_off, "SPINDLE_BEFORE_FEED", "with the spindle stopped"),
+        (policy.coolant_before_cut && coolant_off, "COOLANT_BEFORE_CUT", "with coolant off"),
+    ];
+    let chan_id = chan.id;
+    let at = format!("line {}: {}", chan.active_pc + 1, line.trim());
+    for (k, (violated, code, what)) in checks.into_iter().enumerate() {
+        if violated && !self.channels[c_idx].policy_warned[k] {
+            self.emit_event(code, &format!("G{} cut {} ({})", motion, what, at), Some(chan_id));
         }
+        self.channels[c_idx].policy_warned[k] = violated;
     }
+}
 
-    pub fn set_active_wcs(&mut self, wcs_index: usize) {
-        if wcs_index < self.work_offsets.len() {
-            self.active_wcs = wcs_index;
+// Caps the feed of a G1 that descends along the tool (plunge or ramp) and
+// warns when the programmed feed is above the cap.
+fn apply_descent_limit(
+    &mut self,
+    c_idx: usize,
+    line: &str,
+    xyz_ids: [Option<u32>; 3],
+    cur_work: &HashMap<u32, f64>,
+    end_work: &HashMap<u32, f64>,
+) {
+    let limits = self.descent_limits;
+    if limits.plunge_feed <= 0.0 && limits.ramp_feed <= 0.0 && limits.ramp_angle_deg <= 0.0 {
+        return;
+    }
+    let mut delta = [0.0; 3];
+    for (k, id) in xyz_ids.iter().enumerate() {
+        if let Some(id) = id {
+            let s = cur_work.get(id).copied().unwrap_or(0.0);
+            delta[k] = end_work.get(id).copied().unwrap_or(s) - s;
         }
     }
+    let dir = self.channels[c_idx].active_tool_direction().unwrap_or(ToolDirection::MinusZ).vector();
+    let descent: f64 = (0..3).map(|k| delta[k] * dir[k]).sum();
+    if descent <= 1e-9 {
+        return;
+    }
+    let lateral = (0..3).map(|k| (delta[k] - descent * dir[k]).powi(2)).sum::<f64>().sqrt();
+    let angle_deg = descent.atan2(lateral).to_degrees();
+    let chan = &self.channels[c_idx];
+    let at = format!("line {}: {}", chan.active_pc + 1, line.trim());
+    let chan_id = chan.id;
+    let feed = chan.feed_rate;
+    let steep = limits.ramp_angle_deg > 0.0 && lateral > 1e-9 && angle_deg > limits.ramp_angle_deg + 1e-9;
+    if steep {
+        let message = format!("ramp at {:.1}° is steeper than {:.1}° ({})", angle_deg, limits.ramp_angle_deg, at);
+        self.raise_alarm("RAMP_ANGLE", &message, AlarmSeverity::Warning, Some(chan_id));
+    }
+    let (kind, cap) = if lateral <= 1e-9 || steep {
+        ("plunge", limits.plunge_feed)
+    } else {
+        ("ramp", limits.ramp_feed)
+    };
+    if cap <= 0.0 {
+        return;
+    }
+    self.channels[c_idx].descent_feed_limit = cap;
+    if feed > cap {
+        let message = format!("F{} exceeds the {} limit, capped at F{} ({})", feed, kind, cap, at);
+        self.raise_alarm("DESCENT_FEED", &message, AlarmSeverity::Warning, Some(chan_id));
+    }
+}
 
-    pub fn add_work_offset(&mut self, label: String) -> usize {
-        let mut offsets = Vec::with_capacity(self.axes.len());
-        for ax in &self.axes {
-            offsets.push(AxisOffset { axis_id: ax.id, value: 0.0 });
+// Range checks for F/S/T/D/H words. Out-of-range S is clamped with a warning;
+// the rest are faults and the block is not executed (returns false).
+#[allow(clippy::too_many_arguments)]
+fn check_word_ranges(
+    &mut self,
+    c_idx: usize,
+    line: &str,
+    f_word: Option<f64>,
+    s_word: &mut Option<f64>,
+    t_word: Option<i32>,
+    d_raw: Option<f64>,
+    h_raw: Option<f64>,
+) -> bool {
+    let limits = self.word_limits;
+    let mut fault = None;
+    let mut warning = None;
+    if let Some(f) = f_word.filter(|f| *f <= 0.0) {
+        fault = Some(format!("F{} must be positive", f));
+    }
+    if let Some(s) = *s_word {
+        if s < 0.0 {
+            fault = Some(format!("S{} must not be negative", s));
+        } else if limits.spindle_max_rpm > 0.0 && s > limits.spindle_max_rpm {
+            warning = Some(format!("S{} exceeds spindle max {} rpm; clamped", s, limits.spindle_max_rpm));
+            *s_word = Some(limits.spindle_max_rpm);
         }
-        self.work_offsets.push(WorkOffset { label, offsets });
-        self.work_offsets.len() - 1
     }
-
-    // ── E-Stop ────────────────────────────────────────────────────────────
-
-    pub fn set_estop(&mut self, s: bool) {
-        self.estop = s;
-        if s {
-            self.feed_hold = false;
-            for chan in self.channels.iter_mut() {
-                chan.is_running = false;
-                chan.paused = false;
-                chan.pending.clear();
-                chan.pause_pending = false;
-                chan.step_once = false;
-                chan.active_pc = -1;
-            }
-            for ax in self.axes.iter_mut() {
-                ax.target = ax.position;
-                ax.velocity = 0.0;
-            }
+    if let Some(turret) = &self.channels[c_idx].turret {
+        if let Some(t) = t_word.filter(|t| *t < 0 || Turret::station_for(*t) > turret.stations) {
+            fault = Some(format!("T{} is not a turret station (1..={})", t, turret.stations));
+        }
+    } else if let Some(t) = t_word.filter(|t| *t < 0 || (limits.magazine_pockets > 0 && *t as u32 > limits.magazine_pockets)) {
+        fault = Some(format!("T{} is not a magazine pocket (1..={})", t, limits.magazine_pockets));
+    }
+    for (letter, raw) in [('D', d_raw), ('H', h_raw)] {
+        let Some(slot) = raw.and_then(Self::resolve_table_slot_index) else { continue };
+        if raw.is_some_and(|r| r < 0.0) || (limits.offset_slots > 0 && slot as u32 > limits.offset_slots) {
+            fault = Some(format!("{}{} is outside the offset table (0..={})", letter, raw.unwrap_or(0.0), limits.offset_slots));
         }
     }
+    let chan_id = self.channels[c_idx].id;
+    let at = format!("line {}: {}", self.channels[c_idx].active_pc + 1, line.trim());
+    if let Some(message) = warning {
+        self.raise_alarm("WORD_RANGE", &format!("{} ({})", message, at), AlarmSeverity::Warning, Some(chan_id));
+    }
+    if let Some(message) = fault {
+        self.raise_alarm("WORD_RANGE", &format!("{} ({})", message, at), AlarmSeverity::Fault, Some(chan_id));
+        return false;
+    }
+    true
+}
 
-    // ── Tick ──────────────────────────────────────────────────────────────
+// A work offset change (G54-G59, G153, or a G52 local shift) between the
+// blocks of a cutter-compensated contour shifts the rest of the profile under
+// the cutter, and a canned cycle (`cycle`: G74/G84/G76 or a repeat of the
+// modal one) drives the tool along its own uncompensated moves. Both are
+// almost always program bugs, so they fault instead of being absorbed
+// (returns false).
+fn check_contour_wcs(&mut self, c_idx: usize, line: &str, g_words: &[i32], cycle: Option<i32>) -> bool {
+    if !matches!(self.channels[c_idx].cutter_comp, 41 | 42) {
+        return true;
+    }
+    let chan_id = self.channels[c_idx].id;
+    if let Some(code) = cycle {
+        let message = format!(
+            "canned cycle G{code} inside a G4{} contour; cancel comp with G40 first (line {}: {})",
+            self.channels[c_idx].cutter_comp % 10,
+            self.channels[c_idx].active_pc + 1,
+            line.trim()
+        );
+        self.raise_alarm("CONTOUR_CANNED_CYCLE", &message, AlarmSeverity::Fault, Some(chan_id));
+        return false;
+    }
+    let wcs = g_words.iter().rev().find_map(|g| match *g {
+        54..=59 => Some((*g - 54) as usize),
+        153 => Some(6),
+        _ => None,
+    });
+    let change = match wcs.filter(|w| *w != self.active_wcs) {
+        Some(wcs) => format!(
+            "work offset change to {}",
+            self.work_offsets.get(wcs).map(|w| w.label.as_str()).unwrap_or("?")
+        ),
+        None if g_words.contains(&52) => "local shift (G52)".to_string(),
+        None => return true,
+    };
+    let message = format!(
+        "{change} inside a G4{} contour; cancel comp with G40 first (line {}: {})",
+        self.channels[c_idx].cutter_comp % 10,
+        self.channels[c_idx].active_pc + 1,
+        line.trim()
+    );
+    self.raise_alarm("CONTOUR_WCS_CHANGE", &message, AlarmSeverity::Fault, Some(chan_id));
+    false
+}
 
-    pub fn tick(&mut self, dt_ms: f64) {
-    if self.estop || dt_ms <= 0.0 { return; }
-    if self.feed_hold {
-        for ax in self.axes.iter_mut() {
-            ax.velocity = 0.0;
-        }
+// G33 (constant lead) / G34 (variable lead) threading block. Lead comes from K
+// (LinuxCNC G33) or F (Fanuc), G34 takes K as the lead change per revolution,
+// and Q is the spindle start angle in degrees used to cut multi-start threads.
+// `chained` continues a thread from the previous block without resyncing.
+#[allow(clippy::too_many_arguments)]
+fn parse_thread_move(
+    &mut self,
+    c_idx: usize,
+    motion: i32,
+    cur_work: &HashMap<u32, f64>,
+    end_work: &HashMap<u32, f64>,
+    f_word: Option<f64>,
+    k_word: Option<f64>,
+    q_word: Option<f64>,
+    chained: bool,
+) {
+    let chan_id = self.channels[c_idx].id;
+    let (lead, lead_change) = if motion == 34 {
+        (f_word.unwrap_or(self.channels[c_idx].thread_lead), k_word.unwrap_or(0.0))
+    } else {
+        (k_word.or(f_word).unwrap_or(self.channels[c_idx].thread_lead), 0.0)
+    };
+    let spindle_on = self.channels[c_idx].spindle_mode != 5 && self.channels[c_idx].spindle_rpm > 0.0;
+    if !spindle_on {
+        let message = format!("G{} threading needs a running spindle (S and M3/M4)", motion);
+        self.raise_alarm("THREAD_SPINDLE", &message, AlarmSeverity::Fault, Some(chan_id));
         return;
     }
-    let dt_sec = dt_ms / 1000.0;
 
-    // ── Helper closure: trapezoidal move for one axis ──────────────────
-    // Returns true if still moving
-    fn move_axis(ax: &mut Axis, feed: f64, dt_sec: f64, stop_at_target: bool) -> bool {
-        let diff = ax.target - ax.position;
-        let dist = diff.abs();
-        if dist <= 0.0005 {
-            ax.position = ax.target;
-            if stop_at_target {
-                ax.velocity = 0.0;
-            }
-            return false;
+    let mut origin: Vec<(u32, f64)> = Vec::new();
+    let mut targets: Vec<(u32, f64)> = Vec::new();
+    let mut length_sq = 0.0;
+    for m in &self.channels[c_idx].axis_map {
+        let (Some(s), Some(e)) = (cur_work.get(&m.axis_id), end_work.get(&m.axis_id)) else {
+            continue;
+        };
+        length_sq += (e - s).powi(2);
+        if let Some(ax) = find_axis(&self.axes, m.axis_id) {
+            origin.push((m.axis_id, ax.position));
         }
+        targets.push((m.axis_id, self.machine_target_with_limits(m.axis_id, self.work_to_machine(m.axis_id, *e))));
+    }
+    // A decreasing lead must stay positive over the whole thread length.
+    let end_lead_sq = lead * lead + 2.0 * lead_change * length_sq.sqrt();
+    if lead <= 0.0 || end_lead_sq <= 0.0 {
+        let message = format!("G{} thread lead {:.4} (change {:.4}/rev) is not positive over the move", motion, lead, lead_change);
+        self.raise_alarm("THREAD_LEAD", &message, AlarmSeverity::Fault, Some(chan_id));
+        return;
+    }
 
-        let dir = diff.signum();
-        let feed = feed.max(1.0);
-        let accel = ax.accel.max(1.0);
-        let mut vel = ax.velocity.max(0.0);
+    let chan = &mut self.channels[c_idx];
+    chan.thread_lead = lead;
+    chan.thread_lead_change = lead_change;
+    chan.thread_start_angle = q_word.unwrap_or(0.0).rem_euclid(360.0);
+    chan.thread_origin = origin;
+    // A thread entered from a non-threading move waits for its spindle angle,
+    // so repeated passes cut the same helix. Chained blocks stay in sync.
+    let rps = chan.spindle_speed() / 60.0;
+    let wait_revs = (chan.thread_start_angle / 360.0 - chan.spindle_revs).rem_euclid(1.0);
+    if !chained && rps > 0.0 && wait_revs > 1e-9 {
+        chan.pending.push_front(PendingEntry::Dwell(wait_revs / rps));
+    }
+    for (id, tgt) in targets {
+        if let Some(ax) = find_axis_mut(&mut self.axes, id) {
+            ax.target = tgt;
+        }
+    }
+}
 
-        let stop_dist = (vel * vel) / (2.0 * accel);
+// Tapping cycle G84 (right-hand) / G74 (left-hand): position over the hole,
+// rapid to R, rigid-tap to Z and back out. The steps run as synthesized
+// blocks (absolute mm, G33.1 for the tap) so spindle_mode follows the cycle;
+// units, distance mode, motion and spindle state are restored after.
+// Returns to the initial Z (G98) unless G99 asks to stay at R.
+fn expand_tap_cycle(
+    &mut self,
+    c_idx: usize,
+    canned: CannedCycle,
+    xy: [Option<f64>; 2],
+    xyz_ids: [Option<u32>; 3],
+    cur_work: &HashMap<u32, f64>,
+) {
+    let chan = &self.channels[c_idx];
+    let chan_id = chan.id;
+    let abs = chan.abs_mode;
+    let retract_to_r = chan.cycle_return_r;
+    let CannedCycle { code: cycle, r: r_word, pitch, initial_z, .. } = canned;
+    let xyz = [xy[0], xy[1], canned.z];
+    let start = xyz_ids.map(|id| id.and_then(|id| cur_work.get(&id).copied()));
+    let problem = if chan.cutter_comp != 40 {
+        Some("cannot run with G41/G42 active".to_string())
+    } else if start[2].is_none() || xyz[2].is_none() {
+        Some("needs a Z axis and a Z word".to_string())
+    } else if !pitch.is_some_and(|p| p > 0.0) {
+        Some("needs a positive pitch (F or K, mm/rev)".to_string())
+    } else if chan.spindle_rpm <= 0.0 {
+        Some("needs a spindle speed (S)".to_string())
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        let message = format!("G{} {} (line {})", cycle, problem, chan.active_pc + 1);
+        self.raise_alarm("TAP_CYCLE", &message, AlarmSeverity::Fault, Some(chan_id));
+        self.channels[c_idx].canned_cycle = None;
+        return;
+    }
+    let r = r_word.map(|r| if abs { r } else { initial_z + r }).unwrap_or(initial_z);
+    let bottom = xyz[2].map(|z| if abs { z } else { r + z }).unwrap_or(r);
+    if bottom >= r {
+        let message = format!("G{} bottom Z{} is not below R{} (line {})", cycle, bottom, r, chan.active_pc + 1);
+        self.raise_alarm("TAP_CYCLE", &message, AlarmSeverity::Fault, Some(chan_id));
+        self.channels[c_idx].canned_cycle = None;
+        return;
+    }
 
-        if stop_at_target && dist <= stop_dist + 0.01 {
-            vel = (vel - accel * dt_sec).max(0.0);
-        } else if vel < feed {
-            vel = (vel + accel * dt_sec).min(feed);
+    let pitch = format_word_value(pitch.unwrap_or(0.0));
+    let mut position = String::from("G21 G90 G0");
+    for (k, letter) in ["X", "Y"].iter().enumerate() {
+        if let (Some(v), Some(s)) = (xyz[k], start[k]) {
+            let v = if abs { v } else { s + v };
+            position.push_str(&format!(" {}{}", letter, format_word_value(v)));
         }
+    }
+    let mut lines = vec![
+        position,
+        format!("Z{}", format_word_value(r)),
+        format!("M{}", if cycle == 84 { 3 } else { 4 }),
+        format!("G33.1 Z{} K{}", format_word_value(bottom), pitch),
+        format!("M{}", chan.spindle_mode),
+    ];
+    if !retract_to_r && initial_z > r {
+        lines.push(format!("G0 Z{}", format_word_value(initial_z)));
+    }
+    lines.push(format!(
+        "G{} G{} G{}",
+        if chan.units_mm { 21 } else { 20 },
+        if abs { 90 } else { 91 },
+        chan.current_motion
+    ));
+    let chan = &mut self.channels[c_idx];
+    for line in lines.into_iter().rev() {
+        chan.injected.push_front(line);
+    }
+}
 
-        let mut step = (vel / 60.0) * dt_sec;
-        if step <= 0.000001 {
-            if dist <= 0.05 {
-                ax.position = ax.target;
-                ax.velocity = 0.0;
-                return false;
-            }
-            vel = (feed * 0.02).max(1.0).min(feed);
-            step = (vel / 60.0) * dt_sec;
-        }
+// M6 with a tool changer: travel to the change position (Z first) in G0,
+// then let the changer swap in the preselected tool. Without a T word, or
+// with that tool already in the spindle, there is nothing to change.
+fn start_tool_change(&mut self, c_idx: usize) {
+    let now_s = self.now_s();
+    let chan = &mut self.channels[c_idx];
+    let Some(changer) = chan.tool_changer.clone() else {
+        return;
+    };
+    let Some(tool) = chan.pending_tool.take().filter(|t| *t != chan.active_tool) else {
+        return;
+    };
+    let is_z = |id: u32| chan.axis_map.iter().any(|m| m.axis_id == id && m.display_label.eq_ignore_ascii_case("Z"));
+    let (z_first, rest): (Vec<_>, Vec<_>) =
+        changer.position.iter().map(|p| (p.axis_id, p.value)).partition(|(id, _)| is_z(*id));
+    for p in &changer.position {
+        // Following blocks continue from wherever the change leaves the axis.
+        chan.programmed_work.remove(&p.axis_id);
+    }
+    chan.current_motion = 0;
+    chan.pending.extend([z_first, rest].into_iter().filter(|seg| !seg.is_empty()).map(PendingEntry::Move));
+    chan.pending.push_back(PendingEntry::ToolChange(tool));
+    chan.tool_change = Some(ToolChangeEvent {
+        from_tool: chan.active_tool,
+        to_tool: tool,
+        phase: "APPROACH".to_string(),
+        phase_started_s: now_s,
+        change_time_s: changer.change_time_s,
+        position: changer.position,
+    });
+    log_at!(self, Info, "interpreter", "Channel {}: tool change T{} -> T{}", self.channels[c_idx].id, self.channels[c_idx].active_tool, tool);
+}
 
-        if step >= dist {
-            ax.position = ax.target;
-            if stop_at_target {
-                ax.velocity = 0.0;
-            } else {
-                ax.velocity = vel;
-            }
-            false
-        } else {
-            ax.position += step * dir;
-            ax.velocity = vel;
-            true
-        }
+// G33.1 rigid tap (LinuxCNC): feed Z to the bottom with the spindle slaved to
+// it at pitch K (or F), stop, reverse the spindle and feed back out to the
+// start, then restore the spindle direction. Both feeds stop exactly, so the
+// spindle decelerates into the bottom and reverses with the axis.
+fn start_rigid_tap(
+    &mut self,
+    c_idx: usize,
+    xyz: [Option<f64>; 3],
+    pitch: Option<f64>,
+    z_id: Option<u32>,
+    cur_work: &HashMap<u32, f64>,
+) {
+    let chan = &self.channels[c_idx];
+    let chan_id = chan.id;
+    let start_work = z_id.and_then(|id| cur_work.get(&id).copied());
+    let problem = if chan.cutter_comp != 40 {
+        Some("cannot run with G41/G42 active")
+    } else if start_work.is_none() || xyz[2].is_none() {
+        Some("needs a Z axis and a Z word")
+    } else if xyz[0].is_some() || xyz[1].is_some() {
+        Some("taps along Z only (position X/Y first)")
+    } else if !pitch.is_some_and(|p| p > 0.0) {
+        Some("needs a positive pitch (K or F, mm/rev)")
+    } else if !matches!(chan.spindle_mode, 3 | 4) || chan.spindle_rpm <= 0.0 {
+        Some("needs a running spindle (S and M3/M4)")
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        let message = format!("G33.1 {} (line {})", problem, chan.active_pc + 1);
+        self.raise_alarm("TAP_CYCLE", &message, AlarmSeverity::Fault, Some(chan_id));
+        return;
     }
+    let (Some(z_id), Some(start_work), Some(z), Some(pitch)) = (z_id, start_work, xyz[2], pitch) else {
+        return;
+    };
+    let Some(start) = find_axis(&self.axes, z_id).map(|ax| ax.position) else { return };
+    let end_work = if chan.abs_mode { z } else { start_work + z };
+    let depth = self.work_to_machine(z_id, end_work) - self.work_to_machine(z_id, start_work);
+    let bottom = self.machine_target_with_limits(z_id, start + depth);
 
-    // ── Homing: takes priority over programs ───────────────────────────
-    if self.is_homing {
-        if self.homing_index >= self.homing_sequence.len() {
-            self.is_homing = false;
-            self.homing_sequence.clear();
-            self.homing_index = 0;
-            console_log!("Homing complete");
-            return;
+    let chan = &mut self.channels[c_idx];
+    chan.current_motion = 33;
+    chan.thread_lead = pitch;
+    chan.thread_lead_change = 0.0;
+    chan.thread_origin = vec![(z_id, start)];
+    chan.rigid_tap = Some(RigidTap { axis_id: z_id, pitch, restore_mode: chan.spindle_mode });
+    chan.programmed_work.insert(z_id, start_work);
+    chan.pending.push_back(PendingEntry::TapReverse);
+    chan.pending.push_back(PendingEntry::Move(vec![(z_id, start)]));
+    chan.pending.push_back(PendingEntry::TapEnd);
+    if let Some(ax) = find_axis_mut(&mut self.axes, z_id) {
+        ax.target = bottom;
+    }
+}
+
+// G76 threading cycle, Fanuc two-block format. `G76 P(mmrraa) Q(dmin) R(d)`
+// stores the finishing passes, tool angle, minimum depth and finishing
+// allowance. `G76 X(U) Z(W) P(k) Q(Δd) F(L)` then cuts a thread of height k
+// (radial) to root X over Z. P/Q depths count µm in G21 and 0.0001" in G20.
+// Passes start at the current point, feed in along the flank at half the
+// tool angle and run as synthesized G33 blocks, each synchronized to the
+// spindle angle so they follow the same helix.
+#[allow(clippy::too_many_arguments)]
+fn expand_thread_cycle(
+    &mut self,
+    c_idx: usize,
+    xz: [Option<f64>; 2],
+    p_word: Option<f64>,
+    q_word: Option<f64>,
+    r_word: Option<f64>,
+    lead: Option<f64>,
+    xz_ids: [Option<u32>; 2],
+    cur_work: &HashMap<u32, f64>,
+) {
+    let chan = &self.channels[c_idx];
+    let chan_id = chan.id;
+    let depth_unit = if chan.units_mm { 0.001 } else { 0.0001 * 25.4 };
+    if xz.iter().all(Option::is_none) {
+        let cycle = &mut self.channels[c_idx].thread_cycle;
+        if let Some(p) = p_word.map(|p| p.round().max(0.0) as u32) {
+            cycle.finish_passes = p / 10000;
+            cycle.angle_deg = (p % 100) as f64;
         }
-        let axis_id = self.homing_sequence[self.homing_index];
-        if let Some(ax) = self.axes.get_mut(axis_id as usize) {
-            let home_feed = if self.homing_rapid {
-                axis_rapid_feed(ax)
-            } else {
-                self.homing_feed.max(1.0)
-            };
-            if ax.velocity < 1.0 {
-                // Avoid ultra-slow startup ramp; honor selected homing feed immediately.
-                ax.velocity = home_feed;
-            }
-            let still_moving = move_axis(ax, home_feed, dt_sec, true);
-            if !still_moving {
-                ax.homed = true;
-                ax.position = 0.0;
-                ax.target = 0.0;
-                ax.velocity = 0.0;
-                self.homing_index += 1;
-                if self.homing_index >= self.homing_sequence.len() {
-                    self.is_homing = false;
-                    self.homing_sequence.clear();
-                    self.homing_index = 0;
-                    console_log!("Homing complete");
-                }
-            }
-        } else {
-            self.homing_index += 1;
+        if let Some(q) = q_word {
+            cycle.min_depth = q.max(0.0) * depth_unit;
+        }
+        if let Some(r) = r_word {
+            cycle.allowance = r.max(0.0);
         }
         return;
     }
+    let abs = chan.abs_mode;
+    let start = xz_ids.map(|id| id.and_then(|id| cur_work.get(&id).copied()));
+    let (Some(x0), Some(z0)) = (start[0], start[1]) else {
+        let message = format!("G76 needs X and Z axes (line {})", chan.active_pc + 1);
+        self.raise_alarm("THREAD_CYCLE", &message, AlarmSeverity::Fault, Some(chan_id));
+        return;
+    };
+    let root = xz[0].map_or(x0, |x| if abs { x } else { x0 + x });
+    let end_z = xz[1].map_or(z0, |z| if abs { z } else { z0 + z });
+    let height = p_word.unwrap_or(0.0) * depth_unit;
+    let first = q_word.unwrap_or(0.0) * depth_unit;
+    let lead = lead.unwrap_or(chan.thread_lead);
+    let problem = if chan.cutter_comp != 40 {
+        Some("cannot run with G41/G42 active".to_string())
+    } else if height <= 0.0 || first <= 0.0 {
+        Some("needs a thread height P and first depth Q".to_string())
+    } else if lead <= 0.0 {
+        Some("needs a positive lead F".to_string())
+    } else if (end_z - z0).abs() <= 1e-9 {
+        Some("needs a Z length".to_string())
+    } else if (x0 - root).abs() < height {
+        Some(format!("starts inside the thread: X{} is within P of root X{}", format_word_value(x0), format_word_value(root)))
+    } else if r_word.is_some_and(|r| r.abs() > 1e-9) {
+        Some("taper (R) is not supported".to_string())
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        let message = format!("G76 {} (line {})", problem, chan.active_pc + 1);
+        self.raise_alarm("THREAD_CYCLE", &message, AlarmSeverity::Fault, Some(chan_id));
+        return;
+    }
+
+    let outward = (x0 - root).signum();
+    let dir = (end_z - z0).signum();
+    let flank = (chan.thread_cycle.angle_deg / 2.0).to_radians().tan();
+    let crest = root + outward * height;
+    let mut lines = Vec::new();
+    for depth in thread_pass_depths(height, first, &chan.thread_cycle) {
+        let shift = -dir * depth * flank;
+        lines.push(format!("G0 Z{}", format_word_value(z0 + shift)));
+        lines.push(format!("X{}", format_word_value(crest - outward * depth)));
+        lines.push(format!("G33 Z{} K{}", format_word_value(end_z + shift), format_word_value(lead)));
+        lines.push(format!("G0 X{}", format_word_value(x0)));
+    }
+    lines.push(format!("Z{}", format_word_value(z0)));
+    // Synthesized blocks are radius, absolute mm; the program's modes return after.
+    lines[0] = format!("G8 G21 G90 {}", lines[0]);
+    lines.push(format!(
+        "G{} G{} G{}{}",
+        if chan.units_mm { 21 } else { 20 },
+        if abs { 90 } else { 91 },
+        chan.current_motion,
+        if chan.diameter_mode { " G7" } else { "" }
+    ));
+    let chan = &mut self.channels[c_idx];
+    for line in lines.into_iter().rev() {
+        chan.injected.push_front(line);
+    }
+}
+
+// Lead (mm/rev) at distance `s` along a thread: lead(n) = L0 + k·n per
+// revolution n, so lead² = L0² + 2·k·s.
+fn thread_lead_at(lead: f64, lead_change: f64, s: f64) -> f64 {
+    (lead * lead + 2.0 * lead_change * s).max(0.0).sqrt()
+}
+
+// Distance already cut along the current thread, from the axes' positions.
+fn thread_progress(&self, c_idx: usize) -> f64 {
+    self.channels[c_idx]
+        .thread_origin
+        .iter()
+        .filter_map(|(id, start)| find_axis(&self.axes, *id).map(|ax| (ax.position - start).powi(2)))
+        .sum::<f64>()
+        .sqrt()
+}
 
-    // ── Channel program execution ──────────────────────────────────────
-    for c_idx in 0..self.channels.len() {
-        if self.channels[c_idx].paused { continue; }
+// Time (s) to travel `dist` with the channel's current motion.
+fn motion_time_s(&self, c_idx: usize, dist: f64) -> f64 {
+    let chan = &self.channels[c_idx];
+    if chan.current_motion == 34 && chan.thread_lead_change.abs() > 1e-12 && chan.spindle_speed() > 0.0 {
+        // Revolutions to go from the current lead to the lead at the end.
+        let s0 = self.thread_progress(c_idx);
+        let l0 = Self::thread_lead_at(chan.thread_lead, chan.thread_lead_change, s0);
+        let l1 = Self::thread_lead_at(chan.thread_lead, chan.thread_lead_change, s0 + dist);
+        let revs = (l1 - l0) / chan.thread_lead_change;
+        return revs / chan.spindle_speed() * 60.0;
+    }
+    let feed = self.effective_feed(c_idx);
+    if feed > 0.0 { dist / feed * 60.0 } else { 0.0 }
+}
 
-        let motion = self.channels[c_idx].current_motion;
-        let feed = if motion == 0 {
-            self.channel_rapid_feed(c_idx)
-        } else {
-            self.channels[c_idx].feed_rate * self.channels[c_idx].feed_override
-        };
-        // 0% feed override behaves like feed-hold for feed moves (G1/G2/G3).
-        // Rapid (G0) is still allowed.
-        if feed <= 0.0 && motion != 0 {
-            for m in &self.channels[c_idx].axis_map {
-                if let Some(ax) = self.axes.get_mut(m.axis_id as usize) {
-                    ax.velocity = 0.0;
+// Runs the channel with instant motion (positions jump to targets) until the
+// program ends or `max_steps` advances were made. Returns false on the step cap.
+// Motion time at the effective feed (no ramps) is added to `time_s`, plus the
+// wait for block processing when blocks run shorter than it.
+fn run_channel_instant(&mut self, c_idx: usize, max_steps: usize, path: &mut Vec<ToolpathPoint>, time_s: &mut f64) -> bool {
+    let mut since_block = f64::INFINITY;
+    for _ in 0..max_steps {
+        if !self.channels[c_idx].is_running {
+            return true;
+        }
+        let mut moved = false;
+        let mut dist_sq = 0.0;
+        for m in &self.channels[c_idx].axis_map {
+            if let Some(ax) = find_axis(&self.axes, m.axis_id) {
+                let delta = ax.target - ax.position;
+                if delta.abs() > 1e-9 {
+                    moved = true;
+                    dist_sq += delta * delta;
                 }
             }
-            continue;
         }
-        let pending_active = !self.channels[c_idx].pending.is_empty();
-        let has_future = pending_active
-            || (self.channels[c_idx].is_running && self.channels[c_idx].pc < self.channels[c_idx].program.len());
-        // Continuous mode: only force exact stop when requested (G61), at final stop,
-        // or when a pause/single-block stop is pending.
-        let stop_at_target = self.channels[c_idx].exact_stop
-            || !has_future
-            || self.channels[c_idx].pause_pending;
-        let mut still_moving = false;
-
+        let duration_s = if moved { self.motion_time_s(c_idx, dist_sq.sqrt()) } else { 0.0 };
+        let step_start_s = *time_s;
+        *time_s += duration_s;
+        since_block += duration_s;
+        if let Some(PendingEntry::Dwell(dwell_s)) = self.channels[c_idx].pending.front() {
+            *time_s += dwell_s;
+            since_block += dwell_s;
+        }
+        if self.channels[c_idx].pending.is_empty() {
+            *time_s += (self.block_time_s - since_block).max(0.0);
+            since_block = 0.0;
+        }
+        self.channels[c_idx].advance_spindle(*time_s - step_start_s);
         for m in &self.channels[c_idx].axis_map {
-            if let Some(ax) = self.axes.get_mut(m.axis_id as usize) {
-                if move_axis(ax, feed, dt_sec, stop_at_target) {
-                    still_moving = true;
-                }
+            if let Some(ax) = find_axis_mut(&mut self.axes, m.axis_id) {
+                ax.position = ax.target;
+                ax.velocity = 0.0;
             }
         }
+        if moved {
+            let mut point = self.toolpath_point(c_idx);
+            point.duration_s = duration_s;
+            path.push(point);
+        }
+        self.advance_channel(c_idx);
+    }
+    !self.channels[c_idx].is_running
+}
 
-        if self.channels[c_idx].is_running && !still_moving {
-            if self.channels[c_idx].pause_pending && self.channels[c_idx].pending.is_empty() {
-                self.channels[c_idx].paused = true;
-                self.channels[c_idx].pause_pending = false;
-                self.channels[c_idx].step_once = false;
-                continue;
-            }
+// Snaps the channel's axes (and turret) to their targets.
+fn complete_channel_motion(&mut self, c_idx: usize) {
+    for m in &self.channels[c_idx].axis_map {
+        if let Some(ax) = find_axis_mut(&mut self.axes, m.axis_id) {
+            ax.position = ax.target;
+            ax.velocity = 0.0;
+        }
+    }
+    if let Some(turret) = self.channels[c_idx].turret.as_mut() {
+        turret.advance(f64::INFINITY);
+    }
+}
 
-            // If we have pending arc segments, execute them before advancing the program counter.
-            if let Some(next) = self.channels[c_idx].pending.pop_front() {
-                for (axis_id, tgt) in next {
-                    if let Some(ax) = self.axes.get_mut(axis_id as usize) {
-                        ax.target = match ax.axis_type {
-                            AxisType::Rotary => normalize_rotary_target(tgt),
-                            AxisType::Linear => tgt.clamp(ax.min_range, ax.max_range),
-                        };
-                    }
-                }
-                continue;
-            }
+// One tick of dry geometric mode: blocks run until the program ends, pauses
+// or the per-tick bound is reached (endless loops stay responsive).
+fn run_channel_dry(&mut self, c_idx: usize) {
+    for _ in 0..DRY_BLOCKS_PER_TICK {
+        self.complete_channel_motion(c_idx);
+        self.record_trace(c_idx);
+        let chan = &self.channels[c_idx];
+        if !chan.is_running || chan.paused || self.estop {
+            return;
+        }
+        self.advance_channel(c_idx);
+    }
+    self.complete_channel_motion(c_idx);
+}
 
-            let current_pc = self.channels[c_idx].pc;
-            if current_pc < self.channels[c_idx].program.len() {
-                let line = self.channels[c_idx].program[current_pc].clone();
-                self.channels[c_idx].active_pc = current_pc as i32;
-                self.parse_line(c_idx, &line);
-                if self.channels[c_idx].single_block || self.channels[c_idx].step_once {
-                    self.channels[c_idx].pause_pending = true;
-                }
-                self.channels[c_idx].pc += 1;
-            } else {
-                self.channels[c_idx].is_running = false;
-                self.channels[c_idx].active_pc = -1;
-            }
+fn record_trace(&mut self, c_idx: usize) {
+    let chan = &self.channels[c_idx];
+    let operation = usize::try_from(chan.active_pc).ok().and_then(|line| {
+        chan.outline
+            .operations
+            .iter()
+            .find(|op| op.start_line <= line && line <= op.end_line)
+            .map(|op| op.index)
+    });
+    let point = TracePoint {
+        operation,
+        position: chan
+            .axis_map
+            .iter()
+            .filter_map(|m| find_axis(&self.axes, m.axis_id))
+            .map(|ax| ax.position)
+            .collect(),
+        motion: chan.current_motion,
+        tool: chan.active_tool,
+    };
+    let tolerance = self.trace_tolerance;
+    self.channels[c_idx].trace.push(point, tolerance);
+}
+
+fn toolpath_point(&self, c_idx: usize) -> ToolpathPoint {
+    let chan = &self.channels[c_idx];
+    ToolpathPoint {
+        block: chan.active_pc.max(0) as usize,
+        motion: chan.current_motion,
+        tool: chan.active_tool,
+        feed: chan.feed_rate,
+        spindle_rpm: chan.spindle_rpm,
+        duration_s: 0.0,
+        tolerance: self.path_tolerance(c_idx),
+        cutter_comp: chan.path_comp,
+        wcs: self.active_wcs,
+        length_comp: self.length_comp_vector(c_idx),
+        position: chan
+            .axis_map
+            .iter()
+            .filter_map(|m| find_axis(&self.axes, m.axis_id))
+            .map(|ax| AxisOffset { axis_id: ax.id, value: ax.position })
+            .collect(),
+    }
+}
+
+// Offline run of `code` on a copy of this machine, leaving the live state untouched.
+fn run_verification(&self, c_idx: usize, code: &str) -> Option<VerificationRun> {
+    if c_idx >= self.channels.len() {
+        return None;
+    }
+    let mut sim = self.clone();
+    sim.logger.silence();
+    sim.estop = false;
+    sim.feed_hold = false;
+    sim.is_homing = false;
+    sim.alarms.clear();
+    {
+        let chan = &mut sim.channels[c_idx];
+        chan.single_block = false;
+        chan.parts_target_reached = false;
+        chan.pending.clear();
+        chan.block_trace = None;
+    }
+    sim.load_program(c_idx, code.to_string());
+
+    // The path starts at the current position so the first move is a segment too.
+    let mut path = vec![sim.toolpath_point(c_idx)];
+    let mut cycle_time_s = 0.0;
+    let completed = sim.run_channel_instant(c_idx, VERIFY_MAX_STEPS, &mut path, &mut cycle_time_s);
+    let coverage = sim.program_coverage(c_idx);
+    Some(VerificationRun {
+        completed,
+        part_count: sim.channels[c_idx].part_count.saturating_sub(self.channels[c_idx].part_count),
+        coverage,
+        path,
+        alarms: sim.alarms,
+        cycle_time_s,
+    })
+}
+
+fn program_coverage(&self, c_idx: usize) -> ProgramCoverage {
+    let Some(chan) = self.channels.get(c_idx) else {
+        return ProgramCoverage::default();
+    };
+    let mut coverage = ProgramCoverage {
+        hits: chan.block_hits.clone(),
+        ..ProgramCoverage::default()
+    };
+    for (idx, line) in chan.program.resident() {
+        if !is_executable_line(line) {
+            continue;
+        }
+        coverage.total_blocks += 1;
+        if chan.block_hits.get(idx).copied().unwrap_or(0) > 0 {
+            coverage.executed_blocks += 1;
+        } else {
+            coverage.unexecuted_lines.push(idx);
         }
     }
+    coverage.percent = if coverage.total_blocks > 0 {
+        coverage.executed_blocks as f64 * 100.0 / coverage.total_blocks as f64
+    } else {
+        100.0
+    };
+    coverage.branches = chan
+        .branch_hits
+        .iter()
+        .map(|(&line, &[taken, not_taken])| BranchCoverage { line, taken, not_taken })
+        .collect();
+    coverage.subprograms = chan.call_hits.iter().map(|(program, &calls)| SubprogramCoverage { program: program.clone(), calls }).collect();
+    coverage
 }
 
 
+// Active work offset plus any G52 local shift.
 fn wcs_offset(&self, axis_id: u32) -> f64 {
-    self.work_offsets
+    let base = self
+        .work_offsets
         .get(self.active_wcs)
         .and_then(|w| w.offsets.iter().find(|o| o.axis_id == axis_id))
         .map(|o| o.value)
-        .unwrap_or(0.0)
+        .unwrap_or(0.0);
+    base + self.local_shift.get(&axis_id).copied().unwrap_or(0.0)
 }
 
 fn machine_to_work(&self, axis_id: u32, machine_pos: f64) -> f64 {
@@ -1351,6 +15165,7 @@ fn work_to_machine(&self, axis_id: u32, work_pos: f64) -> f64 {
     work_pos + self.wcs_offset(axis_id)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn peek_next_comp_linear_xy(
     &self,
     c_idx: usize,
@@ -1360,12 +15175,16 @@ fn peek_next_comp_linear_xy(
     abs_mode: bool,
     units_mm: bool,
     cutter_comp_mode: i32,
+    plane_letters: (u8, u8), // comp-plane axes, X/Y for a vertical tool
 ) -> Option<(f64, f64, i32)> {
     let chan = self.channels.get(c_idx)?;
     if !chan.is_running {
         return None;
     }
-    let next_pc = chan.pc + 1;
+    let mut next_pc = chan.pc + 1;
+    while self.block_deleted(chan.program.get(next_pc)?) {
+        next_pc += 1;
+    }
     let line = chan.program.get(next_pc)?;
     let bytes = line.as_bytes();
     let mut i = 0;
@@ -1399,7 +15218,8 @@ fn peek_next_comp_linear_xy(
         if c == b'G' {
             i += 1;
             let (val, len) = self.parse_float_bytes(&bytes[i..]);
-            if let Some(v) = val {
+            // Decimal G codes (G90.1, ...) never affect the look-ahead.
+            if let Some(v) = val.filter(|v| (v * 10.0).round() as i32 % 10 == 0) {
                 let g = v.round() as i32;
                 g_words.push(g);
                 if g == 20 {
@@ -1411,7 +15231,7 @@ fn peek_next_comp_linear_xy(
             i += len;
             continue;
         }
-        if c == b'X' {
+        if c == plane_letters.0 {
             i += 1;
             let (val, len) = self.parse_float_bytes(&bytes[i..]);
             let unit = if units_mm_word { 1.0 } else { 25.4 };
@@ -1422,7 +15242,7 @@ fn peek_next_comp_linear_xy(
             i += len;
             continue;
         }
-        if c == b'Y' {
+        if c == plane_letters.1 {
             i += 1;
             let (val, len) = self.parse_float_bytes(&bytes[i..]);
             let unit = if units_mm_word { 1.0 } else { 25.4 };
@@ -1439,20 +15259,42 @@ fn peek_next_comp_linear_xy(
     let mut abs = abs_mode;
     let mut motion = current_motion;
     let mut comp = cutter_comp_mode;
+    let mut diameter = chan.diameter_mode;
+    let reversed = self.mirror_reverses(c_idx, chan.plane);
     for g in g_words {
         match g {
+            7 => diameter = true,
+            8 => diameter = false,
             90 => abs = true,
             91 => abs = false,
-            0 | 1 | 2 | 3 => motion = g,
+            0..=3 => motion = g,
             40 => comp = 40,
-            41 => comp = 41,
-            42 => comp = 42,
+            41 => comp = if reversed { 42 } else { 41 },
+            42 => comp = if reversed { 41 } else { 42 },
             _ => {}
         }
     }
     if motion != 1 || !matches!(comp, 41 | 42) || (!x_set && !y_set) {
         return None;
     }
+    if diameter {
+        if plane_letters.0 == b'X' {
+            x = x.map(|v| v / 2.0);
+        }
+        if plane_letters.1 == b'X' {
+            y = y.map(|v| v / 2.0);
+        }
+    }
+    for (letter, word) in [(plane_letters.0, &mut x), (plane_letters.1, &mut y)] {
+        let center = chan
+            .axis_map
+            .iter()
+            .find(|m| m.display_label.eq_ignore_ascii_case(&char::from(letter).to_string()))
+            .and_then(|m| chan.mirror.get(&m.axis_id));
+        if let Some(center) = center {
+            *word = word.map(|v| if abs { 2.0 * center - v } else { -v });
+        }
+    }
 
     let ex = if x_set {
         if abs {
@@ -1475,7 +15317,37 @@ fn peek_next_comp_linear_xy(
     Some((ex, ey, comp))
 }
 
+// Raises BLOCK_FORMAT for modal conflicts. Returns false when the dialect
+// rejects the block.
+fn check_block_format(&mut self, c_idx: usize, line: &str) -> bool {
+    let conflicts = block_modal_conflicts(line);
+    let Some((group, first, second)) = conflicts.first() else {
+        return true;
+    };
+    let rejects = self.dialect.modal_conflict_rejects_block();
+    let chan = &self.channels[c_idx];
+    let message = format!(
+        "{} and {} in one block ({} group) at line {}{}",
+        first,
+        second,
+        group,
+        chan.active_pc + 1,
+        if rejects { "" } else { "; last one applies" }
+    );
+    let chan_id = chan.id;
+    let severity = if rejects { AlarmSeverity::Fault } else { AlarmSeverity::Warning };
+    self.raise_alarm("BLOCK_FORMAT", &message, severity, Some(chan_id));
+    !rejects
+}
+
 fn parse_line(&mut self, c_idx: usize, line: &str) {
+    self.channels[c_idx].arc_feed_limit = 0.0;
+    self.channels[c_idx].descent_feed_limit = 0.0;
+    let Some(expanded) = self.substitute_parameters(c_idx, line) else { return };
+    let line = expanded.as_str();
+    if !self.check_block_format(c_idx, line) {
+        return;
+    }
     let cutter_comp_before = self.channels[c_idx].cutter_comp;
     let comp_entry_pending_before = self.channels[c_idx].comp_entry_pending;
     let mut known_labels: Vec<(String, u32)> = self.channels[c_idx]
@@ -1483,7 +15355,7 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
         .iter()
         .map(|m| (m.display_label.to_uppercase(), m.axis_id))
         .collect();
-    known_labels.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    known_labels.sort_by_key(|l| std::cmp::Reverse(l.0.len()));
 
     // Helper: label -> axis_id
     let axis_id_for = |lbl: &str, known: &Vec<(String, u32)>| -> Option<u32> {
@@ -1498,18 +15370,17 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
     // Current positions in programmed WORK coordinates for mapped axes.
     // - Prefer cached programmed geometry position (uncompensated)
     // - Fallback to machine position converted to work coords
-    // - If G43 is active, decompensate Z on fallback so tool-length doesn't stack
-    let z_axis_for_comp = axis_id_for("Z", &known_labels);
-    let length_comp_active_now = self.channels[c_idx].length_comp_active;
-    let tool_length_now = self.channels[c_idx].tool_length;
+    // - If G43 is active, decompensate the tool axis on fallback so tool-length doesn't stack
+    let xyz_for_comp = ["X", "Y", "Z"].map(|l| axis_id_for(l, &known_labels));
+    let length_comp_now = self.length_comp_vector(c_idx);
     let mut cur_work: std::collections::HashMap<u32, f64> = std::collections::HashMap::new();
     for (_, axis_id) in &known_labels {
         if let Some(wp) = self.channels[c_idx].programmed_work.get(axis_id).copied() {
             cur_work.insert(*axis_id, wp);
-        } else if let Some(ax) = self.axes.get(*axis_id as usize) {
+        } else if let Some(ax) = find_axis(&self.axes, *axis_id) {
             let mut w = self.machine_to_work(*axis_id, ax.position);
-            if length_comp_active_now && Some(*axis_id) == z_axis_for_comp {
-                w -= tool_length_now;
+            if let Some(k) = xyz_for_comp.iter().position(|id| *id == Some(*axis_id)) {
+                w -= length_comp_now[k];
             }
             cur_work.insert(*axis_id, w);
         }
@@ -1520,6 +15391,7 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
 
     // Parsed words
     let mut g_words: Vec<i32> = Vec::new();
+    let mut g_decimal: Vec<i32> = Vec::new(); // tenths, e.g. 901 = G90.1
     let mut m_words: Vec<i32> = Vec::new();
     let mut f_word: Option<f64> = None;
     let mut s_word: Option<f64> = None;
@@ -1533,11 +15405,15 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
     let mut i_off: Option<f64> = None;
     let mut j_off: Option<f64> = None;
     let mut r_word: Option<f64> = None;
+    let mut p_word: Option<f64> = None;
+    let mut k_word: Option<f64> = None;
+    let mut q_word: Option<f64> = None;
     let mut d_word: Option<f64> = None;
     let mut d_word_raw: Option<f64> = None;
     let mut h_word: Option<f64> = None;
     let mut h_word_raw: Option<f64> = None;
     let mut units_mm_word = self.channels[c_idx].units_mm;
+    let mut extra_words: Vec<(u32, f64)> = Vec::new(); // non-XYZ axis words, scaled
 
     while i < bytes.len() {
         if bytes[i].is_ascii_whitespace() {
@@ -1578,7 +15454,7 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
                     cur_work.get(axis_id).copied().unwrap_or(0.0) + v_scaled
                 };
                 let tgt = self.machine_target_with_limits(*axis_id, self.work_to_machine(*axis_id, v_work));
-                if let Some(ax) = self.axes.get_mut(*axis_id as usize) {
+                if let Some(ax) = find_axis_mut(&mut self.axes, *axis_id) {
                     ax.target = tgt;
                 }
             }
@@ -1591,12 +15467,17 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
             i += 1;
             let (val, len) = self.parse_float_bytes(&bytes[i..]);
             if let Some(v) = val {
-                let g = v.round() as i32;
-                g_words.push(g);
-                if g == 20 {
-                    units_mm_word = false;
-                } else if g == 21 {
-                    units_mm_word = true;
+                let g10 = (v * 10.0).round() as i32;
+                if g10 % 10 != 0 {
+                    g_decimal.push(g10);
+                } else {
+                    let g = g10 / 10;
+                    g_words.push(g);
+                    if g == 20 {
+                        units_mm_word = false;
+                    } else if g == 21 {
+                        units_mm_word = true;
+                    }
                 }
             }
             i += len;
@@ -1669,6 +15550,28 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
             i += len;
             continue;
         }
+        if c == b'P' {
+            i += 1;
+            let (val, len) = self.parse_float_bytes(&bytes[i..]);
+            p_word = val;
+            i += len;
+            continue;
+        }
+        if c == b'K' {
+            i += 1;
+            let (val, len) = self.parse_float_bytes(&bytes[i..]);
+            let unit = if units_mm_word { 1.0 } else { 25.4 };
+            k_word = val.map(|v| v * unit);
+            i += len;
+            continue;
+        }
+        if c == b'Q' {
+            i += 1;
+            let (val, len) = self.parse_float_bytes(&bytes[i..]);
+            q_word = val;
+            i += len;
+            continue;
+        }
         if c == b'D' {
             i += 1;
             let (val, len) = self.parse_float_bytes(&bytes[i..]);
@@ -1724,8 +15627,11 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
                 i += label.len();
                 let (val, len) = self.parse_float_bytes(&bytes[i..]);
                 if let Some(v) = val {
-                    let unit = if units_mm_word { 1.0 } else { 25.4 };
+                    // Rotary words are degrees in either unit mode.
+                    let rotary = find_axis(&self.axes, *axis_id).is_some_and(|ax| ax.axis_type == AxisType::Rotary);
+                    let unit = if units_mm_word || rotary { 1.0 } else { 25.4 };
                     let v_scaled = v * unit;
+                    extra_words.push((*axis_id, v_scaled));
                     // Interpret as WORK coordinate.
                     let v_work = if self.channels[c_idx].abs_mode {
                         v_scaled
@@ -1734,7 +15640,7 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
                     };
                     // Compute target without holding a mutable borrow of `self.axes`.
                     let tgt = self.machine_target_with_limits(*axis_id, self.work_to_machine(*axis_id, v_work));
-                    if let Some(ax) = self.axes.get_mut(*axis_id as usize) {
+                    if let Some(ax) = find_axis_mut(&mut self.axes, *axis_id) {
                         ax.target = tgt;
                     }
                 }
@@ -1748,8 +15654,106 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
         }
     }
 
+    // Diameter programming (G7, or the channel default): X is the diameter,
+    // except as a G4 dwell time.
+    let diameter_mode = g_words
+        .iter()
+        .rev()
+        .find_map(|g| match g {
+            7 => Some(true),
+            8 => Some(false),
+            _ => None,
+        })
+        .unwrap_or(self.channels[c_idx].diameter_mode);
+    let center_absolute = !g_decimal.contains(&911) && (g_decimal.contains(&901) || self.channels[c_idx].arc_center_absolute);
+    if diameter_mode && !g_words.contains(&4) {
+        x = x.map(|v| v / 2.0);
+        // An absolute arc center is an X position too; incremental I stays a radius.
+        if center_absolute {
+            i_off = i_off.map(|v| v / 2.0);
+        }
+    }
+
+    // Mirror image (G51.1): X/Y/Z words reflect about the mirror centers, and
+    // an odd number of mirrored axes in the plane reverses arcs and comp side.
+    let mirror_block = g_decimal.iter().any(|g| matches!(*g, 501 | 511));
+    let mirrorable = !mirror_block && !g_words.iter().any(|g| matches!(*g, 4 | 52 | 53));
+    if mirrorable && !self.channels[c_idx].mirror.is_empty() {
+        let absolute = g_words
+            .iter()
+            .rev()
+            .find_map(|g| match g {
+                90 => Some(true),
+                91 => Some(false),
+                _ => None,
+            })
+            .unwrap_or(self.channels[c_idx].abs_mode);
+        let motion = g_words
+            .iter()
+            .rev()
+            .copied()
+            .find(|g| matches!(*g, 0..=3 | 33 | 34))
+            .unwrap_or(self.channels[c_idx].current_motion);
+        let mirror = &self.channels[c_idx].mirror;
+        let words = [&mut x, &mut y, &mut z].into_iter().zip([&mut i_off, &mut j_off, &mut k_word]);
+        for (id, (word, offset)) in xyz_for_comp.iter().zip(words) {
+            let Some(center) = id.and_then(|id| mirror.get(&id)).copied() else { continue };
+            *word = word.map(|v| if absolute { 2.0 * center - v } else { -v });
+            if matches!(motion, 2 | 3) {
+                *offset = offset.map(|v| if center_absolute { 2.0 * center - v } else { -v });
+            }
+        }
+    }
+    let plane = g_words.iter().rev().copied().find(|g| matches!(*g, 17..=19)).map_or(self.channels[c_idx].plane, |g| g as u8);
+    if mirrorable && self.mirror_reverses(c_idx, plane) {
+        for g in g_words.iter_mut() {
+            *g = match *g {
+                2 => 3,
+                3 => 2,
+                41 => 42,
+                42 => 41,
+                other => other,
+            };
+        }
+    }
+
+    // Threading blocks (G33/G34), rigid taps (G33.1) and tapping cycles
+    // (G74/G84) take F as the lead or pitch (mm/rev), not as a feed rate.
+    let thread_motion = g_words
+        .iter()
+        .rev()
+        .copied()
+        .find(|g| matches!(*g, 0..=3 | 33 | 34))
+        .unwrap_or(self.channels[c_idx].current_motion);
+    let tap_cycle = g_words.iter().rev().copied().find(|g| matches!(*g, 74 | 84));
+    let rigid_tap = g_decimal.contains(&331);
+    // G80 or a group-01 word of the program cancels an active cycle (the
+    // cycle's own synthesized moves do not); an X/Y block repeats it.
+    let cycle_cancel = g_words.contains(&80)
+        || (!self.channels[c_idx].synthesizing && g_words.iter().any(|g| matches!(*g, 0..=3 | 33 | 34)));
+    if cycle_cancel {
+        self.channels[c_idx].canned_cycle = None;
+    }
+    let cycle_repeat = tap_cycle.is_none()
+        && (x_set || y_set)
+        && !self.channels[c_idx].synthesizing
+        && self.channels[c_idx].canned_cycle.is_some();
+    let thread_block =
+        matches!(thread_motion, 33 | 34) || rigid_tap || tap_cycle.is_some() || cycle_repeat || g_words.contains(&76);
+
+    let f_feed = f_word.filter(|_| !thread_block);
+    if !self.check_word_ranges(c_idx, line, f_feed, &mut s_word, t_word, d_word_raw, h_word_raw) {
+        return;
+    }
+    let cycle = tap_cycle
+        .or(g_words.contains(&76).then_some(76))
+        .or(self.channels[c_idx].canned_cycle.as_ref().map(|c| c.code).filter(|_| cycle_repeat));
+    if !self.check_contour_wcs(c_idx, line, &g_words, cycle) {
+        return;
+    }
+
     // Apply feed
-    if let Some(f) = f_word {
+    if let Some(f) = f_word.filter(|_| !thread_block) {
         self.channels[c_idx].feed_rate = f;
     }
     if let Some(s) = s_word {
@@ -1757,45 +15761,51 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
     }
     if let Some(t) = t_word {
         let idx = t.max(0);
-        self.channels[c_idx].active_tool = idx;
-        self.channels[c_idx].active_d = 0;
-        self.channels[c_idx].active_h = 0;
-        if idx == 0 {
-            // T0 = unload tool (no length/radius comp baseline from loaded tool).
-            self.channels[c_idx].tool_length = 0.0;
-            self.channels[c_idx].tool_radius = 0.0;
-            self.channels[c_idx].length_comp_active = false;
-            self.channels[c_idx].cutter_comp = 40;
-            self.channels[c_idx]
-                .tool_table
-                .insert(0, ToolTableEntry { radius: 0.0, length: 0.0 });
+        if let Some(turret) = self.channels[c_idx].turret.as_mut() {
+            // A turret selects its station on T itself; there is no M6.
+            let station = Turret::station_for(idx) as i32;
+            turret.index_to(station as u32);
+            self.set_active_tool(c_idx, station);
         } else {
-            let table_entry = self.channels[c_idx].tool_table.get(&idx).copied();
-            if let Some(entry) = table_entry {
-                self.channels[c_idx].tool_length = entry.length;
-                self.channels[c_idx].tool_radius = entry.radius.abs();
-                // Keep D0/H0 mirrored to active tool for practical table workflows.
-                self.channels[c_idx].tool_table.insert(0, entry);
-            }
+            // T preselects; M6 loads it.
+            self.channels[c_idx].pending_tool = Some(idx);
         }
     }
+    // Without a changer M6 swaps at once, before the block's G43/G41 pick up
+    // the new tool's offsets.
+    let swap_now = m_words.contains(&6) && self.channels[c_idx].tool_changer.is_none();
+    if let Some(tool) = self.channels[c_idx].pending_tool.take_if(|_| swap_now) {
+        self.set_active_tool(c_idx, tool);
+    }
 
     let has_axis_motion_words = x_set || y_set || z_set;
     let has_xy_motion_words = x_set || y_set;
-    let g40_requested = g_words.iter().any(|g| *g == 40);
-    let g41_requested = g_words.iter().any(|g| *g == 41);
-    let g42_requested = g_words.iter().any(|g| *g == 42);
+    let g40_requested = g_words.contains(&40);
+    let g41_requested = g_words.contains(&41);
+    let g42_requested = g_words.contains(&42);
 
     // Apply modal G-codes (G90/G91/G17 and WCS selection)
     for g in &g_words {
         match *g {
             90 => self.channels[c_idx].abs_mode = true,
             91 => self.channels[c_idx].abs_mode = false,
+            98 => self.channels[c_idx].cycle_return_r = false,
+            99 => self.channels[c_idx].cycle_return_r = true,
             20 => self.channels[c_idx].units_mm = false,
             21 => self.channels[c_idx].units_mm = true,
+            7 => self.channels[c_idx].diameter_mode = true,
+            8 => self.channels[c_idx].diameter_mode = false,
             17 => self.channels[c_idx].plane = 17,
+            18 => self.channels[c_idx].plane = 18,
+            19 => self.channels[c_idx].plane = 19,
             61 => self.channels[c_idx].exact_stop = true,
-            64 => self.channels[c_idx].exact_stop = false,
+            64 => {
+                // P/Q tighten or relax the profile until the next G64; plain G64 restores it.
+                let chan = &mut self.channels[c_idx];
+                chan.exact_stop = false;
+                chan.path_tolerance = p_word.filter(|p| *p > 0.0);
+                chan.comp_tolerance = q_word.filter(|q| *q > 0.0);
+            }
             54 => self.active_wcs = 0,
             55 => self.active_wcs = 1,
             56 => self.active_wcs = 2,
@@ -1837,14 +15847,43 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
         }
     }
 
+    // Part counting on the configured cycle-complete M code.
+    let part_m_code = self.channels[c_idx].part_count_m_code;
+    if m_words.contains(&part_m_code) {
+        self.count_completed_part(c_idx);
+    }
+
+    // Decimal modal G-codes.
+    for g in &g_decimal {
+        match *g {
+            901 => self.channels[c_idx].arc_center_absolute = true,
+            911 => self.channels[c_idx].arc_center_absolute = false,
+            _ => {}
+        }
+    }
+
     // Apply modal M-codes.
     for m in &m_words {
         match *m {
+            0 => {
+                self.channels[c_idx].pause_pending = true;
+                self.channels[c_idx].program_stop = Some(0);
+                log_at!(self, Info, "interpreter", "Channel {}: program stop", self.channels[c_idx].id);
+            }
+            1 if self.optional_stop => {
+                self.channels[c_idx].pause_pending = true;
+                self.channels[c_idx].program_stop = Some(1);
+                log_at!(self, Info, "interpreter", "Channel {}: optional stop", self.channels[c_idx].id);
+            }
+            2 | 30 => self.channels[c_idx].program_end = Some(*m),
             3 => self.channels[c_idx].spindle_mode = 3,
             4 => self.channels[c_idx].spindle_mode = 4,
             5 => self.channels[c_idx].spindle_mode = 5,
+            6 => self.start_tool_change(c_idx),
             8 => self.channels[c_idx].coolant_on = true,
             9 => self.channels[c_idx].coolant_on = false,
+            48 => self.channels[c_idx].overrides_program_locked = false,
+            49 => self.channels[c_idx].overrides_program_locked = true,
             _ => {}
         }
     }
@@ -1870,17 +15909,142 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
         self.channels[c_idx].tool_length = self.resolve_h_length(c_idx, h_raw, h);
     }
 
+    // G4 dwell: P (dialect units) or X (seconds); axis words do not move.
+    if g_words.contains(&4) {
+        let x_seconds = x.map(|v| if units_mm_word { v } else { v / 25.4 });
+        let seconds = p_word.map(|p| p * self.dialect.dwell_p_seconds()).or(x_seconds).unwrap_or(0.0);
+        if seconds > 0.0 {
+            self.channels[c_idx].pending.push_back(PendingEntry::Dwell(seconds));
+        }
+        return;
+    }
+
+    // G51.1 sets a mirror center per X/Y/Z word, G50.1 cancels the named axes
+    // (all without words). Modal arc direction and comp side follow along.
+    if mirror_block {
+        let reversed = self.mirror_reverses(c_idx, self.channels[c_idx].plane);
+        let words: Vec<(u32, f64)> = xyz_for_comp.into_iter().zip([x, y, z]).filter_map(|(id, v)| Some((id?, v?))).collect();
+        let chan = &mut self.channels[c_idx];
+        if g_decimal.contains(&511) {
+            chan.mirror.extend(words);
+        } else if words.is_empty() {
+            chan.mirror.clear();
+        } else {
+            for (id, _) in words {
+                chan.mirror.remove(&id);
+            }
+        }
+        if self.mirror_reverses(c_idx, self.channels[c_idx].plane) != reversed {
+            let chan = &mut self.channels[c_idx];
+            chan.current_motion = match chan.current_motion {
+                2 => 3,
+                3 => 2,
+                other => other,
+            };
+            chan.cutter_comp = match chan.cutter_comp {
+                41 => 42,
+                42 => 41,
+                other => other,
+            };
+        }
+        return;
+    }
+
+    // G52: the X/Y/Z words set a local shift of the work system rather than
+    // move; a zero word cancels that axis's shift.
+    if g_words.contains(&52) {
+        for (id, shift) in xyz_for_comp.into_iter().zip([x, y, z]) {
+            let (Some(id), Some(shift)) = (id, shift) else { continue };
+            let old = self.local_shift.get(&id).copied().unwrap_or(0.0);
+            if shift.abs() <= self.tolerances.position_mm {
+                self.local_shift.remove(&id);
+            } else {
+                self.local_shift.insert(id, shift);
+            }
+            // The tool stays put, so its work position moves the other way.
+            if let Some(w) = self.channels[c_idx].programmed_work.get_mut(&id) {
+                *w -= self.local_shift.get(&id).copied().unwrap_or(0.0) - old;
+            }
+        }
+        return;
+    }
+
+    // G53: axis words are machine positions for this block only, reached in
+    // G0/G1 regardless of work offset, G52 shift, G90/G91 and tool length.
+    if g_words.contains(&53) {
+        let motion = g_words
+            .iter()
+            .rev()
+            .copied()
+            .find(|g| matches!(*g, 0..=3 | 33 | 34))
+            .unwrap_or(self.channels[c_idx].current_motion);
+        if !matches!(motion, 0 | 1) || matches!(self.channels[c_idx].cutter_comp, 41 | 42) {
+            let chan_id = self.channels[c_idx].id;
+            let message = format!(
+                "G53 needs G0/G1 with cutter comp cancelled (line {}: {})",
+                self.channels[c_idx].active_pc + 1,
+                line.trim()
+            );
+            self.raise_alarm("MACHINE_COORDS", &message, AlarmSeverity::Fault, Some(chan_id));
+            return;
+        }
+        self.channels[c_idx].current_motion = motion;
+        let xyz_words = xyz_for_comp.into_iter().zip([x, y, z]).filter_map(|(id, v)| Some((id?, v?)));
+        for (id, machine) in xyz_words.chain(extra_words).collect::<Vec<_>>() {
+            let tgt = self.machine_target_with_limits(id, machine);
+            if let Some(ax) = find_axis_mut(&mut self.axes, id) {
+                ax.target = tgt;
+            }
+            // Following blocks continue from the work position this lands on.
+            if let Some(k) = xyz_for_comp.iter().position(|a| *a == Some(id)) {
+                let work = self.machine_to_work(id, tgt) - length_comp_now[k];
+                self.channels[c_idx].programmed_work.insert(id, work);
+            }
+        }
+        return;
+    }
+
+    if rigid_tap {
+        self.start_rigid_tap(c_idx, [x, y, z], k_word.or(f_word), xyz_for_comp[2], &cur_work);
+        return;
+    }
+    if let Some(code) = tap_cycle {
+        let initial_z = xyz_for_comp[2].and_then(|id| cur_work.get(&id).copied()).unwrap_or(0.0);
+        let cycle = CannedCycle { code, z, r: r_word, pitch: k_word.or(f_word), initial_z };
+        self.channels[c_idx].canned_cycle = Some(cycle);
+        self.expand_tap_cycle(c_idx, cycle, [x, y], xyz_for_comp, &cur_work);
+        return;
+    }
+    if cycle_repeat {
+        let Some(cycle) = self.channels[c_idx].canned_cycle.as_mut() else { return };
+        cycle.z = z.or(cycle.z);
+        cycle.r = r_word.or(cycle.r);
+        cycle.pitch = k_word.or(f_word).or(cycle.pitch);
+        let cycle = *cycle;
+        self.expand_tap_cycle(c_idx, cycle, [x, y], xyz_for_comp, &cur_work);
+        return;
+    }
+    if g_words.contains(&76) {
+        let xz_ids = [xyz_for_comp[0], xyz_for_comp[2]];
+        self.expand_thread_cycle(c_idx, [x, z], p_word, q_word, r_word, f_word, xz_ids, &cur_work);
+        return;
+    }
+
     // Motion mode: prefer the last motion G-word on the line, otherwise keep modal motion.
     let mut motion: Option<i32> = None;
     for g in &g_words {
-        if matches!(*g, 0 | 1 | 2 | 3) {
+        if matches!(*g, 0..=3 | 33 | 34) {
             motion = Some(*g);
         }
     }
     let motion = motion.unwrap_or(self.channels[c_idx].current_motion);
-    if !matches!(motion, 0 | 1 | 2 | 3) {
+    if !matches!(motion, 0..=3 | 33 | 34) {
         return;
     }
+    if has_axis_motion_words {
+        self.check_cutting_policy(c_idx, motion, line);
+    }
+    let previous_motion = self.channels[c_idx].current_motion;
     self.channels[c_idx].current_motion = motion;
     let cutter_comp_just_enabled = matches!(cutter_comp_before, 40)
         && matches!(self.channels[c_idx].cutter_comp, 41 | 42);
@@ -1893,15 +16057,24 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
     let x_id = axis_id_for("X", &known_labels);
     let y_id = axis_id_for("Y", &known_labels);
     let z_id = axis_id_for("Z", &known_labels);
+    // Cutter comp works in the plane normal to the tool: XY for a vertical
+    // tool, the head's plane for an aggregate tool.
+    let xyz_ids = [x_id, y_id, z_id];
+    let xyz_set = [x_set, y_set, z_set];
+    let (plane_a, plane_b) = self.channels[c_idx]
+        .active_tool_direction()
+        .unwrap_or(ToolDirection::MinusZ)
+        .comp_plane();
+    let (comp_a_id, comp_b_id) = (xyz_ids[plane_a], xyz_ids[plane_b]);
+    let length_comp = self.length_comp_vector(c_idx);
     let cutter_comp = if g40_cancel_on_motion {
         cutter_comp_before
     } else {
         self.channels[c_idx].cutter_comp
     };
+    self.channels[c_idx].path_comp = cutter_comp;
     let comp_entry_pending_now = self.channels[c_idx].comp_entry_pending;
     let tool_radius = self.channels[c_idx].tool_radius.max(0.0);
-    let length_comp_active = self.channels[c_idx].length_comp_active;
-    let tool_length = self.channels[c_idx].tool_length;
 
     // Build programmed end point in WORK coordinates (uncompensated).
     let mut end_work = cur_work.clone();
@@ -1935,9 +16108,11 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
     let mut corner_transition_work: Vec<(f64, f64)> = Vec::new();
     let mut comp_linear_next: Option<CompLinearState> = None;
 
-    // Cutter compensation: offset XY endpoint normal to move direction.
-    if matches!(motion, 1 | 2 | 3) && tool_radius > 0.0 && matches!(cutter_comp, 41 | 42) {
-        if let (Some(xid), Some(yid)) = (x_id, y_id) {
+    // Cutter compensation: offset the comp-plane endpoint normal to move direction.
+    // The nested `if` mirrors the controller's steps: comp active, then a comp plane.
+    #[allow(clippy::collapsible_if)]
+    if matches!(motion, 1..=3) && tool_radius > 0.0 && matches!(cutter_comp, 41 | 42) {
+        if let (Some(xid), Some(yid)) = (comp_a_id, comp_b_id) {
             let sx = cur_work.get(&xid).copied().unwrap_or(0.0);
             let sy = cur_work.get(&yid).copied().unwrap_or(0.0);
             let ex = end_work.get(&xid).copied().unwrap_or(sx);
@@ -1965,6 +16140,7 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
                         self.channels[c_idx].abs_mode,
                         self.channels[c_idx].units_mm,
                         self.channels[c_idx].cutter_comp,
+                        (b"XYZ"[plane_a], b"XYZ"[plane_b]),
                     ) {
                         if next_comp == cutter_comp {
                             let ndx = nex - ex;
@@ -1992,28 +16168,43 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
                                         let t_curr = (join.0 - start_off.0) * dir_x + (join.1 - start_off.1) * dir_y;
                                         if t_curr >= -1e-6 && t_curr <= len + 1e-6 {
                                             end_off = join;
+                                            self.trace_decision(c_idx, || {
+                                                format!("trim: inside corner, end cut back {:.4} to the next offset line", len - t_curr)
+                                            });
+                                        } else {
+                                            self.trace_decision(c_idx, || "trim: none, offset lines meet outside this segment".into());
                                         }
                                     }
+                                } else {
+                                    self.trace_decision(c_idx, || "trim: none, outside corner with the next block".into());
                                 }
                             }
+                        } else {
+                            self.trace_decision(c_idx, || format!("trim: none, next block switches to G{next_comp}"));
                         }
+                    } else {
+                        self.trace_decision(c_idx, || "trim: none, next block is not a compensated line".into());
                     }
                 }
                 end_work_motion.insert(xid, end_off.0);
                 end_work_motion.insert(yid, end_off.1);
 
+                let comp_join = self.comp_tolerance(c_idx);
                 if motion == 1 {
-                    let force_entry = (cutter_comp_just_enabled && (x_set ^ y_set)) || comp_entry_pending_now;
+                    let force_entry = (cutter_comp_just_enabled && (xyz_set[plane_a] ^ xyz_set[plane_b])) || comp_entry_pending_now;
                     if force_entry {
                         let entry_gap = ((start_off.0 - sx).powi(2) + (start_off.1 - sy).powi(2)).sqrt();
                         if entry_gap > 1e-6 {
                             corner_transition_work.push(start_off);
+                            self.trace_decision(c_idx, || format!("entry: step {entry_gap:.4} onto the offset path first"));
+                        } else {
+                            self.trace_decision(c_idx, || "entry: already on the offset path".into());
                         }
                     } else if let Some(prev) = self.channels[c_idx].comp_linear_prev {
                         if prev.mode == cutter_comp
                             && (prev.radius - tool_radius).abs() <= 1e-6
-                            && (prev.end_prog_x - sx).abs() <= 1e-4
-                            && (prev.end_prog_y - sy).abs() <= 1e-4
+                            && (prev.end_prog_x - sx).abs() <= comp_join
+                            && (prev.end_prog_y - sy).abs() <= comp_join
                         {
                             let corner_gap = ((prev.end_off_x - start_off.0).powi(2) + (prev.end_off_y - start_off.1).powi(2)).sqrt();
                             if corner_gap > 1e-5 {
@@ -2028,6 +16219,8 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
                                         start_off,
                                         tool_radius,
                                     );
+                                    let points = corner_transition_work.len();
+                                    self.trace_decision(c_idx, || format!("corner: outside, arc around the corner in {points} points"));
                                 } else {
                                     // Inside corners should keep each programmed segment direction.
                                     // Join by intersection of the two compensated lines (miter join),
@@ -2039,12 +16232,20 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
                                         (dir_x, dir_y),
                                     ) {
                                         corner_transition_work.push(join);
+                                        self.trace_decision(c_idx, || "corner: inside, miter at the offset-line intersection".into());
                                     } else {
                                         corner_transition_work.push(start_off);
+                                        self.trace_decision(c_idx, || "corner: offset lines parallel, straight step".into());
                                     }
                                 }
+                            } else {
+                                self.trace_decision(c_idx, || "corner: tangent, no transition".into());
                             }
+                        } else {
+                            self.trace_decision(c_idx, || "corner: not joined, previous block differs in side, radius or end".into());
                         }
+                    } else {
+                        self.trace_decision(c_idx, || "entry: ramp onto the offset path along this block".into());
                     }
 
                     comp_linear_next = Some(CompLinearState {
@@ -2058,77 +16259,85 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
                         radius: tool_radius,
                     });
                 }
+            } else {
+                self.trace_decision(c_idx, || "comp: skipped, no motion in the comp plane".into());
+            }
+        }
+    }
+
+    // Spindle-synchronized threading: straight move at spindle speed x lead.
+    if matches!(motion, 33 | 34) {
+        let chained = matches!(previous_motion, 33 | 34);
+        self.parse_thread_move(c_idx, motion, &cur_work, &end_work, f_word, k_word, q_word, chained);
+        for id in [x_id, y_id, z_id].into_iter().flatten() {
+            if let Some(vw) = end_work.get(&id).copied() {
+                self.channels[c_idx].programmed_work.insert(id, vw);
             }
         }
+        self.channels[c_idx].comp_linear_prev = None;
+        return;
     }
 
     // Linear moves
     if motion == 0 || motion == 1 {
+        if motion == 1 {
+            self.apply_descent_limit(c_idx, line, xyz_ids, &cur_work, &end_work);
+        }
         let rapid_feed = if motion == 0 {
             self.channel_rapid_feed(c_idx)
         } else {
             0.0
         };
-        let mut x_move = x_set;
-        let mut y_move = y_set;
-        let z_move = z_set;
+        let mut moves = xyz_set;
 
         // Under active G41/G42, compensation may require moving the orthogonal axis
         // even if that axis word is omitted in the block.
         if motion == 1 && tool_radius > 0.0 && matches!(cutter_comp, 41 | 42) {
-            if let Some(xid) = x_id {
-                let s = cur_work.get(&xid).copied().unwrap_or(0.0);
-                let e = end_work_motion.get(&xid).copied().unwrap_or(s);
-                if (e - s).abs() > 1e-9 {
-                    x_move = true;
-                }
-            }
-            if let Some(yid) = y_id {
-                let s = cur_work.get(&yid).copied().unwrap_or(0.0);
-                let e = end_work_motion.get(&yid).copied().unwrap_or(s);
+            for k in [plane_a, plane_b] {
+                let Some(id) = xyz_ids[k] else { continue; };
+                let s = cur_work.get(&id).copied().unwrap_or(0.0);
+                let e = end_work_motion.get(&id).copied().unwrap_or(s);
                 if (e - s).abs() > 1e-9 {
-                    y_move = true;
+                    moves[k] = true;
                 }
             }
         }
 
         let mut final_seg: Vec<(u32, f64)> = Vec::new();
-        for (id_opt, do_move) in [(x_id, x_move), (y_id, y_move), (z_id, z_move)] {
-            let Some(id) = id_opt else { continue; };
-            if !do_move {
+        for k in 0..3 {
+            let Some(id) = xyz_ids[k] else { continue; };
+            if !moves[k] {
                 continue;
             }
             if let Some(vw) = end_work_motion.get(&id).copied() {
-                let mut vw_comp = vw;
-                if Some(id) == z_id && length_comp_active {
-                    vw_comp += tool_length;
-                }
+                let vw_comp = vw + length_comp[k];
                 let tgt = self.machine_target_with_limits(id, self.work_to_machine(id, vw_comp));
                 final_seg.push((id, tgt));
             }
         }
 
         // Insert smooth corner transition for compensated linear paths.
+        #[allow(clippy::collapsible_if)]
         if motion == 1 && !corner_transition_work.is_empty() {
-            if let (Some(xid), Some(yid)) = (x_id, y_id) {
+            if let (Some(xid), Some(yid)) = (comp_a_id, comp_b_id) {
                 for (idx, (wx, wy)) in corner_transition_work.iter().enumerate() {
                     let x_tgt = self.machine_target_with_limits(xid, self.work_to_machine(xid, *wx));
                     let y_tgt = self.machine_target_with_limits(yid, self.work_to_machine(yid, *wy));
 
                     if idx == 0 {
-                        if let Some(ax) = self.axes.get_mut(xid as usize) {
+                        if let Some(ax) = find_axis_mut(&mut self.axes, xid) {
                             ax.target = x_tgt;
                         }
-                        if let Some(ax) = self.axes.get_mut(yid as usize) {
+                        if let Some(ax) = find_axis_mut(&mut self.axes, yid) {
                             ax.target = y_tgt;
                         }
                     } else {
-                        self.channels[c_idx].pending.push_back(vec![(xid, x_tgt), (yid, y_tgt)]);
+                        self.channels[c_idx].pending.push_back(PendingEntry::Move(vec![(xid, x_tgt), (yid, y_tgt)]));
                     }
                 }
 
                 if !final_seg.is_empty() {
-                    self.channels[c_idx].pending.push_back(final_seg);
+                    self.channels[c_idx].pending.push_back(PendingEntry::Move(final_seg));
                 }
 
                 for id in [x_id, y_id, z_id].into_iter().flatten() {
@@ -2149,7 +16358,7 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
         }
 
         for (id, tgt) in final_seg {
-            if let Some(ax) = self.axes.get_mut(id as usize) {
+            if let Some(ax) = find_axis_mut(&mut self.axes, id) {
                 ax.target = tgt;
                 if motion == 0 {
                     ax.velocity = ax.velocity.max(rapid_feed);
@@ -2185,13 +16394,26 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
         return;
     }
 
-    // Arc moves: only XY plane supported (G17)
-    if self.channels[c_idx].plane != 17 {
-        self.channels[c_idx].comp_linear_prev = None;
-        return;
-    }
+    // Arc moves in the selected plane. X/Y/Z indices of the two in-plane axes,
+    // ordered so G2 is clockwise looking down the third (helix) axis, and the
+    // matching center words: G17 XY/IJ, G18 ZX/KI, G19 YZ/JK.
     self.channels[c_idx].comp_linear_prev = None;
-    let (Some(xid), Some(yid)) = (x_id, y_id) else { return; };
+    let (ia, ib, ih) = match self.channels[c_idx].plane {
+        18 => (2, 0, 1),
+        19 => (1, 2, 0),
+        _ => (0, 1, 2),
+    };
+    let centers = [i_off, j_off, k_word];
+    let (i_off, j_off) = (centers[ia], centers[ib]);
+    let (Some(xid), Some(yid)) = (xyz_ids[ia], xyz_ids[ib]) else { return; };
+    // Cutter comp only in its own plane; reversed axis order swaps the side.
+    let comp_side = if (plane_a, plane_b) == (ia, ib) {
+        1.0
+    } else if (plane_b, plane_a) == (ia, ib) {
+        -1.0
+    } else {
+        0.0
+    };
 
     let sx = cur_work.get(&xid).copied().unwrap_or(0.0);
     let sy = cur_work.get(&yid).copied().unwrap_or(0.0);
@@ -2201,8 +16423,14 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
     let cw = motion == 2; // G2 = CW, G3 = CCW
 
     // Determine center in WORK coords.
-    let (cx, cy) = if i_off.is_some() || j_off.is_some() {
-        (sx + i_off.unwrap_or(0.0), sy + j_off.unwrap_or(0.0))
+    let arc_center_absolute = self.channels[c_idx].arc_center_absolute;
+    let ijk_given = i_off.is_some() || j_off.is_some();
+    let (cx, cy) = if ijk_given {
+        if arc_center_absolute {
+            (i_off.unwrap_or(sx), j_off.unwrap_or(sy))
+        } else {
+            (sx + i_off.unwrap_or(0.0), sy + j_off.unwrap_or(0.0))
+        }
     } else if let Some(r) = r_word {
         let dx = ex - sx;
         let dy = ey - sy;
@@ -2242,6 +16470,41 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
     if r <= 1e-9 {
         return;
     }
+    if ijk_given {
+        // Start and end must lie on the same circle; otherwise the center is wrong.
+        let r_end = ((ex - cx).powi(2) + (ey - cy).powi(2)).sqrt();
+        let tol = self.tolerances.arc_radius_mm + self.tolerances.arc_radius_rel * r;
+        if (r_end - r).abs() > tol {
+            let (ax, ay) = if arc_center_absolute {
+                (sx + i_off.unwrap_or(0.0), sy + j_off.unwrap_or(0.0))
+            } else {
+                (i_off.unwrap_or(sx), j_off.unwrap_or(sy))
+            };
+            let alt_ok = {
+                let r0 = ((sx - ax).powi(2) + (sy - ay).powi(2)).sqrt();
+                let r1 = ((ex - ax).powi(2) + (ey - ay).powi(2)).sqrt();
+                r0 > 1e-9 && (r1 - r0).abs() <= self.tolerances.arc_radius_mm + self.tolerances.arc_radius_rel * r0
+            };
+            let mut message = format!(
+                "Arc radius mismatch: start r={:.4}, end r={:.4} (line {})",
+                r,
+                r_end,
+                self.channels[c_idx].active_pc + 1
+            );
+            if alt_ok {
+                message.push_str(if arc_center_absolute {
+                    "; IJK look incremental (G91.1)"
+                } else {
+                    "; IJK look absolute (G90.1)"
+                });
+            }
+            let chan_id = self.channels[c_idx].id;
+            self.raise_alarm("ARC_RADIUS", &message, AlarmSeverity::Fault, Some(chan_id));
+            return;
+        }
+    }
+
+    self.channels[c_idx].arc_feed_limit = self.arc_centripetal_feed_limit(xid, yid, r);
 
     let a0 = (sy - cy).atan2(sx - cx);
     let a1 = (ey - cy).atan2(ex - cx);
@@ -2259,26 +16522,35 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
             da += std::f64::consts::TAU;
         }
     }
+    // Start == end with IJK is a full circle; atan2 noise must not collapse it.
+    let full_circle = ijk_given && ((ex - sx).powi(2) + (ey - sy).powi(2)).sqrt() <= self.tolerances.arc_radius_mm;
+    if full_circle {
+        da = if cw { -std::f64::consts::TAU } else { std::f64::consts::TAU };
+    }
+    // P = number of turns; each extra turn adds a full revolution.
+    let turns = p_word.map(|p| p.round().max(1.0)).unwrap_or(1.0);
+    da += da.signum() * std::f64::consts::TAU * (turns - 1.0);
 
     let arc_len = r * da.abs();
     // Segment count from chord error tolerance (mm) with safe clamps.
-    let tol = 0.005_f64;
+    let tol = self.path_tolerance(c_idx);
     let n_by_tol = if r <= tol {
         3.0
     } else {
         let step_ang = 2.0 * (1.0 - (tol / r)).clamp(-1.0, 1.0).acos();
-        if step_ang.is_finite() && step_ang > 1e-6 {
+        if step_ang.is_finite() && step_ang > self.tolerances.angle_rad {
             (da.abs() / step_ang).ceil()
         } else {
             3.0
         }
     };
     let n_by_len = (arc_len / 1.5_f64).ceil();
-    let n = n_by_tol.max(n_by_len).clamp(24.0, 1440.0) as usize;
+    let n = n_by_tol.max(n_by_len).clamp(24.0, 1440.0 * turns) as usize;
 
-    // Helical Z if present
-    let sz = z_id.and_then(|id| cur_work.get(&id).copied());
-    let ez = z_id.and_then(|id| end_work.get(&id).copied());
+    // Helical motion along the third axis if present
+    let h_id = xyz_ids[ih];
+    let sz = h_id.and_then(|id| cur_work.get(&id).copied());
+    let ez = h_id.and_then(|id| end_work.get(&id).copied());
 
     for k in 1..=n {
         let t = k as f64 / n as f64;
@@ -2286,30 +16558,27 @@ fn parse_line(&mut self, c_idx: usize, line: &str) {
         let mut px = cx + r * ang.cos();
         let mut py = cy + r * ang.sin();
 
-        if tool_radius > 0.0 && matches!(cutter_comp, 41 | 42) {
+        if tool_radius > 0.0 && matches!(cutter_comp, 41 | 42) && comp_side != 0.0 {
             let dir = da.signum(); // +1 CCW, -1 CW
             let tx = -ang.sin() * dir;
             let ty = ang.cos() * dir;
             let left_nx = -ty;
             let left_ny = tx;
-            let sign = if cutter_comp == 41 { 1.0 } else { -1.0 };
+            let sign = if cutter_comp == 41 { comp_side } else { -comp_side };
             px += left_nx * tool_radius * sign;
             py += left_ny * tool_radius * sign;
         }
 
         let mut seg: Vec<(u32, f64)> = Vec::new();
-        seg.push((xid, self.work_to_machine(xid, px)));
-        seg.push((yid, self.work_to_machine(yid, py)));
+        seg.push((xid, self.work_to_machine(xid, px + length_comp[ia])));
+        seg.push((yid, self.work_to_machine(yid, py + length_comp[ib])));
 
-        if let (Some(zid), Some(szv), Some(ezv)) = (z_id, sz, ez) {
-            let mut pz = szv + (ezv - szv) * t;
-            if length_comp_active {
-                pz += tool_length;
-            }
-            seg.push((zid, self.work_to_machine(zid, pz)));
+        if let (Some(hid), Some(szv), Some(ezv)) = (h_id, sz, ez) {
+            let pz = szv + (ezv - szv) * t;
+            seg.push((hid, self.work_to_machine(hid, pz + length_comp[ih])));
         }
 
-        self.channels[c_idx].pending.push_back(seg);
+        self.channels[c_idx].pending.push_back(PendingEntry::Move(seg));
     }
 
     // Update programmed position cache from uncompensated geometric end point.
@@ -2366,17 +16635,21 @@ fn parse_float_bytes(&self, bytes: &[u8]) -> (Option<f64>, usize) {
 
 
     pub fn get_full_state(&self) -> JsValue {
+        let cutting: Vec<(f64, f64)> = (0..self.channels.len()).map(|i| self.cutting_state(i)).collect();
         let state = MachineState {
             axes: self.axes.clone(),
-            channels: self.channels.iter().map(|c| ChannelStatus {
+            channels: self.channels.iter().enumerate().map(|(i, c)| ChannelStatus {
                 id: c.id,
                 is_running: c.is_running,
                 paused: c.paused,
+                run_state: c.run_state().to_string(),
                 active_pc: c.active_pc,
+                block_number: c.block_number,
                 pc: c.pc,
                 axis_map: c.axis_map.clone(),
                 current_motion: c.current_motion,
                 exact_stop: c.exact_stop,
+                path_tolerance: c.path_tolerance.unwrap_or(self.tolerances.arc_chord_mm),
                 cutter_comp: c.cutter_comp,
                 tool_radius: c.tool_radius,
                 length_comp_active: c.length_comp_active,
@@ -2389,38 +16662,138 @@ fn parse_float_bytes(&self, bytes: &[u8]) -> (Option<f64>, usize) {
                 coolant_on: c.coolant_on,
                 feed_rate: c.feed_rate,
                 feed_override: c.feed_override,
+                spindle_override: c.spindle_override,
+                feed_override_enabled: c.feed_override_enabled,
+                spindle_override_enabled: c.spindle_override_enabled,
+                overrides_program_locked: c.overrides_program_locked,
                 single_block: c.single_block,
+                dry_geometric: c.dry_geometric,
+                arc_center_absolute: c.arc_center_absolute,
+                thread_lead: c.thread_lead,
+                thread_lead_change: c.thread_lead_change,
+                thread_start_angle: c.thread_start_angle,
+                rigid_tapping: c.rigid_tap.is_some(),
+                spindle_actual_rpm: c.spindle_actual_rpm,
+                spindle_angle: c.spindle_revs * 360.0,
+                retracing: c.retracing,
+                auto_hold: c.auto_hold,
+                turret_station: c.turret.as_ref().map(|t| t.station).unwrap_or(0),
+                turret_angle: c.turret.as_ref().map(|t| t.angle_deg).unwrap_or(0.0),
+                pending_tool: c.pending_tool,
+                tool_change: c.tool_change.clone(),
+                part_count: c.part_count,
+                parts_total: c.parts_total,
+                part_target: c.part_target,
+                parts_target_reached: c.parts_target_reached,
                 programmed_work: c.axis_map.iter().map(|m| AxisOffset {
                     axis_id: m.axis_id,
                     value: c.programmed_work.get(&m.axis_id).copied().unwrap_or(0.0),
                 }).collect(),
+                surface_speed: cutting[i].0,
+                chip_load: cutting[i].1,
             }).collect(),
             estop: self.estop,
             feed_hold: self.feed_hold,
+            block_delete: self.block_delete,
+            optional_stop: self.optional_stop,
             active_wcs: self.active_wcs,
             work_offsets: self.work_offsets.clone(),
             is_homing: self.is_homing,
+            alarms: self.alarms.clone(),
+            dialect: self.dialect,
+            jogs: self.jogs.clone(),
+            local_shift: self.local_shift.iter().map(|(&axis_id, &value)| AxisOffset { axis_id, value }).collect(),
         };
         serde_wasm_bindgen::to_value(&state).unwrap_or(JsValue::NULL)
     }
 
     pub fn set_axis_accel(&mut self, axis_id: u32, accel: f64) {
-        if let Some(ax) = self.axes.get_mut(axis_id as usize) {
+        if !self.permitted(Permission::Settings, "set_axis_accel") {
+            return;
+        }
+        if let Some(ax) = find_axis_mut(&mut self.axes, axis_id) {
             ax.accel = accel;
         }
     }
     #[wasm_bindgen]
     pub fn set_axis_machine_zero(&mut self, axis_id: u32, machine_zero: f64) {
-        if let Some(ax) = self.axes.get_mut(axis_id as usize) {
+        if !self.permitted(Permission::Settings, "set_axis_machine_zero") {
+            return;
+        }
+        if let Some(ax) = find_axis_mut(&mut self.axes, axis_id) {
             ax.machine_zero = machine_zero;
         }
     }
     #[wasm_bindgen]
     pub fn set_axis_invert(&mut self, axis_id: u32, invert: bool) {
-        if let Some(ax) = self.axes.get_mut(axis_id as usize) {
+        if !self.permitted(Permission::Settings, "set_axis_invert") {
+            return;
+        }
+        if let Some(ax) = find_axis_mut(&mut self.axes, axis_id) {
             ax.invert = invert;
         }
     }
+    /// Kinematic chain of an axis: `side` is "tool" or "table", `link` the
+    /// X/Y/Z/A/B/C direction it drives ("" = from its name).
+    #[wasm_bindgen]
+    pub fn set_axis_kinematics(&mut self, axis_id: u32, side: String, link: String) -> bool {
+        let side = match side.trim().to_lowercase().as_str() {
+            "tool" => AxisSide::Tool,
+            "table" => AxisSide::Table,
+            _ => return false,
+        };
+        let link = link.trim().to_uppercase().chars().next();
+        if link.is_some_and(|c| !"XYZABC".contains(c)) || !self.permitted(Permission::Settings, "set_axis_kinematics") {
+            return false;
+        }
+        let Some(ax) = find_axis_mut(&mut self.axes, axis_id) else { return false; };
+        ax.side = side;
+        ax.link_axis = link;
+        true
+    }
+
+    /// Range handling of a rotary axis: "continuous" (wraps ±180°) or
+    /// "limited" (clamped to its min/max). False for linear axes.
+    pub fn set_rotary_mode(&mut self, axis_id: u32, mode: String) -> bool {
+        let mode = match mode.trim().to_lowercase().as_str() {
+            "continuous" => RotaryMode::Continuous,
+            "limited" => RotaryMode::Limited,
+            _ => return false,
+        };
+        if !self.permitted(Permission::Settings, "set_rotary_mode") {
+            return false;
+        }
+        let Some(ax) = find_axis_mut(&mut self.axes, axis_id) else { return false; };
+        if ax.axis_type != AxisType::Rotary {
+            return false;
+        }
+        ax.rotary_mode = mode;
+        true
+    }
 }
 
 
+
+// ── Native embedding ───────────────────────────────────────────────────────
+// Plain Rust entry points for native hosts (the `vmill-verify` CLI, test
+// harnesses); kept out of the wasm-bindgen surface.
+
+impl MachineBrain {
+    /// Applies a machine configuration given as JSON (see `reconfigure`).
+    pub fn apply_config_json(&mut self, config_json: &str) -> ReconfigureReport {
+        self.reconfigure_json(config_json)
+    }
+
+    /// Job report of `code` verified offline; `None` for an unknown channel.
+    pub fn verification_report(&self, channel_index: usize, code: &str) -> Option<JobReport> {
+        self.verification_job_report(channel_index, code)
+    }
+}
+
+impl JobReport {
+    /// Gate for CI: the program ran to completion within the machine's
+    /// travel, without warnings or faults.
+    pub fn passed(&self) -> bool {
+        self.completed && self.travel_exceeded.is_empty() && self.alarms.iter().all(|a| a.severity == AlarmSeverity::Info)
+    }
+}
@@ -1033,6 +1033,7 @@ export default function App() {
           if (!r.trim()) return;
           const [ch, lab, lin, min, max, acc, inv, zero] = r.split(';');
           const id = sim.add_axis(lab, lin === '1' ? AxisType.Linear : AxisType.Rotary, +min, +max);
+          if (id === undefined) return;
           sim.set_axis_accel(id, +(acc ?? 2000));
           sim.set_axis_invert(id, inv === '1');
           (sim as any).set_axis_machine_zero?.(id, +(zero ?? 0));
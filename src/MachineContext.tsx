@@ -84,6 +84,7 @@ export function MachineProvider({ children }: { children: ReactNode }) {
           +min,
           +max
         );
+        if (axisId === undefined) return;
         core.set_axis_accel(axisId, +(accel ?? 2000));
         core.set_axis_invert(axisId, invert === '1');
         (core as any).set_axis_machine_zero?.(axisId, +(machineZero ?? 0));